@@ -0,0 +1,53 @@
+use std::{
+    env::args,
+    hint::black_box,
+    thread,
+    time::{Duration, Instant},
+};
+
+use futex::mutex::Mutex;
+
+/// Show the spin budget of a contended mutex converging for short and for long hold times.
+///
+/// Short holds should settle near the full budget of `256` on a machine with spare cores, and long holds near zero.
+/// On a single core, spinning never lets the holder run, so both settle near zero.
+pub fn main() {
+    let threads = args()
+        .nth(1)
+        .map(|n| n.parse().unwrap())
+        .unwrap_or_else(|| thread::available_parallelism().unwrap().get().clamp(2, 8));
+
+    run("short holds", threads, 1 << 16, || {
+        for i in 0..64 {
+            black_box(i);
+        }
+    });
+    run("long holds", threads, 1 << 8, || {
+        thread::sleep(Duration::from_millis(1));
+    });
+}
+
+fn run(name: &str, threads: usize, locks: usize, hold: impl Fn() + Sync) {
+    let m = Mutex::new(0);
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for _ in 0..locks {
+                    let mut guard = m.lock();
+                    hold();
+                    *guard += 1;
+                }
+            });
+        }
+        while m.try_lock().is_none_or(|n| *n < threads * locks) {
+            thread::sleep(Duration::from_millis(50));
+            println!("{name}: spin budget {}", m.spin_budget());
+        }
+    });
+    println!(
+        "{name}: {threads} threads x {locks} locks in {:?}; final spin budget {}",
+        start.elapsed(),
+        m.spin_budget()
+    );
+}
@@ -1,80 +1,80 @@
 use std::{
     env::args,
-    io,
     sync::atomic::{AtomicU32, Ordering},
 };
 
-use futex::{futex_wait, futex_wake, FutexWaitContext, WakeWaiters, U31};
+use futex::{futex_wait_ptr, futex_wake_ptr, FutexError, WakeWaiters};
 use nix::{
     sys::wait::wait,
     unistd::{fork, ForkResult},
 };
-use rustix::mm::{mmap_anonymous, MapFlags, ProtFlags};
+use rustix::mm::{mmap_anonymous, munmap, MapFlags, ProtFlags};
+
+const UNAVAILABLE: u32 = 0;
+const AVAILABLE: u32 = 1;
 
 /// Based on the `futex_demo` on <https://lwn.net/Articles/638283/>.
+///
+/// The two futex words live in a mapping unmapped by hand, so they are only ever reached through raw pointers.
+/// A reference to a word lives no longer than one atomic operation, and none is made for a wait or a wake.
+///
+/// [`futex::ipc::demo`] is the same alternation over [`futex::ipc::SharedMapping`] instead.
 pub fn main() {
     let n_loops = args().nth(1).map(|n| n.parse().unwrap()).unwrap_or(5);
 
-    let i_addr = unsafe {
+    let len = std::mem::size_of::<[u32; 2]>();
+    let region = unsafe {
         mmap_anonymous(
             std::ptr::null_mut(),
-            std::mem::size_of::<AtomicU32>() * 2,
+            len,
             ProtFlags::READ | ProtFlags::WRITE,
             MapFlags::SHARED,
         )
     }
     .expect("mmap");
-    let i_addr: *mut [AtomicU32; 2] = unsafe { std::mem::transmute(i_addr) };
-
-    let futex_1 = unsafe { &(*i_addr)[0] };
-    let futex_2 = unsafe { &(*i_addr)[1] };
-
-    futex_1.store(FutexState::Unavailable.into(), Ordering::SeqCst);
-    futex_2.store(FutexState::Available.into(), Ordering::SeqCst);
+    // Page-aligned, so both words are 4-byte aligned
+    let futex_1 = region.cast::<u32>();
+    let futex_2 = unsafe { futex_1.add(1) };
+    unsafe {
+        futex_1.write(UNAVAILABLE);
+        futex_2.write(AVAILABLE);
+    }
 
     let child_pid = unsafe { fork() }.expect("fork");
+    let pid = std::process::id();
     match child_pid {
         ForkResult::Parent { .. } => {
             for j in 0..n_loops {
-                f_wait(futex_2);
-                let pid = std::process::id();
+                unsafe { f_wait(futex_2) };
                 println!("Parent  ({pid}) {j}");
-                f_post(futex_1);
+                unsafe { f_post(futex_1) };
             }
 
             wait().unwrap();
         }
         ForkResult::Child => {
             for j in 0..n_loops {
-                f_wait(futex_1);
-                let pid = std::process::id();
+                unsafe { f_wait(futex_1) };
                 println!("Child  ({pid}) {j}");
-                f_post(futex_2);
+                unsafe { f_post(futex_2) };
             }
         }
     }
-}
 
-enum FutexState {
-    Unavailable = 0,
-    Available,
-}
-impl From<FutexState> for u32 {
-    fn from(value: FutexState) -> Self {
-        value as u32
-    }
+    // Each process unmaps its own view; no word is touched past here
+    unsafe { munmap(region, len) }.expect("munmap");
 }
 
-fn f_wait(futex_p: &AtomicU32) {
+/// Take the word from available to unavailable, sleeping while it is unavailable.
+///
+/// # Safety
+///
+/// `futex_p` must be 4-byte aligned and mapped for the duration of the call, and only ever accessed atomically.
+unsafe fn f_wait(futex_p: *mut u32) {
     loop {
         // Is the futex available?
-        if futex_p
-            .compare_exchange(
-                FutexState::Available.into(),
-                FutexState::Unavailable.into(),
-                Ordering::Acquire,
-                Ordering::Relaxed,
-            )
+        if unsafe { AtomicU32::from_ptr(futex_p) }
+            .compare_exchange(AVAILABLE, UNAVAILABLE, Ordering::Acquire, Ordering::Relaxed)
             .is_ok()
         {
             // Yes
@@ -82,32 +82,24 @@ fn f_wait(futex_p: &AtomicU32) {
         }
 
         // Futex is not available; wait
-        if let Err(e) = futex_wait(FutexWaitContext {
-            word: futex_p,
-            expected: FutexState::Unavailable.into(),
-            timeout: None,
-        }) {
-            if !matches!(e.kind(), io::ErrorKind::WouldBlock) {
-                panic!("futex-FUTEX_WAIT");
+        if let Err(e) = unsafe { futex_wait_ptr(futex_p, UNAVAILABLE, None) } {
+            if !matches!(e, FutexError::ValueMismatch | FutexError::Interrupted) {
+                panic!("futex-FUTEX_WAIT: {e}");
             }
         }
     }
 }
 
-fn f_post(futex_p: &AtomicU32) {
-    if futex_p
-        .compare_exchange(
-            FutexState::Unavailable.into(),
-            FutexState::Available.into(),
-            Ordering::Release,
-            Ordering::Relaxed,
-        )
+/// Make the word available and wake its waiter.
+///
+/// # Safety
+///
+/// Learn more from [`f_wait`].
+unsafe fn f_post(futex_p: *mut u32) {
+    if unsafe { AtomicU32::from_ptr(futex_p) }
+        .compare_exchange(UNAVAILABLE, AVAILABLE, Ordering::Release, Ordering::Relaxed)
         .is_ok()
     {
-        if let Err(e) = futex_wake(futex_p, WakeWaiters::Amount(U31::new(1).unwrap())) {
-            if !matches!(e.kind(), io::ErrorKind::WouldBlock) {
-                panic!("futex-FUTEX_WAKE");
-            }
-        }
+        unsafe { futex_wake_ptr(futex_p, WakeWaiters::ONE) }.expect("futex-FUTEX_WAKE");
     }
 }
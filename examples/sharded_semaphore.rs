@@ -0,0 +1,39 @@
+use std::{env::args, thread, time::Instant};
+
+use futex::{semaphore::Semaphore, sharded_semaphore::ShardedSemaphore};
+
+/// Compare the throughput of a plain and a sharded semaphore, each thread taking and giving back permits in a tight loop.
+///
+/// The sharded one should pull ahead at 32 threads and more on a machine with as many cores; on a few cores, the two stay close.
+pub fn main() {
+    let threads = args().nth(1).map(|n| n.parse().unwrap()).unwrap_or(32);
+    let rounds = args().nth(2).map(|n| n.parse().unwrap()).unwrap_or(1 << 16);
+
+    let sem = Semaphore::new(threads as u32);
+    run("plain", threads, rounds, || sem.wait(), || sem.signal());
+    let sem = ShardedSemaphore::new(threads as u32);
+    run("sharded", threads, rounds, || sem.wait(), || sem.signal());
+}
+
+fn run(
+    name: &str,
+    threads: usize,
+    rounds: usize,
+    wait: impl Fn() + Sync,
+    signal: impl Fn() + Sync,
+) {
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for _ in 0..rounds {
+                    wait();
+                    signal();
+                }
+            });
+        }
+    });
+    let elapsed = start.elapsed();
+    let ops = (threads * rounds) as f64 / elapsed.as_secs_f64();
+    println!("{name}: {threads} threads x {rounds} rounds in {elapsed:?}; {ops:.0} waits/s");
+}
@@ -0,0 +1,38 @@
+use std::{env::args, hint::black_box, time::Instant};
+
+use futex::{mutex::Mutex, semaphore::Semaphore};
+
+/// Time the uncontended fast paths: one thread locking and unlocking a mutex, and taking and giving back a semaphore permit, in a tight loop.
+///
+/// Neither path makes a syscall, so each round should take tens of nanoseconds at most.
+/// The wait of a semaphore guesses a single permit for its first CAS, so one holding many permits pays for a second CAS every round.
+pub fn main() {
+    let rounds = args().nth(1).map(|n| n.parse().unwrap()).unwrap_or(1 << 24);
+
+    let mutex = Mutex::new(0_u64);
+    run("mutex lock/unlock", rounds, || *mutex.lock() += 1);
+    let sem = Semaphore::new(1);
+    run("semaphore wait/signal, 1 permit", rounds, || {
+        sem.wait();
+        sem.signal();
+    });
+    let sem = Semaphore::new(64);
+    run("semaphore wait/signal, 64 permits", rounds, || {
+        sem.wait();
+        sem.signal();
+    });
+}
+
+fn run(name: &str, rounds: usize, round: impl Fn()) {
+    // Warm up
+    for _ in 0..rounds / 16 {
+        round();
+    }
+    let start = Instant::now();
+    for _ in 0..rounds {
+        black_box(&round)();
+    }
+    let elapsed = start.elapsed();
+    let ns = elapsed.as_nanos() as f64 / rounds as f64;
+    println!("{name}: {rounds} rounds in {elapsed:?}; {ns:.2} ns/round");
+}
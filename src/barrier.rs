@@ -0,0 +1,223 @@
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    observer::{futex_wake_from, observed_futex_wait, Primitive},
+    FutexError, FutexScope, FutexTimeout, FutexWaitContext, TimeoutMeasure, WakeWaiters,
+};
+
+const COUNT_BITS: u32 = 16;
+const COUNT_MASK: u32 = (1 << COUNT_BITS) - 1;
+
+/// # Word layout
+///
+/// - Bits 0..16: the number of parties arrived in the current generation.
+/// - Bits 16..32: the generation, bumped when the last party arrives.
+///
+/// The generation has 16 bits, so it comes back around after `1 << 16` trips.
+/// A waiter still rechecking the word after its generation tripped takes a generation `1 << 16` trips later for its own, and sleeps through it, or, timing out, withdraws an arrival of it.
+/// With at most `parties` threads using the barrier, the next generation cannot trip before that waiter arrives again, so this takes more threads than `parties` and a waiter descheduled for all of those `1 << 16` trips.
+///
+/// # Timeouts
+///
+/// A party that times out deregisters its arrival, so the barrier stays usable and the remaining parties still need the full complement.
+/// Deregistering and tripping are both CAS on the same word, so a timeout racing the last arrival either withdraws before the trip or observes the trip and succeeds.
+#[derive(Debug)]
+pub struct Barrier {
+    word: AtomicU32,
+    parties: u32,
+}
+impl Barrier {
+    /// # Panic
+    ///
+    /// If `parties` is zero or does not fit in 16 bits.
+    pub fn new(parties: u32) -> Self {
+        assert!(0 < parties);
+        assert!(parties <= COUNT_MASK);
+        Self {
+            word: AtomicU32::new(0),
+            parties,
+        }
+    }
+
+    pub fn wait(&self) -> BarrierWaitResult {
+        self.wait_deadline(None).unwrap()
+    }
+
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<BarrierWaitResult, BarrierTimedOut> {
+        self.wait_deadline(Instant::now().checked_add(timeout))
+    }
+
+    fn wait_deadline(
+        &self,
+        deadline: Option<Instant>,
+    ) -> Result<BarrierWaitResult, BarrierTimedOut> {
+        // Arrive
+        let mut word = self.word.load(Ordering::Relaxed);
+        let generation = loop {
+            let generation = word >> COUNT_BITS;
+            let count = word & COUNT_MASK;
+            let new = if count + 1 == self.parties {
+                generation.wrapping_add(1) << COUNT_BITS
+            } else {
+                (generation << COUNT_BITS) | (count + 1)
+            };
+            match self
+                .word
+                .compare_exchange(word, new, Ordering::AcqRel, Ordering::Relaxed)
+            {
+                Ok(_) => {
+                    if count + 1 == self.parties {
+                        futex_wake_from(
+                            Primitive::Barrier,
+                            &self.word,
+                            WakeWaiters::All,
+                            FutexScope::Shared,
+                        )
+                        .unwrap();
+                        return Ok(BarrierWaitResult { is_leader: true });
+                    }
+                    break generation;
+                }
+                Err(actual) => word = actual,
+            }
+        };
+
+        // Wait for the trip
+        loop {
+            let word = self.word.load(Ordering::Acquire);
+            if word >> COUNT_BITS != generation {
+                return Ok(BarrierWaitResult { is_leader: false });
+            }
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        // Deregister unless the generation has just tripped
+                        if self
+                            .word
+                            .compare_exchange(word, word - 1, Ordering::AcqRel, Ordering::Acquire)
+                            .is_ok()
+                        {
+                            return Err(BarrierTimedOut);
+                        }
+                        continue;
+                    }
+                    Some(FutexTimeout::For(remaining, TimeoutMeasure::MonoTime))
+                }
+                None => None,
+            };
+            if let Err(e) = observed_futex_wait(
+                Primitive::Barrier,
+                FutexWaitContext {
+                    word: &self.word,
+                    expected: word,
+                    timeout,
+                    scope: FutexScope::Shared,
+                },
+            ) {
+                if !matches!(e.error, FutexError::ValueMismatch | FutexError::TimedOut) {
+                    panic!("{e}");
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierWaitResult {
+    is_leader: bool,
+}
+impl BarrierWaitResult {
+    /// Exactly one party of each generation is the leader.
+    pub fn is_leader(&self) -> bool {
+        self.is_leader
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierTimedOut;
+impl std::fmt::Display for BarrierTimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "barrier wait timed out")
+    }
+}
+impl std::error::Error for BarrierTimedOut {}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::{mock_backend::MockBackend, workers::ScopedWorkers};
+
+    use super::*;
+
+    #[test]
+    fn test_generations() {
+        let barrier = Barrier::new(4);
+        let leaders: usize = crate::scope(|s| {
+            ScopedWorkers::spawn_n(s, 4, |_| {
+                (0..100).filter(|_| barrier.wait().is_leader()).count()
+            })
+            .join_timeout(Duration::from_secs(10))
+        })
+        .into_iter()
+        .sum();
+        assert_eq!(leaders, 100);
+    }
+
+    #[test]
+    fn test_timeout_deregisters() {
+        let barrier = Barrier::new(3);
+        assert_eq!(
+            barrier.wait_timeout(Duration::from_millis(20)),
+            Err(BarrierTimedOut)
+        );
+
+        let mock = MockBackend::install();
+        mock.intercept(&barrier);
+        thread::scope(|s| {
+            let a = s.spawn(|| barrier.wait());
+            let b = s.spawn(|| barrier.wait_timeout(Duration::from_secs(10)));
+            // The timed-out arrival does not count
+            mock.wait_until_parked(2);
+            assert!(!a.is_finished());
+            assert!(!b.is_finished());
+
+            let c = barrier.wait();
+            let a = a.join().unwrap();
+            let b = b.join().unwrap().unwrap();
+            let leaders = [a, b, c].iter().filter(|r| r.is_leader()).count();
+            assert_eq!(leaders, 1);
+        });
+
+        // Later generations are unaffected
+        thread::scope(|s| {
+            s.spawn(|| barrier.wait());
+            s.spawn(|| barrier.wait());
+            barrier.wait_timeout(Duration::from_secs(10)).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_timeout_racing_last_arrival() {
+        let barrier = Barrier::new(2);
+        for _ in 0..1000 {
+            thread::scope(|s| {
+                let timed = s.spawn(|| barrier.wait_timeout(Duration::from_micros(50)));
+                thread::sleep(Duration::from_micros(50));
+                match barrier.wait_timeout(Duration::from_micros(50)) {
+                    // Both made it into the same generation
+                    Ok(_) => assert!(timed.join().unwrap().is_ok()),
+                    Err(BarrierTimedOut) => {
+                        // The other one must have withdrawn as well or it would have been tripped by this arrival
+                        assert!(timed.join().unwrap().is_err())
+                    }
+                }
+            });
+            assert_eq!(barrier.word.load(Ordering::SeqCst) & COUNT_MASK, 0);
+        }
+    }
+}
@@ -0,0 +1,1146 @@
+//! Drop-in replacements for the locks of [`std::sync`], built on the primitives of this crate.
+//!
+//! The names, signatures, and poisoning match those of [`std::sync`], so a codebase can migrate one `use` at a time:
+//!
+//! ```
+//! use futex::compat::{Condvar, Mutex};
+//!
+//! let pair = (Mutex::new(false), Condvar::new());
+//! std::thread::scope(|s| {
+//!     s.spawn(|| {
+//!         *pair.0.lock().unwrap() = true;
+//!         pair.1.notify_one();
+//!     });
+//!     let mut started = pair.0.lock().unwrap();
+//!     while !*started {
+//!         started = pair.1.wait(started).unwrap();
+//!     }
+//! });
+//! ```
+//!
+//! The errors are those of [`std::sync`]; [`WaitTimeoutResult`], [`OnceState`], and [`BarrierWaitResult`] are look-alikes, since the standard ones cannot be built outside of it.
+//!
+//! # Poisoning
+//!
+//! A guard dropped while its thread panics poisons its lock, unless the thread was already panicking when it took the guard.
+//! Read guards of [`RwLock`] never poison.
+//!
+//! [`Recoverable`] repairs and unpoisons any of the poisoning types the same way.
+
+use std::{
+    cell::UnsafeCell,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        LockResult, PoisonError, TryLockError, TryLockResult,
+    },
+    time::{Duration, Instant},
+};
+
+pub use crate::barrier::BarrierWaitResult;
+use crate::{
+    barrier, cond_var,
+    deadline::TimedOut,
+    futex_enum::FutexEnum,
+    mutex,
+    observer::{futex_wake_from, observed_futex_wait, Primitive},
+    rw_lock::RawFutexRwLock,
+    violation::violation,
+    FutexError, FutexScope, FutexTimeout, FutexWaitContext, WakeWaiters,
+};
+
+/// The poison flag of a lock.
+#[derive(Debug)]
+struct Flag(AtomicBool);
+impl Flag {
+    const fn new() -> Self {
+        Self(AtomicBool::new(false))
+    }
+
+    fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn clear(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    /// Taken along with a guard.
+    fn guard(&self) -> PoisonOnPanic {
+        PoisonOnPanic {
+            panicking: std::thread::panicking(),
+        }
+    }
+
+    fn done(&self, guard: &PoisonOnPanic) {
+        if !guard.panicking && std::thread::panicking() {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn result<T>(&self, value: T) -> LockResult<T> {
+        match self.get() {
+            true => Err(PoisonError::new(value)),
+            false => Ok(value),
+        }
+    }
+}
+/// Whether the thread was already panicking when it took the guard.
+#[derive(Debug)]
+struct PoisonOnPanic {
+    panicking: bool,
+}
+
+/// A type that a panicking holder poisons, repaired the same way whatever the type.
+///
+/// A recovery takes the same exclusive access as a write, so it waits for the holders to leave, including the readers of a [`RwLock`]; [`Self::recover_until`] bounds that wait.
+pub trait Recoverable {
+    /// What a recovery hands to its closure.
+    type Inner: ?Sized;
+
+    /// Only a snapshot.
+    fn is_poisoned(&self) -> bool;
+
+    /// Trust the state as it is, without repairing it.
+    fn clear_poison(&self);
+
+    /// Repair the state with `f` under exclusive access, and clear the poison only once `f` returns.
+    ///
+    /// If `f` panics, the poison stays and the panic propagates.
+    fn recover_with(&self, f: impl FnOnce(&mut Self::Inner));
+
+    /// [`Self::recover_with`], but give up with `f` not run once `deadline` passes without exclusive access.
+    fn recover_until(
+        &self,
+        deadline: Instant,
+        f: impl FnOnce(&mut Self::Inner),
+    ) -> Result<(), TimedOut>;
+}
+
+/// [`std::sync::Mutex`] on [`mutex::Mutex`].
+pub struct Mutex<T> {
+    inner: mutex::Mutex<T>,
+    poison: Flag,
+}
+impl<T> Mutex<T> {
+    pub const fn new(t: T) -> Self {
+        Self {
+            inner: mutex::Mutex::new(t),
+            poison: Flag::new(),
+        }
+    }
+
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        let guard = MutexGuard::new(self, self.inner.lock());
+        self.poison.result(guard)
+    }
+
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        let Some(inner) = self.inner.try_lock() else {
+            return Err(TryLockError::WouldBlock);
+        };
+        Ok(self.poison.result(MutexGuard::new(self, inner))?)
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    pub fn clear_poison(&self) {
+        self.poison.clear();
+    }
+
+    /// Only a snapshot.
+    #[cfg(feature = "registry")]
+    pub(crate) fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.poison.get();
+        let value = self.inner.into_inner();
+        match poisoned {
+            true => Err(PoisonError::new(value)),
+            false => Ok(value),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let poisoned = self.poison.get();
+        let value = self.inner.get_mut();
+        match poisoned {
+            true => Err(PoisonError::new(value)),
+            false => Ok(value),
+        }
+    }
+}
+impl<T> Recoverable for Mutex<T> {
+    type Inner = T;
+
+    fn is_poisoned(&self) -> bool {
+        self.is_poisoned()
+    }
+
+    fn clear_poison(&self) {
+        self.clear_poison();
+    }
+
+    fn recover_with(&self, f: impl FnOnce(&mut T)) {
+        let mut guard = MutexGuard::new(self, self.inner.lock());
+        f(&mut guard);
+        self.poison.clear();
+    }
+
+    fn recover_until(&self, deadline: Instant, f: impl FnOnce(&mut T)) -> Result<(), TimedOut> {
+        let inner = self.inner.lock_until(deadline).ok_or(TimedOut(()))?;
+        let mut guard = MutexGuard::new(self, inner);
+        f(&mut guard);
+        self.poison.clear();
+        Ok(())
+    }
+}
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+impl<T> From<T> for Mutex<T> {
+    fn from(t: T) -> Self {
+        Self::new(t)
+    }
+}
+impl<T: std::fmt::Debug> std::fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("Mutex");
+        match self.inner.try_lock() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+        d.field("poisoned", &self.poison.get());
+        d.finish_non_exhaustive()
+    }
+}
+
+/// [`std::sync::MutexGuard`].
+pub struct MutexGuard<'a, T> {
+    lock: &'a Mutex<T>,
+    /// Taken out by [`Condvar`] to wait on, skipping the drop of this guard
+    inner: ManuallyDrop<mutex::MutexGuard<'a, T>>,
+    poison: PoisonOnPanic,
+}
+impl<'a, T> MutexGuard<'a, T> {
+    fn new(lock: &'a Mutex<T>, inner: mutex::MutexGuard<'a, T>) -> Self {
+        Self {
+            lock,
+            inner: ManuallyDrop::new(inner),
+            poison: lock.poison.guard(),
+        }
+    }
+
+    /// Hand the inner guard over to a wait, poisoning the lock if the thread is panicking, as a drop would.
+    fn into_inner(self) -> (&'a Mutex<T>, mutex::MutexGuard<'a, T>) {
+        let mut this = ManuallyDrop::new(self);
+        this.lock.poison.done(&this.poison);
+        (this.lock, unsafe { ManuallyDrop::take(&mut this.inner) })
+    }
+}
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.poison.done(&self.poison);
+        unsafe { ManuallyDrop::drop(&mut self.inner) };
+    }
+}
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+impl<T: std::fmt::Debug> std::fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(&**self, f)
+    }
+}
+impl<T: std::fmt::Display> std::fmt::Display for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&**self, f)
+    }
+}
+
+/// [`std::sync::Condvar`] on [`cond_var::CondVar`].
+#[derive(Debug)]
+pub struct Condvar {
+    inner: cond_var::CondVar,
+}
+impl Condvar {
+    pub fn new() -> Self {
+        Self {
+            inner: cond_var::CondVar::new(),
+        }
+    }
+
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> LockResult<MutexGuard<'a, T>> {
+        let (lock, inner) = guard.into_inner();
+        let inner = self.inner.wait(inner);
+        lock.poison.result(MutexGuard::new(lock, inner))
+    }
+
+    pub fn wait_while<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> LockResult<MutexGuard<'a, T>>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard)?;
+        }
+        Ok(guard)
+    }
+
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        dur: Duration,
+    ) -> LockResult<(MutexGuard<'a, T>, WaitTimeoutResult)> {
+        let (lock, inner) = guard.into_inner();
+        let (inner, timed_out) = self.inner.wait_timeout(inner, dur);
+        lock.poison
+            .result((MutexGuard::new(lock, inner), WaitTimeoutResult(timed_out)))
+    }
+
+    pub fn wait_timeout_while<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        dur: Duration,
+        mut condition: F,
+    ) -> LockResult<(MutexGuard<'a, T>, WaitTimeoutResult)>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        let deadline = Instant::now().checked_add(dur);
+        while condition(&mut *guard) {
+            let remaining = deadline.map_or(dur, |deadline| {
+                deadline.saturating_duration_since(Instant::now())
+            });
+            if remaining.is_zero() {
+                return Ok((guard, WaitTimeoutResult(true)));
+            }
+            guard = self.wait_timeout(guard, remaining)?.0;
+        }
+        Ok((guard, WaitTimeoutResult(false)))
+    }
+
+    pub fn notify_one(&self) {
+        self.inner.notify_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.inner.notify_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// [`std::sync::WaitTimeoutResult`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
+impl WaitTimeoutResult {
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+/// [`std::sync::RwLock`] on [`RawFutexRwLock`].
+pub struct RwLock<T> {
+    raw: RawFutexRwLock,
+    poison: Flag,
+    data: UnsafeCell<T>,
+}
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+impl<T> RwLock<T> {
+    pub const fn new(t: T) -> Self {
+        Self {
+            raw: RawFutexRwLock::new(),
+            poison: Flag::new(),
+            data: UnsafeCell::new(t),
+        }
+    }
+
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+        self.raw.lock_shared();
+        self.poison.result(RwLockReadGuard { lock: self })
+    }
+
+    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<'_, T>> {
+        if !self.raw.try_lock_shared() {
+            return Err(TryLockError::WouldBlock);
+        }
+        Ok(self.poison.result(RwLockReadGuard { lock: self })?)
+    }
+
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
+        self.raw.lock_exclusive();
+        self.poison.result(RwLockWriteGuard {
+            lock: self,
+            poison: self.poison.guard(),
+        })
+    }
+
+    pub fn try_write(&self) -> TryLockResult<RwLockWriteGuard<'_, T>> {
+        if !self.raw.try_lock_exclusive() {
+            return Err(TryLockError::WouldBlock);
+        }
+        Ok(self.poison.result(RwLockWriteGuard {
+            lock: self,
+            poison: self.poison.guard(),
+        })?)
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    pub fn clear_poison(&self) {
+        self.poison.clear();
+    }
+
+    /// Only a snapshot; shared or exclusive.
+    #[cfg(feature = "registry")]
+    pub(crate) fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.poison.get();
+        let value = self.data.into_inner();
+        match poisoned {
+            true => Err(PoisonError::new(value)),
+            false => Ok(value),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let poisoned = self.poison.get();
+        let value = self.data.get_mut();
+        match poisoned {
+            true => Err(PoisonError::new(value)),
+            false => Ok(value),
+        }
+    }
+}
+impl<T> Recoverable for RwLock<T> {
+    type Inner = T;
+
+    fn is_poisoned(&self) -> bool {
+        self.is_poisoned()
+    }
+
+    fn clear_poison(&self) {
+        self.clear_poison();
+    }
+
+    fn recover_with(&self, f: impl FnOnce(&mut T)) {
+        self.raw.lock_exclusive();
+        self.recover_locked(f);
+    }
+
+    fn recover_until(&self, deadline: Instant, f: impl FnOnce(&mut T)) -> Result<(), TimedOut> {
+        if !self.raw.try_lock_exclusive_until(deadline) {
+            return Err(TimedOut(()));
+        }
+        self.recover_locked(f);
+        Ok(())
+    }
+}
+impl<T> RwLock<T> {
+    /// Must hold the lock exclusively, which the guard releases.
+    fn recover_locked(&self, f: impl FnOnce(&mut T)) {
+        let mut guard = RwLockWriteGuard {
+            lock: self,
+            poison: self.poison.guard(),
+        };
+        f(&mut guard);
+        self.poison.clear();
+    }
+}
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+impl<T> From<T> for RwLock<T> {
+    fn from(t: T) -> Self {
+        Self::new(t)
+    }
+}
+impl<T: std::fmt::Debug> std::fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("RwLock");
+        match self.try_read() {
+            Ok(guard) => d.field("data", &&*guard),
+            Err(TryLockError::Poisoned(e)) => d.field("data", &&**e.get_ref()),
+            Err(TryLockError::WouldBlock) => d.field("data", &format_args!("<locked>")),
+        };
+        d.field("poisoned", &self.poison.get());
+        d.finish_non_exhaustive()
+    }
+}
+
+/// [`std::sync::RwLockReadGuard`].
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { self.lock.raw.unlock_shared() };
+    }
+}
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+impl<T: std::fmt::Debug> std::fmt::Debug for RwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// [`std::sync::RwLockWriteGuard`].
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    poison: PoisonOnPanic,
+}
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.poison.done(&self.poison);
+        unsafe { self.lock.raw.unlock_exclusive() };
+    }
+}
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+impl<T: std::fmt::Debug> std::fmt::Debug for RwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// [`std::sync::Barrier`] on [`barrier::Barrier`].
+#[derive(Debug)]
+pub struct Barrier {
+    inner: barrier::Barrier,
+}
+impl Barrier {
+    /// A barrier of zero parties behaves as one of a single party, as in [`std::sync::Barrier`].
+    ///
+    /// # Panic
+    ///
+    /// If `n` does not fit in 16 bits; learn more from [`barrier::Barrier::new`].
+    pub fn new(n: usize) -> Self {
+        let n = u32::try_from(n.max(1)).unwrap();
+        Self {
+            inner: barrier::Barrier::new(n),
+        }
+    }
+
+    pub fn wait(&self) -> BarrierWaitResult {
+        self.inner.wait()
+    }
+}
+
+crate::futex_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum OnceWord {
+        Incomplete = 0,
+        Running,
+        Complete,
+        Poisoned,
+    }
+}
+
+/// [`std::sync::Once`] on a futex word.
+#[derive(Debug)]
+pub struct Once {
+    state: AtomicU32,
+}
+impl Once {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(OnceWord::Incomplete as u32),
+        }
+    }
+
+    /// # Panic
+    ///
+    /// If `f` panicked on an earlier call, poisoning the instance.
+    pub fn call_once<F: FnOnce()>(&self, f: F) {
+        if self.is_completed() {
+            return;
+        }
+        let mut f = Some(f);
+        self.call(false, None, &mut |_| f.take().unwrap()())
+            .unwrap();
+    }
+
+    /// [`Self::call_once`], but also run on a poisoned instance, telling `f` through [`OnceState::is_poisoned`].
+    pub fn call_once_force<F: FnOnce(&OnceState)>(&self, f: F) {
+        if self.is_completed() {
+            return;
+        }
+        let mut f = Some(f);
+        self.call(true, None, &mut |state| f.take().unwrap()(state))
+            .unwrap();
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state() == OnceWord::Complete
+    }
+
+    fn state(&self) -> OnceWord {
+        let word = self.state.load(Ordering::Acquire);
+        match OnceWord::from_word(word) {
+            Ok(state) => state,
+            Err(_) => violation!(UnknownState, word),
+        }
+    }
+
+    /// `f` is called at most once, and not at all past `deadline`.
+    #[cold]
+    fn call(
+        &self,
+        ignore_poisoning: bool,
+        deadline: Option<Instant>,
+        f: &mut dyn FnMut(&OnceState),
+    ) -> Result<(), TimedOut> {
+        let mut f = Some(f);
+        let timeout = deadline.map(FutexTimeout::Until);
+        loop {
+            let state = self.state();
+            match state {
+                OnceWord::Complete => return Ok(()),
+                OnceWord::Poisoned if !ignore_poisoning => {
+                    panic!("Once instance has previously been poisoned")
+                }
+                OnceWord::Incomplete | OnceWord::Poisoned => {
+                    if self
+                        .state
+                        .compare_exchange(
+                            state.into(),
+                            OnceWord::Running.into(),
+                            Ordering::Acquire,
+                            Ordering::Acquire,
+                        )
+                        .is_err()
+                    {
+                        continue;
+                    }
+                    let mut finish = Finish {
+                        state: &self.state,
+                        to: OnceWord::Poisoned,
+                    };
+                    let once_state = OnceState {
+                        poisoned: state == OnceWord::Poisoned,
+                    };
+                    (f.take().unwrap())(&once_state);
+                    finish.to = OnceWord::Complete;
+                    return Ok(());
+                }
+                OnceWord::Running => {
+                    if timeout.is_some_and(|t| t.remaining().is_zero()) {
+                        return Err(TimedOut(()));
+                    }
+                    if let Err(e) = observed_futex_wait(
+                        Primitive::Once,
+                        FutexWaitContext {
+                            word: &self.state,
+                            expected: OnceWord::Running.into(),
+                            timeout,
+                            scope: FutexScope::Shared,
+                        },
+                    ) {
+                        if !matches!(e.error, FutexError::ValueMismatch | FutexError::TimedOut) {
+                            panic!("{e}");
+                        }
+                    }
+                }
+            }
+        }
+
+        /// Poison the state if `f` unwinds
+        struct Finish<'a> {
+            state: &'a AtomicU32,
+            to: OnceWord,
+        }
+        impl Drop for Finish<'_> {
+            fn drop(&mut self) {
+                self.state.store(self.to.into(), Ordering::Release);
+                futex_wake_from(
+                    Primitive::Once,
+                    self.state,
+                    WakeWaiters::All,
+                    FutexScope::Shared,
+                )
+                .unwrap();
+            }
+        }
+    }
+}
+/// A recovery runs `f` in place of the initializer unless the instance is complete, completing it once `f` returns.
+impl Recoverable for Once {
+    type Inner = ();
+
+    fn is_poisoned(&self) -> bool {
+        self.state() == OnceWord::Poisoned
+    }
+
+    /// Let the next call run its initializer as if the instance were new.
+    fn clear_poison(&self) {
+        let _ = self.state.compare_exchange(
+            OnceWord::Poisoned.into(),
+            OnceWord::Incomplete.into(),
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+
+    fn recover_with(&self, f: impl FnOnce(&mut ())) {
+        let mut f = Some(f);
+        self.call(true, None, &mut |_| f.take().unwrap()(&mut ()))
+            .unwrap();
+    }
+
+    fn recover_until(&self, deadline: Instant, f: impl FnOnce(&mut ())) -> Result<(), TimedOut> {
+        let mut f = Some(f);
+        self.call(true, Some(deadline), &mut |_| f.take().unwrap()(&mut ()))
+    }
+}
+impl Default for Once {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// [`std::sync::OnceState`].
+#[derive(Debug)]
+pub struct OnceState {
+    poisoned: bool,
+}
+impl OnceState {
+    /// Whether an earlier call panicked.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Ported from the tests of [`std::sync`].
+
+    use std::{
+        panic::AssertUnwindSafe,
+        sync::{atomic::AtomicUsize, Arc},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_mutex_smoke() {
+        let m = Mutex::new(());
+        drop(m.lock().unwrap());
+        drop(m.lock().unwrap());
+    }
+
+    #[test]
+    fn test_mutex_lots_and_lots() {
+        const J: u32 = 1000;
+        const K: u32 = 3;
+        let m = Mutex::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..K * 2 {
+                s.spawn(|| {
+                    for _ in 0..J {
+                        *m.lock().unwrap() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*m.lock().unwrap(), J * K * 2);
+    }
+
+    #[test]
+    fn test_mutex_try_lock() {
+        let m = Mutex::new(());
+        let guard = m.try_lock().unwrap();
+        assert!(matches!(m.try_lock(), Err(TryLockError::WouldBlock)));
+        drop(guard);
+        *m.try_lock().unwrap() = ();
+    }
+
+    #[test]
+    fn test_mutex_into_inner_and_get_mut() {
+        let mut m = Mutex::new(vec![1]);
+        m.get_mut().unwrap().push(2);
+        assert_eq!(m.into_inner().unwrap(), [1, 2]);
+    }
+
+    #[test]
+    fn test_mutex_poison() {
+        let m = Arc::new(Mutex::new(1));
+        assert!(!m.is_poisoned());
+        let m2 = Arc::clone(&m);
+        let res = std::thread::spawn(move || {
+            let _guard = m2.lock().unwrap();
+            panic!("test panic in inner thread to poison mutex");
+        })
+        .join();
+        assert!(res.is_err());
+        assert!(m.is_poisoned());
+        match m.lock() {
+            Ok(_) => panic!("lock of poisoned mutex succeeded"),
+            Err(e) => assert_eq!(**e.get_ref(), 1),
+        }
+        assert!(matches!(m.try_lock(), Err(TryLockError::Poisoned(_))));
+
+        m.clear_poison();
+        assert!(!m.is_poisoned());
+        assert_eq!(*m.lock().unwrap(), 1);
+
+        let m = Arc::into_inner(m).unwrap();
+        let _ = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = m.lock().unwrap();
+            panic!();
+        }));
+        assert_eq!(m.into_inner().unwrap_err().into_inner(), 1);
+    }
+
+    #[test]
+    fn test_mutex_access_in_unwind() {
+        let m = Mutex::new(1);
+        let _ = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            struct Unwinder<'a>(&'a Mutex<i32>);
+            impl Drop for Unwinder<'_> {
+                fn drop(&mut self) {
+                    *self.0.lock().unwrap() += 1;
+                }
+            }
+            let _u = Unwinder(&m);
+            panic!();
+        }));
+        // A guard taken while already panicking does not poison
+        assert!(!m.is_poisoned());
+        assert_eq!(*m.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_condvar_notify_one() {
+        let m = Mutex::new(());
+        let c = Condvar::new();
+        let g = m.lock().unwrap();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let _g = m.lock().unwrap();
+                c.notify_one();
+            });
+            let _g = c.wait(g).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_condvar_notify_all() {
+        const N: usize = 10;
+        let pair = (Mutex::new(0), Condvar::new());
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::scope(|s| {
+            for _ in 0..N {
+                let tx = tx.clone();
+                let pair = &pair;
+                s.spawn(move || {
+                    let mut cnt = pair.0.lock().unwrap();
+                    *cnt += 1;
+                    if *cnt == N {
+                        tx.send(()).unwrap();
+                    }
+                    while *cnt != 0 {
+                        cnt = pair.1.wait(cnt).unwrap();
+                    }
+                    tx.send(()).unwrap();
+                });
+            }
+            drop(tx);
+            rx.recv().unwrap();
+            *pair.0.lock().unwrap() = 0;
+            pair.1.notify_all();
+            for _ in 0..N {
+                rx.recv().unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_condvar_wait_while() {
+        let pair = (Mutex::new(false), Condvar::new());
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                *pair.0.lock().unwrap() = true;
+                pair.1.notify_one();
+            });
+            let guard = pair
+                .1
+                .wait_while(pair.0.lock().unwrap(), |started| !*started);
+            assert!(*guard.unwrap());
+        });
+    }
+
+    #[test]
+    fn test_condvar_wait_timeout() {
+        let m = Mutex::new(());
+        let c = Condvar::new();
+        let g = m.lock().unwrap();
+        let (g, res) = c.wait_timeout(g, Duration::from_millis(1)).unwrap();
+        assert!(res.timed_out());
+
+        let start = Instant::now();
+        let (_g, res) = c
+            .wait_timeout_while(g, Duration::from_millis(10), |_| true)
+            .unwrap();
+        assert!(res.timed_out());
+        assert!(start.elapsed() >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_condvar_wait_poisoned() {
+        let pair = Arc::new((Mutex::new(()), Condvar::new()));
+        let pair2 = Arc::clone(&pair);
+        let guard = pair.0.lock().unwrap();
+        let handle = std::thread::spawn(move || {
+            let _g = pair2.0.lock().unwrap();
+            pair2.1.notify_one();
+            panic!();
+        });
+        let err = pair.1.wait(guard).unwrap_err();
+        drop(err);
+        assert!(handle.join().is_err());
+        assert!(pair.0.is_poisoned());
+    }
+
+    #[test]
+    fn test_rwlock_frob() {
+        const N: u32 = 8;
+        const M: usize = 500;
+        let r = RwLock::new(());
+        std::thread::scope(|s| {
+            for i in 0..N {
+                let r = &r;
+                s.spawn(move || {
+                    for j in 0..M {
+                        if (i as usize + j).is_multiple_of(5) {
+                            drop(r.write().unwrap());
+                        } else {
+                            drop(r.read().unwrap());
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_rwlock_try() {
+        let r = RwLock::new(0);
+        let read = r.try_read().unwrap();
+        let read2 = r.try_read().unwrap();
+        assert!(matches!(r.try_write(), Err(TryLockError::WouldBlock)));
+        drop((read, read2));
+        let write = r.try_write().unwrap();
+        assert!(matches!(r.try_read(), Err(TryLockError::WouldBlock)));
+        drop(write);
+    }
+
+    #[test]
+    fn test_rwlock_poison_rules() {
+        let r = Arc::new(RwLock::new(1));
+        let r2 = Arc::clone(&r);
+        let _ = std::thread::spawn(move || {
+            let _lock = r2.read().unwrap();
+            panic!();
+        })
+        .join();
+        assert!(!r.is_poisoned());
+        assert!(r.write().is_ok());
+
+        let r2 = Arc::clone(&r);
+        let _ = std::thread::spawn(move || {
+            let _lock = r2.write().unwrap();
+            panic!();
+        })
+        .join();
+        assert!(r.is_poisoned());
+        assert!(r.read().is_err());
+        assert!(r.write().is_err());
+        r.clear_poison();
+        assert_eq!(*r.read().unwrap(), 1);
+
+        let mut r = Arc::into_inner(r).unwrap();
+        *r.get_mut().unwrap() += 1;
+        assert_eq!(r.into_inner().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_barrier() {
+        const N: usize = 10;
+        let barrier = Barrier::new(N);
+        let leaders = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..N {
+                s.spawn(|| {
+                    if barrier.wait().is_leader() {
+                        leaders.fetch_add(1, Ordering::Relaxed);
+                    }
+                });
+            }
+        });
+        assert_eq!(leaders.load(Ordering::Relaxed), 1);
+
+        // Zero parties does not block
+        assert!(Barrier::new(0).wait().is_leader());
+    }
+
+    #[test]
+    fn test_once_stampede() {
+        static O: Once = Once::new();
+        static RUN: AtomicUsize = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..10 {
+                s.spawn(|| {
+                    O.call_once(|| {
+                        RUN.fetch_add(1, Ordering::Relaxed);
+                    });
+                    assert!(O.is_completed());
+                });
+            }
+        });
+        assert_eq!(RUN.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_once_poison() {
+        let o = Once::new();
+        let t = std::panic::catch_unwind(|| o.call_once(|| panic!()));
+        assert!(t.is_err());
+        let t = std::panic::catch_unwind(|| o.call_once(|| {}));
+        assert!(t.is_err());
+
+        let mut called = false;
+        o.call_once_force(|state| {
+            assert!(state.is_poisoned());
+            called = true;
+        });
+        assert!(called);
+        o.call_once(|| {});
+        assert!(o.is_completed());
+    }
+
+    /// Panic in a scoped thread while `hold` holds `primitive`.
+    fn poison<R: Recoverable + Sync>(primitive: &R, hold: impl FnOnce(&R) + Send) {
+        std::thread::scope(|s| {
+            let res = s.spawn(|| hold(primitive)).join();
+            assert!(res.is_err());
+        });
+        assert!(primitive.is_poisoned());
+    }
+
+    #[test]
+    fn test_recover_mutex() {
+        let m = Mutex::new(vec![1, 2]);
+        poison(&m, |m| {
+            m.lock().unwrap().push(3);
+            let _guard = m.lock().unwrap();
+            panic!();
+        });
+
+        // A panicking recovery leaves the poison
+        let res = std::panic::catch_unwind(AssertUnwindSafe(|| m.recover_with(|_| panic!())));
+        assert!(res.is_err());
+        assert!(Recoverable::is_poisoned(&m));
+
+        m.recover_with(|v| v.truncate(2));
+        assert!(!Recoverable::is_poisoned(&m));
+        m.lock().unwrap().push(4);
+        assert_eq!(*m.lock().unwrap(), [1, 2, 4]);
+    }
+
+    #[test]
+    fn test_recover_rwlock_with_readers() {
+        let lock = RwLock::new(0);
+        poison(&lock, |lock| {
+            let mut guard = lock.write().unwrap();
+            *guard = -1;
+            panic!();
+        });
+
+        std::thread::scope(|s| {
+            let reader = lock.read().unwrap_err().into_inner();
+            // Bounded while the reader stays
+            let deadline = Instant::now() + Duration::from_millis(10);
+            assert!(lock.recover_until(deadline, |_| unreachable!()).is_err());
+            assert!(Recoverable::is_poisoned(&lock));
+
+            let recovery = s.spawn(|| lock.recover_with(|v| *v = 0));
+            std::thread::sleep(Duration::from_millis(10));
+            assert!(!recovery.is_finished());
+            assert_eq!(*reader, -1);
+            drop(reader);
+        });
+        assert!(!Recoverable::is_poisoned(&lock));
+        *lock.write().unwrap() += 1;
+        assert_eq!(*lock.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_recover_once() {
+        let once = Once::new();
+        poison(&once, |once| once.call_once(|| panic!()));
+
+        let mut recovered = false;
+        let deadline = Instant::now() + Duration::from_secs(1);
+        once.recover_until(deadline, |()| recovered = true).unwrap();
+        assert!(recovered);
+        assert!(!once.is_poisoned());
+        assert!(once.is_completed());
+        once.call_once(|| unreachable!());
+
+        // Or just start over
+        let once = Once::new();
+        poison(&once, |once| once.call_once(|| panic!()));
+        once.clear_poison();
+        let mut called = false;
+        once.call_once(|| called = true);
+        assert!(called);
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    mutex::Mutex,
+    ring_buffer::{CellValue, FullPolicy, RecvError, RingBuffer, WriteError},
+    slot::{SlotCell, SlotError},
+};
+
+/// Bounded work queue where each submitter blocks until its own request is processed.
+///
+/// Each request travels with a oneshot [`SlotCell`] that the worker completes, so a response wakes exactly the submitter waiting for it.
+///
+/// At most `N - 1` requests wait for a worker; further submitters block until a worker takes one.
+#[derive(Debug)]
+pub struct CompletionQueue<Req, Resp, const N: usize> {
+    jobs: RingBuffer<(Req, Arc<SlotCell<Resp>>), N>,
+    /// The ring buffer takes a single reader at a time
+    reader: Mutex<()>,
+}
+impl<Req, Resp, const N: usize> CompletionQueue<Req, Resp, N> {
+    /// # Panic
+    ///
+    /// Same as [`RingBuffer::new`].
+    pub fn new() -> Self {
+        Self {
+            jobs: RingBuffer::builder()
+                .full_policy(FullPolicy::Block)
+                .stats(false)
+                .build(),
+            reader: Mutex::new(()),
+        }
+    }
+
+    /// Block until a worker completes `request`.
+    ///
+    /// # Panic
+    ///
+    /// If the worker drops the [`Job`] without completing it.
+    pub fn submit(&self, request: Req) -> Resp {
+        let slot = Arc::new(SlotCell::new());
+        if self.jobs.write((request, slot.clone())).is_err() {
+            unreachable!("a blocking write never hands the value back");
+        }
+        match slot.take_blocking(None) {
+            Ok(response) => response,
+            Err(_) => panic!("the worker dropped the job without completing it"),
+        }
+    }
+
+    /// Like [`Self::submit`], but give up once `timeout` passes.
+    ///
+    /// # Abandonment
+    ///
+    /// A request that never made it into the queue is handed back with [`SubmitError::Full`].
+    /// Otherwise the job is abandoned: a worker skips it if it is still queued, and a worker completing it gets the response back from [`Job::complete`].
+    /// Either way, the request and the response are dropped with the job.
+    pub fn submit_timeout(
+        &self,
+        request: Req,
+        timeout: Duration,
+    ) -> Result<Resp, SubmitError<Req>> {
+        let deadline = Instant::now().checked_add(timeout);
+        let slot = Arc::new(SlotCell::new());
+        match self.jobs.write_timeout((request, slot.clone()), timeout) {
+            Ok(()) => (),
+            Err(WriteError::TimedOut((request, _))) => return Err(SubmitError::Full(request)),
+            Err(_) => unreachable!("only a timeout fails a blocking write"),
+        }
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        match slot.take_blocking(remaining) {
+            Ok(response) => Ok(response),
+            Err(SlotError::Cancelled) => Err(SubmitError::Dropped),
+            Err(SlotError::TimedOut) => {
+                // Abandon with the slot locked, so a response that just arrived is not lost
+                let mut m = slot.write();
+                match std::mem::replace(&mut **m.locked(), CellValue::Cancelled) {
+                    CellValue::Some(response) => Ok(response),
+                    _ => Err(SubmitError::TimedOut),
+                }
+            }
+        }
+    }
+
+    /// Block until a job is submitted; skip the abandoned ones.
+    pub fn next(&self) -> Job<Req, Resp> {
+        self.next_inner(None).unwrap()
+    }
+
+    /// Like [`Self::next`], but return [`None`] if no job comes in within `timeout`.
+    pub fn next_timeout(&self, timeout: Duration) -> Option<Job<Req, Resp>> {
+        self.next_inner(Instant::now().checked_add(timeout))
+    }
+
+    fn next_inner(&self, deadline: Option<Instant>) -> Option<Job<Req, Resp>> {
+        let _reader = match deadline {
+            Some(deadline) => self.reader.lock_until(deadline)?,
+            None => self.reader.lock(),
+        };
+        loop {
+            let read = match deadline {
+                Some(deadline) => self
+                    .jobs
+                    .read_timeout(deadline.saturating_duration_since(Instant::now())),
+                None => self.jobs.read(),
+            };
+            let (request, slot) = match read {
+                Ok(job) => job,
+                Err(RecvError::TimedOut) => return None,
+                Err(e) => unreachable!("the queue is never closed: {e}"),
+            };
+            if slot.is_cancelled() {
+                continue;
+            }
+            return Some(Job {
+                request,
+                completion: Completion { slot: Some(slot) },
+            });
+        }
+    }
+
+    /// Only a snapshot.
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    /// Only a snapshot.
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+}
+impl<Req, Resp, const N: usize> Default for CompletionQueue<Req, Resp, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A request taken by a worker, along with the means to answer its submitter.
+#[derive(Debug)]
+pub struct Job<Req, Resp> {
+    request: Req,
+    completion: Completion<Resp>,
+}
+impl<Req, Resp> Job<Req, Resp> {
+    pub fn request(&self) -> &Req {
+        &self.request
+    }
+
+    /// Hand the response to the submitter; return it back if the submitter has abandoned the job.
+    pub fn complete(self, response: Resp) -> Result<(), Resp> {
+        self.completion.complete(response)
+    }
+
+    /// Take the request out, keeping the means to answer.
+    pub fn into_parts(self) -> (Req, Completion<Resp>) {
+        (self.request, self.completion)
+    }
+
+    /// Only a snapshot.
+    pub fn is_abandoned(&self) -> bool {
+        self.completion.is_abandoned()
+    }
+}
+
+/// The answering end of a [`Job`].
+///
+/// Dropping it without completing fails the submitter with [`SubmitError::Dropped`].
+#[derive(Debug)]
+pub struct Completion<Resp> {
+    /// Taken on completion
+    slot: Option<Arc<SlotCell<Resp>>>,
+}
+impl<Resp> Completion<Resp> {
+    /// Learn more from [`Job::complete`].
+    pub fn complete(mut self, response: Resp) -> Result<(), Resp> {
+        self.slot.take().unwrap().put(response)
+    }
+
+    /// Only a snapshot.
+    pub fn is_abandoned(&self) -> bool {
+        self.slot.as_ref().unwrap().is_cancelled()
+    }
+}
+impl<Resp> Drop for Completion<Resp> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            slot.cancel();
+        }
+    }
+}
+
+/// Why [`CompletionQueue::submit_timeout`] returned no response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubmitError<Req> {
+    /// The queue stayed full until the timeout, so the request is handed back
+    Full(Req),
+    /// The job is abandoned
+    TimedOut,
+    /// The worker dropped the job without completing it
+    Dropped,
+}
+impl<Req> std::fmt::Display for SubmitError<Req> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubmitError::Full(_) => write!(f, "timed out waiting for room in the queue"),
+            SubmitError::TimedOut => write!(f, "timed out waiting for the response"),
+            SubmitError::Dropped => write!(f, "job dropped without completion"),
+        }
+    }
+}
+impl<Req: std::fmt::Debug> std::error::Error for SubmitError<Req> {}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+        thread,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_own_responses() {
+        const SUBMITTERS: usize = 8;
+        const SUBMITS: usize = 256;
+        let queue = CompletionQueue::<(usize, usize), (usize, usize), 4>::new();
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            let workers = (0..2)
+                .map(|_| {
+                    s.spawn(|| loop {
+                        let Some(job) = queue.next_timeout(Duration::from_millis(10)) else {
+                            if done.load(Ordering::SeqCst) {
+                                return;
+                            }
+                            continue;
+                        };
+                        let &(submitter, i) = job.request();
+                        job.complete((submitter, i * 2)).unwrap();
+                    })
+                })
+                .collect::<Vec<_>>();
+            let submitters = (0..SUBMITTERS)
+                .map(|submitter| {
+                    let queue = &queue;
+                    s.spawn(move || {
+                        for i in 0..SUBMITS {
+                            assert_eq!(queue.submit((submitter, i)), (submitter, i * 2));
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            for submitter in submitters {
+                submitter.join().unwrap();
+            }
+            done.store(true, Ordering::SeqCst);
+            for worker in workers {
+                worker.join().unwrap();
+            }
+        });
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_abandon_without_leak() {
+        #[derive(Debug)]
+        struct Counted<'a>(&'a AtomicUsize);
+        impl Drop for Counted<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        let created = AtomicUsize::new(0);
+        let dropped = AtomicUsize::new(0);
+        let counted = || {
+            created.fetch_add(1, Ordering::SeqCst);
+            Counted(&dropped)
+        };
+
+        let queue = CompletionQueue::<Counted, Counted, 4>::new();
+        thread::scope(|s| {
+            // Abandoned while queued
+            assert!(matches!(
+                queue.submit_timeout(counted(), Duration::from_millis(10)),
+                Err(SubmitError::TimedOut)
+            ));
+            // Abandoned while being processed
+            let worker = s.spawn(|| {
+                let job = queue.next();
+                thread::sleep(Duration::from_millis(100));
+                assert!(job.is_abandoned());
+                assert!(job.complete(counted()).is_err());
+            });
+            assert!(matches!(
+                queue.submit_timeout(counted(), Duration::from_millis(50)),
+                Err(SubmitError::TimedOut)
+            ));
+            worker.join().unwrap();
+
+            // Dropped by the worker
+            let worker = s.spawn(|| drop(queue.next()));
+            assert!(matches!(
+                queue.submit_timeout(counted(), Duration::from_secs(10)),
+                Err(SubmitError::Dropped)
+            ));
+            worker.join().unwrap();
+
+            // Completed in time
+            let worker = s.spawn(|| {
+                let job = queue.next();
+                job.complete(counted()).unwrap();
+            });
+            assert!(queue
+                .submit_timeout(counted(), Duration::from_secs(10))
+                .is_ok());
+            worker.join().unwrap();
+
+            // Queue full
+            for _ in 0..3 {
+                s.spawn(|| queue.submit_timeout(counted(), Duration::from_millis(50)));
+            }
+            thread::sleep(Duration::from_millis(10));
+            assert!(matches!(
+                queue.submit_timeout(counted(), Duration::from_millis(10)),
+                Err(SubmitError::Full(_))
+            ));
+        });
+        assert!(queue.next_timeout(Duration::ZERO).is_none());
+        drop(queue);
+        assert_eq!(
+            created.load(Ordering::SeqCst),
+            dropped.load(Ordering::SeqCst)
+        );
+    }
+}
@@ -0,0 +1,386 @@
+//! Sleep on several primitives at once until any of them is probably ready.
+//!
+//! ```
+//! use std::time::{Duration, Instant};
+//!
+//! use futex::{
+//!     composite::{composite_wait, WaitSource, WhichReady},
+//!     event::Event,
+//!     mutex::Mutex,
+//!     shutdown::ShutdownToken,
+//! };
+//!
+//! let m = Mutex::new(());
+//! let event = Event::new();
+//! let token = ShutdownToken::new();
+//! let _guard = m.lock();
+//! event.set();
+//! let ready = composite_wait(
+//!     &[
+//!         WaitSource::mutex(&m),
+//!         WaitSource::event(&event),
+//!         WaitSource::shutdown(&token),
+//!     ],
+//!     Some(Instant::now() + Duration::from_secs(1)),
+//! );
+//! assert_eq!(ready, WhichReady::Source(1));
+//! ```
+
+use std::{
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    event::Event,
+    mutex,
+    observer::{futex_wake_from, Primitive},
+    resumed_futex_wait,
+    shutdown::ShutdownToken,
+    FutexError, FutexScope, FutexTimeout, FutexWaitContext, TimeoutMeasure, WaiterGuard,
+    WakeWaiters,
+};
+
+/// How long a waiter sleeps between checks of the other sources on kernels without `futex_waitv`.
+const FALLBACK_SLICE: Duration = Duration::from_millis(10);
+/// `FUTEX_WAITV_MAX` from `linux/futex.h`
+pub const MAX_SOURCES: usize = 128;
+
+static WAITV_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// A futex word to sleep on while it holds `expected`.
+#[derive(Debug)]
+pub struct WaitSource<'a> {
+    word: &'a AtomicU32,
+    expected: u32,
+    scope: FutexScope,
+    /// The primitive, if it wakes one waiter per release, so that a wake consumed here is owed to its other waiters
+    passes_wake_on: Option<Primitive>,
+    /// Keeps the primitive issuing wakes while the source is alive
+    _waiter: Option<WaiterGuard<'a>>,
+}
+impl<'a> WaitSource<'a> {
+    /// Ready once `word` no longer holds `expected`.
+    ///
+    /// Whoever changes `word` has to `FUTEX_WAKE` it, since it is slept on without a waiters counter.
+    pub fn word(word: &'a AtomicU32, expected: u32) -> Self {
+        Self::word_in(word, expected, FutexScope::Shared)
+    }
+
+    /// [`Self::word`] paired with the wakes of `scope`.
+    pub(crate) fn word_in(word: &'a AtomicU32, expected: u32, scope: FutexScope) -> Self {
+        Self {
+            word,
+            expected,
+            scope,
+            passes_wake_on: None,
+            _waiter: None,
+        }
+    }
+
+    /// Ready once the mutex is probably unlocked; lock it afterwards to find out.
+    ///
+    /// Marks a locked mutex contended, so that its unlock issues a `FUTEX_WAKE`.
+    /// That wake is meant for a single waiter, so [`composite_wait`] passes it on to the mutex's other waiters whenever it was the one woken through this source: the caller may not lock the mutex, and a plain lock would not mark it contended again.
+    ///
+    /// # Panic
+    ///
+    /// If the mutex inherits priority, since its unlock wakes through the kernel's own queue.
+    pub fn mutex<T>(m: &'a mutex::Mutex<T>) -> Self {
+        assert!(!m.is_pi());
+        let word = m.futex_word();
+        let _ = word.compare_exchange(
+            mutex::State::Locked.into(),
+            mutex::State::Contended.into(),
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+        Self {
+            passes_wake_on: Some(Primitive::Mutex),
+            ..Self::word_in(word, mutex::State::Contended.into(), m.scope())
+        }
+    }
+
+    /// Ready once the event is set or has been set since this call.
+    ///
+    /// An [`Event::set_one`] since this call makes it ready too, though it takes no claim; [`composite_wait`] passes the wake of the set on to the event's other waiters.
+    pub fn event(event: &'a Event) -> Self {
+        let (word, expected, waiter) = event.register();
+        Self {
+            word,
+            expected,
+            scope: FutexScope::Shared,
+            passes_wake_on: Some(Primitive::Event),
+            _waiter: Some(waiter),
+        }
+    }
+
+    /// Ready once the token trips.
+    pub fn shutdown(token: &'a ShutdownToken) -> Self {
+        Self::word(token.word(), 0)
+    }
+
+    fn is_ready(&self) -> bool {
+        self.word.load(Ordering::SeqCst) != self.expected
+    }
+
+    /// Hand a wake this source consumed to the next waiter of its primitive.
+    fn pass_wake_on(&self) {
+        if let Some(primitive) = self.passes_wake_on {
+            futex_wake_from(primitive, self.word, WakeWaiters::ONE, self.scope).unwrap();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhichReady {
+    /// The index of the source that is probably ready; the lowest one if several are.
+    Source(usize),
+    TimedOut,
+}
+
+/// Sleep until any of `sources` is probably ready or `deadline` passes.
+///
+/// Sleeps with `futex_waitv` (Linux 5.16+).
+/// On older kernels, it sleeps on the first source for at most [`FALLBACK_SLICE`] at a time and rechecks the others in between.
+///
+/// # Panic
+///
+/// If `sources` is empty or holds more than [`MAX_SOURCES`].
+pub fn composite_wait(sources: &[WaitSource<'_>], deadline: Option<Instant>) -> WhichReady {
+    assert!(!sources.is_empty() && sources.len() <= MAX_SOURCES);
+    crate::wake_scope::flush();
+    loop {
+        if let Some(i) = sources.iter().position(WaitSource::is_ready) {
+            return WhichReady::Source(i);
+        }
+        let timeout = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return WhichReady::TimedOut;
+                }
+                Some(remaining)
+            }
+            None => None,
+        };
+        if WAITV_UNSUPPORTED.load(Ordering::Relaxed) {
+            if wait_sliced(&sources[0], timeout) {
+                sources[0].pass_wake_on();
+            }
+            continue;
+        }
+        match futex_waitv(sources, timeout) {
+            // Woken, though the word could have changed back since
+            Ok(i) => {
+                sources[i].pass_wake_on();
+                return match sources.iter().position(WaitSource::is_ready) {
+                    Some(ready) => WhichReady::Source(ready),
+                    None => WhichReady::Source(i),
+                };
+            }
+            Err(FutexError::ValueMismatch | FutexError::Interrupted | FutexError::TimedOut) => (),
+            // Missing, or denied by seccomp
+            Err(FutexError::Unsupported) => WAITV_UNSUPPORTED.store(true, Ordering::Relaxed),
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+                WAITV_UNSUPPORTED.store(true, Ordering::Relaxed);
+            }
+            Err(e) => panic!("{e}"),
+        }
+    }
+}
+
+/// Return whether the sleep ended in a wake.
+fn wait_sliced(source: &WaitSource<'_>, timeout: Option<Duration>) -> bool {
+    let slice = timeout.map_or(FALLBACK_SLICE, |t| t.min(FALLBACK_SLICE));
+    match resumed_futex_wait(FutexWaitContext {
+        word: source.word,
+        expected: source.expected,
+        timeout: Some(FutexTimeout::For(slice, TimeoutMeasure::MonoTime)),
+        scope: source.scope,
+    }) {
+        Ok(()) => true,
+        Err(FutexError::ValueMismatch | FutexError::TimedOut) => false,
+        Err(e) => panic!("{e}"),
+    }
+}
+
+/// `struct futex_waitv` from `linux/futex.h`
+#[repr(C)]
+struct FutexWaitv {
+    val: u64,
+    uaddr: u64,
+    flags: u32,
+    __reserved: u32,
+}
+const FUTEX2_SIZE_U32: u32 = 0x02;
+const FUTEX2_PRIVATE: u32 = 0x80;
+
+/// Sleep on all the sources at once; return the index of the one woken.
+///
+/// Each source is private only if its scope is, to pair with the wakes of its primitive.
+fn futex_waitv(sources: &[WaitSource<'_>], timeout: Option<Duration>) -> Result<usize, FutexError> {
+    let waiters = sources
+        .iter()
+        .map(|source| FutexWaitv {
+            val: source.expected.into(),
+            uaddr: source.word.as_ptr() as u64,
+            flags: match source.scope {
+                FutexScope::Shared => FUTEX2_SIZE_U32,
+                FutexScope::Private => FUTEX2_SIZE_U32 | FUTEX2_PRIVATE,
+            },
+            __reserved: 0,
+        })
+        .collect::<Vec<_>>();
+    // The timeout of `futex_waitv` is absolute
+    let deadline = match timeout {
+        Some(timeout) => {
+            let mut now = libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            };
+            if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) } != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            let nsec = now.tv_nsec + timeout.subsec_nanos() as libc::c_long;
+            Some(libc::timespec {
+                tv_sec: libc::time_t::try_from(timeout.as_secs())
+                    .unwrap_or(libc::time_t::MAX)
+                    .saturating_add(now.tv_sec)
+                    .saturating_add(nsec / 1_000_000_000),
+                tv_nsec: nsec % 1_000_000_000,
+            })
+        }
+        None => None,
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_futex_waitv,
+            waiters.as_ptr(),
+            waiters.len() as libc::c_uint,
+            0 as libc::c_uint,
+            deadline
+                .as_ref()
+                .map_or(std::ptr::null(), |d| d as *const libc::timespec),
+            libc::CLOCK_MONOTONIC,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(ret as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::futex_wake;
+
+    use super::*;
+
+    const PATIENCE: Duration = Duration::from_secs(10);
+
+    /// Sleep on a word that never changes, a mutex held by another thread, an unset event, and an untripped token, while `make_ready` runs on the other thread.
+    ///
+    /// The other thread unlocks the mutex at the same point if `winner` is the mutex.
+    fn race(winner: usize, make_ready: impl FnOnce(&AtomicU32, &Event, &ShutdownToken) + Send) {
+        let word = AtomicU32::new(0);
+        let m = mutex::Mutex::new(());
+        let event = Event::new();
+        let token = ShutdownToken::new();
+        let locked = Event::new();
+        let done = Event::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                let guard = m.lock();
+                locked.set();
+                thread::sleep(Duration::from_millis(20));
+                make_ready(&word, &event, &token);
+                if winner != 1 {
+                    // Keep the mutex from looking ready too
+                    done.wait();
+                }
+                drop(guard);
+            });
+            locked.wait();
+            let sources = [
+                WaitSource::word(&word, 0),
+                WaitSource::mutex(&m),
+                WaitSource::event(&event),
+                WaitSource::shutdown(&token),
+            ];
+            let ready = composite_wait(&sources, Some(Instant::now() + PATIENCE));
+            done.set();
+            assert_eq!(ready, WhichReady::Source(winner));
+        });
+    }
+
+    #[test]
+    fn test_word_wins() {
+        race(0, |word, _, _| {
+            word.store(1, Ordering::SeqCst);
+            futex_wake(word, WakeWaiters::All).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_mutex_wins() {
+        race(1, |_, _, _| ());
+    }
+
+    #[test]
+    fn test_event_wins() {
+        race(2, |_, event, _| event.set());
+    }
+
+    #[test]
+    fn test_shutdown_wins() {
+        race(3, |_, _, token| token.shutdown());
+    }
+
+    #[test]
+    fn test_mutex_wake_passed_on() {
+        let m = mutex::Mutex::new(());
+        let event = Event::new();
+        let guard = m.lock();
+        thread::scope(|s| {
+            let composite = s.spawn(|| {
+                let sources = [WaitSource::mutex(&m), WaitSource::event(&event)];
+                composite_wait(&sources, Some(Instant::now() + PATIENCE))
+            });
+            // Queue the composite waiter first, so that it is the one the unlock wakes
+            thread::sleep(Duration::from_millis(20));
+            let plain = s.spawn(|| {
+                let start = Instant::now();
+                drop(m.lock_for(PATIENCE));
+                start.elapsed()
+            });
+            thread::sleep(Duration::from_millis(20));
+            drop(guard);
+            // The composite waiter never locks the mutex
+            assert_eq!(composite.join().unwrap(), WhichReady::Source(0));
+            assert!(plain.join().unwrap() < PATIENCE);
+        });
+    }
+
+    #[test]
+    fn test_timeout() {
+        let word = AtomicU32::new(0);
+        let token = ShutdownToken::new();
+        let sources = [WaitSource::word(&word, 0), WaitSource::shutdown(&token)];
+        let start = Instant::now();
+        let ready = composite_wait(&sources, Some(start + Duration::from_millis(30)));
+        assert_eq!(ready, WhichReady::TimedOut);
+        assert!(Duration::from_millis(30) <= start.elapsed());
+        assert!(start.elapsed() < PATIENCE);
+    }
+
+    #[test]
+    fn test_sliced_wait_returns() {
+        let word = AtomicU32::new(0);
+        let start = Instant::now();
+        assert!(!wait_sliced(&WaitSource::word(&word, 0), None));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -1,91 +1,531 @@
-use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
 
-use crate::{futex_wake, mutex, resumed_futex_wait, FutexWaitContext, WakeWaiters, U31};
+use crate::{
+    deadline::TimedOut,
+    mutex,
+    observer::{futex_wake_from, observed_futex_wait, Primitive},
+    semaphore::Semaphore,
+    shutdown::{futex_wait_or_shutdown, Shutdown, ShutdownToken},
+    Futex, FutexError, FutexTimeout, TimeoutMeasure, WaiterGuard, WaitersCounter, WakeWaiters, U31,
+};
 
+/// # Zero initialization
+///
+/// The all-zero bit pattern is a condition variable made as by [`Self::new`].
 #[derive(Debug)]
+#[repr(C)]
 pub struct CondVar {
-    counter: AtomicU32,
-    waiters: Option<AtomicUsize>,
+    counter: Futex,
+    waiters: WaitersCounter,
+    /// Registrations of [`Self::wait_for_permit`] callers not yet turned into handoffs
+    permit_waiters: AtomicU32,
+    /// Permits of [`Self::notify_into`] not yet claimed by a [`Self::wait_for_permit`] caller
+    handoffs: AtomicU32,
+    /// Slept on by [`Self::wait_for_permit`] callers instead of `counter`, so that a handoff never wakes a plain waiter
+    permit_counter: Futex,
 }
 impl CondVar {
     pub fn new() -> Self {
         Self {
-            counter: AtomicU32::new(0),
-            waiters: Some(AtomicUsize::new(0)),
+            counter: Futex::new(0),
+            waiters: WaitersCounter::new(),
+            permit_waiters: AtomicU32::new(0),
+            handoffs: AtomicU32::new(0),
+            permit_counter: Futex::new(0),
+        }
+    }
+
+    /// Register each waiter with a plain `SeqCst` store to a byte of its thread instead of an RMW on a counter shared by all waiters; [`Self::waiters`] still counts them exactly.
+    ///
+    /// Must not be shared between processes; learn more from [`FutexScope::Private`](crate::FutexScope::Private).
+    pub fn new_private() -> Self {
+        Self {
+            counter: Futex::new(0),
+            waiters: WaitersCounter::private(),
+            permit_waiters: AtomicU32::new(0),
+            handoffs: AtomicU32::new(0),
+            permit_counter: Futex::new(0),
         }
     }
 
     pub fn new_slow() -> Self {
         Self {
-            counter: AtomicU32::new(0),
-            waiters: None,
+            counter: Futex::new(0),
+            waiters: WaitersCounter::disabled(),
+            permit_waiters: AtomicU32::new(0),
+            handoffs: AtomicU32::new(0),
+            permit_counter: Futex::new(0),
         }
     }
 
     /// Could be a spurious wake-up
+    ///
+    /// # Protocol
+    ///
+    /// No notification issued after the predicate was checked under `m` can be lost:
+    ///
+    /// 1. The waiter registers itself in `waiters` and then samples `counter`, both `SeqCst` and both while holding `m`.
+    /// 1. The notifier increments `counter` and then reads `waiters`, both `SeqCst`.
+    /// 1. All four accesses are in the single total order of `SeqCst` operations, so either:
+    ///    - the notifier's read of `waiters` observes the registration, so it issues `FUTEX_WAKE`; or
+    ///    - the waiter's sample of `counter` already observes the increment, so it is not the notification it missed.
+    /// 1. In the first case, if `FUTEX_WAIT` has not queued the waiter by the time `FUTEX_WAKE` runs, the kernel compares `counter` against the stale sample and returns [`FutexError::ValueMismatch`](crate::FutexError::ValueMismatch) instead of sleeping.
+    ///    The kernel orders the comparison and the wake-up with `smp_mb()`.
+    ///    - References:
+    ///      - futex implementation: <https://elixir.bootlin.com/linux/v5.11.1/source/kernel/futex.c#L111>
+    ///      - `smp_mb()`: <https://lwn.net/Articles/847481/>
+    ///
+    /// A notifier holding `m` while changing the predicate is additionally ordered after the `Release` unlock of `m`, so the predicate change and the increment are both visible once the waiter relocks.
     pub fn wait<'a, T>(&self, m: mutex::MutexGuard<'a, T>) -> mutex::MutexGuard<'a, T> {
-        if let Some(waiters) = &self.waiters {
-            waiters.fetch_add(1, Ordering::Relaxed);
+        self.wait_inner(m, None).0
+    }
+
+    /// Could be a spurious wake-up
+    ///
+    /// Return `true` along with the relocked guard if it timed out.
+    ///
+    /// Learn the protocol from [`Self::wait`].
+    pub fn wait_timeout<'a, T>(
+        &self,
+        m: mutex::MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> (mutex::MutexGuard<'a, T>, bool) {
+        self.wait_inner(m, Some(timeout))
+    }
+
+    /// Could be a spurious wake-up
+    ///
+    /// Return [`TimedOut`] with the relocked guard once `deadline` has passed, so waits chained on the same `deadline` never outlast it together.
+    pub fn wait_deadline<'a, T>(
+        &self,
+        m: mutex::MutexGuard<'a, T>,
+        deadline: impl Into<Instant>,
+    ) -> Result<mutex::MutexGuard<'a, T>, TimedOut<mutex::MutexGuard<'a, T>>> {
+        let remaining = deadline.into().saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(TimedOut(m));
         }
-        let c = self.counter.load(Ordering::Relaxed);
+        match self.wait_inner(m, Some(remaining)) {
+            (m, true) => Err(TimedOut(m)),
+            (m, false) => Ok(m),
+        }
+    }
+
+    /// Block as long as `condition` returns `true`, rechecking it under the lock after every wake-up.
+    ///
+    /// Return [`TimedOut`] with the relocked guard if `condition` still returns `true` once `deadline` has passed.
+    pub fn wait_while_deadline<'a, T>(
+        &self,
+        mut m: mutex::MutexGuard<'a, T>,
+        deadline: impl Into<Instant>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> Result<mutex::MutexGuard<'a, T>, TimedOut<mutex::MutexGuard<'a, T>>> {
+        let deadline = deadline.into();
+        while condition(&mut *m) {
+            if deadline <= Instant::now() {
+                return Err(TimedOut(m));
+            }
+            m = match self.wait_deadline(m, deadline) {
+                Ok(m) | Err(TimedOut(m)) => m,
+            };
+        }
+        Ok(m)
+    }
+
+    /// Could be a spurious wake-up
+    ///
+    /// Once `token` trips, give up with [`Shutdown`] and leave `m` unlocked.
+    ///
+    /// Learn the protocol from [`Self::wait`].
+    pub fn wait_or_shutdown<'a, T>(
+        &self,
+        m: mutex::MutexGuard<'a, T>,
+        token: &ShutdownToken,
+    ) -> Result<mutex::MutexGuard<'a, T>, Shutdown> {
+        if token.is_shutdown() {
+            return Err(Shutdown);
+        }
+        let (c, waiter) = self.register();
+        let m = m.unlock();
+
+        let res = futex_wait_or_shutdown(
+            Primitive::CondVar,
+            &self.counter,
+            c,
+            self.waiters.scope(),
+            token,
+        );
+        drop(waiter);
+        res?;
+
+        m.lock_or_shutdown(token)
+    }
+
+    fn wait_inner<'a, T>(
+        &self,
+        m: mutex::MutexGuard<'a, T>,
+        timeout: Option<Duration>,
+    ) -> (mutex::MutexGuard<'a, T>, bool) {
+        let (c, waiter) = self.register();
         let m = m.unlock();
+        let timed_out = self.park(c, waiter, timeout);
+        (m.lock(), timed_out)
+    }
 
-        if let Err(e) = resumed_futex_wait(FutexWaitContext {
-            word: &self.counter,
-            expected: c,
-            timeout: None,
-        }) {
-            if !matches!(e.kind(), std::io::ErrorKind::WouldBlock) {
-                panic!("{e}");
+    /// [`Self::wait`] for a permit handed over by [`Self::notify_into`].
+    ///
+    /// Return `true` along with the relocked guard if the caller now holds a permit of the semaphore passed to [`Self::notify_into`], to be given back with [`Semaphore::signal`]; otherwise it was a spurious wake-up or a timeout.
+    ///
+    /// Sleeps on a futex word of its own: [`Self::notify_into`] and [`Self::notify_all`] wake it, while [`Self::notify_one`] and [`Self::notify_n`] only wake [`Self::wait`] callers.
+    ///
+    /// # Protocol
+    ///
+    /// On top of that of [`Self::wait`], with `permit_counter` in place of `counter`, a handed-over permit is never lost, even to a waiter timing out as it is notified:
+    ///
+    /// 1. Each waiter adds one registration to `permit_waiters` before sampling `permit_counter`.
+    /// 1. The notifier turns one registration into a handoff: it takes it from `permit_waiters` and then adds to `handoffs`.
+    ///    With no registration to take, it deposits the permit into the semaphore instead.
+    /// 1. On its way out, woken or not, each waiter takes either a handoff, leaving with a permit, or a registration, leaving without one.
+    ///    It tries the handoff first, so the waiter a handoff woke takes it unless another waiter leaving at the same time did.
+    /// 1. So the registrations left plus the handoffs, including those a notifier is about to add, always match the waiters yet to leave, and every handoff is taken by exactly one of them.
+    pub fn wait_for_permit<'a, T>(
+        &self,
+        m: mutex::MutexGuard<'a, T>,
+        timeout: Option<Duration>,
+    ) -> (mutex::MutexGuard<'a, T>, bool) {
+        self.permit_waiters.fetch_add(1, Ordering::SeqCst);
+        let waiter = self.waiters.register(Ordering::SeqCst);
+        let c = self.permit_counter.load(Ordering::SeqCst);
+        let m = m.unlock();
+        self.park_on(&self.permit_counter, c, waiter, timeout);
+        let claimed = loop {
+            if take_one(&self.handoffs) {
+                break true;
+            }
+            if take_one(&self.permit_waiters) {
+                break false;
             }
+            // A notifier took the registration and is about to add the handoff
+            std::thread::yield_now();
+        };
+        (m.lock(), claimed)
+    }
+
+    /// [`Self::wait`] on the write lock of an [`RwLock`](crate::rw_lock::RwLock).
+    ///
+    /// Could be a spurious wake-up
+    ///
+    /// Learn the protocol from [`Self::wait`]; the write lock plays the role of the mutex.
+    #[cfg(feature = "lock_api")]
+    pub fn wait_write<'a, T>(
+        &self,
+        guard: crate::rw_lock::RwLockWriteGuard<'a, T>,
+    ) -> crate::rw_lock::RwLockWriteGuard<'a, T> {
+        self.wait_write_inner(guard, None).0
+    }
+
+    /// Could be a spurious wake-up
+    ///
+    /// Return `true` along with the relocked guard if it timed out.
+    #[cfg(feature = "lock_api")]
+    pub fn wait_write_timeout<'a, T>(
+        &self,
+        guard: crate::rw_lock::RwLockWriteGuard<'a, T>,
+        timeout: Duration,
+    ) -> (crate::rw_lock::RwLockWriteGuard<'a, T>, bool) {
+        self.wait_write_inner(guard, Some(timeout))
+    }
+
+    /// Block as long as `condition` returns `true`, rechecking it under the write lock after every wake-up.
+    #[cfg(feature = "lock_api")]
+    pub fn wait_write_while<'a, T>(
+        &self,
+        mut guard: crate::rw_lock::RwLockWriteGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> crate::rw_lock::RwLockWriteGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait_write(guard);
         }
-        if let Some(waiters) = &self.waiters {
-            waiters.fetch_sub(1, Ordering::Relaxed);
+        guard
+    }
+
+    #[cfg(feature = "lock_api")]
+    fn wait_write_inner<'a, T>(
+        &self,
+        mut guard: crate::rw_lock::RwLockWriteGuard<'a, T>,
+        timeout: Option<Duration>,
+    ) -> (crate::rw_lock::RwLockWriteGuard<'a, T>, bool) {
+        let (c, waiter) = self.register();
+        let timed_out = crate::rw_lock::RwLockWriteGuard::unlocked(&mut guard, || {
+            self.park(c, waiter, timeout)
+        });
+        (guard, timed_out)
+    }
+
+    /// [`Self::wait`] on the read lock of an [`RwLock`](crate::rw_lock::RwLock).
+    ///
+    /// Could be a spurious wake-up
+    ///
+    /// The protocol of [`Self::wait`] still holds: the notifier has to change the predicate under the write lock, which excludes the waiter until it has sampled `counter` and released its read lock.
+    #[cfg(feature = "lock_api")]
+    pub fn wait_read<'a, T>(
+        &self,
+        guard: crate::rw_lock::RwLockReadGuard<'a, T>,
+    ) -> crate::rw_lock::RwLockReadGuard<'a, T> {
+        self.wait_read_inner(guard, None).0
+    }
+
+    /// Could be a spurious wake-up
+    ///
+    /// Return `true` along with the relocked guard if it timed out.
+    #[cfg(feature = "lock_api")]
+    pub fn wait_read_timeout<'a, T>(
+        &self,
+        guard: crate::rw_lock::RwLockReadGuard<'a, T>,
+        timeout: Duration,
+    ) -> (crate::rw_lock::RwLockReadGuard<'a, T>, bool) {
+        self.wait_read_inner(guard, Some(timeout))
+    }
+
+    /// Block as long as `condition` returns `true`, rechecking it under the read lock after every wake-up.
+    #[cfg(feature = "lock_api")]
+    pub fn wait_read_while<'a, T>(
+        &self,
+        mut guard: crate::rw_lock::RwLockReadGuard<'a, T>,
+        mut condition: impl FnMut(&T) -> bool,
+    ) -> crate::rw_lock::RwLockReadGuard<'a, T> {
+        while condition(&*guard) {
+            guard = self.wait_read(guard);
         }
+        guard
+    }
 
-        m.lock()
+    #[cfg(feature = "lock_api")]
+    fn wait_read_inner<'a, T>(
+        &self,
+        mut guard: crate::rw_lock::RwLockReadGuard<'a, T>,
+        timeout: Option<Duration>,
+    ) -> (crate::rw_lock::RwLockReadGuard<'a, T>, bool) {
+        let (c, waiter) = self.register();
+        let timed_out =
+            crate::rw_lock::RwLockReadGuard::unlocked(&mut guard, || self.park(c, waiter, timeout));
+        (guard, timed_out)
     }
 
-    pub fn notify_one(&self) {
-        if let Some(waiters) = &self.waiters {
-            if waiters.load(Ordering::Relaxed) == 0 {
-                return;
+    /// Register in `waiters` and sample `counter` while still holding the lock.
+    fn register(&self) -> (u32, WaiterGuard<'_>) {
+        let waiter = self.waiters.register(Ordering::SeqCst);
+        (self.counter.load(Ordering::SeqCst), waiter)
+    }
+
+    /// Sleep unless `counter` moved past `c`, then deregister.
+    ///
+    /// Return `true` if it timed out.
+    fn park(&self, c: u32, waiter: WaiterGuard<'_>, timeout: Option<Duration>) -> bool {
+        self.park_on(&self.counter, c, waiter, timeout)
+    }
+
+    /// [`Self::park`] on `word`.
+    fn park_on(
+        &self,
+        word: &Futex,
+        c: u32,
+        _waiter: WaiterGuard<'_>,
+        timeout: Option<Duration>,
+    ) -> bool {
+        let mut timed_out = false;
+        if let Err(e) = observed_futex_wait(
+            Primitive::CondVar,
+            word.context(
+                c,
+                timeout.map(|t| FutexTimeout::For(t, TimeoutMeasure::MonoTime)),
+                self.waiters.scope(),
+            ),
+        ) {
+            match e.error {
+                FutexError::ValueMismatch => (),
+                FutexError::TimedOut => timed_out = true,
+                _ => panic!("{e}"),
             }
         }
-        // Because the implementation of `FUTEX_WAKE` has already called `smp_mb()`, `self.counter` has always been incremented before the other thread is waken.
-        // - References:
-        //   - futex implementation: <https://elixir.bootlin.com/linux/v5.11.1/source/kernel/futex.c#L111>
-        //   - `smp_mb()`: <https://lwn.net/Articles/847481/>
-        self.counter.fetch_add(1, Ordering::Relaxed);
-        if let Err(e) = futex_wake(&self.counter, WakeWaiters::Amount(U31::new(1).unwrap())) {
+        timed_out
+    }
+
+    pub fn notify_one(&self) {
+        self.notify(WakeWaiters::ONE);
+    }
+
+    /// Wake the [`Self::wait_for_permit`] callers too.
+    pub fn notify_all(&self) {
+        // Same ordering argument as `notify`, over the permit waiters' own word and registrations
+        self.permit_counter.fetch_add(1, Ordering::SeqCst);
+        self.notify(WakeWaiters::All);
+        if self.permit_waiters.load(Ordering::SeqCst) == 0
+            && self.handoffs.load(Ordering::SeqCst) == 0
+        {
+            return;
+        }
+        if let Err(e) = futex_wake_from(
+            Primitive::CondVar,
+            &self.permit_counter,
+            WakeWaiters::All,
+            self.waiters.scope(),
+        ) {
             panic!("{e}");
         }
     }
 
-    pub fn notify_all(&self) {
-        if let Some(waiters) = &self.waiters {
-            if waiters.load(Ordering::Relaxed) == 0 {
-                return;
-            }
+    /// Wake at most `n` waiters.
+    ///
+    /// Return the number of waiters that were woken up.
+    ///
+    /// The syscall is skipped only if no waiters are registered; a non-zero count always issues one `FUTEX_WAKE` of `n`.
+    pub fn notify_n(&self, n: U31) -> usize {
+        self.notify(n.into())
+    }
+
+    /// Hand a fresh permit of `sem` straight to a [`Self::wait_for_permit`] caller, waking it, instead of waking it only to have it race for a permit; wait morphing.
+    ///
+    /// With no such caller registered, the permit is deposited into `sem` as by [`Semaphore::signal`].
+    /// [`Self::wait`] callers are never woken, since permit waiters sleep on a word of their own.
+    ///
+    /// Learn the protocol from [`Self::wait_for_permit`].
+    pub fn notify_into(&self, sem: &Semaphore) {
+        if !take_one(&self.permit_waiters) {
+            sem.signal();
+            return;
         }
-        self.counter.fetch_add(1, Ordering::Relaxed);
-        if let Err(e) = futex_wake(&self.counter, WakeWaiters::All) {
+        self.handoffs.fetch_add(1, Ordering::SeqCst);
+        self.permit_counter.fetch_add(1, Ordering::SeqCst);
+        let one = WakeWaiters::ONE;
+        if let Err(e) = futex_wake_from(
+            Primitive::CondVar,
+            &self.permit_counter,
+            one,
+            self.waiters.scope(),
+        ) {
             panic!("{e}");
         }
     }
+
+    /// Learn the ordering argument from [`Self::wait`].
+    fn notify(&self, amount: WakeWaiters) -> usize {
+        // The increment must precede the `waiters` check; otherwise a waiter registering in between would be skipped
+        self.counter.fetch_add(1, Ordering::SeqCst);
+        if self.waiters.load(Ordering::SeqCst) == Some(0) {
+            return 0;
+        }
+        match futex_wake_from(
+            Primitive::CondVar,
+            &self.counter,
+            amount,
+            self.waiters.scope(),
+        ) {
+            Ok(woken) => woken,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// Only a snapshot.
+    ///
+    /// Return [`None`] if the condition variable does not count its waiters.
+    pub fn waiters(&self) -> Option<usize> {
+        self.waiters.load(Ordering::Relaxed)
+    }
+}
+/// Decrement `word` unless it is zero; return whether it did.
+fn take_one(word: &AtomicU32) -> bool {
+    word.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        .is_ok()
 }
 impl Default for CondVar {
     fn default() -> Self {
         Self::new()
     }
 }
+/// Learn more from the zero initialization of [`CondVar`].
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Zeroable for CondVar {}
 
 #[cfg(test)]
 mod tests {
-    use std::{thread, time::Duration};
+    use std::{sync::atomic::AtomicUsize, thread};
+
+    use crate::{
+        deadline::Deadline,
+        mock_backend::{with_failing_waits, Injection, MockBackend},
+    };
 
     use super::*;
 
+    /// Three waits chained on one deadline never outlast it together, however the notifications are timed.
+    #[test]
+    fn test_chained_deadline() {
+        const BUDGET: Duration = Duration::from_millis(150);
+        // Delays of the notifications releasing the first two waits; `None` never releases them
+        let schedules = [
+            [None, None],
+            [Some(10), Some(10)],
+            [Some(100), None],
+            [Some(140), Some(5)],
+        ];
+        for schedule in schedules {
+            let m = mutex::Mutex::new(0);
+            let cv = CondVar::new();
+            let start = Instant::now();
+            let deadline = Deadline::after(BUDGET);
+            thread::scope(|s| {
+                s.spawn(|| {
+                    for (stage, delay) in schedule.into_iter().enumerate() {
+                        let Some(delay) = delay else {
+                            break;
+                        };
+                        thread::sleep(Duration::from_millis(delay));
+                        *m.lock() = stage + 1;
+                        cv.notify_all();
+                    }
+                    // Spurious notifications
+                    while !deadline.is_expired() {
+                        thread::sleep(Duration::from_millis(5));
+                        cv.notify_all();
+                    }
+                });
+
+                let mut guard = m.lock();
+                for stage in 0..3 {
+                    guard = match cv.wait_while_deadline(guard, deadline, |s| *s <= stage) {
+                        Ok(guard) | Err(TimedOut(guard)) => guard,
+                    };
+                }
+                drop(guard);
+                let elapsed = start.elapsed();
+                assert!(deadline.is_expired());
+                assert!(elapsed < BUDGET + Duration::from_millis(50), "{elapsed:?}");
+            });
+        }
+    }
+
+    #[test]
+    fn test_deadline_across_primitives() {
+        let m = mutex::Mutex::new(());
+        let sem = Semaphore::new(0);
+        let cv = CondVar::new();
+        let start = Instant::now();
+        let deadline = Deadline::after(Duration::from_millis(60));
+        let guard = m.lock_deadline(deadline).unwrap();
+        assert!(sem.wait_deadline(deadline).is_err());
+        assert!(deadline.is_expired());
+        assert!(deadline.remaining().is_zero());
+        // Already expired, so the rest return at once
+        let guard = cv.wait_deadline(guard, deadline).unwrap_err().into_inner();
+        drop(guard);
+        assert!(m.lock_deadline(deadline).is_ok());
+        assert!(start.elapsed() < Duration::from_millis(110));
+    }
+
     #[test]
     fn test_cond_var() {
         let m = mutex::Mutex::new(0);
@@ -111,4 +551,232 @@ mod tests {
         // while still allowing for a few spurious wake ups.
         assert!(wake_ups < 10);
     }
+
+    #[test]
+    fn test_wait_timeout() {
+        let m = mutex::Mutex::new(());
+        let cv = CondVar::new();
+        let start = std::time::Instant::now();
+        let (_guard, timed_out) = cv.wait_timeout(m.lock(), Duration::from_millis(50));
+        assert!(timed_out);
+        assert!(Duration::from_millis(50) <= start.elapsed());
+    }
+
+    #[test]
+    fn test_notify_n() {
+        let m = mutex::Mutex::new(());
+        let cv = CondVar::new();
+        let proceeded = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    let guard = m.lock();
+                    drop(cv.wait(guard));
+                    proceeded.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+            while cv.waiters() != Some(8) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            // Let the waiters get queued in the kernel
+            thread::sleep(Duration::from_millis(100));
+
+            assert_eq!(cv.notify_n(U31::new(3).unwrap()), 3);
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(proceeded.load(Ordering::SeqCst), 3);
+            assert_eq!(cv.waiters(), Some(5));
+
+            assert_eq!(cv.notify_n(U31::new(8).unwrap()), 5);
+        });
+        assert_eq!(proceeded.load(Ordering::SeqCst), 8);
+    }
+
+    /// Hangs if a notification is ever lost.
+    #[test]
+    fn test_ping_pong_no_lost_wake_up() {
+        const ROUNDS: usize = 1 << 20;
+        let m = mutex::Mutex::new(false);
+        let cv = CondVar::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..ROUNDS {
+                    let mut turn = m.lock();
+                    while !*turn {
+                        turn = cv.wait(turn);
+                    }
+                    *turn = false;
+                    drop(turn);
+                    cv.notify_one();
+                }
+            });
+            for _ in 0..ROUNDS {
+                let mut turn = m.lock();
+                while *turn {
+                    turn = cv.wait(turn);
+                }
+                *turn = true;
+                drop(turn);
+                cv.notify_one();
+            }
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "CondVar futex Wait on 0x")]
+    fn test_error_context() {
+        let m = mutex::Mutex::new(());
+        let cv = CondVar::new();
+        let mock = MockBackend::install();
+        mock.intercept(&cv);
+        mock.inject(Injection::Denied);
+        let _ = cv.wait(m.lock());
+    }
+
+    #[test]
+    fn test_unrepresentable_timeout_waits_untimed() {
+        let m = mutex::Mutex::new(false);
+        let cv = CondVar::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                while cv.waiters() != Some(1) {
+                    thread::yield_now();
+                }
+                *m.lock() = true;
+                cv.notify_one();
+            });
+            let mut guard = m.lock();
+            while !*guard {
+                let timed_out;
+                (guard, timed_out) = cv.wait_timeout(guard, Duration::MAX);
+                assert!(!timed_out);
+            }
+        });
+    }
+
+    #[test]
+    fn test_unwinding_wait_deregisters() {
+        let m = mutex::Mutex::new(());
+        let cv = CondVar::new();
+        assert!(with_failing_waits(|| drop(cv.wait(m.lock()))).is_err());
+        assert_eq!(cv.waiters(), Some(0));
+        // Left unlocked by the unwinding wait
+        assert!(m.try_lock().is_some());
+    }
+
+    #[cfg(feature = "lock_api")]
+    #[test]
+    fn test_wait_write() {
+        use crate::rw_lock::RwLock;
+
+        let lock = RwLock::new(false);
+        let cv = CondVar::new();
+        thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                let ready = cv.wait_write_while(lock.write(), |ready| !*ready);
+                assert!(*ready);
+            });
+            while cv.waiters() != Some(1) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            // Readers proceed while the waiter is parked
+            assert!(!*lock.try_read().unwrap());
+            assert!(!waiter.is_finished());
+
+            *lock.write() = true;
+            cv.notify_all();
+        });
+
+        let (guard, timed_out) = cv.wait_read_timeout(lock.read(), Duration::from_millis(10));
+        assert!(timed_out);
+        assert!(*guard);
+    }
+
+    #[test]
+    fn test_notify_into_hands_over_permit() {
+        let m = mutex::Mutex::new(());
+        let cv = CondVar::new();
+        let sem = Semaphore::new(0);
+        thread::scope(|s| {
+            let waiter = s.spawn(|| cv.wait_for_permit(m.lock(), None).1);
+            while cv.waiters() != Some(1) {
+                thread::yield_now();
+            }
+            cv.notify_into(&sem);
+            assert!(waiter.join().unwrap());
+        });
+        // Held by the waiter, not deposited
+        assert_eq!(sem.available_permits(), 0);
+
+        // Nobody to hand it to
+        cv.notify_into(&sem);
+        assert_eq!(sem.available_permits(), 1);
+        let (_, claimed) = cv.wait_for_permit(m.lock(), Some(Duration::from_millis(10)));
+        assert!(!claimed);
+    }
+
+    #[test]
+    fn test_permit_waiters_sleep_apart() {
+        let m = mutex::Mutex::new(());
+        let cv = CondVar::new();
+        let sem = Semaphore::new(0);
+        let mock = MockBackend::install();
+        mock.intercept(&cv);
+        thread::scope(|s| {
+            let plain = s.spawn(|| drop(cv.wait(m.lock())));
+            mock.wait_until_parked(1);
+            let permit = s.spawn(|| cv.wait_for_permit(m.lock(), None).1);
+            mock.wait_until_parked(2);
+
+            // Only the permit waiter is woken, and it holds the permit
+            cv.notify_into(&sem);
+            assert!(permit.join().unwrap());
+            mock.wait_until_parked(1);
+            assert!(!plain.is_finished());
+
+            // And a plain notification only wakes the plain waiter
+            let permit = s.spawn(|| cv.wait_for_permit(m.lock(), None).1);
+            mock.wait_until_parked(2);
+            cv.notify_one();
+            plain.join().unwrap();
+            assert!(!permit.is_finished());
+            cv.notify_all();
+            assert!(!permit.join().unwrap());
+        });
+        assert_eq!(sem.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_notify_into_races_timeouts() {
+        const WAITERS: usize = 4;
+        const NOTIFIES: usize = 2_000;
+        let m = mutex::Mutex::new(());
+        let cv = CondVar::new();
+        let sem = Semaphore::new(0);
+        let claimed = AtomicUsize::new(0);
+        let done = std::sync::atomic::AtomicBool::new(false);
+        thread::scope(|s| {
+            for i in 0..WAITERS {
+                let (m, cv, claimed, done) = (&m, &cv, &claimed, &done);
+                s.spawn(move || {
+                    // Timeouts short enough to keep expiring as the notifications land
+                    let timeout = Duration::from_micros(50 * (i as u64 + 1));
+                    while !done.load(Ordering::SeqCst) {
+                        if cv.wait_for_permit(m.lock(), Some(timeout)).1 {
+                            claimed.fetch_add(1, Ordering::SeqCst);
+                        }
+                    }
+                });
+            }
+            for i in 0..NOTIFIES {
+                cv.notify_into(&sem);
+                if i % 16 == 0 {
+                    thread::yield_now();
+                }
+            }
+            done.store(true, Ordering::SeqCst);
+        });
+        let claimed = claimed.into_inner();
+        assert_eq!(claimed + sem.available_permits() as usize, NOTIFIES);
+        assert_eq!(cv.handoffs.load(Ordering::SeqCst), 0);
+    }
 }
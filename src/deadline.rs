@@ -0,0 +1,68 @@
+//! One deadline shared by a chain of timed waits.
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use futex::{cond_var::CondVar, deadline::Deadline, mutex::Mutex};
+//!
+//! let m = Mutex::new(false);
+//! let cv = CondVar::new();
+//! let deadline = Deadline::after(Duration::from_millis(10));
+//! let guard = m.lock_deadline(deadline).unwrap();
+//! // Only gets what is left of the budget
+//! let timed_out = cv.wait_while_deadline(guard, deadline, |ready| !*ready).unwrap_err();
+//! assert!(!*timed_out.0);
+//! assert!(deadline.is_expired());
+//! ```
+
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, std::hash::Hash)]
+pub struct Deadline(Instant);
+impl Deadline {
+    pub fn at(instant: Instant) -> Self {
+        Self(instant)
+    }
+
+    pub fn after(timeout: Duration) -> Self {
+        Self(Instant::now() + timeout)
+    }
+
+    pub fn instant(self) -> Instant {
+        self.0
+    }
+
+    /// Zero once the deadline has passed.
+    pub fn remaining(self) -> Duration {
+        self.0.saturating_duration_since(Instant::now())
+    }
+
+    pub fn is_expired(self) -> bool {
+        self.remaining().is_zero()
+    }
+}
+impl From<Instant> for Deadline {
+    fn from(value: Instant) -> Self {
+        Self(value)
+    }
+}
+impl From<Deadline> for Instant {
+    fn from(value: Deadline) -> Self {
+        value.0
+    }
+}
+
+/// A `*_deadline` call reached its deadline; carries what the call hands back either way, e.g., the relocked guard.
+#[derive(Debug)]
+pub struct TimedOut<G = ()>(pub G);
+impl<G> TimedOut<G> {
+    pub fn into_inner(self) -> G {
+        self.0
+    }
+}
+impl<G> std::fmt::Display for TimedOut<G> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "deadline reached while blocking")
+    }
+}
+impl<G: std::fmt::Debug> std::error::Error for TimedOut<G> {}
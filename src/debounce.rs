@@ -0,0 +1,190 @@
+//! Let only one of many threads trigger the same response per time window.
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use futex::debounce::Debouncer;
+//!
+//! let debouncer = Debouncer::new(Duration::from_secs(60));
+//! assert!(debouncer.try_fire());
+//! assert!(!debouncer.try_fire());
+//! ```
+
+use std::{
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    observer::{observed_futex_wait, Primitive},
+    FutexError, FutexScope, FutexTimeout, FutexWaitContext, TimeoutMeasure,
+};
+
+/// A gate that opens once per window.
+///
+/// The first fire wins right away; every later one wins only if at least the window has passed since the previous winner.
+/// A window of zero lets every fire win.
+///
+/// # Clock granularity
+///
+/// Windows are measured on the monotonic clock of [`Instant`], so wall-clock adjustments have no effect.
+/// Two winners are always at least a window apart as read from that clock, which on a clock coarser than the window can be up to one clock tick less in real time.
+/// A thread blocked in [`Self::fire_or_wait`] is woken by a kernel timer, which can fire late by the timer slack of the thread, so the winner after an idle spell may come somewhat after the window ends.
+#[derive(Debug)]
+pub struct Debouncer {
+    origin: Instant,
+    window: Duration,
+    /// Nanoseconds from `origin` to the last winner, plus one; zero before the first
+    last_fire: AtomicU64,
+    /// Bumped by every winner.
+    ///
+    /// Losers sleep on it with a timeout, so that they are not woken up before their next chance opens.
+    fires: AtomicU32,
+}
+impl Debouncer {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            origin: Instant::now(),
+            window,
+            last_fire: AtomicU64::new(0),
+            fires: AtomicU32::new(0),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// The time of the last winner.
+    ///
+    /// Only a snapshot.
+    pub fn last_fired(&self) -> Option<Instant> {
+        match self.last_fire.load(Ordering::Acquire) {
+            0 => None,
+            stamp => Some(self.origin + Duration::from_nanos(stamp - 1)),
+        }
+    }
+
+    /// Return `true` for the single winner of the current window.
+    ///
+    /// Lock-free; never blocks.
+    pub fn try_fire(&self) -> bool {
+        self.fire_or_remaining().is_none()
+    }
+
+    /// Block until the caller wins a window, re-competing each time the current one ends.
+    pub fn fire_or_wait(&self) {
+        loop {
+            let sample = self.fires.load(Ordering::SeqCst);
+            let Some(remaining) = self.fire_or_remaining() else {
+                return;
+            };
+            if let Err(e) = observed_futex_wait(
+                Primitive::Debouncer,
+                FutexWaitContext {
+                    word: &self.fires,
+                    expected: sample,
+                    timeout: Some(FutexTimeout::For(remaining, TimeoutMeasure::MonoTime)),
+                    scope: FutexScope::Shared,
+                },
+            ) {
+                if !matches!(e.error, FutexError::ValueMismatch | FutexError::TimedOut) {
+                    panic!("{e}");
+                }
+            }
+        }
+    }
+
+    /// Return [`None`] if the caller won, or else how long the current window still lasts.
+    fn fire_or_remaining(&self) -> Option<Duration> {
+        let window = u64::try_from(self.window.as_nanos()).unwrap_or(u64::MAX);
+        let mut last = self.last_fire.load(Ordering::Acquire);
+        loop {
+            // A stamp of zero is reserved for never fired; it takes 584 years to overflow
+            let now = u64::try_from(self.origin.elapsed().as_nanos()).unwrap() + 1;
+            if last != 0 {
+                let opens = last.saturating_add(window);
+                if now < opens {
+                    return Some(Duration::from_nanos(opens - now));
+                }
+            }
+            match self.last_fire.compare_exchange_weak(
+                last,
+                now,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    self.fires.fetch_add(1, Ordering::SeqCst);
+                    return None;
+                }
+                Err(actual) => last = actual,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::mutex::Mutex;
+
+    use super::*;
+
+    /// The stamps of the winners of a tight loop, in order
+    fn compete(debouncer: &Debouncer, threads: usize, run: Duration) -> Vec<Instant> {
+        let won = Mutex::new(vec![]);
+        let start = Instant::now();
+        thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| {
+                    while start.elapsed() < run {
+                        if debouncer.try_fire() {
+                            won.lock().push(debouncer.last_fired().unwrap());
+                        }
+                    }
+                });
+            }
+        });
+        let mut won = won.into_inner();
+        won.sort();
+        won
+    }
+
+    #[test]
+    fn test_one_winner_per_window() {
+        let window = Duration::from_millis(50);
+        let debouncer = Debouncer::new(window);
+        let mut won = compete(&debouncer, 16, Duration::from_secs(1));
+        // How many windows passed depends on the scheduling, but every winner was counted once
+        assert!(!won.is_empty());
+        assert_eq!(won.len(), debouncer.fires.load(Ordering::Relaxed) as usize);
+        // A winner preempted for a whole window records the stamp of a later one
+        won.dedup();
+        for pair in won.windows(2) {
+            assert!(window <= pair[1] - pair[0]);
+        }
+    }
+
+    #[test]
+    fn test_zero_window() {
+        let debouncer = Debouncer::new(Duration::ZERO);
+        assert!((0..100).all(|_| debouncer.try_fire()));
+    }
+
+    #[test]
+    fn test_losers_wait_their_turn() {
+        let window = Duration::from_millis(30);
+        let debouncer = Debouncer::new(window);
+        let start = Instant::now();
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| debouncer.fire_or_wait());
+            }
+        });
+        // The first wins right away, each of the rest a window after the one before
+        assert!(window * 3 <= start.elapsed());
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
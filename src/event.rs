@@ -0,0 +1,425 @@
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    observer::{futex_wake_from, observed_futex_wait, Primitive},
+    FutexError, FutexScope, FutexTimeout, FutexWaitContext, TimeoutMeasure, WaiterGuard,
+    WaitersCounter, WakeWaiters,
+};
+
+const SET_BIT: u32 = 1;
+const GENERATION_ONE: u32 = 1 << 1;
+const GENERATIONS: u32 = 0x00ff_fffe;
+const ONE_GENERATION_ONE: u32 = 1 << 24;
+
+/// How [`Event::set_with`] releases waiters.
+///
+/// | Mode | Waiters parked at the call | Later arrivals |
+/// | --- | --- | --- |
+/// | [`Self::WakeAll`] | All proceed | Proceed until a [`Event::reset`] |
+/// | [`Self::WakeOneAndReset`] | Exactly one proceeds | Block, as if the winner had reset the event |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventSetMode {
+    WakeAll,
+    WakeOneAndReset,
+}
+
+/// A manual-reset event.
+///
+/// # Word layout
+///
+/// - Bit 0: whether the event is set.
+/// - Bits 1..24: the generation, bumped by every [`Self::set`] on an unset event.
+/// - Bits 24..32: the one-generation, bumped by every [`Self::set_one`].
+///
+/// A waiter samples the word while the event is unset and sleeps on that exact value.
+/// Because every set bumps the generation, a [`Self::reset`] right after a [`Self::set`] can never return the word to the sampled value, so the waiter cannot sleep through the set it should have seen.
+/// The generation wraps after 2^23 sets, far more than can happen within a single wait.
+///
+/// A [`Self::set_one`] leaves the event unset and deposits a claim instead; a waiter that sees only the one-generation move since its sample proceeds if it takes a claim, and otherwise samples anew and sleeps again.
+///
+/// # Fairness
+///
+/// For the same reason, a set releases every waiter parked before it even if a reset follows before they run, so no waiter is ever bypassed.
+/// A [`Self::set_one`] releases whichever waiter takes the claim first.
+///
+/// # Zero initialization
+///
+/// The all-zero bit pattern is an unset event, made as by [`Self::new`].
+#[derive(Debug)]
+#[repr(C)]
+pub struct Event {
+    word: AtomicU32,
+    waiters: WaitersCounter,
+    /// Releases deposited by [`Self::set_one`] and not yet taken by a waiter
+    claims: AtomicU32,
+}
+impl Event {
+    pub fn new() -> Self {
+        Self {
+            word: AtomicU32::new(0),
+            waiters: WaitersCounter::new(),
+            claims: AtomicU32::new(0),
+        }
+    }
+
+    pub fn new_slow() -> Self {
+        Self {
+            word: AtomicU32::new(0),
+            waiters: WaitersCounter::disabled(),
+            claims: AtomicU32::new(0),
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.word.load(Ordering::Acquire) & SET_BIT != 0
+    }
+
+    /// Wake all waiters.
+    pub fn set(&self) {
+        self.set_with(EventSetMode::WakeAll);
+    }
+
+    /// Let exactly one of the waiters parked at the call proceed, leaving the event unset.
+    ///
+    /// Return `false` without effect if there is no waiter to release or the event is already set.
+    /// Racing calls each release a different waiter, as long as there are enough.
+    ///
+    /// An event from [`Self::new_slow`] cannot tell whether anyone waits, so the claim is kept for the next waiter woken by a set.
+    pub fn set_one(&self) -> bool {
+        if self.waiters.load(Ordering::SeqCst) == Some(0) {
+            return false;
+        }
+        self.claims.fetch_add(1, Ordering::SeqCst);
+        let res = self
+            .word
+            .fetch_update(Ordering::SeqCst, Ordering::Relaxed, |word| {
+                if word & SET_BIT != 0 {
+                    return None;
+                }
+                Some(word.wrapping_add(ONE_GENERATION_ONE))
+            });
+        if res.is_err() {
+            // Everyone proceeds anyway
+            self.take_claim();
+            return false;
+        }
+        // The last waiter could have left since the first check; it checks the word after leaving, so one of the two sees the other
+        if self.waiters.load(Ordering::SeqCst) == Some(0) && self.take_claim() {
+            return false;
+        }
+        // A woken waiter that loses the claim to a waiter timing out goes back to sleep, and a woken `WaitSource` passes the wake on
+        futex_wake_from(
+            Primitive::Event,
+            &self.word,
+            WakeWaiters::ONE,
+            FutexScope::Shared,
+        )
+        .unwrap();
+        true
+    }
+
+    /// [`Self::set`] or [`Self::set_one`] by `mode`.
+    ///
+    /// Learn more from [`EventSetMode`].
+    pub fn set_with(&self, mode: EventSetMode) {
+        if mode == EventSetMode::WakeOneAndReset {
+            self.set_one();
+            return;
+        }
+        let res = self
+            .word
+            .fetch_update(Ordering::SeqCst, Ordering::Relaxed, |word| {
+                if word & SET_BIT != 0 {
+                    return None;
+                }
+                let generation = word.wrapping_add(GENERATION_ONE) & GENERATIONS;
+                Some(word & !GENERATIONS | generation | SET_BIT)
+            });
+        if res.is_err() {
+            // Already set
+            return;
+        }
+        // Everyone parked proceeds, so no claim is left for a later waiter
+        self.claims.store(0, Ordering::SeqCst);
+        if self.waiters.load(Ordering::SeqCst) == Some(0) {
+            return;
+        }
+        futex_wake_from(
+            Primitive::Event,
+            &self.word,
+            WakeWaiters::All,
+            FutexScope::Shared,
+        )
+        .unwrap();
+    }
+
+    pub fn reset(&self) {
+        self.word.fetch_and(!SET_BIT, Ordering::Release);
+    }
+
+    /// Block until the event is set or has been set since the call.
+    pub fn wait(&self) {
+        self.wait_deadline(None);
+    }
+
+    /// Return `false` on timeout.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.wait_deadline(Instant::now().checked_add(timeout))
+    }
+
+    fn wait_deadline(&self, deadline: Option<Instant>) -> bool {
+        let mut sample = self.word.load(Ordering::Acquire);
+        if sample & SET_BIT != 0 {
+            return true;
+        }
+        let waiter = self.waiters.register(Ordering::SeqCst);
+        loop {
+            let word = self.word.load(Ordering::SeqCst);
+            if word != sample {
+                // Any generation change means a set happened since the sample
+                if word & GENERATIONS != sample & GENERATIONS {
+                    return true;
+                }
+                // Only `set_one`s since the sample
+                if self.take_claim() {
+                    return true;
+                }
+                sample = word;
+                continue;
+            }
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        drop(waiter);
+                        return self.leave_timed_out(sample);
+                    }
+                    Some(FutexTimeout::For(remaining, TimeoutMeasure::MonoTime))
+                }
+                None => None,
+            };
+            if let Err(e) = observed_futex_wait(
+                Primitive::Event,
+                FutexWaitContext {
+                    word: &self.word,
+                    expected: sample,
+                    timeout,
+                    scope: FutexScope::Shared,
+                },
+            ) {
+                if !matches!(e.error, FutexError::ValueMismatch | FutexError::TimedOut) {
+                    panic!("{e}");
+                }
+            }
+        }
+    }
+
+    /// Take one of the claims deposited by [`Self::set_one`], if any.
+    fn take_claim(&self) -> bool {
+        self.claims
+            .fetch_update(Ordering::SeqCst, Ordering::Relaxed, |c| c.checked_sub(1))
+            .is_ok()
+    }
+
+    /// Settle the claims left behind by a waiter timing out with `sample`, after it stopped counting as a waiter.
+    ///
+    /// Return whether it proceeds after all.
+    fn leave_timed_out(&self, sample: u32) -> bool {
+        let word = self.word.load(Ordering::SeqCst);
+        if word & GENERATIONS != sample & GENERATIONS {
+            return true;
+        }
+        // A `set_one` that still counted this waiter
+        if word != sample && self.take_claim() {
+            return true;
+        }
+        if self.claims.load(Ordering::SeqCst) == 0 {
+            return false;
+        }
+        match self.waiters() {
+            // Keep a claim nobody is left to take from releasing a later arrival
+            Some(0) => self.claims.store(0, Ordering::SeqCst),
+            // The single wake of the claim could have been this waiter's; hand it to another one
+            _ => {
+                futex_wake_from(
+                    Primitive::Event,
+                    &self.word,
+                    WakeWaiters::ONE,
+                    FutexScope::Shared,
+                )
+                .unwrap();
+            }
+        }
+        false
+    }
+
+    /// Register as a waiter for a [`crate::composite::WaitSource`].
+    ///
+    /// Return the word along with a value it holds only as long as no set has happened since the call.
+    pub(crate) fn register(&self) -> (&AtomicU32, u32, WaiterGuard<'_>) {
+        let sample = self.word.load(Ordering::Acquire);
+        let waiter = self.waiters.register_shared(Ordering::SeqCst);
+        // Already set: a value the word cannot hold until a reset
+        let expected = sample & !SET_BIT;
+        (&self.word, expected, waiter)
+    }
+
+    /// Only a snapshot.
+    ///
+    /// Return [`None`] if the event does not count its waiters.
+    pub fn waiters(&self) -> Option<usize> {
+        self.waiters.load(Ordering::Relaxed)
+    }
+}
+impl Default for Event {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+/// Learn more from the zero initialization of [`Event`].
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Zeroable for Event {}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, AtomicUsize},
+        thread,
+    };
+
+    use crate::mock_backend::{MockBackend, WakeRecord};
+
+    use super::*;
+
+    #[test]
+    fn test_set_wakes_waiter() {
+        let event = Event::new();
+        let mock = MockBackend::install();
+        mock.intercept(&event);
+        thread::scope(|s| {
+            let waiter = s.spawn(|| event.wait());
+            mock.wait_until_parked(1);
+            assert!(!waiter.is_finished());
+            event.set();
+        });
+        assert!(event.is_set());
+        assert!(event.wait_timeout(Duration::ZERO));
+        event.reset();
+        assert!(!event.wait_timeout(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn test_unrepresentable_timeout_waits_untimed() {
+        let event = Event::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                event.set();
+            });
+            assert!(event.wait_timeout(Duration::MAX));
+        });
+    }
+
+    #[test]
+    fn test_set_one() {
+        let event = Event::new();
+        assert!(!event.set_one());
+        let proceeded = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    if event.wait_timeout(Duration::from_millis(300)) {
+                        proceeded.fetch_add(1, Ordering::SeqCst);
+                    }
+                });
+            }
+            while event.waiters() != Some(8) {
+                thread::yield_now();
+            }
+            assert!(event.set_one());
+            assert!(!event.is_set());
+        });
+        assert_eq!(proceeded.into_inner(), 1);
+        assert!(!event.is_set());
+        // Late arrivals find nothing to claim
+        assert!(!event.wait_timeout(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn test_set_one_wakes_one() {
+        let event = Event::new();
+        let mock = MockBackend::install();
+        mock.intercept(&event);
+        let proceeded = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    event.wait();
+                    proceeded.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+            mock.wait_until_parked(4);
+            assert!(event.set_one());
+            assert_eq!(
+                mock.wakes(),
+                [WakeRecord {
+                    addr: event.word.as_ptr() as usize,
+                    requested: Some(1),
+                    woken: 1,
+                }]
+            );
+            while proceeded.load(Ordering::SeqCst) == 0 {
+                thread::yield_now();
+            }
+            mock.wait_until_parked(3);
+            assert_eq!(proceeded.load(Ordering::SeqCst), 1);
+            event.set();
+        });
+        assert_eq!(proceeded.into_inner(), 4);
+    }
+
+    #[test]
+    fn test_racing_set_one() {
+        let event = Event::new();
+        let proceeded = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    if event.wait_timeout(Duration::from_millis(300)) {
+                        proceeded.fetch_add(1, Ordering::SeqCst);
+                    }
+                });
+            }
+            while event.waiters() != Some(8) {
+                thread::yield_now();
+            }
+            for _ in 0..3 {
+                s.spawn(|| event.set_with(EventSetMode::WakeOneAndReset));
+            }
+        });
+        assert_eq!(proceeded.into_inner(), 3);
+    }
+
+    #[test]
+    fn test_set_reset_hammer() {
+        let event = Event::new();
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..u16::MAX {
+                    event.set();
+                    event.reset();
+                }
+                done.store(true, Ordering::SeqCst);
+                event.set();
+            });
+            while !done.load(Ordering::SeqCst) {
+                // Each wait overlaps with at least one set window
+                assert!(event.wait_timeout(Duration::from_secs(5)));
+            }
+        });
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    deadline::TimedOut, futex_wait_bitset, futex_wake_bitset, Bitset, Futex, FutexError,
+    FutexScope, FutexTimeout, TimeoutMeasure, WakeWaiters,
+};
+
+/// The state [`FlagSet::wait_for`] waits for the flags to reach.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOrClear {
+    Set,
+    Clear,
+}
+
+/// Up to 32 boolean flags packed into one futex word, on which threads block until some of them are set or cleared.
+///
+/// # Protocol
+///
+/// A waiter sleeps with `FUTEX_WAIT_BITSET` on the mask of its flags, and a flip wakes with the mask of the flags it changed, so a waiter is never woken by the flips of flags it does not watch.
+/// A flip of flags that no waiter watches makes no syscall at all:
+///
+/// 1. The waiter adds its mask to `watched` and then samples the word, both `SeqCst`.
+/// 1. The flipper changes the word with a `SeqCst` RMW and then loads `watched`.
+/// 1. So either the waiter samples the flip, or the flipper finds the mask and wakes, failing the sleep of the waiter if it comes first.
+///
+/// `watched` is coarse: the masks of the waiters only leave it once the last waiter does.
+#[derive(Debug, Default)]
+pub struct FlagSet {
+    word: Futex,
+    /// The union of the masks of the registered waiters in the low half and their number in the high half
+    watched: AtomicU64,
+}
+impl FlagSet {
+    /// Usable in a `static`.
+    pub const fn new(flags: u32) -> Self {
+        Self {
+            word: Futex::new(flags),
+            watched: AtomicU64::new(0),
+        }
+    }
+
+    /// Return whether the flag was set before.
+    ///
+    /// # Panic
+    ///
+    /// If `bit` is past 31.
+    pub fn set(&self, bit: u32) -> bool {
+        let mask = mask_of(bit);
+        let prev = self.word.fetch_or(mask, Ordering::SeqCst);
+        self.notify(!prev & mask);
+        prev & mask != 0
+    }
+
+    /// Return whether the flag was set before.
+    ///
+    /// # Panic
+    ///
+    /// If `bit` is past 31.
+    pub fn clear(&self, bit: u32) -> bool {
+        let mask = mask_of(bit);
+        let prev = self.word.fetch_and(!mask, Ordering::SeqCst);
+        self.notify(prev & mask);
+        prev & mask != 0
+    }
+
+    /// Return whether the flag was set before.
+    ///
+    /// # Panic
+    ///
+    /// If `bit` is past 31.
+    pub fn toggle(&self, bit: u32) -> bool {
+        let mask = mask_of(bit);
+        let prev = self.word.fetch_xor(mask, Ordering::SeqCst);
+        self.notify(mask);
+        prev & mask != 0
+    }
+
+    /// Only a snapshot.
+    pub fn load(&self) -> u32 {
+        self.word.load(Ordering::Acquire)
+    }
+
+    /// Block until every flag in `mask` is in `state`, and return the whole word as observed then.
+    ///
+    /// Fail with the word last observed once `timeout` passes.
+    ///
+    /// # Panic
+    ///
+    /// If `mask` is zero.
+    pub fn wait_for(
+        &self,
+        mask: u32,
+        state: SetOrClear,
+        timeout: Option<Duration>,
+    ) -> Result<u32, TimedOut<u32>> {
+        let bitset = Bitset::new(mask).expect("no flag to wait for");
+        let reached = |word: u32| match state {
+            SetOrClear::Set => word & mask == mask,
+            SetOrClear::Clear => word & mask == 0,
+        };
+        let word = self.word.load(Ordering::Acquire);
+        if reached(word) {
+            return Ok(word);
+        }
+        let timeout = timeout.map(|t| FutexTimeout::For(t, TimeoutMeasure::MonoTime).anchored());
+        let _watch = self.watch(mask);
+        loop {
+            let word = self.word.load(Ordering::SeqCst);
+            if reached(word) {
+                return Ok(word);
+            }
+            if timeout.is_some_and(|t| t.remaining().is_zero()) {
+                return Err(TimedOut(word));
+            }
+            let cx = self.word.context(word, timeout, FutexScope::Shared);
+            if let Err(e) = futex_wait_bitset(cx, bitset) {
+                if !matches!(
+                    e,
+                    FutexError::ValueMismatch | FutexError::Interrupted | FutexError::TimedOut
+                ) {
+                    panic!("{e}");
+                }
+            }
+        }
+    }
+
+    /// Wake the waiters watching any of the `changed` flags.
+    fn notify(&self, changed: u32) {
+        let Some(changed) = Bitset::new(changed) else {
+            return;
+        };
+        // Paired with the registration in `Self::wait_for`
+        let watched = self.watched.load(Ordering::SeqCst) as u32;
+        if watched & changed.get() == 0 {
+            return;
+        }
+        futex_wake_bitset(&self.word, WakeWaiters::All, changed, FutexScope::Shared).unwrap();
+    }
+
+    fn watch(&self, mask: u32) -> Watch<'_> {
+        self.watched
+            .fetch_update(Ordering::SeqCst, Ordering::Relaxed, |watched| {
+                Some((watched + WATCHER) | u64::from(mask))
+            })
+            .unwrap();
+        Watch {
+            watched: &self.watched,
+        }
+    }
+}
+
+/// One waiter in the high half of [`FlagSet::watched`].
+const WATCHER: u64 = 1 << 32;
+
+fn mask_of(bit: u32) -> u32 {
+    Bitset::bit(bit).expect("flag past bit 31").get()
+}
+
+/// Deregisters a waiter from [`FlagSet::watched`] on drop, emptying the mask with the last one.
+struct Watch<'a> {
+    watched: &'a AtomicU64,
+}
+impl Drop for Watch<'_> {
+    fn drop(&mut self) {
+        self.watched
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |watched| {
+                let watched = watched - WATCHER;
+                Some(if watched < WATCHER { 0 } else { watched })
+            })
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::mock_backend::{WAIT_SYSCALLS, WAKE_SYSCALLS};
+
+    #[test]
+    fn test_set_clear_toggle() {
+        let flags = FlagSet::new(0b100);
+        assert!(!flags.set(0));
+        assert!(flags.set(0));
+        assert!(flags.clear(2));
+        assert!(!flags.clear(2));
+        assert!(!flags.toggle(5));
+        assert_eq!(flags.load(), 0b10_0001);
+        assert_eq!(
+            flags.wait_for(0b10_0001, SetOrClear::Set, None).unwrap(),
+            0b10_0001
+        );
+        assert_eq!(
+            flags.wait_for(0b110, SetOrClear::Clear, None).unwrap(),
+            0b10_0001
+        );
+        // No waiter, so no wake
+        assert_eq!(WAKE_SYSCALLS.get(), 0);
+    }
+
+    #[test]
+    fn test_wait_for_timeout() {
+        let flags = FlagSet::new(0b1);
+        let start = Instant::now();
+        let res = flags.wait_for(0b11, SetOrClear::Set, Some(Duration::from_millis(20)));
+        assert_eq!(res.unwrap_err().into_inner(), 0b1);
+        assert!(Duration::from_millis(20) <= start.elapsed());
+        assert_eq!(flags.watched.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_unrelated_flips_wake_no_waiter() {
+        const FLIPS: usize = 1000;
+        let flags = FlagSet::new(0b10);
+        let unrelated_wakes = std::thread::scope(|s| {
+            let set_waiter = s.spawn(|| {
+                let word = flags.wait_for(0b1, SetOrClear::Set, None).unwrap();
+                (word, WAIT_SYSCALLS.get())
+            });
+            let clear_waiter = s.spawn(|| {
+                flags.wait_for(0b10, SetOrClear::Clear, None).unwrap();
+                WAIT_SYSCALLS.get()
+            });
+            // Both waiters registered; neither watches bit 2
+            while flags.watched.load(Ordering::SeqCst) >> 32 < 2 {
+                std::thread::yield_now();
+            }
+            std::thread::sleep(Duration::from_millis(10));
+            let wakes = WAKE_SYSCALLS.get();
+            for _ in 0..FLIPS {
+                flags.toggle(2);
+            }
+            let unrelated_wakes = WAKE_SYSCALLS.get() - wakes;
+
+            flags.set(0);
+            let (word, waits) = set_waiter.join().unwrap();
+            assert_eq!(word & 0b1, 0b1);
+            // Neither woken nor failed by the flips it does not watch
+            assert!(waits <= 2, "{waits}");
+            assert!(!clear_waiter.is_finished());
+            let start = Instant::now();
+            flags.clear(1);
+            let waits = clear_waiter.join().unwrap();
+            assert!(start.elapsed() < Duration::from_secs(1));
+            assert!(waits <= 2, "{waits}");
+            unrelated_wakes
+        });
+        assert_eq!(unrelated_wakes, 0);
+        assert_eq!(flags.watched.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,458 @@
+//! Opt-in flight recorder of the futex waits and wakes the crate issues, for reconstructing hangs that only show up under load.
+//!
+//! Each thread records into a ring of its own, overwriting its oldest entries; [`dump`] merges the rings of every thread that ever recorded into one trace ordered by time.
+//! Recording takes a clock read and a handful of relaxed stores, with no lock and no allocation past a thread's first record.
+//!
+//! Covers [`crate::futex_wait`] and [`crate::futex_wake`], which the primitives sleep and wake through.
+//! The multi-word waits of [`crate::composite`], the requeues, and [`crate::signal`] posts are not recorded.
+//!
+//! ```
+//! std::panic::set_hook(Box::new(|info| {
+//!     let _ = futex::flight_recorder::dump(&mut std::io::stderr());
+//!     eprintln!("{info}");
+//! }));
+//! ```
+
+use std::{
+    fmt::{self, Write as _},
+    io,
+    os::fd::RawFd,
+    ptr::null_mut,
+    sync::atomic::{
+        fence, AtomicBool, AtomicI64, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering,
+    },
+};
+
+use crate::{FutexError, WakeWaiters};
+
+/// Entries kept per thread
+pub const CAPACITY: usize = 256;
+/// [`Entry::value`] of a wake of all waiters
+pub const ALL: u32 = u32::MAX;
+
+/// Every ring ever made, linked through [`Ring::next`] and never freed
+static RINGS: AtomicPtr<Ring> = AtomicPtr::new(null_mut());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Wait,
+    Wake,
+}
+
+/// A recorded futex operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    /// Nanoseconds on the monotonic clock; a wait is stamped when it returns and a wake when it is issued, so a wake precedes the waits it ends
+    pub time: u64,
+    pub tid: u32,
+    pub op: Op,
+    /// Of the futex word
+    pub addr: usize,
+    /// The expected value of a wait, or the waiters a wake asked for, with [`ALL`] for all of them
+    pub value: u32,
+    /// `0` for a wait and the waiters woken up for a wake, or the `errno`
+    pub result: Result<usize, i32>,
+}
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.time / 1_000_000_000;
+        let nanos = self.time % 1_000_000_000;
+        write!(f, "{secs}.{nanos:09} tid {} ", self.tid)?;
+        match self.op {
+            Op::Wait => write!(f, "wait {:#x} expected {}: ", self.addr, self.value)?,
+            Op::Wake if self.value == ALL => write!(f, "wake {:#x} all: ", self.addr)?,
+            Op::Wake => write!(f, "wake {:#x} up to {}: ", self.addr, self.value)?,
+        }
+        match (self.op, self.result) {
+            (Op::Wait, Ok(_)) => write!(f, "woken"),
+            (Op::Wait, Err(libc::EAGAIN)) => write!(f, "value mismatch"),
+            (Op::Wait, Err(libc::ETIMEDOUT)) => write!(f, "timed out"),
+            (Op::Wake, Ok(woken)) => write!(f, "woke {woken}"),
+            (_, Err(errno)) => write!(f, "errno {errno}"),
+        }
+    }
+}
+
+/// Record a wait that returned `res`.
+pub(crate) fn record_wait(addr: *const AtomicU32, expected: u32, res: &Result<(), FutexError>) {
+    let result = match res {
+        Ok(()) => Ok(0),
+        Err(e) => Err(e.raw_os_error().unwrap_or(0)),
+    };
+    record(now(), Op::Wait, addr as usize, expected, result);
+}
+
+/// Record a wake issued at `time` that returned `res`.
+pub(crate) fn record_wake(
+    time: u64,
+    addr: *mut u32,
+    waiters: WakeWaiters,
+    res: &Result<usize, FutexError>,
+) {
+    let waiters = match waiters {
+        WakeWaiters::Amount(n) => n.get(),
+        WakeWaiters::All => ALL,
+    };
+    let result = match res {
+        Ok(woken) => Ok(*woken),
+        Err(e) => Err(e.raw_os_error().unwrap_or(0)),
+    };
+    record(time, Op::Wake, addr as usize, waiters, result);
+}
+
+/// Nanoseconds on the monotonic clock.
+pub(crate) fn now() -> u64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64
+}
+
+fn record(time: u64, op: Op, addr: usize, value: u32, result: Result<usize, i32>) {
+    thread_local! {
+        static CLAIM: Claim = Claim::new();
+    }
+    // Nothing is recorded while the thread is being torn down
+    let _ = CLAIM.try_with(|claim| claim.ring.push(claim.tid, time, op, addr, value, result));
+}
+
+/// Write the trace of every thread, one entry per line, ordered by time.
+///
+/// Allocates, so it suits a panic hook but not a signal handler; learn more from [`dump_to_fd`].
+pub fn dump(w: &mut impl io::Write) -> io::Result<()> {
+    let mut out = String::new();
+    for entry in entries() {
+        writeln!(out, "{entry}").unwrap();
+    }
+    w.write_all(out.as_bytes())
+}
+
+/// [`dump`] with `write(2)` only, neither allocating nor locking, so that it can run in a signal handler.
+///
+/// Quadratic in the number of entries, since it merges the rings without a buffer.
+pub fn dump_to_fd(fd: RawFd) -> io::Result<()> {
+    let mut last: Option<(u64, usize)> = None;
+    loop {
+        let mut next: Option<((u64, usize), Entry)> = None;
+        let mut seq = 0;
+        for ring in rings() {
+            for slot in ring.slots.iter() {
+                seq += 1;
+                let Some(entry) = slot.read() else {
+                    continue;
+                };
+                let key = (entry.time, seq);
+                if last.is_some_and(|last| key <= last) {
+                    continue;
+                }
+                if next.is_none_or(|(next, _)| key < next) {
+                    next = Some((key, entry));
+                }
+            }
+        }
+        let Some((key, entry)) = next else {
+            return Ok(());
+        };
+        last = Some(key);
+        let mut line = LineBuf::new();
+        let _ = writeln!(line, "{entry}");
+        let mut bytes = line.as_bytes();
+        while !bytes.is_empty() {
+            let n = unsafe { libc::write(fd, bytes.as_ptr().cast(), bytes.len()) };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            bytes = &bytes[n as usize..];
+        }
+    }
+}
+
+/// The entries of every thread, ordered by time.
+///
+/// At most [`CAPACITY`] per thread that ever recorded, counting those of exited threads until another thread takes over their ring.
+pub fn entries() -> Vec<Entry> {
+    let mut entries = rings()
+        .flat_map(|ring| ring.slots.iter().filter_map(Slot::read))
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|e| e.time);
+    entries
+}
+
+fn rings() -> impl Iterator<Item = &'static Ring> {
+    let head = RINGS.load(Ordering::Acquire);
+    std::iter::successors(unsafe { head.as_ref() }, |ring| unsafe {
+        ring.next.load(Ordering::Acquire).as_ref()
+    })
+}
+
+/// The ring a live thread records into, released to the next new thread on exit.
+struct Claim {
+    ring: &'static Ring,
+    tid: u32,
+}
+impl Claim {
+    fn new() -> Self {
+        let tid = rustix::thread::gettid()
+            .as_raw_nonzero()
+            .get()
+            .unsigned_abs();
+        // Reuse the ring of an exited thread first, so that the rings stay as many as the threads alive at once
+        let ring = rings()
+            .find(|ring| {
+                ring.claimed
+                    .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            })
+            .unwrap_or_else(Ring::push_new);
+        Self { ring, tid }
+    }
+}
+impl Drop for Claim {
+    fn drop(&mut self) {
+        self.ring.claimed.store(false, Ordering::Release);
+    }
+}
+
+struct Ring {
+    next: AtomicPtr<Ring>,
+    /// Held by a live thread
+    claimed: AtomicBool,
+    /// Entries ever pushed; only touched by the claiming thread
+    pushed: AtomicU64,
+    slots: [Slot; CAPACITY],
+}
+impl Ring {
+    /// Make a claimed ring and link it into [`RINGS`].
+    fn push_new() -> &'static Ring {
+        let ring: &'static Ring = Box::leak(Box::new(Ring {
+            next: AtomicPtr::new(null_mut()),
+            claimed: AtomicBool::new(true),
+            pushed: AtomicU64::new(0),
+            slots: std::array::from_fn(|_| Slot::default()),
+        }));
+        let ptr = ring as *const Ring as *mut Ring;
+        let mut head = RINGS.load(Ordering::Relaxed);
+        loop {
+            ring.next.store(head, Ordering::Relaxed);
+            match RINGS.compare_exchange_weak(head, ptr, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return ring,
+                Err(actual) => head = actual,
+            }
+        }
+    }
+
+    fn push(
+        &self,
+        tid: u32,
+        time: u64,
+        op: Op,
+        addr: usize,
+        value: u32,
+        result: Result<usize, i32>,
+    ) {
+        let n = self.pushed.load(Ordering::Relaxed);
+        self.pushed.store(n + 1, Ordering::Relaxed);
+        let slot = &self.slots[(n % CAPACITY as u64) as usize];
+        // A seqlock with a single writer: readers discard the slot while the stamp is zero or changes under them
+        slot.stamp.store(0, Ordering::Relaxed);
+        fence(Ordering::Release);
+        slot.time.store(time, Ordering::Relaxed);
+        slot.tid.store(tid, Ordering::Relaxed);
+        slot.addr.store(addr, Ordering::Relaxed);
+        slot.value.store(value, Ordering::Relaxed);
+        let result = match result {
+            Ok(n) => n as i64,
+            Err(errno) => -i64::from(errno),
+        };
+        slot.result.store(result, Ordering::Relaxed);
+        let op = match op {
+            Op::Wait => 0,
+            Op::Wake => 1,
+        };
+        slot.stamp.store((n + 1) << 1 | op, Ordering::Release);
+    }
+}
+
+#[derive(Default)]
+struct Slot {
+    /// Number of the entry counted from one, shifted left by one to hold the [`Op`]; zero while empty or being written
+    stamp: AtomicU64,
+    time: AtomicU64,
+    tid: AtomicU32,
+    addr: AtomicUsize,
+    value: AtomicU32,
+    /// Nonnegative on success, or the negated `errno`
+    result: AtomicI64,
+}
+impl Slot {
+    fn read(&self) -> Option<Entry> {
+        let stamp = self.stamp.load(Ordering::Acquire);
+        if stamp == 0 {
+            return None;
+        }
+        let result = self.result.load(Ordering::Relaxed);
+        let entry = Entry {
+            time: self.time.load(Ordering::Relaxed),
+            tid: self.tid.load(Ordering::Relaxed),
+            op: if stamp & 1 == 0 { Op::Wait } else { Op::Wake },
+            addr: self.addr.load(Ordering::Relaxed),
+            value: self.value.load(Ordering::Relaxed),
+            result: if 0 <= result {
+                Ok(result as usize)
+            } else {
+                Err(-result as i32)
+            },
+        };
+        fence(Ordering::Acquire);
+        (self.stamp.load(Ordering::Relaxed) == stamp).then_some(entry)
+    }
+}
+
+/// Formats one line on the stack.
+struct LineBuf {
+    buf: [u8; 160],
+    len: usize,
+}
+impl LineBuf {
+    fn new() -> Self {
+        Self {
+            buf: [0; 160],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+impl fmt::Write for LineBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if self.buf.len() < end {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, thread};
+
+    use crate::{mutex::Mutex, semaphore::Semaphore};
+
+    use super::*;
+
+    /// Every woken wait on `addr` follows a wake on it, and the wakes woke at least as many waiters as were woken.
+    fn assert_paired(trace: &[Entry], addr: usize) {
+        let mut woken = 0;
+        let mut waits = 0;
+        for entry in trace.iter().filter(|e| e.addr == addr) {
+            match (entry.op, entry.result) {
+                (Op::Wake, Ok(n)) => woken += n,
+                (Op::Wait, Ok(_)) => {
+                    assert!(0 < woken, "{entry} before any wake");
+                    waits += 1;
+                }
+                _ => (),
+            }
+        }
+        // Spurious returns aside, each woken wait used up one woken waiter
+        assert!(waits <= woken, "{waits} waits woken by {woken} wakes");
+    }
+
+    #[test]
+    fn test_stress_trace() {
+        const THREADS: usize = 4;
+        const ROUNDS: usize = 16;
+        let m = Mutex::new(0);
+        let sem = Semaphore::new(0);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..ROUNDS {
+                        let mut guard = m.lock();
+                        // Held long enough for the others to sleep
+                        thread::sleep(std::time::Duration::from_micros(200));
+                        *guard += 1;
+                    }
+                });
+                s.spawn(|| {
+                    for _ in 0..ROUNDS {
+                        sem.wait();
+                    }
+                });
+            }
+            for _ in 0..THREADS * ROUNDS {
+                sem.signal();
+                thread::yield_now();
+            }
+        });
+        assert_eq!(*m.lock(), THREADS * ROUNDS);
+
+        let trace = entries();
+        let mut per_thread = HashMap::<u32, usize>::new();
+        for entry in &trace {
+            *per_thread.entry(entry.tid).or_default() += 1;
+        }
+        // Bounded by the rings, each of which holds up to `CAPACITY` of the threads that took it in turn
+        assert!(trace.len() <= rings().count() * CAPACITY);
+        assert!(trace.windows(2).all(|w| w[0].time <= w[1].time));
+
+        let m_addr = m.futex_word() as *const AtomicU32 as usize;
+        let sem_addr = sem.futex_word() as *const AtomicU32 as usize;
+        // A ring that wrapped around may have lost the wakes of the waits it kept
+        let oldest = rings()
+            .filter(|ring| CAPACITY as u64 <= ring.pushed.load(Ordering::Relaxed))
+            .filter_map(|ring| {
+                ring.slots
+                    .iter()
+                    .filter_map(Slot::read)
+                    .map(|e| e.time)
+                    .min()
+            })
+            .max()
+            .unwrap_or(0);
+        let kept = trace
+            .iter()
+            .copied()
+            .filter(|e| oldest <= e.time)
+            .collect::<Vec<_>>();
+        assert!(kept.iter().any(|e| e.addr == m_addr && e.op == Op::Wait));
+        assert_paired(&kept, m_addr);
+        assert_paired(&kept, sem_addr);
+
+        let mut out = vec![];
+        dump(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(&format!("wait {m_addr:#x}")));
+        assert!(out.contains(&format!("wake {m_addr:#x}")));
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let reader = thread::spawn(move || {
+            let mut out = vec![];
+            let mut buf = [0_u8; 4096];
+            loop {
+                let n = unsafe { libc::read(fds[0], buf.as_mut_ptr().cast(), buf.len()) };
+                if n <= 0 {
+                    unsafe { libc::close(fds[0]) };
+                    return String::from_utf8(out).unwrap();
+                }
+                out.extend_from_slice(&buf[..n as usize]);
+            }
+        });
+        dump_to_fd(fds[1]).unwrap();
+        unsafe { libc::close(fds[1]) };
+        let out = reader.join().unwrap();
+        assert!(out.contains(&format!("wait {m_addr:#x}")));
+    }
+}
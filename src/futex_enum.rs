@@ -0,0 +1,104 @@
+/// A unit enum stored in a futex word.
+///
+/// Implement it with [`crate::futex_enum!`].
+pub trait FutexEnum: Sized {
+    fn to_word(self) -> u32;
+
+    /// Return [`UnknownState`] rather than panic, since a word in shared memory can be written to anything by another process.
+    fn from_word(word: u32) -> Result<Self, UnknownState>;
+}
+
+/// The futex word holds none of the variants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownState(pub u32);
+impl std::fmt::Display for UnknownState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown state: {}", self.0)
+    }
+}
+impl std::error::Error for UnknownState {}
+
+/// Define a unit enum along with its [`FutexEnum`], `From<_> for u32`, and `TryFrom<u32>` implementations.
+///
+/// ```
+/// futex::futex_enum! {
+///     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+///     pub enum Light {
+///         Off = 0,
+///         On,
+///     }
+/// }
+/// assert_eq!(u32::from(Light::On), 1);
+/// assert_eq!(Light::try_from(1), Ok(Light::On));
+/// assert_eq!(Light::try_from(2), Err(futex::futex_enum::UnknownState(2)));
+/// ```
+#[macro_export]
+macro_rules! futex_enum {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $(
+                $(#[$variant_meta:meta])*
+                $variant:ident $(= $value:expr)?
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis enum $name {
+            $(
+                $(#[$variant_meta])*
+                $variant $(= $value)?
+            ),*
+        }
+        impl $crate::futex_enum::FutexEnum for $name {
+            fn to_word(self) -> u32 {
+                self as u32
+            }
+
+            fn from_word(word: u32) -> Result<Self, $crate::futex_enum::UnknownState> {
+                $(
+                    if word == $name::$variant as u32 {
+                        return Ok($name::$variant);
+                    }
+                )*
+                Err($crate::futex_enum::UnknownState(word))
+            }
+        }
+        impl From<$name> for u32 {
+            fn from(value: $name) -> Self {
+                $crate::futex_enum::FutexEnum::to_word(value)
+            }
+        }
+        impl TryFrom<u32> for $name {
+            type Error = $crate::futex_enum::UnknownState;
+            fn try_from(value: u32) -> Result<Self, Self::Error> {
+                $crate::futex_enum::FutexEnum::from_word(value)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    crate::futex_enum! {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        enum Sparse {
+            A = 3,
+            B,
+            C = 10,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for state in [Sparse::A, Sparse::B, Sparse::C] {
+            assert_eq!(Sparse::from_word(state.to_word()), Ok(state));
+        }
+        assert_eq!(u32::from(Sparse::B), 4);
+        for word in [0, 5, 9, 11, u32::MAX] {
+            assert_eq!(Sparse::from_word(word), Err(UnknownState(word)));
+        }
+    }
+}
@@ -0,0 +1,538 @@
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use crate::{futex_wait, FutexError, FutexScope, FutexTimeout, FutexWaitContext, TimeoutMeasure};
+
+/// What to do each time a waiter finds it has to keep waiting.
+///
+/// The counts are consumed as the strategy progresses, so start every wait from a fresh copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleStrategy {
+    /// Lowest latency; never enters the kernel.
+    BusySpin,
+    /// Spin `spins` times, then yield the thread every time.
+    Yielding { spins: usize },
+    /// Spin `spins` times, yield `yields` times, then sleep on the futex word for at most `park` every time.
+    ///
+    /// Lowest CPU usage if both counts are zero.
+    Parking {
+        spins: usize,
+        yields: usize,
+        park: Duration,
+    },
+}
+impl IdleStrategy {
+    /// Return once the next step of the progression is done, or earlier if `word` is woken up or is not `expected`.
+    ///
+    /// Callers should recheck their condition after every return.
+    pub fn idle(&mut self, word: &AtomicU32, expected: u32) {
+        match self.step() {
+            IdleStep::Spin => std::hint::spin_loop(),
+            IdleStep::Yield => std::thread::yield_now(),
+            IdleStep::Park(park) => {
+                if let Err(e) = futex_wait(FutexWaitContext {
+                    word,
+                    expected,
+                    timeout: Some(FutexTimeout::For(park, TimeoutMeasure::MonoTime)),
+                    scope: FutexScope::Shared,
+                }) {
+                    if !matches!(
+                        e,
+                        FutexError::ValueMismatch | FutexError::TimedOut | FutexError::Interrupted
+                    ) {
+                        panic!("{e}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Advance the progression for callers that park on something other than a bare futex word.
+    pub(crate) fn step(&mut self) -> IdleStep {
+        match self {
+            IdleStrategy::BusySpin => IdleStep::Spin,
+            IdleStrategy::Yielding { spins } => {
+                if 0 < *spins {
+                    *spins -= 1;
+                    return IdleStep::Spin;
+                }
+                IdleStep::Yield
+            }
+            IdleStrategy::Parking {
+                spins,
+                yields,
+                park,
+            } => {
+                if 0 < *spins {
+                    *spins -= 1;
+                    return IdleStep::Spin;
+                }
+                if 0 < *yields {
+                    *yields -= 1;
+                    return IdleStep::Yield;
+                }
+                IdleStep::Park(*park)
+            }
+        }
+    }
+}
+
+/// Exponential backoff for a CAS retry loop: each [`Self::spin`] issues twice as many spin-loop hints as the last, up to a cap.
+///
+/// Retrying right after a failed CAS keeps every contender hammering the same cache line, so they mostly invalidate each other's attempts.
+/// Backing off for longer after each failure spreads the retries out; [`Self::jittered`] also desynchronizes contenders that failed at the same moment.
+///
+/// ```
+/// use std::sync::atomic::{AtomicU32, Ordering};
+///
+/// use futex::idle::CasBackoff;
+///
+/// let word = AtomicU32::new(0);
+/// let mut backoff = CasBackoff::new().jittered();
+/// let mut value = word.load(Ordering::Relaxed);
+/// while let Err(actual) =
+///     word.compare_exchange_weak(value, value + 1, Ordering::AcqRel, Ordering::Relaxed)
+/// {
+///     value = actual;
+///     backoff.spin();
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CasBackoff {
+    step: u32,
+    max_step: u32,
+    jitter: bool,
+}
+impl CasBackoff {
+    /// Spins of at most `1 << 6` hints
+    pub const DEFAULT_MAX_STEP: u32 = 6;
+
+    pub const fn new() -> Self {
+        Self::with_max_step(Self::DEFAULT_MAX_STEP)
+    }
+
+    /// Cap the spins at `1 << max_step` hints.
+    ///
+    /// # Panic
+    ///
+    /// If `max_step` reaches `32`.
+    pub const fn with_max_step(max_step: u32) -> Self {
+        assert!(max_step < u32::BITS);
+        Self {
+            step: 0,
+            max_step,
+            jitter: false,
+        }
+    }
+
+    /// Shorten each spin to a random length between half and all of [`Self::spins`].
+    pub const fn jittered(mut self) -> Self {
+        self.jitter = true;
+        self
+    }
+
+    /// Spin-loop hints the next [`Self::spin`] issues, before any jitter.
+    pub fn spins(&self) -> u32 {
+        1 << self.step
+    }
+
+    /// Whether the spins have stopped growing.
+    ///
+    /// Callers that would rather yield or sleep than keep spinning at the cap switch over here.
+    pub fn is_capped(&self) -> bool {
+        self.step == self.max_step
+    }
+
+    /// Back off before the next attempt; return the number of hints issued.
+    pub fn spin(&mut self) -> u32 {
+        let mut spins = self.spins();
+        if self.jitter {
+            let half = spins / 2;
+            spins -= xorshift() % (half + 1);
+        }
+        for _ in 0..spins {
+            std::hint::spin_loop();
+        }
+        if self.step < self.max_step {
+            self.step += 1;
+        }
+        spins
+    }
+
+    /// Start over from a single hint, e.g., after a successful CAS in a loop that keeps going.
+    pub fn reset(&mut self) {
+        self.step = 0;
+    }
+}
+impl Default for CasBackoff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How [`crate::busy_futex_wait_with`] and other retry loops pause between attempts that found the futex word not as expected.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use futex::idle::Backoff;
+///
+/// let backoff = Backoff::exponential(Duration::from_micros(1), Duration::from_millis(1));
+/// # let _ = backoff;
+/// ```
+#[derive(Default)]
+pub enum Backoff<'a> {
+    /// One spin-loop hint; what [`crate::busy_futex_wait`] does.
+    #[default]
+    Spin,
+    /// Yield the thread every time.
+    Yield,
+    /// Sleep for `next`, doubling it after every sleep until it reaches `max`.
+    Exponential { next: Duration, max: Duration },
+    /// Call the closure with the number of pauses before this one.
+    Custom {
+        pause: &'a mut dyn FnMut(u32),
+        pauses: u32,
+    },
+}
+impl<'a> Backoff<'a> {
+    /// # Panic
+    ///
+    /// If `min` is zero or exceeds `max`.
+    pub fn exponential(min: Duration, max: Duration) -> Self {
+        assert!(!min.is_zero() && min <= max);
+        Self::Exponential { next: min, max }
+    }
+
+    pub fn custom(pause: &'a mut dyn FnMut(u32)) -> Self {
+        Self::Custom { pause, pauses: 0 }
+    }
+
+    /// Pause before the next attempt.
+    pub fn pause(&mut self) {
+        match self {
+            Self::Spin => std::hint::spin_loop(),
+            Self::Yield => std::thread::yield_now(),
+            Self::Exponential { next, max } => {
+                std::thread::sleep(*next);
+                *next = (*next * 2).min(*max);
+            }
+            Self::Custom { pause, pauses } => {
+                pause(*pauses);
+                *pauses = pauses.saturating_add(1);
+            }
+        }
+    }
+}
+impl std::fmt::Debug for Backoff<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Spin => write!(f, "Spin"),
+            Self::Yield => write!(f, "Yield"),
+            Self::Exponential { next, max } => f
+                .debug_struct("Exponential")
+                .field("next", next)
+                .field("max", max)
+                .finish(),
+            Self::Custom { pauses, .. } => f
+                .debug_struct("Custom")
+                .field("pauses", pauses)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+/// A per-thread xorshift32, good enough to spread retries out.
+fn xorshift() -> u32 {
+    thread_local! {
+        static STATE: Cell<u32> = const { Cell::new(0) };
+    }
+    STATE.with(|state| {
+        let mut x = state.get();
+        if x == 0 {
+            // Seeded by the address of the thread's own state, never zero
+            x = (state as *const Cell<u32> as usize as u32) | 1;
+        }
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        state.set(x);
+        x
+    })
+}
+
+/// Backoff for the crate's lock-free retry loops, so that a loop losing its CAS over and over under adversarial scheduling degrades to sleeping instead of burning a core.
+///
+/// Spin with a jittered [`CasBackoff`] until it caps, then yield, then sleep briefly on a word nobody wakes.
+/// A loop behind a `try_` call takes [`Self::spinning`] instead, which never leaves the capped spin.
+#[derive(Debug)]
+pub(crate) struct RetryBudget {
+    backoff: CasBackoff,
+    idle: IdleStrategy,
+}
+impl RetryBudget {
+    pub(crate) const MAX_SPIN_STEP: u32 = CasBackoff::DEFAULT_MAX_STEP;
+    pub(crate) const YIELDS: usize = 16;
+    pub(crate) const PARK: Duration = Duration::from_micros(50);
+
+    pub(crate) fn new() -> Self {
+        Self {
+            backoff: CasBackoff::with_max_step(Self::MAX_SPIN_STEP).jittered(),
+            idle: IdleStrategy::Parking {
+                spins: 0,
+                yields: Self::YIELDS,
+                park: Self::PARK,
+            },
+        }
+    }
+
+    /// Never yield or sleep; for the loops of calls that must not block.
+    pub(crate) fn spinning() -> Self {
+        Self {
+            backoff: CasBackoff::with_max_step(Self::MAX_SPIN_STEP).jittered(),
+            idle: IdleStrategy::BusySpin,
+        }
+    }
+
+    /// Back off before the next attempt.
+    pub(crate) fn retry(&mut self) {
+        static BACKOFF: AtomicU32 = AtomicU32::new(0);
+        if !self.backoff.is_capped() || matches!(self.idle, IdleStrategy::BusySpin) {
+            self.backoff.spin();
+            return;
+        }
+        self.idle.idle(&BACKOFF, 0);
+    }
+}
+
+/// Spin budget of a contended lock that adapts to whether recent spinning paid off, like the adaptive mutexes of glibc.
+///
+/// Keeps an exponentially weighted average of how often the spinning of a contended acquisition still ended in a sleep, and spins the less the more often it did.
+/// About one in [`Self::PROBE_PERIOD`] acquisitions spins the full budget regardless, so that a budget worn down to zero recovers once the hold times shrink.
+///
+/// Only touched off the fast path, with relaxed loads and stores; racing updates lose one of them at worst.
+///
+/// The all-zero bit pattern is the full budget, as made by [`Self::new`].
+#[derive(Debug)]
+#[repr(transparent)]
+pub(crate) struct AdaptiveSpin {
+    /// Rate of spinning in vain, out of [`Self::ONE`]
+    wasted: AtomicU32,
+}
+impl AdaptiveSpin {
+    pub(crate) const MAX_SPINS: u32 = 256;
+    pub(crate) const PROBE_PERIOD: u32 = 32;
+    const ONE: u32 = 1 << 12;
+    /// The newest outcome weighs `1 / 2^WEIGHT_SHIFT`
+    const WEIGHT_SHIFT: u32 = 3;
+
+    pub(crate) const fn new() -> Self {
+        Self {
+            wasted: AtomicU32::new(0),
+        }
+    }
+
+    /// Spins the next contended acquisition may take before sleeping.
+    pub(crate) fn budget(&self) -> u32 {
+        if xorshift().is_multiple_of(Self::PROBE_PERIOD) {
+            return Self::MAX_SPINS;
+        }
+        self.snapshot()
+    }
+
+    /// Only a snapshot; the budget without probing.
+    pub(crate) fn snapshot(&self) -> u32 {
+        let wasted = self.wasted.load(Ordering::Relaxed).min(Self::ONE);
+        Self::MAX_SPINS * (Self::ONE - wasted) / Self::ONE
+    }
+
+    /// Report whether spinning through `budget` acquired before it ran out; nothing is learned from a zero budget.
+    pub(crate) fn record(&self, budget: u32, paid_off: bool) {
+        if budget == 0 {
+            return;
+        }
+        let wasted = self.wasted.load(Ordering::Relaxed).min(Self::ONE);
+        let target = if paid_off { 0 } else { Self::ONE };
+        let wasted = wasted - (wasted >> Self::WEIGHT_SHIFT) + (target >> Self::WEIGHT_SHIFT);
+        self.wasted.store(wasted, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IdleStep {
+    Spin,
+    Yield,
+    Park(Duration),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::Ordering,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{futex_wake, mock_backend::WAIT_SYSCALLS, WakeWaiters};
+
+    use super::*;
+
+    const STRATEGIES: [IdleStrategy; 4] = [
+        IdleStrategy::BusySpin,
+        IdleStrategy::Yielding { spins: 16 },
+        IdleStrategy::Parking {
+            spins: 16,
+            yields: 4,
+            park: Duration::from_millis(10),
+        },
+        IdleStrategy::Parking {
+            spins: 0,
+            yields: 0,
+            park: Duration::from_secs(10),
+        },
+    ];
+
+    #[test]
+    fn test_eventually_succeeds() {
+        for strategy in STRATEGIES {
+            let word = AtomicU32::new(0);
+            thread::scope(|s| {
+                s.spawn(|| {
+                    let mut idle = strategy;
+                    while word.load(Ordering::Acquire) == 0 {
+                        idle.idle(&word, 0);
+                    }
+                });
+                thread::sleep(Duration::from_millis(20));
+                word.store(1, Ordering::Release);
+                futex_wake(&word, WakeWaiters::All).unwrap();
+            });
+        }
+    }
+
+    #[test]
+    fn test_progression() {
+        let mut idle = IdleStrategy::Parking {
+            spins: 2,
+            yields: 1,
+            park: Duration::from_millis(1),
+        };
+        let steps = (0..5).map(|_| idle.step()).collect::<Vec<_>>();
+        assert_eq!(
+            steps,
+            [
+                IdleStep::Spin,
+                IdleStep::Spin,
+                IdleStep::Yield,
+                IdleStep::Park(Duration::from_millis(1)),
+                IdleStep::Park(Duration::from_millis(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_only_parking_sleeps() {
+        let word = AtomicU32::new(0);
+        let park = Duration::from_millis(50);
+
+        let waits = WAIT_SYSCALLS.get();
+        IdleStrategy::BusySpin.idle(&word, 0);
+        IdleStrategy::Yielding { spins: 0 }.idle(&word, 0);
+        assert_eq!(WAIT_SYSCALLS.get(), waits);
+
+        let start = Instant::now();
+        IdleStrategy::Parking {
+            spins: 0,
+            yields: 0,
+            park,
+        }
+        .idle(&word, 0);
+        assert_eq!(WAIT_SYSCALLS.get(), waits + 1);
+        assert!(park <= start.elapsed());
+    }
+
+    #[test]
+    fn test_cas_backoff_escalation() {
+        let mut backoff = CasBackoff::with_max_step(3);
+        let spins = (0..6).map(|_| backoff.spin()).collect::<Vec<_>>();
+        assert_eq!(spins, [1, 2, 4, 8, 8, 8]);
+        assert!(backoff.is_capped());
+        backoff.reset();
+        assert_eq!(backoff.spins(), 1);
+        assert!(!backoff.is_capped());
+
+        let mut backoff = CasBackoff::new().jittered();
+        let mut capped = (0..64)
+            .map(|_| backoff.spin())
+            .skip(CasBackoff::DEFAULT_MAX_STEP as usize)
+            .collect::<Vec<_>>();
+        let cap = 1 << CasBackoff::DEFAULT_MAX_STEP;
+        assert!(capped.iter().all(|&n| cap / 2 <= n && n <= cap));
+        capped.dedup();
+        assert!(1 < capped.len(), "never jittered");
+    }
+
+    #[test]
+    fn test_retry_budget_escalation() {
+        let mut budget = RetryBudget::new();
+        for _ in 0..RetryBudget::MAX_SPIN_STEP {
+            assert!(!budget.backoff.is_capped());
+            budget.retry();
+        }
+        assert!(budget.backoff.is_capped());
+        let steps = (0..RetryBudget::YIELDS + 2)
+            .map(|_| budget.idle.step())
+            .collect::<Vec<_>>();
+        let (yields, parks) = steps.split_at(RetryBudget::YIELDS);
+        assert!(yields.iter().all(|s| *s == IdleStep::Yield));
+        assert!(parks
+            .iter()
+            .all(|s| *s == IdleStep::Park(RetryBudget::PARK)));
+
+        // Parking on the backoff word is a bounded sleep
+        let start = Instant::now();
+        budget.retry();
+        assert!(RetryBudget::PARK <= start.elapsed());
+    }
+
+    #[test]
+    fn test_spinning_retry_budget_never_idles() {
+        let mut budget = RetryBudget::spinning();
+        for _ in 0..RetryBudget::MAX_SPIN_STEP + RetryBudget::YIELDS as u32 + 2 {
+            budget.retry();
+        }
+        assert!(budget.backoff.is_capped());
+        assert!((0..RetryBudget::YIELDS + 2).all(|_| budget.idle.step() == IdleStep::Spin));
+    }
+
+    #[test]
+    fn test_adaptive_spin_converges() {
+        let spin = AdaptiveSpin::new();
+        assert_eq!(spin.snapshot(), AdaptiveSpin::MAX_SPINS);
+
+        for _ in 0..64 {
+            spin.record(AdaptiveSpin::MAX_SPINS, false);
+        }
+        assert!(spin.snapshot() <= 2, "{}", spin.snapshot());
+        // Nothing to learn without spinning
+        spin.record(0, true);
+        assert!(spin.snapshot() <= 2);
+        // Probes still spin now and then
+        let probes = (0..AdaptiveSpin::PROBE_PERIOD * 64)
+            .filter(|_| spin.budget() == AdaptiveSpin::MAX_SPINS)
+            .count();
+        assert!(0 < probes);
+
+        for _ in 0..64 {
+            spin.record(AdaptiveSpin::MAX_SPINS, true);
+        }
+        assert!(
+            AdaptiveSpin::MAX_SPINS - 2 <= spin.snapshot(),
+            "{}",
+            spin.snapshot()
+        );
+    }
+}
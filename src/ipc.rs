@@ -0,0 +1,50 @@
+//! Plumbing for primitives shared between a process and the children it forks.
+
+use std::{ops::Deref, ptr::NonNull};
+
+use rustix::mm::{MapFlags, ProtFlags};
+
+use crate::shared_cell::SharedSafe;
+
+pub mod demo;
+
+/// A `T` in an anonymous shared mapping, which children forked afterwards share with the parent instead of copying.
+///
+/// The value is never dropped; each process unmaps its own view of it on drop.
+#[derive(Debug)]
+pub struct SharedMapping<T: SharedSafe> {
+    ptr: NonNull<T>,
+}
+// Only ever hands out `&T`, and `T: SharedSafe` is `Sync`
+unsafe impl<T: SharedSafe> Send for SharedMapping<T> {}
+unsafe impl<T: SharedSafe> Sync for SharedMapping<T> {}
+impl<T: SharedSafe> SharedMapping<T> {
+    pub fn new(value: T) -> std::io::Result<Self> {
+        let ptr = unsafe {
+            rustix::mm::mmap_anonymous(
+                std::ptr::null_mut(),
+                std::mem::size_of::<T>(),
+                ProtFlags::READ | ProtFlags::WRITE,
+                MapFlags::SHARED,
+            )
+        }?;
+        // Page-aligned, which covers the alignment of any `T` short of a page
+        let ptr = ptr.cast::<T>();
+        unsafe { ptr.write(value) };
+        Ok(Self {
+            ptr: NonNull::new(ptr).unwrap(),
+        })
+    }
+}
+impl<T: SharedSafe> Deref for SharedMapping<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+impl<T: SharedSafe> Drop for SharedMapping<T> {
+    fn drop(&mut self) {
+        unsafe { rustix::mm::munmap(self.ptr.as_ptr().cast(), std::mem::size_of::<T>()) }.unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+//! The two-process demonstration of `examples/alternate_writes.rs`, over a [`super::SharedMapping`] instead of raw words, kept in the library so that it runs under the tests.
+//!
+//! Based on the `futex_demo` on <https://lwn.net/Articles/638283/>.
+
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use crate::{ping_pong::PingPong, shared_cell::SharedSafe};
+
+/// What both processes of [`alternating_counters`] share.
+#[derive(Debug)]
+#[repr(C)]
+pub struct AlternatingCounters {
+    ping_pong: PingPong,
+    /// Turns taken by side A and side B
+    counts: [AtomicU32; 2],
+    /// Number of the last turn taken by either side, counted from zero
+    last: AtomicU32,
+    /// Turns that did not follow right after the last one
+    out_of_order: AtomicU32,
+}
+unsafe impl SharedSafe for AlternatingCounters {}
+impl AlternatingCounters {
+    pub fn new() -> Self {
+        Self {
+            ping_pong: PingPong::new(),
+            counts: [AtomicU32::new(0), AtomicU32::new(0)],
+            last: AtomicU32::new(u32::MAX),
+            out_of_order: AtomicU32::new(0),
+        }
+    }
+
+    /// Only a snapshot.
+    pub fn counts(&self) -> [u32; 2] {
+        [
+            self.counts[0].load(Ordering::Relaxed),
+            self.counts[1].load(Ordering::Relaxed),
+        ]
+    }
+
+    /// Only a snapshot.
+    ///
+    /// Zero as long as the sides strictly alternate.
+    pub fn out_of_order(&self) -> u32 {
+        self.out_of_order.load(Ordering::Relaxed)
+    }
+}
+impl Default for AlternatingCounters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Which side of [`AlternatingCounters`] a process drives; side A goes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    A,
+    B,
+}
+
+/// Take `iterations` turns as `role`, counting each in `region` and calling `on_turn` with its index while holding the turn.
+///
+/// Each process must drive a different role.
+/// Give up with [`TurnTimedOut`] if the other side keeps the turn for longer than `turn_timeout`, e.g., on a lost wake-up or a crashed peer.
+pub fn alternating_counters(
+    region: &AlternatingCounters,
+    role: Role,
+    iterations: u32,
+    turn_timeout: Option<Duration>,
+    mut on_turn: impl FnMut(u32),
+) -> Result<(), TurnTimedOut> {
+    let (side, parity) = match role {
+        Role::A => (region.ping_pong.side_a(), 0),
+        Role::B => (region.ping_pong.side_b(), 1),
+    };
+    for i in 0..iterations {
+        if !side.wait_turn(turn_timeout) {
+            return Err(TurnTimedOut { iteration: i });
+        }
+        let turn = 2 * i + parity;
+        if region.last.swap(turn, Ordering::Relaxed) != turn.wrapping_sub(1) {
+            region.out_of_order.fetch_add(1, Ordering::Relaxed);
+        }
+        region.counts[parity as usize].fetch_add(1, Ordering::Relaxed);
+        on_turn(i);
+        side.pass_turn();
+    }
+    Ok(())
+}
+
+/// The other side of [`alternating_counters`] kept the turn too long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TurnTimedOut {
+    /// Of the turn waited for
+    pub iteration: u32,
+}
+impl std::fmt::Display for TurnTimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "timed out waiting for turn {}", self.iteration)
+    }
+}
+impl std::error::Error for TurnTimedOut {}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_alternating_threads() {
+        const ITERATIONS: u32 = 1_000;
+        let region = AlternatingCounters::new();
+        thread::scope(|s| {
+            let b = s.spawn(|| alternating_counters(&region, Role::B, ITERATIONS, None, |_| ()));
+            alternating_counters(&region, Role::A, ITERATIONS, None, |_| ()).unwrap();
+            b.join().unwrap().unwrap();
+        });
+        assert_eq!(region.counts(), [ITERATIONS, ITERATIONS]);
+        assert_eq!(region.out_of_order(), 0);
+
+        // Side B is left waiting with nobody to pass it the turn
+        let timeout = Some(Duration::from_millis(10));
+        assert_eq!(
+            alternating_counters(&region, Role::B, 1, timeout, |_| ()),
+            Err(TurnTimedOut { iteration: 0 })
+        );
+    }
+}
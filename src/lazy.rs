@@ -0,0 +1,209 @@
+use std::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    ops::Deref,
+    panic::{RefUnwindSafe, UnwindSafe},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::{
+    futex_enum::FutexEnum,
+    observer::{futex_wake_from, observed_futex_wait, Primitive},
+    violation::violation,
+    FutexError, FutexScope, FutexWaitContext, WakeWaiters,
+};
+
+crate::futex_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum State {
+        Incomplete = 0,
+        Running,
+        Complete,
+        Poisoned,
+    }
+}
+
+/// A value initialized by `F` on first access, usable in statics.
+///
+/// Concurrent first accesses sleep on a futex word until the one running `F` is done.
+pub struct Lazy<T, F = fn() -> T> {
+    state: AtomicU32,
+    value: UnsafeCell<MaybeUninit<T>>,
+    /// Taken by the thread moving the state to [`State::Running`]
+    init: UnsafeCell<Option<F>>,
+}
+// Like `std::sync::LazyLock`: `F` is called on whichever thread gets there first, and `T` is shared once initialized
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+// A panicking `F` poisons the instance rather than leaving it half-initialized
+impl<T: RefUnwindSafe + UnwindSafe, F: UnwindSafe> RefUnwindSafe for Lazy<T, F> {}
+impl<T: UnwindSafe, F: UnwindSafe> UnwindSafe for Lazy<T, F> {}
+impl<T, F> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Self {
+            state: AtomicU32::new(State::Incomplete as u32),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+            init: UnsafeCell::new(Some(init)),
+        }
+    }
+
+    /// Return [`None`] if not initialized yet, without initializing.
+    pub fn get(this: &Self) -> Option<&T> {
+        if this.state() != State::Complete {
+            return None;
+        }
+        Some(unsafe { (*this.value.get()).assume_init_ref() })
+    }
+
+    fn state(&self) -> State {
+        let word = self.state.load(Ordering::Acquire);
+        match State::from_word(word) {
+            Ok(state) => state,
+            Err(_) => violation!(UnknownState, word),
+        }
+    }
+}
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    /// Initialize the value if not yet and return it.
+    ///
+    /// # Panic
+    ///
+    /// If `F` panicked, on this or an earlier call.
+    pub fn force(this: &Self) -> &T {
+        loop {
+            match this.state() {
+                State::Complete => return unsafe { (*this.value.get()).assume_init_ref() },
+                State::Poisoned => panic!("Lazy instance has previously been poisoned"),
+                State::Incomplete => {
+                    if this
+                        .state
+                        .compare_exchange(
+                            State::Incomplete.into(),
+                            State::Running.into(),
+                            Ordering::Acquire,
+                            Ordering::Relaxed,
+                        )
+                        .is_ok()
+                    {
+                        return this.run_init();
+                    }
+                }
+                State::Running => {
+                    if let Err(e) = observed_futex_wait(
+                        Primitive::Lazy,
+                        FutexWaitContext {
+                            word: &this.state,
+                            expected: State::Running.into(),
+                            timeout: None,
+                            scope: FutexScope::Shared,
+                        },
+                    ) {
+                        if !matches!(e.error, FutexError::ValueMismatch) {
+                            panic!("{e}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[cold]
+    fn run_init(&self) -> &T {
+        /// Poison the state if `F` unwinds
+        struct Finish<'a> {
+            state: &'a AtomicU32,
+            to: State,
+        }
+        impl Drop for Finish<'_> {
+            fn drop(&mut self) {
+                self.state.store(self.to.into(), Ordering::Release);
+                futex_wake_from(
+                    Primitive::Lazy,
+                    self.state,
+                    WakeWaiters::All,
+                    FutexScope::Shared,
+                )
+                .unwrap();
+            }
+        }
+
+        let mut finish = Finish {
+            state: &self.state,
+            to: State::Poisoned,
+        };
+        // Only the thread that moved the state to `Running` gets here
+        let init = unsafe { (*self.init.get()).take() }.unwrap();
+        let value = unsafe { (*self.value.get()).write(init()) };
+        finish.to = State::Complete;
+        drop(finish);
+        value
+    }
+}
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        Self::force(self)
+    }
+}
+impl<T: std::fmt::Debug, F> std::fmt::Debug for Lazy<T, F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_tuple("Lazy");
+        match Self::get(self) {
+            Some(value) => d.field(value),
+            None => d.field(&format_args!("<uninit>")),
+        };
+        d.finish()
+    }
+}
+impl<T, F> Drop for Lazy<T, F> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == u32::from(State::Complete) {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::AtomicUsize, time::Duration};
+
+    use super::*;
+
+    static CONFIG: Lazy<Vec<u32>> = Lazy::new(|| vec![1, 2, 3]);
+
+    #[test]
+    fn test_static() {
+        assert_eq!(CONFIG.len(), 3);
+        assert_eq!(Lazy::get(&CONFIG), Some(&vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_racing_first_deref() {
+        let calls = AtomicUsize::new(0);
+        let lazy = Lazy::new(|| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            std::thread::sleep(Duration::from_millis(50));
+            String::from("ready")
+        });
+        assert_eq!(Lazy::get(&lazy), None);
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| assert_eq!(*lazy, "ready"));
+            }
+        });
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert_eq!(Lazy::get(&lazy).map(String::as_str), Some("ready"));
+    }
+
+    #[test]
+    fn test_poisoned() {
+        let lazy: Lazy<u32, _> = Lazy::new(|| panic!("init failed"));
+        assert!(std::panic::catch_unwind(|| *lazy).is_err());
+        let e = std::panic::catch_unwind(|| *lazy).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<&str>(),
+            Some(&"Lazy instance has previously been poisoned")
+        );
+        assert_eq!(Lazy::get(&lazy), None);
+    }
+}
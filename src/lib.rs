@@ -1,20 +1,246 @@
-use std::{mem::transmute, sync::atomic::AtomicU32, time::Duration};
+use std::{
+    num::NonZeroU32,
+    sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering},
+    time::{Duration, Instant, SystemTime},
+};
 
+pub mod barrier;
+pub mod compat;
+pub mod completion;
+pub mod composite;
 pub mod cond_var;
+pub mod deadline;
+pub mod debounce;
+pub mod event;
+pub mod flag_set;
+#[cfg(feature = "flight-recorder")]
+pub mod flight_recorder;
+pub mod futex_enum;
+pub mod idle;
+pub mod ipc;
+pub mod lazy;
+#[cfg(all(test, loom))]
+mod loom_model;
+pub mod mailbox;
+#[cfg(test)]
+mod mock_backend;
 pub mod mutex;
+pub mod named;
+pub mod observer;
+pub mod parallel;
+pub mod persistent;
+mod pi;
+pub mod ping_pong;
+pub mod probe;
+pub mod pubsub;
+#[cfg(feature = "registry")]
+pub mod registry;
+#[cfg(all(debug_assertions, feature = "relocation-check"))]
+mod relocation;
 pub mod ring_buffer;
+pub mod robust;
+pub mod rw_lock;
 pub mod semaphore;
+pub mod sharded_semaphore;
+pub mod shared_cell;
+pub mod shared_ring_buffer;
+pub mod shutdown;
+pub mod signal;
+pub mod slot;
+pub mod state_machine;
+pub mod teardown;
+pub mod traced;
+pub mod violation;
+pub mod waitable;
+mod wake_scope;
+pub mod workers;
+
+pub use wake_scope::wake_scope;
+pub use workers::scope;
 
 #[derive(Debug, Clone, Copy)]
 pub struct FutexWaitContext<'a> {
     pub word: &'a AtomicU32,
     pub expected: u32,
-    pub timeout: Option<(Duration, TimeoutMeasure)>,
+    pub timeout: Option<FutexTimeout>,
+    /// Must match the scope of the wakes meant for the waiter
+    pub scope: FutexScope,
+}
+
+/// A futex word with its own wait and wake calls, all of [`FutexScope::Shared`].
+///
+/// The free functions taking a [`FutexWaitContext`] remain for the other scopes and wait flavors; [`Self::context`] builds one.
+///
+/// ```
+/// use std::sync::atomic::Ordering;
+///
+/// use futex::Futex;
+///
+/// static READY: Futex = Futex::new(0);
+///
+/// std::thread::scope(|s| {
+///     s.spawn(|| {
+///         READY.store(1, Ordering::Release);
+///         READY.wake_all().unwrap();
+///     });
+///     while READY.load(Ordering::Acquire) == 0 {
+///         let _ = READY.wait(0);
+///     }
+/// });
+/// ```
+#[derive(Debug, Default)]
+#[repr(transparent)]
+pub struct Futex(AtomicU32);
+impl Futex {
+    pub const fn new(value: u32) -> Self {
+        Self(AtomicU32::new(value))
+    }
+
+    /// View a word owned elsewhere as a [`Futex`].
+    pub fn from_atomic(word: &AtomicU32) -> &Self {
+        // SAFETY: `Futex` is a transparent wrapper of `AtomicU32`
+        unsafe { &*(word as *const AtomicU32).cast::<Self>() }
+    }
+
+    pub fn into_inner(self) -> u32 {
+        self.0.into_inner()
+    }
+
+    /// A wait on this word, for the free functions.
+    pub fn context(
+        &self,
+        expected: u32,
+        timeout: Option<FutexTimeout>,
+        scope: FutexScope,
+    ) -> FutexWaitContext<'_> {
+        FutexWaitContext {
+            word: &self.0,
+            expected,
+            timeout,
+            scope,
+        }
+    }
+
+    /// Learn more from [`futex_wait`].
+    pub fn wait(&self, expected: u32) -> Result<(), FutexError> {
+        futex_wait(self.context(expected, None, FutexScope::Shared))
+    }
+
+    /// Learn more from [`futex_wait`].
+    pub fn wait_timeout(
+        &self,
+        expected: u32,
+        timeout: Duration,
+        measure: TimeoutMeasure,
+    ) -> Result<(), FutexError> {
+        let timeout = FutexTimeout::For(timeout, measure);
+        futex_wait(self.context(expected, Some(timeout), FutexScope::Shared))
+    }
+
+    /// Learn more from [`futex_wake`].
+    pub fn wake(&self, waiters: WakeWaiters) -> Result<usize, FutexError> {
+        futex_wake(&self.0, waiters)
+    }
+
+    /// Return whether a waiter was woken up.
+    pub fn wake_one(&self) -> Result<bool, FutexError> {
+        Ok(self.wake(WakeWaiters::ONE)? != 0)
+    }
+
+    /// Return the number of waiters that were woken up.
+    pub fn wake_all(&self) -> Result<usize, FutexError> {
+        self.wake(WakeWaiters::All)
+    }
+}
+impl std::ops::Deref for Futex {
+    type Target = AtomicU32;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// How the kernel keys a futex word; a wait only pairs with the wakes of the same scope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum FutexScope {
+    /// Keyed by the mapping holding the word, so that other processes mapping it reach the same waiters
+    #[default]
+    Shared,
+    /// Keyed by the address in this process alone, using `FUTEX_PRIVATE_FLAG`, which spares the kernel the lookup of the mapping
+    ///
+    /// A primitive waiting in this scope must not be shared between processes, e.g., through [`crate::shared_cell::SharedCell`], since the waits and wakes of one process never reach the other.
+    /// The `new_private` constructors of the primitives also register their waiters in bytes assigned per process, which another process would clash with.
+    Private,
+}
+impl FutexScope {
+    fn flags(self) -> rustix::thread::FutexFlags {
+        match self {
+            FutexScope::Shared => rustix::thread::FutexFlags::empty(),
+            FutexScope::Private => rustix::thread::FutexFlags::PRIVATE,
+        }
+    }
+}
+
+/// When a futex wait gives up with [`FutexError::TimedOut`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FutexTimeout {
+    /// From when the wait starts, so re-issuing the wait restarts the whole duration
+    For(Duration, TimeoutMeasure),
+    /// On the monotonic clock, using `FUTEX_WAIT_BITSET`
+    Until(Instant),
+    /// On the real-time clock, so it follows the system clock being set, using `FUTEX_WAIT_BITSET`
+    UntilSystemTime(SystemTime),
+}
+impl FutexTimeout {
+    /// Turn a [`Self::For`] into the deadline it reaches from now, so that every re-issued wait shares that deadline.
+    pub fn anchored(self) -> Self {
+        match self {
+            Self::For(t, TimeoutMeasure::MonoTime) => {
+                Instant::now().checked_add(t).map_or(self, Self::Until)
+            }
+            Self::For(t, TimeoutMeasure::RealTime) => SystemTime::now()
+                .checked_add(t)
+                .map_or(self, Self::UntilSystemTime),
+            Self::Until(_) | Self::UntilSystemTime(_) => self,
+        }
+    }
+
+    /// Zero once the deadline has passed.
+    pub fn remaining(self) -> Duration {
+        match self {
+            Self::For(t, _) => t,
+            Self::Until(deadline) => deadline.saturating_duration_since(Instant::now()),
+            Self::UntilSystemTime(deadline) => deadline
+                .duration_since(SystemTime::now())
+                .unwrap_or(Duration::ZERO),
+        }
+    }
+
+    /// The deadline as the kernel takes it for `FUTEX_WAIT_BITSET`, along with the flag of its clock.
+    fn absolute(self) -> (rustix::thread::Timespec, rustix::thread::FutexFlags) {
+        match self {
+            Self::For(t, measure) => (absolute_timespec(t, measure), measure.flags()),
+            Self::Until(_) => (
+                absolute_timespec(self.remaining(), TimeoutMeasure::MonoTime),
+                rustix::thread::FutexFlags::empty(),
+            ),
+            Self::UntilSystemTime(deadline) => {
+                // A deadline before the epoch has passed all the same
+                let since_epoch = deadline
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or(Duration::ZERO);
+                let timespec = rustix::thread::Timespec {
+                    tv_sec: i64::try_from(since_epoch.as_secs()).unwrap_or(i64::MAX),
+                    tv_nsec: i64::from(since_epoch.subsec_nanos()),
+                };
+                (timespec, rustix::thread::FutexFlags::CLOCK_REALTIME)
+            }
+        }
+    }
 }
 
 /// # Behaviors
 ///
-/// - If the futex word's value is not `expected`, it returns [`std::io::ErrorKind::WouldBlock`] error immediately
+/// - If the futex word's value is not `expected`, it returns [`FutexError::ValueMismatch`] immediately
 ///   - to prevent lost wake-ups
 /// - If the futex word's value matches `expected`, it sleeps until `FUTEX_WAKE` is called at `addr`
 ///
@@ -22,11 +248,69 @@ pub struct FutexWaitContext<'a> {
 ///
 /// The [`Ok`] return can be a spurious wake-up.
 /// Therefore, callers should use the futex word's value to decide whether to continue to block or not.
-pub fn futex_wait(cx: FutexWaitContext<'_>) -> std::io::Result<()> {
-    let timeout_duration = cx.timeout.map(|(t, _m)| t);
-    let measure = cx.timeout.map(|(_t, m)| m);
+pub fn futex_wait(cx: FutexWaitContext<'_>) -> Result<(), FutexError> {
+    unsafe { futex_wait_ptr_in(cx.word.as_ptr(), cx.expected, cx.timeout, cx.scope) }
+}
+/// [`futex_wait`] of [`FutexScope::Shared`] on a word reached only through a raw pointer, e.g., in a mapping whose lifetime is managed by hand.
+///
+/// No reference to the word is made, so none outlives the mapping or aliases the writes of other processes.
+///
+/// # Safety
+///
+/// For the duration of the call, `word` must be:
+///
+/// - non-null and 4-byte aligned;
+/// - mapped and readable, since the kernel reads it to compare with `expected`;
+/// - only written to atomically, by this process or any other.
+pub unsafe fn futex_wait_ptr(
+    word: *const u32,
+    expected: u32,
+    timeout: Option<FutexTimeout>,
+) -> Result<(), FutexError> {
+    unsafe { futex_wait_ptr_in(word, expected, timeout, FutexScope::Shared) }
+}
+/// # Safety
+///
+/// Learn more from [`futex_wait_ptr`].
+unsafe fn futex_wait_ptr_in(
+    word: *const u32,
+    expected: u32,
+    timeout: Option<FutexTimeout>,
+    scope: FutexScope,
+) -> Result<(), FutexError> {
+    let res = unsafe { futex_wait_syscall(word, expected, timeout, scope) };
+    #[cfg(feature = "flight-recorder")]
+    flight_recorder::record_wait(word.cast(), expected, &res);
+    res
+}
+unsafe fn futex_wait_syscall(
+    word: *const u32,
+    expected: u32,
+    timeout: Option<FutexTimeout>,
+    scope: FutexScope,
+) -> Result<(), FutexError> {
+    #[cfg(test)]
+    if let Some(res) = mock_backend::wait(FutexWaitContext {
+        word: unsafe { AtomicU32::from_ptr(word.cast_mut()) },
+        expected,
+        timeout,
+        scope,
+    }) {
+        return res.map_err(FutexError::from);
+    }
+    let (timeout_duration, measure) = match timeout {
+        None => (None, None),
+        Some(FutexTimeout::For(t, measure)) => (Some(t), Some(measure)),
+        // Only `FUTEX_WAIT_BITSET` takes an absolute deadline
+        Some(FutexTimeout::Until(_) | FutexTimeout::UntilSystemTime(_)) => {
+            return unsafe {
+                futex_wait_bitset_syscall(word, expected, timeout, scope, Bitset::ALL)
+            };
+        }
+    };
     let utime = timeout_duration.map(|t| {
-        let tv_sec: i64 = unsafe { std::mem::transmute(t.as_secs()) };
+        // Saturates; the kernel clamps the wake-up time to the end of its clock
+        let tv_sec = i64::try_from(t.as_secs()).unwrap_or(i64::MAX);
         let tv_nsec = i64::from(t.subsec_nanos());
         rustix::thread::Timespec { tv_sec, tv_nsec }
     });
@@ -34,117 +318,1998 @@ pub fn futex_wait(cx: FutexWaitContext<'_>) -> std::io::Result<()> {
         Some(utime) => utime as *const _,
         None => std::ptr::null(),
     };
-    let flags = match measure {
-        Some(TimeoutMeasure::RealTime) => rustix::thread::FutexFlags::CLOCK_REALTIME,
-        None | Some(TimeoutMeasure::MonoTime) => rustix::thread::FutexFlags::empty(),
-    };
+    let flags =
+        measure.map_or(rustix::thread::FutexFlags::empty(), TimeoutMeasure::flags) | scope.flags();
     let ret = unsafe {
         rustix::thread::futex(
-            cx.word.as_ptr(),
+            word.cast_mut(),
             rustix::thread::FutexOperation::Wait,
             flags,
-            cx.expected,
+            expected,
             utime,
             std::ptr::null_mut(), // ignored
             0,                    // ignored
         )
-    }?;
+    }
+    .map_err(FutexError::from_errno)?;
     assert_eq!(ret, 0);
     Ok(())
 }
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TimeoutMeasure {
     RealTime,
     MonoTime,
 }
+impl TimeoutMeasure {
+    fn flags(self) -> rustix::thread::FutexFlags {
+        match self {
+            TimeoutMeasure::RealTime => rustix::thread::FutexFlags::CLOCK_REALTIME,
+            TimeoutMeasure::MonoTime => rustix::thread::FutexFlags::empty(),
+        }
+    }
+}
 
-/// Retry on [`std::io::ErrorKind::Interrupted`].
+/// Retry on [`FutexError::Interrupted`].
+///
+/// The retries share one deadline: a [`FutexTimeout::For`] is [anchored](FutexTimeout::anchored) before the first wait.
 ///
 /// Learn more from [`futex_wait`].
-pub fn resumed_futex_wait(cx: FutexWaitContext<'_>) -> std::io::Result<()> {
+pub fn resumed_futex_wait(cx: FutexWaitContext<'_>) -> Result<(), FutexError> {
+    let cx = FutexWaitContext {
+        timeout: cx.timeout.map(FutexTimeout::anchored),
+        ..cx
+    };
     loop {
-        let Err(e) = futex_wait(cx) else {
-            return Ok(());
+        match futex_wait(cx) {
+            Err(FutexError::Interrupted) => continue,
+            res => return res,
+        }
+    }
+}
+
+/// [`resumed_futex_wait`] that also retries on spurious wake-ups, returning only once the futex word's value is no longer `expected`.
+///
+/// The retries share one deadline as in [`resumed_futex_wait`].
+/// Once it has passed, fail with [`FutexError::TimedOut`] if the word still holds `expected`; a word found changed wins over the timeout.
+/// A deadline passed on entry still checks the word once, without sleeping.
+///
+/// Learn more from [`futex_wait`].
+pub fn genuine_futex_wait(cx: FutexWaitContext<'_>) -> Result<(), FutexError> {
+    let cx = FutexWaitContext {
+        timeout: cx.timeout.map(FutexTimeout::anchored),
+        ..cx
+    };
+    loop {
+        let timed_out = match resumed_futex_wait(cx) {
+            Ok(()) | Err(FutexError::ValueMismatch) => {
+                cx.timeout.is_some_and(|t| t.remaining().is_zero())
+            }
+            Err(FutexError::TimedOut) => true,
+            Err(e) => return Err(e),
         };
-        if matches!(e.kind(), std::io::ErrorKind::Interrupted) {
-            continue;
+        if cx.word.load(Ordering::Acquire) != cx.expected {
+            return Ok(());
+        }
+        if timed_out {
+            return Err(FutexError::TimedOut);
         }
-        return Err(e);
     }
 }
 
-/// Busy looping on [`std::io::ErrorKind::WouldBlock`].
+/// [`resumed_futex_wait`] with the signals of the calling thread blocked, so that handlers of frequent signals, e.g., the `SIGPROF` of a sampling profiler, neither interrupt the wait nor cost it a round trip each.
+///
+/// The signals arriving meanwhile stay pending and their handlers run once the wait returns, when the previous mask is restored, also on unwinding.
+/// Costs two extra syscalls to set and restore the mask, so only worth it for waits expected to sleep.
+///
+/// # Never blocked
+///
+/// - `SIGKILL` and `SIGSTOP`, which cannot be blocked.
+/// - `SIGSEGV`, `SIGBUS`, `SIGFPE`, `SIGILL`, `SIGTRAP`, and `SIGSYS`, raised by faults.
+/// - `SIGINT`, `SIGTERM`, `SIGQUIT`, and `SIGHUP`, so that a request to end the process is never held up by the wait.
+///
+/// Learn more from [`futex_wait`].
+pub fn futex_wait_uninterruptible(cx: FutexWaitContext<'_>) -> Result<(), FutexError> {
+    let _masked = signal::MaskedSignals::block();
+    resumed_futex_wait(cx)
+}
+
+/// Busy looping on [`FutexError::ValueMismatch`].
+///
+/// Learn more from [`busy_futex_wait_with`].
+pub fn busy_futex_wait(cx: FutexWaitContext<'_>) -> Result<(), FutexError> {
+    busy_futex_wait_with(cx, idle::Backoff::Spin)
+}
+
+/// Loop on [`FutexError::ValueMismatch`], pausing with `backoff` while the futex word's value is not `expected`.
 ///
 /// Learn more from [`resumed_futex_wait`].
-pub fn busy_futex_wait(cx: FutexWaitContext<'_>) -> std::io::Result<()> {
+pub fn busy_futex_wait_with(
+    cx: FutexWaitContext<'_>,
+    mut backoff: idle::Backoff<'_>,
+) -> Result<(), FutexError> {
+    let cx = FutexWaitContext {
+        timeout: cx.timeout.map(FutexTimeout::anchored),
+        ..cx
+    };
     loop {
-        let Err(e) = resumed_futex_wait(cx) else {
-            return Ok(());
-        };
-        if matches!(e.kind(), std::io::ErrorKind::WouldBlock) {
-            continue;
+        match resumed_futex_wait(cx) {
+            Err(FutexError::ValueMismatch) => {
+                if cx.word.load(std::sync::atomic::Ordering::Relaxed) != cx.expected {
+                    backoff.pause();
+                }
+            }
+            res => return res,
         }
-        return Err(e);
     }
 }
 
-/// Returns the number of waiters that were woken up.
-pub fn futex_wake(addr: &AtomicU32, waiters: WakeWaiters) -> std::io::Result<usize> {
-    let waiters = match waiters {
-        WakeWaiters::Amount(n) => n.get(),
-        WakeWaiters::All => unsafe { transmute(i32::MAX) },
+/// Loop on [`FutexError::ValueMismatch`], idling with `idle` until the futex word's value is `expected` again.
+///
+/// Learn more from [`resumed_futex_wait`].
+pub fn idle_futex_wait(
+    cx: FutexWaitContext<'_>,
+    mut idle: idle::IdleStrategy,
+) -> Result<(), FutexError> {
+    let cx = FutexWaitContext {
+        timeout: cx.timeout.map(FutexTimeout::anchored),
+        ..cx
     };
+    loop {
+        match resumed_futex_wait(cx) {
+            Err(FutexError::ValueMismatch) => {
+                let actual = cx.word.load(std::sync::atomic::Ordering::Relaxed);
+                if actual != cx.expected {
+                    idle.idle(cx.word, actual);
+                }
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Returns the number of waiters that were woken up.
+pub fn futex_wake(addr: &AtomicU32, waiters: WakeWaiters) -> Result<usize, FutexError> {
+    unsafe { futex_wake_ptr(addr.as_ptr(), waiters) }
+}
+/// [`futex_wake`] on a word reached only through a raw pointer, the counterpart of [`futex_wait_ptr`].
+///
+/// # Safety
+///
+/// For the duration of the call, `word` must be non-null, 4-byte aligned, and mapped.
+/// The kernel never reads the word for a wake, but it resolves the mapping to find the waiters.
+pub unsafe fn futex_wake_ptr(word: *const u32, waiters: WakeWaiters) -> Result<usize, FutexError> {
+    unsafe { futex_wake_ptr_in(word.cast_mut(), waiters, FutexScope::Shared) }
+}
+/// [`futex_wake`] of the waiters of [`FutexScope::Private`], using `FUTEX_PRIVATE_FLAG`.
+///
+/// Waiters of [`FutexScope::Shared`] are not woken up, and neither are those of other processes.
+pub fn futex_wake_private(addr: &AtomicU32, waiters: WakeWaiters) -> Result<usize, FutexError> {
+    futex_wake_in(addr, waiters, FutexScope::Private)
+}
+/// [`futex_wake`] of at most one waiter.
+///
+/// # Panic
+///
+/// If the wake fails, which only a word that the kernel cannot reach makes it do.
+pub fn wake_one(addr: &AtomicU32) -> usize {
+    futex_wake(addr, WakeWaiters::ONE).unwrap()
+}
+/// [`futex_wake`] of every waiter.
+///
+/// # Panic
+///
+/// If the wake fails, which only a word that the kernel cannot reach makes it do.
+pub fn wake_all(addr: &AtomicU32) -> usize {
+    futex_wake(addr, WakeWaiters::All).unwrap()
+}
+pub(crate) fn futex_wake_in(
+    addr: &AtomicU32,
+    waiters: WakeWaiters,
+    scope: FutexScope,
+) -> Result<usize, FutexError> {
+    unsafe { futex_wake_ptr_in(addr.as_ptr(), waiters, scope) }
+}
+/// [`futex_wake`] on an address that may no longer hold a live futex word.
+///
+/// # Safety
+///
+/// The kernel never dereferences `addr` for a wake, but the caller must tolerate waking an unrelated waiter at the same address.
+pub(crate) unsafe fn futex_wake_ptr_in(
+    addr: *mut u32,
+    waiters: WakeWaiters,
+    scope: FutexScope,
+) -> Result<usize, FutexError> {
+    #[cfg(feature = "flight-recorder")]
+    let time = flight_recorder::now();
+    let res = unsafe { futex_wake_syscall(addr, waiters, scope) };
+    #[cfg(feature = "flight-recorder")]
+    flight_recorder::record_wake(time, addr, waiters, &res);
+    res
+}
+unsafe fn futex_wake_syscall(
+    addr: *mut u32,
+    waiters: WakeWaiters,
+    scope: FutexScope,
+) -> Result<usize, FutexError> {
+    #[cfg(test)]
+    if let Some(res) = mock_backend::wake(addr, waiters, scope) {
+        return res.map_err(FutexError::from);
+    }
+    let waiters = waiters.count();
     let woken_waiters = unsafe {
         rustix::thread::futex(
-            addr.as_ptr(),
+            addr,
             rustix::thread::FutexOperation::Wake,
-            rustix::thread::FutexFlags::empty(),
+            scope.flags(),
             waiters,
             std::ptr::null(),     // ignored
             std::ptr::null_mut(), // ignored
             0,                    // ignored
         )
-    }?;
+    }
+    .map_err(FutexError::from_errno)?;
     Ok(woken_waiters)
 }
-#[derive(Debug, Clone, Copy)]
-pub enum WakeWaiters {
-    Amount(U31),
-    All,
+/// [`futex_wait`], but only woken by the wakes whose [`Bitset`] intersects `mask`, using `FUTEX_WAIT_BITSET`.
+///
+/// A plain [`futex_wake`] wakes it regardless, as if with [`Bitset::ALL`].
+pub fn futex_wait_bitset(cx: FutexWaitContext<'_>, mask: Bitset) -> Result<(), FutexError> {
+    #[cfg(test)]
+    if let Some(res) = mock_backend::wait_bitset(cx, mask) {
+        return res.map_err(FutexError::from);
+    }
+    unsafe { futex_wait_bitset_syscall(cx.word.as_ptr(), cx.expected, cx.timeout, cx.scope, mask) }
+}
+unsafe fn futex_wait_bitset_syscall(
+    word: *const u32,
+    expected: u32,
+    timeout: Option<FutexTimeout>,
+    scope: FutexScope,
+    mask: Bitset,
+) -> Result<(), FutexError> {
+    // Unlike `FUTEX_WAIT`, the kernel takes an absolute deadline on the clock of the flags
+    let (deadline, flags) = match timeout.map(FutexTimeout::absolute) {
+        Some((deadline, flags)) => (Some(deadline), flags | scope.flags()),
+        None => (None, scope.flags()),
+    };
+    let deadline = match &deadline {
+        Some(deadline) => deadline as *const _,
+        None => std::ptr::null(),
+    };
+    let ret = unsafe {
+        rustix::thread::futex(
+            word.cast_mut(),
+            rustix::thread::FutexOperation::WaitBitset,
+            flags,
+            expected,
+            deadline,
+            std::ptr::null_mut(), // ignored
+            mask.get(),
+        )
+    }
+    .map_err(FutexError::from_errno)?;
+    assert_eq!(ret, 0);
+    Ok(())
+}
+/// `timeout` from now on the clock of `measure`.
+fn absolute_timespec(timeout: Duration, measure: TimeoutMeasure) -> rustix::thread::Timespec {
+    let clock = match measure {
+        TimeoutMeasure::RealTime => libc::CLOCK_REALTIME,
+        TimeoutMeasure::MonoTime => libc::CLOCK_MONOTONIC,
+    };
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    assert_eq!(unsafe { libc::clock_gettime(clock, &mut now) }, 0);
+    let mut tv_sec = i64::try_from(timeout.as_secs())
+        .unwrap_or(i64::MAX)
+        .saturating_add(now.tv_sec);
+    let mut tv_nsec = now.tv_nsec + i64::from(timeout.subsec_nanos());
+    if 1_000_000_000 <= tv_nsec {
+        tv_sec = tv_sec.saturating_add(1);
+        tv_nsec -= 1_000_000_000;
+    }
+    rustix::thread::Timespec { tv_sec, tv_nsec }
+}
+/// Wake up to `waiters` of the waiters whose mask intersects `mask`, using `FUTEX_WAKE_BITSET`.
+///
+/// Lets one word serve several kinds of waiters, e.g., readers and writers waiting with different masks.
+///
+/// Returns the number of waiters that were woken up, all of them with an intersecting mask and waiting in `scope`.
+pub fn futex_wake_bitset(
+    addr: &AtomicU32,
+    waiters: WakeWaiters,
+    mask: Bitset,
+    scope: FutexScope,
+) -> Result<usize, FutexError> {
+    #[cfg(test)]
+    if let Some(res) = mock_backend::wake_bitset(addr.as_ptr(), waiters, mask, scope) {
+        return res.map_err(FutexError::from);
+    }
+    let waiters = waiters.count();
+    // Not an operation rustix knows of
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            addr.as_ptr(),
+            libc::FUTEX_WAKE_BITSET | scope.flags().bits() as libc::c_int,
+            waiters,
+            std::ptr::null::<libc::timespec>(), // ignored
+            std::ptr::null::<u32>(),            // ignored
+            mask.get(),
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(ret as usize)
 }
+/// The mask of [`futex_wait_bitset`] and [`futex_wake_bitset`]; never empty, since the kernel rejects an empty one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Bitset(NonZeroU32);
+impl Bitset {
+    /// Intersects every mask, as the plain wait and wake use.
+    pub const ALL: Bitset = Bitset(NonZeroU32::MAX);
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, std::hash::Hash)]
-pub struct U31(u32);
-impl U31 {
-    pub fn new(v: u32) -> Option<U31> {
-        if u32::try_from(i32::MAX).unwrap() < v {
-            return None;
+    pub const fn new(mask: u32) -> Option<Bitset> {
+        match NonZeroU32::new(mask) {
+            Some(mask) => Some(Self(mask)),
+            None => None,
         }
-        Some(Self(v))
     }
 
-    pub fn get(&self) -> u32 {
-        self.0
+    /// The mask of the single bit `index`; return [`None`] past bit 31.
+    pub const fn bit(index: u32) -> Option<Bitset> {
+        match 1_u32.checked_shl(index) {
+            Some(mask) => Self::new(mask),
+            None => None,
+        }
+    }
+
+    pub const fn get(self) -> u32 {
+        self.0.get()
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::sync::Arc;
+/// Try to take the priority-inheriting lock on `word` for the calling thread without sleeping, using `FUTEX_TRYLOCK_PI`; return `false` if another thread holds it.
+///
+/// Unlike a plain CAS, the kernel also takes over from a holder that exited with `word` on its robust list.
+/// The word follows the kernel's encoding: `0` is unlocked, otherwise the low 30 bits hold the TID of the holder.
+///
+/// # Owner died
+///
+/// A takeover from a dead holder still acquires the lock, but fails with an error wrapping [`OwnerDied`], so that a caller propagating it with `?` does not mistake the lock for sound.
+/// The caller then holds the lock with [`OwnerDied::FLAG`] kept on the word, and should repair the protected state before clearing the flag.
+///
+/// That is why this returns [`std::io::Result`] rather than [`FutexError`], which has no room for a payload like [`OwnerDied`].
+#[cfg(feature = "pi")]
+pub fn futex_trylock_pi(word: &AtomicU32) -> std::io::Result<bool> {
+    let tid = rustix::thread::gettid()
+        .as_raw_nonzero()
+        .get()
+        .unsigned_abs();
+    if !pi::try_lock_kernel(word, tid)? {
+        return Ok(false);
+    }
+    if word.load(Ordering::Relaxed) & pi::OWNER_DIED != 0 {
+        return Err(std::io::Error::other(OwnerDied));
+    }
+    Ok(true)
+}
+/// Take the priority-inheriting lock on `word` for the calling thread, sleeping in `FUTEX_LOCK_PI` while another thread holds it.
+///
+/// An uncontended lock is a single CAS in userspace; only contention reaches the kernel, which queues the caller by priority and boosts the holder meanwhile.
+/// The word follows the encoding of [`futex_trylock_pi`].
+///
+/// `timeout` is measured on the realtime clock, as the kernel only takes absolute deadlines on it for this operation.
+///
+/// # Errors
+///
+/// - [`std::io::ErrorKind::TimedOut`] if `timeout` passes first; the caller does not hold the lock.
+/// - The raw OS error `EDEADLK` if the calling thread already holds the lock.
+/// - An error wrapping [`OwnerDied`] if the previous holder died holding it; the caller then holds the lock, as with [`futex_trylock_pi`], which also tells why this is a [`std::io::Result`].
+#[cfg(feature = "pi")]
+pub fn futex_lock_pi(word: &AtomicU32, timeout: Option<Duration>) -> std::io::Result<()> {
+    let tid = rustix::thread::gettid()
+        .as_raw_nonzero()
+        .get()
+        .unsigned_abs();
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+    if !pi::lock_kernel(word, tid, deadline)? {
+        return Err(std::io::ErrorKind::TimedOut.into());
+    }
+    if word.load(Ordering::Relaxed) & pi::OWNER_DIED != 0 {
+        return Err(std::io::Error::other(OwnerDied));
+    }
+    Ok(())
+}
+/// Release the priority-inheriting lock on `word` held by the calling thread.
+///
+/// Without waiters, a single CAS in userspace; otherwise `FUTEX_UNLOCK_PI` hands the lock to the highest-priority waiter.
+///
+/// # Errors
+///
+/// The raw OS error `EPERM` if the calling thread does not hold the lock.
+///
+/// A [`std::io::Result`], like the locking side of [`futex_lock_pi`].
+#[cfg(feature = "pi")]
+pub fn futex_unlock_pi(word: &AtomicU32) -> std::io::Result<()> {
+    let tid = rustix::thread::gettid()
+        .as_raw_nonzero()
+        .get()
+        .unsigned_abs();
+    pi::unlock_kernel(word, tid)
+}
+/// The previous holder of a priority-inheriting lock died holding it, and the lock now belongs to the caller; the `EOWNERDEAD` of [`futex_trylock_pi`] and [`futex_lock_pi`].
+///
+/// Recover it from the error with `e.get_ref().and_then(|e| e.downcast_ref::<OwnerDied>())`.
+#[cfg(feature = "pi")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OwnerDied;
+#[cfg(feature = "pi")]
+impl OwnerDied {
+    /// `FUTEX_OWNER_DIED`, kept on the word until the new holder clears it
+    pub const FLAG: u32 = pi::OWNER_DIED;
+}
+#[cfg(feature = "pi")]
+impl std::fmt::Display for OwnerDied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "acquired a lock whose previous holder died holding it")
+    }
+}
+#[cfg(feature = "pi")]
+impl std::error::Error for OwnerDied {}
 
-    use super::*;
+/// Sleep on `cond_word` while it holds `expected`, to be moved onto the priority-inheriting lock on `pi_word` by [`futex_cmp_requeue_pi`] and handed the lock there, using `FUTEX_WAIT_REQUEUE_PI`.
+///
+/// The two halves of a condition variable whose mutex is priority-inheriting: waking a waiter does not make it race the signaler for the mutex, and a waiter queued on the mutex lends its priority to the holder.
+/// The caller must not hold the lock on `pi_word` while sleeping, and only [`futex_cmp_requeue_pi`] may wake it; a plain [`futex_wake`] on `cond_word` fails with `EINVAL` while it sleeps.
+///
+/// `timeout` is measured on the monotonic clock.
+///
+/// Returns a [`std::io::Result`], since it ends up locking `pi_word` as [`futex_lock_pi`] does.
+#[cfg(feature = "pi")]
+pub fn futex_wait_requeue_pi(
+    cond_word: &AtomicU32,
+    expected: u32,
+    pi_word: &AtomicU32,
+    timeout: Option<Duration>,
+) -> std::io::Result<RequeuePiWait> {
+    let deadline = timeout.map(|t| absolute_timespec(t, TimeoutMeasure::MonoTime));
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            cond_word.as_ptr(),
+            libc::FUTEX_WAIT_REQUEUE_PI,
+            expected,
+            deadline
+                .as_ref()
+                .map_or(std::ptr::null(), |t| t as *const rustix::thread::Timespec),
+            pi_word.as_ptr(),
+            0, // ignored
+        )
+    };
+    if ret == 0 {
+        return Ok(RequeuePiWait::Locked);
+    }
+    let e = std::io::Error::last_os_error();
+    match e.kind() {
+        std::io::ErrorKind::WouldBlock => Ok(RequeuePiWait::WouldBlock),
+        std::io::ErrorKind::TimedOut => Ok(RequeuePiWait::TimedOut),
+        _ => Err(e),
+    }
+}
+/// How [`futex_wait_requeue_pi`] returned, telling whether the caller now holds the lock.
+#[cfg(feature = "pi")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequeuePiWait {
+    /// Requeued and handed the lock; the caller holds it.
+    ///
+    /// The kernel reports the same whether [`futex_cmp_requeue_pi`] took the lock for the waiter right away or the waiter queued on the lock until an unlock handed it over.
+    Locked,
+    /// Returned without the lock: `cond_word` no longer held `expected`, or the wake-up was spurious
+    WouldBlock,
+    /// Returned without the lock: the timeout passed, before or after the requeue
+    TimedOut,
+}
+/// Take the lock on `pi_word` for the first waiter of `cond_word` if it is free and wake that waiter, and move up to `requeue` more waiters onto `pi_word`, unless `cond_word` no longer holds `expected`, using `FUTEX_CMP_REQUEUE_PI`.
+///
+/// The first waiter is moved onto `pi_word` too if the lock is held, even if `requeue` is zero; so `requeue` zero signals one waiter and [`RequeueCount::All`] broadcasts.
+/// Only serves waiters of [`futex_wait_requeue_pi`] on the same pair of words.
+///
+/// The kernel only wakes one waiter, so `wake` must be one; anything else fails with `EINVAL`.
+/// Fails with [`std::io::ErrorKind::WouldBlock`] if `cond_word` does not hold `expected`.
+///
+/// Returns the number of waiters woken up plus those requeued, as the kernel reports them together.
+/// Unlike [`futex_cmp_requeue`], it stays on [`std::io::Result`] with the rest of the priority-inheriting calls, whose locks it takes on behalf of a waiter.
+#[cfg(feature = "pi")]
+pub fn futex_cmp_requeue_pi(
+    cond_word: &AtomicU32,
+    expected: u32,
+    pi_word: &AtomicU32,
+    wake: WakeWaiters,
+    requeue: RequeueCount,
+) -> std::io::Result<usize> {
+    #[cfg(test)]
+    if let Some(e) = mock_backend::wake_unserved() {
+        return Err(e);
+    }
+    let wake = wake.count();
+    let requeue = requeue.count();
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            cond_word.as_ptr(),
+            libc::FUTEX_CMP_REQUEUE_PI,
+            wake,
+            // The kernel reads the count of waiters to requeue from the timeout argument
+            requeue as usize,
+            pi_word.as_ptr(),
+            expected,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
 
-    #[test]
-    fn test_wait_would_block() {
-        let word = AtomicU32::new(0);
-        let Err(e) = futex_wait(FutexWaitContext {
-            word: &word,
-            expected: 1,
-            timeout: None,
-        }) else {
-            panic!();
-        };
-        assert!(matches!(e.kind(), std::io::ErrorKind::WouldBlock));
+/// Wake up to `wake` waiters of `from` and move up to `requeue` of the rest to sleep on `to` instead, without waking them, using `FUTEX_REQUEUE`.
+///
+/// Meant for handing waiters over, e.g., from a condition variable to its mutex, so that they are woken one by one as the mutex frees up instead of all at once.
+///
+/// Returns the number of waiters woken up plus those requeued, as the kernel reports them together.
+pub fn futex_requeue(
+    from: &AtomicU32,
+    to: &AtomicU32,
+    wake: WakeWaiters,
+    requeue: RequeueCount,
+) -> Result<usize, FutexError> {
+    #[cfg(test)]
+    if let Some(res) = mock_backend::requeue(from.as_ptr(), to.as_ptr(), wake, requeue) {
+        return res.map_err(FutexError::from);
+    }
+    let wake = wake.count();
+    let requeue = requeue.count();
+    unsafe {
+        rustix::thread::futex(
+            from.as_ptr(),
+            rustix::thread::FutexOperation::Requeue,
+            rustix::thread::FutexFlags::empty(),
+            wake,
+            // The kernel reads the count of waiters to requeue from the timeout argument
+            requeue as usize as *const rustix::thread::Timespec,
+            to.as_ptr(),
+            0, // ignored
+        )
+    }
+    .map_err(FutexError::from_errno)
+}
+
+/// [`futex_requeue`], unless `from` no longer holds `expected`, in which case it fails with [`FutexError::ValueMismatch`] and touches no waiter, using `FUTEX_CMP_REQUEUE`.
+///
+/// The check and the moves happen atomically in the kernel, so nobody is requeued against a stale state of `from`.
+///
+/// # Return
+///
+/// The kernel only reports the sum of the waiters woken and requeued.
+/// It wakes the first waiters before requeueing any, though, so the sum splits exactly: up to `wake` of it were woken and the rest were requeued.
+pub fn futex_cmp_requeue(
+    from: &AtomicU32,
+    to: &AtomicU32,
+    wake: WakeWaiters,
+    requeue: RequeueCount,
+    expected: u32,
+) -> Result<Requeued, FutexError> {
+    let wake = wake.count();
+    #[cfg(test)]
+    if let Some(res) = mock_backend::cmp_requeue(from, to, wake, requeue, expected) {
+        return res
+            .map(|total| Requeued::split(total, wake))
+            .map_err(FutexError::from);
+    }
+    let requeue = requeue.count();
+    let total = unsafe {
+        rustix::thread::futex(
+            from.as_ptr(),
+            rustix::thread::FutexOperation::CmpRequeue,
+            rustix::thread::FutexFlags::empty(),
+            wake,
+            // The kernel reads the count of waiters to requeue from the timeout argument
+            requeue as usize as *const rustix::thread::Timespec,
+            to.as_ptr(),
+            expected,
+        )
+    }
+    .map_err(FutexError::from_errno)?;
+    Ok(Requeued::split(total, wake))
+}
+/// What [`futex_cmp_requeue`] did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Requeued {
+    pub woken: usize,
+    pub requeued: usize,
+}
+impl Requeued {
+    fn split(total: usize, wake: u32) -> Self {
+        let woken = total.min(wake as usize);
+        Self {
+            woken,
+            requeued: total - woken,
+        }
+    }
+}
+
+/// Apply `op` to `word2`, wake up to `wake1` waiters of `word1`, and also wake up to `wake2` waiters of `word2` if the value `word2` held before the operation passes the comparison of `op`, all in one syscall, using `FUTEX_WAKE_OP`.
+///
+/// Meant for releasing a lock word and signaling another word at once, e.g., a mutex and its condition variable, without a second round trip into the kernel.
+/// Only the wake of `word2` is conditional; the kernel always wakes the waiters of `word1`.
+///
+/// Returns the number of waiters woken up on both words together.
+pub fn futex_wake_op(
+    word1: &AtomicU32,
+    word2: &AtomicU32,
+    wake1: WakeWaiters,
+    wake2: WakeWaiters,
+    op: WakeOp,
+) -> Result<usize, FutexError> {
+    #[cfg(test)]
+    if let Some(e) = mock_backend::wake_unserved() {
+        return Err(e.into());
+    }
+    let wake1 = wake1.count();
+    let wake2 = wake2.count();
+    // Not an operation rustix knows of
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word1.as_ptr(),
+            libc::FUTEX_WAKE_OP,
+            wake1,
+            // The kernel reads the count of waiters to wake on `word2` from the timeout argument
+            wake2 as usize as *const libc::timespec,
+            word2.as_ptr(),
+            op.get(),
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(ret as usize)
+}
+/// The operation and comparison of [`futex_wake_op`], packed the way the kernel expects.
+///
+/// The operation turns the old value of `word2` into the new one; the comparison of the old value, as an `i32`, against its operand decides whether the waiters of `word2` are woken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WakeOp(u32);
+impl WakeOp {
+    /// The kernel sign-extends both operands from 12 bits.
+    const OPERAND: core::ops::RangeInclusive<i32> = -(1 << 11)..=(1 << 11) - 1;
+
+    /// Return [`None`] if an operand does not fit in the encoding.
+    pub const fn new(
+        op: WakeOpKind,
+        arg: WakeOpArg,
+        cmp: WakeOpCmp,
+        cmp_arg: i32,
+    ) -> Option<WakeOp> {
+        let (shift, arg) = match arg {
+            WakeOpArg::Value(v) => {
+                if v < *Self::OPERAND.start() || *Self::OPERAND.end() < v {
+                    return None;
+                }
+                (0, v)
+            }
+            WakeOpArg::Shift(n) => {
+                if 31 < n {
+                    return None;
+                }
+                (libc::FUTEX_OP_OPARG_SHIFT, n as i32)
+            }
+        };
+        if cmp_arg < *Self::OPERAND.start() || *Self::OPERAND.end() < cmp_arg {
+            return None;
+        }
+        let op = op as i32 | shift;
+        let encoded = (op << 28) | ((cmp as i32) << 24) | ((arg & 0xfff) << 12) | (cmp_arg & 0xfff);
+        Some(Self(encoded as u32))
+    }
+
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+}
+/// What [`WakeOp`] does to `word2`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(i32)]
+pub enum WakeOpKind {
+    /// Store the operand
+    Set = libc::FUTEX_OP_SET,
+    Add = libc::FUTEX_OP_ADD,
+    Or = libc::FUTEX_OP_OR,
+    /// Clear the bits of the operand
+    AndN = libc::FUTEX_OP_ANDN,
+    Xor = libc::FUTEX_OP_XOR,
+}
+/// The operand of a [`WakeOpKind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WakeOpArg {
+    /// Within the 12-bit signed range, `-2048..=2047`
+    Value(i32),
+    /// `1 << n`, for bit operations beyond the reach of [`WakeOpArg::Value`]; `n` is at most 31
+    Shift(u32),
+}
+/// How [`WakeOp`] compares the old value of `word2` against its operand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(i32)]
+pub enum WakeOpCmp {
+    Eq = libc::FUTEX_OP_CMP_EQ,
+    Ne = libc::FUTEX_OP_CMP_NE,
+    Lt = libc::FUTEX_OP_CMP_LT,
+    Le = libc::FUTEX_OP_CMP_LE,
+    Gt = libc::FUTEX_OP_CMP_GT,
+    Ge = libc::FUTEX_OP_CMP_GE,
+}
+
+/// Wake up to `count_hint` waiters, skipping the syscall if it is zero.
+///
+/// `count_hint` is usually a snapshot of a waiters counter; it is clamped to [`U31::MAX`].
+///
+/// Returns the number of waiters that were woken up.
+pub fn wake_waiters(addr: &AtomicU32, count_hint: usize) -> Result<usize, FutexError> {
+    if count_hint == 0 {
+        return Ok(0);
+    }
+    futex_wake(addr, WakeWaiters::at_most(count_hint))
+}
+/// Why a [`futex_wait`] or [`futex_wake`] failed.
+///
+/// The outcomes callers routinely handle get a variant of their own; anything else is kept as [`Self::Os`].
+#[derive(Debug)]
+pub enum FutexError {
+    /// The futex word's value was not `expected`, so the thread did not sleep; `EAGAIN`.
+    ValueMismatch,
+    /// `ETIMEDOUT`
+    TimedOut,
+    /// A signal handler ran; `EINTR`.
+    Interrupted,
+    /// The kernel lacks the operation; `ENOSYS`.
+    Unsupported,
+    Os(std::io::Error),
+}
+impl FutexError {
+    /// The errno the kernel returned, if any.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            Self::ValueMismatch => Some(libc::EAGAIN),
+            Self::TimedOut => Some(libc::ETIMEDOUT),
+            Self::Interrupted => Some(libc::EINTR),
+            Self::Unsupported => Some(libc::ENOSYS),
+            Self::Os(e) => e.raw_os_error(),
+        }
+    }
+
+    fn from_errno(errno: rustix::io::Errno) -> Self {
+        std::io::Error::from(errno).into()
+    }
+}
+impl From<std::io::Error> for FutexError {
+    fn from(e: std::io::Error) -> Self {
+        match e.raw_os_error() {
+            Some(libc::EAGAIN) => Self::ValueMismatch,
+            Some(libc::ETIMEDOUT) => Self::TimedOut,
+            Some(libc::EINTR) => Self::Interrupted,
+            Some(libc::ENOSYS) => Self::Unsupported,
+            _ => Self::Os(e),
+        }
+    }
+}
+/// For callers still on [`std::io::Result`]; the errno, and so the [`std::io::ErrorKind`], is kept.
+impl From<FutexError> for std::io::Error {
+    fn from(e: FutexError) -> Self {
+        match e {
+            FutexError::Os(e) => e,
+            e => std::io::Error::from_raw_os_error(e.raw_os_error().unwrap()),
+        }
+    }
+}
+impl std::fmt::Display for FutexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ValueMismatch => write!(f, "futex word did not hold the expected value"),
+            Self::TimedOut => write!(f, "futex wait timed out"),
+            Self::Interrupted => write!(f, "futex wait interrupted by a signal"),
+            Self::Unsupported => write!(f, "futex operation not supported by the kernel"),
+            Self::Os(e) => write!(f, "{e}"),
+        }
+    }
+}
+impl std::error::Error for FutexError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Os(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A futex operation of a primitive failed.
+///
+/// The raw [`futex_wait`] and [`futex_wake`] return a bare [`FutexError`]; the primitives attach the context.
+#[derive(Debug)]
+pub struct FutexOpError {
+    pub context: FutexErrorContext,
+    pub error: FutexError,
+}
+impl std::fmt::Display for FutexOpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let FutexErrorContext {
+            op,
+            addr,
+            primitive,
+        } = self.context;
+        write!(f, "{primitive:?} futex {op:?} on {addr:#x}: {}", self.error)
+    }
+}
+impl std::error::Error for FutexOpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FutexErrorContext {
+    pub op: FutexOp,
+    /// The address of the futex word
+    pub addr: usize,
+    pub primitive: observer::Primitive,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FutexOp {
+    Wait,
+    Wake,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum WakeWaiters {
+    Amount(U31),
+    All,
+}
+impl WakeWaiters {
+    pub const ONE: WakeWaiters = WakeWaiters::Amount(U31(1));
+
+    /// Learn more from [`U31::clamping`].
+    pub const fn at_most(n: usize) -> Self {
+        Self::Amount(U31::clamping(n))
+    }
+
+    /// The count as the kernel takes it, with [`Self::All`] as [`U31::MAX`].
+    const fn count(self) -> u32 {
+        match self {
+            Self::Amount(n) => n.get(),
+            Self::All => U31::MAX.get(),
+        }
+    }
+}
+impl From<U31> for WakeWaiters {
+    fn from(n: U31) -> Self {
+        Self::Amount(n)
+    }
+}
+
+/// How many of the waiters left after the wake [`futex_requeue`] moves.
+#[derive(Debug, Clone, Copy)]
+pub enum RequeueCount {
+    Amount(U31),
+    All,
+}
+impl RequeueCount {
+    /// Learn more from [`U31::clamping`].
+    pub const fn at_most(n: usize) -> Self {
+        Self::Amount(U31::clamping(n))
+    }
+
+    /// The count as the kernel takes it, with [`Self::All`] as [`U31::MAX`].
+    const fn count(self) -> u32 {
+        match self {
+            Self::Amount(n) => n.get(),
+            Self::All => U31::MAX.get(),
+        }
+    }
+}
+impl From<U31> for RequeueCount {
+    fn from(n: U31) -> Self {
+        Self::Amount(n)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, std::hash::Hash)]
+pub struct U31(u32);
+impl U31 {
+    pub const MAX: U31 = U31(i32::MAX.unsigned_abs());
+
+    pub const fn new(v: u32) -> Option<U31> {
+        if Self::MAX.0 < v {
+            return None;
+        }
+        Some(Self(v))
+    }
+
+    /// Saturate at [`Self::MAX`].
+    pub const fn clamping(v: usize) -> U31 {
+        if (Self::MAX.0 as usize) < v {
+            return Self::MAX;
+        }
+        Self(v as u32)
+    }
+
+    pub const fn get(&self) -> u32 {
+        self.0
+    }
+}
+impl TryFrom<usize> for U31 {
+    type Error = std::num::TryFromIntError;
+
+    fn try_from(v: usize) -> Result<Self, Self::Error> {
+        Ok(Self(i32::try_from(v)?.unsigned_abs()))
+    }
+}
+
+/// Per-thread slots of every [`WaitersCounter`] made by [`WaitersCounter::private`].
+const WAITER_SLOTS: usize = 8;
+/// One bit per slot held by a live thread
+static HELD_WAITER_SLOTS: AtomicU32 = AtomicU32::new(0);
+
+/// The slot of the current thread in every private [`WaitersCounter`], held until the thread exits.
+///
+/// Every counter has the slot back at zero by then, since its waiter guards never outlive the waits of the thread.
+struct WaiterSlot(Option<usize>);
+impl WaiterSlot {
+    fn hold() -> Self {
+        let held = HELD_WAITER_SLOTS.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |held| {
+            let slot = held.trailing_ones() as usize;
+            (slot < WAITER_SLOTS).then(|| held | (1 << slot))
+        });
+        Self(held.ok().map(|held| held.trailing_ones() as usize))
+    }
+}
+impl Drop for WaiterSlot {
+    fn drop(&mut self) {
+        if let Some(slot) = self.0 {
+            HELD_WAITER_SLOTS.fetch_and(!(1 << slot), Ordering::Relaxed);
+        }
+    }
+}
+thread_local! {
+    static WAITER_SLOT: WaiterSlot = WaiterSlot::hold();
+}
+
+/// A primitive's optional waiters counter, e.g., absent from [`crate::semaphore::Semaphore::new_slow`].
+///
+/// Unlike `Option<AtomicUsize>`, the all-zero bit pattern is a valid counter at zero.
+///
+/// # Private counters
+///
+/// A counter from [`Self::private`] registers the waiters of the first [`WAITER_SLOTS`] threads to wait in a byte of their own, with a plain load and store instead of an RMW on a word every waiter contends on.
+/// Reading the count sums the bytes, so the registrations stay exact, and a `SeqCst` store still takes part in the single total order that the no-lost-wakeup protocols of the primitives rely on.
+///
+/// The slots are numbered per process, so two processes could hold the same byte; a private counter must never be shared between processes.
+/// The primitive owning one therefore also waits and wakes with [`FutexScope::Private`].
+/// The all-zero bit pattern keeps the slots off, so a primitive zero-initialized in shared memory stays safe.
+///
+/// # Relocation
+///
+/// In debug builds with the `relocation-check` feature, the registrations are also tracked per address of the counter in a process-local side table, and [`Self::scope`], asked by every wait and wake of the primitive owning it, panics if the counter holds registrations the table has not seen at its current address: the primitive was moved while threads were registered with it.
+/// Those threads would otherwise sleep on the futex word at the old address forever, since the wakes now go to the new one.
+///
+/// The table only sees the registrations of this process, so the check is for primitives that no other process waits on.
+/// It keeps the counter itself untouched, so the layout does not depend on the build.
+#[derive(Debug)]
+#[repr(C)]
+pub(crate) struct WaitersCounter {
+    count: AtomicUsize,
+    /// Registrations per thread slot, each only ever written by the thread holding the slot
+    slots: [AtomicU8; WAITER_SLOTS],
+    disabled: bool,
+    private: bool,
+}
+impl WaitersCounter {
+    pub(crate) const fn new() -> Self {
+        Self {
+            count: AtomicUsize::new(0),
+            slots: [const { AtomicU8::new(0) }; WAITER_SLOTS],
+            disabled: false,
+            private: false,
+        }
+    }
+
+    pub(crate) const fn disabled() -> Self {
+        Self {
+            disabled: true,
+            ..Self::new()
+        }
+    }
+
+    /// Must not be shared between processes, e.g., through [`crate::shared_cell::SharedCell`], since the bytes are assigned per process.
+    ///
+    /// Learn more from the [private counters](Self#private-counters).
+    pub(crate) const fn private() -> Self {
+        Self {
+            private: true,
+            ..Self::new()
+        }
+    }
+
+    /// Count the calling thread until the guard drops, storing with `ordering`.
+    ///
+    /// The guard must be dropped on the calling thread.
+    pub(crate) fn register(&self, ordering: Ordering) -> WaiterGuard<'_> {
+        #[cfg(all(debug_assertions, feature = "relocation-check"))]
+        self.enter();
+        let guard = self.register_count(ordering);
+        #[cfg(all(debug_assertions, feature = "relocation-check"))]
+        let guard = self.tracked(guard);
+        guard
+    }
+
+    /// [`Self::register`] in the shared word even if the counter is private, for guards that may be dropped on another thread.
+    pub(crate) fn register_shared(&self, ordering: Ordering) -> WaiterGuard<'_> {
+        #[cfg(all(debug_assertions, feature = "relocation-check"))]
+        self.enter();
+        let guard = WaiterGuard::new(self.as_ref(), ordering);
+        #[cfg(all(debug_assertions, feature = "relocation-check"))]
+        let guard = self.tracked(guard);
+        guard
+    }
+
+    fn register_count(&self, ordering: Ordering) -> WaiterGuard<'_> {
+        if self.disabled {
+            return WaiterGuard::new(None, ordering);
+        }
+        if self.private {
+            if let Some(slot) = WAITER_SLOT.try_with(|slot| slot.0).ok().flatten() {
+                let slot = &self.slots[slot];
+                // Only this thread writes the byte
+                let n = slot.load(Ordering::Relaxed);
+                if n < u8::MAX {
+                    slot.store(n + 1, ordering);
+                    return WaiterGuard::with(Registration::Slot(slot));
+                }
+            }
+        }
+        WaiterGuard::new(Some(&self.count), ordering)
+    }
+
+    /// [`Self::register`] only if fewer than `max` are counted, as a single CAS so that concurrent callers cannot overshoot it.
+    ///
+    /// # Panic
+    ///
+    /// If the counter is private and `max` bounds anything.
+    pub(crate) fn try_register(&self, max: usize, ordering: Ordering) -> Option<WaiterGuard<'_>> {
+        if self.private {
+            assert_eq!(max, usize::MAX);
+            return Some(self.register(ordering));
+        }
+        #[cfg(all(debug_assertions, feature = "relocation-check"))]
+        self.enter();
+        let Some(guard) = WaiterGuard::try_new(self.as_ref(), max, ordering) else {
+            #[cfg(all(debug_assertions, feature = "relocation-check"))]
+            relocation::leave(self.address());
+            return None;
+        };
+        #[cfg(all(debug_assertions, feature = "relocation-check"))]
+        let guard = self.tracked(guard);
+        Some(guard)
+    }
+
+    /// The scope of the futex operations of the primitive owning the counter.
+    ///
+    /// # Panic
+    ///
+    /// With the `relocation-check` feature in debug builds, if the primitive was moved while threads were registered with it; learn more from the [relocation](Self#relocation).
+    pub(crate) fn scope(&self) -> FutexScope {
+        #[cfg(all(debug_assertions, feature = "relocation-check"))]
+        relocation::check(self.address(), || self.load(Ordering::Relaxed).unwrap_or(0));
+        match self.private {
+            true => FutexScope::Private,
+            false => FutexScope::Shared,
+        }
+    }
+
+    /// Return [`None`] if disabled.
+    pub(crate) fn load(&self, ordering: Ordering) -> Option<usize> {
+        if self.disabled {
+            return None;
+        }
+        let mut n = self.count.load(ordering);
+        if self.private {
+            n += self
+                .slots
+                .iter()
+                .map(|slot| usize::from(slot.load(ordering)))
+                .sum::<usize>();
+        }
+        Some(n)
+    }
+
+    /// The shared word alone, for guards that may be dropped on another thread.
+    pub(crate) fn as_ref(&self) -> Option<&AtomicUsize> {
+        (!self.disabled).then_some(&self.count)
+    }
+
+    /// Moves along with the primitive owning the counter.
+    #[cfg(all(debug_assertions, feature = "relocation-check"))]
+    fn address(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Enter the side table before counting a registration.
+    ///
+    /// # Panic
+    ///
+    /// If the primitive was moved while threads were registered with it.
+    #[cfg(all(debug_assertions, feature = "relocation-check"))]
+    fn enter(&self) {
+        relocation::enter(self.address(), || self.load(Ordering::Relaxed).unwrap_or(0));
+    }
+
+    /// Let `guard` leave the side table once it stops counting.
+    #[cfg(all(debug_assertions, feature = "relocation-check"))]
+    fn tracked<'a>(&self, mut guard: WaiterGuard<'a>) -> WaiterGuard<'a> {
+        guard.address = Some(self.address());
+        guard
+    }
+}
+
+#[derive(Debug)]
+enum Registration<'a> {
+    None,
+    Count(&'a AtomicUsize),
+    Slot(&'a AtomicU8),
+}
+
+/// Counts one waiter in a primitive's waiters counter until dropped.
+///
+/// The decrement running on drop keeps the counter accurate even if the wait in between unwinds.
+#[derive(Debug)]
+#[must_use = "if unused the waiter will immediately deregister"]
+pub(crate) struct WaiterGuard<'a> {
+    registration: Registration<'a>,
+    /// The address of the counter to leave the side table of on drop; only set by [`WaitersCounter`]
+    #[cfg(all(debug_assertions, feature = "relocation-check"))]
+    address: Option<usize>,
+}
+impl<'a> WaiterGuard<'a> {
+    /// Increment `waiters` with `ordering`; a primitive without a counter passes [`None`].
+    pub(crate) fn new(waiters: Option<&'a AtomicUsize>, ordering: Ordering) -> Self {
+        let Some(waiters) = waiters else {
+            return Self::with(Registration::None);
+        };
+        waiters.fetch_add(1, ordering);
+        Self::with(Registration::Count(waiters))
+    }
+
+    fn with(registration: Registration<'a>) -> Self {
+        Self {
+            registration,
+            #[cfg(all(debug_assertions, feature = "relocation-check"))]
+            address: None,
+        }
+    }
+
+    /// [`Self::new`] only if fewer than `max` are counted, as a single CAS so that concurrent callers cannot overshoot it.
+    pub(crate) fn try_new(
+        waiters: Option<&'a AtomicUsize>,
+        max: usize,
+        ordering: Ordering,
+    ) -> Option<Self> {
+        let Some(waiters) = waiters else {
+            return Some(Self::with(Registration::None));
+        };
+        waiters
+            .fetch_update(ordering, Ordering::Relaxed, |n| (n < max).then_some(n + 1))
+            .ok()?;
+        Some(Self::with(Registration::Count(waiters)))
+    }
+}
+impl Drop for WaiterGuard<'_> {
+    fn drop(&mut self) {
+        match self.registration {
+            Registration::None => (),
+            Registration::Count(waiters) => {
+                waiters.fetch_sub(1, Ordering::Relaxed);
+            }
+            Registration::Slot(slot) => {
+                slot.store(slot.load(Ordering::Relaxed) - 1, Ordering::Relaxed)
+            }
+        }
+        #[cfg(all(debug_assertions, feature = "relocation-check"))]
+        if let Some(address) = self.address {
+            relocation::leave(address);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicBool, Arc};
+
+    use super::*;
+    use crate::mock_backend::WAIT_SYSCALLS;
+
+    #[test]
+    fn test_futex_wrapper() {
+        static WORD: Futex = Futex::new(0);
+        assert!(matches!(
+            WORD.wait_timeout(0, Duration::from_millis(5), TimeoutMeasure::MonoTime),
+            Err(FutexError::TimedOut)
+        ));
+        assert!(matches!(WORD.wait(1), Err(FutexError::ValueMismatch)));
+        assert!(!WORD.wake_one().unwrap());
+
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                while WORD.load(Ordering::Acquire) == 0 {
+                    let _ = WORD.wait(0);
+                }
+            });
+            std::thread::sleep(Duration::from_millis(10));
+            WORD.store(1, Ordering::Release);
+            let mut woken = 0;
+            while !waiter.is_finished() {
+                woken += WORD.wake_all().unwrap();
+                std::thread::yield_now();
+            }
+            assert!(woken <= 1);
+        });
+
+        // The same word as the free functions see it
+        let word = AtomicU32::new(3);
+        let futex = Futex::from_atomic(&word);
+        assert_eq!(futex.load(Ordering::Relaxed), 3);
+        assert!(matches!(
+            futex_wait(futex.context(4, None, FutexScope::Private)),
+            Err(FutexError::ValueMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_busy_wait_backoff() {
+        /// Wait syscalls of a busy wait on a word mismatched for 20ms
+        fn attempts(backoff: idle::Backoff<'_>) -> usize {
+            let word = AtomicU32::new(1);
+            let done = AtomicBool::new(false);
+            std::thread::scope(|s| {
+                s.spawn(|| {
+                    std::thread::sleep(Duration::from_millis(20));
+                    word.store(0, Ordering::Relaxed);
+                    while !done.load(Ordering::Relaxed) {
+                        futex_wake_private(&word, WakeWaiters::All).unwrap();
+                        std::thread::yield_now();
+                    }
+                });
+                let before = WAIT_SYSCALLS.get();
+                busy_futex_wait_with(
+                    FutexWaitContext {
+                        word: &word,
+                        expected: 0,
+                        timeout: None,
+                        scope: FutexScope::Private,
+                    },
+                    backoff,
+                )
+                .unwrap();
+                done.store(true, Ordering::Relaxed);
+                WAIT_SYSCALLS.get() - before
+            })
+        }
+
+        let spin = attempts(idle::Backoff::Spin);
+        let exponential = attempts(idle::Backoff::exponential(
+            Duration::from_micros(1),
+            Duration::from_millis(1),
+        ));
+        assert!(exponential < 64, "{exponential}");
+        assert!(exponential * 10 < spin, "{exponential} vs {spin}");
+
+        let mut pauses = Vec::new();
+        let mut record = |n| pauses.push(n);
+        attempts(idle::Backoff::custom(&mut record));
+        assert!(pauses.iter().copied().eq(0..pauses.len() as u32));
+    }
+
+    #[test]
+    fn test_genuine_wait_total_deadline() {
+        let word = AtomicU32::new(0);
+        let cx = |timeout| FutexWaitContext {
+            word: &word,
+            expected: 0,
+            timeout: Some(FutexTimeout::For(timeout, TimeoutMeasure::MonoTime)),
+            scope: FutexScope::Private,
+        };
+
+        // Zero left still checks the word
+        let e = genuine_futex_wait(cx(Duration::ZERO)).unwrap_err();
+        assert!(matches!(e, FutexError::TimedOut));
+        word.store(1, Ordering::Relaxed);
+        genuine_futex_wait(cx(Duration::ZERO)).unwrap();
+        word.store(0, Ordering::Relaxed);
+
+        // Spurious wakes every millisecond do not restart the timeout
+        let done = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    futex_wake_private(&word, WakeWaiters::All).unwrap();
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            });
+            let start = Instant::now();
+            let e = genuine_futex_wait(cx(Duration::from_millis(50))).unwrap_err();
+            let elapsed = start.elapsed();
+            done.store(true, Ordering::Relaxed);
+            assert!(matches!(e, FutexError::TimedOut));
+            assert!(Duration::from_millis(50) <= elapsed, "{elapsed:?}");
+            assert!(elapsed < Duration::from_millis(500), "{elapsed:?}");
+        });
+
+        // A change is reported as such
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(10));
+                word.store(1, Ordering::Release);
+                futex_wake_private(&word, WakeWaiters::All).unwrap();
+            });
+            genuine_futex_wait(cx(Duration::from_secs(10))).unwrap();
+        });
+    }
+
+    #[test]
+    #[cfg(all(debug_assertions, feature = "relocation-check"))]
+    fn test_relocated_primitive_trips_debug_assertion() {
+        let sem = semaphore::Semaphore::new(0);
+        std::thread::scope(|s| {
+            s.spawn(|| sem.wait());
+            while sem.waiters() != Some(1) {
+                std::thread::yield_now();
+            }
+            // A bitwise copy, as a reallocation would make; its wake would miss the waiter
+            let moved = std::mem::ManuallyDrop::new(unsafe { std::ptr::read(&sem) });
+            let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| moved.signal()));
+            assert!(res.is_err());
+            sem.signal();
+        });
+    }
+
+    #[test]
+    fn test_moved_between_waits() {
+        let mut mutexes = vec![mutex::Mutex::new(0)];
+        for _ in 0..2 {
+            let m = &mutexes[0];
+            std::thread::scope(|s| {
+                let guard = m.lock();
+                s.spawn(|| *m.lock() += 1);
+                while m.waiters() != Some(1) {
+                    std::thread::yield_now();
+                }
+                drop(guard);
+            });
+            // Reallocate with no thread waiting
+            mutexes.reserve(mutexes.capacity() + 1);
+        }
+        assert_eq!(*mutexes[0].lock(), 2);
+    }
+
+    #[test]
+    fn test_private_waiters_beyond_slots() {
+        const THREADS: usize = 2 * WAITER_SLOTS;
+        let counter = WaitersCounter::private();
+        let registered = std::sync::Barrier::new(THREADS + 1);
+        let release = std::sync::Barrier::new(THREADS + 1);
+        std::thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    let _outer = counter.register(Ordering::SeqCst);
+                    let _nested = counter.register(Ordering::SeqCst);
+                    registered.wait();
+                    release.wait();
+                });
+            }
+            registered.wait();
+            // Some in slots, depending on the slots other tests hold, and the rest in the shared word
+            assert_eq!(counter.load(Ordering::SeqCst), Some(2 * THREADS));
+            assert!(counter.count.load(Ordering::SeqCst) != 0);
+            release.wait();
+        });
+        assert_eq!(counter.load(Ordering::SeqCst), Some(0));
+        assert_eq!(WaitersCounter::disabled().load(Ordering::SeqCst), None);
+    }
+
+    #[test]
+    fn test_wait_would_block() {
+        let word = AtomicU32::new(0);
+        let Err(e) = futex_wait(FutexWaitContext {
+            word: &word,
+            expected: 1,
+            timeout: None,
+            scope: FutexScope::Shared,
+        }) else {
+            panic!();
+        };
+        assert!(matches!(e, FutexError::ValueMismatch));
+    }
+
+    #[test]
+    fn test_wait_wake_ptr() {
+        use rustix::mm::{mmap_anonymous, munmap, MapFlags, ProtFlags};
+
+        let len = std::mem::size_of::<u32>();
+        let map = unsafe {
+            mmap_anonymous(
+                std::ptr::null_mut(),
+                len,
+                ProtFlags::READ | ProtFlags::WRITE,
+                MapFlags::SHARED,
+            )
+        }
+        .unwrap();
+        let word = map.cast::<u32>();
+        let res = unsafe { futex_wait_ptr(word, 1, None) };
+        assert!(matches!(res, Err(FutexError::ValueMismatch)));
+        let timeout = FutexTimeout::For(Duration::from_millis(10), TimeoutMeasure::MonoTime);
+        let res = unsafe { futex_wait_ptr(word, 0, Some(timeout)) };
+        assert!(matches!(res, Err(FutexError::TimedOut)));
+
+        // Raw pointers are not `Send`
+        let addr = word as usize;
+        std::thread::scope(|s| {
+            let waiter = s.spawn(move || {
+                let word = addr as *mut u32;
+                while unsafe { AtomicU32::from_ptr(word) }.load(Ordering::Acquire) == 0 {
+                    let _ = unsafe { futex_wait_ptr(word, 0, None) };
+                }
+            });
+            std::thread::sleep(Duration::from_millis(10));
+            unsafe { AtomicU32::from_ptr(word) }.store(1, Ordering::Release);
+            unsafe { futex_wake_ptr(word, WakeWaiters::ONE) }.unwrap();
+            waiter.join().unwrap();
+        });
+        unsafe { munmap(map, len) }.unwrap();
+    }
+
+    #[test]
+    fn test_u31_clamping() {
+        assert_eq!(U31::clamping(0).get(), 0);
+        assert_eq!(U31::clamping(i32::MAX as usize).get(), i32::MAX as u32);
+        assert_eq!(U31::clamping(i32::MAX as usize + 1), U31::MAX);
+        assert_eq!(U31::clamping(usize::MAX), U31::MAX);
+        assert!(matches!(
+            WakeWaiters::at_most(usize::MAX),
+            WakeWaiters::Amount(U31::MAX)
+        ));
+    }
+
+    #[test]
+    fn test_u31_boundaries() {
+        let max = i32::MAX as u32;
+        assert_eq!(U31::new(0).unwrap().get(), 0);
+        assert_eq!(U31::new(max), Some(U31::MAX));
+        assert_eq!(U31::new(max + 1), None);
+        assert_eq!(U31::new(u32::MAX), None);
+        assert_eq!(U31::try_from(0_usize).unwrap().get(), 0);
+        assert_eq!(U31::try_from(max as usize), Ok(U31::MAX));
+        assert!(U31::try_from(max as usize + 1).is_err());
+        assert!(U31::try_from(usize::MAX).is_err());
+
+        assert_eq!(WakeWaiters::All.count(), max);
+        assert_eq!(WakeWaiters::ONE.count(), 1);
+        assert_eq!(WakeWaiters::from(U31::MAX).count(), max);
+        assert_eq!(WakeWaiters::from(U31::new(0).unwrap()).count(), 0);
+        assert_eq!(RequeueCount::All.count(), max);
+
+        let word = AtomicU32::new(0);
+        assert_eq!(wake_one(&word), 0);
+        assert_eq!(wake_all(&word), 0);
+    }
+
+    #[test]
+    fn test_wake_waiters_zero() {
+        let word = AtomicU32::new(0);
+        assert_eq!(wake_waiters(&word, 0).unwrap(), 0);
+        assert_eq!(wake_waiters(&word, usize::MAX).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_scopes_pair_only_with_themselves() {
+        let word = AtomicU32::new(0);
+        let ready = AtomicUsize::new(0);
+        for scope in [FutexScope::Shared, FutexScope::Private] {
+            std::thread::scope(|s| {
+                let waiter = s.spawn(|| {
+                    ready.fetch_add(1, Ordering::SeqCst);
+                    futex_wait(FutexWaitContext {
+                        word: &word,
+                        expected: 0,
+                        timeout: None,
+                        scope,
+                    })
+                });
+                while ready.load(Ordering::SeqCst) == 0 {
+                    std::thread::yield_now();
+                }
+                std::thread::sleep(Duration::from_millis(50));
+                // The mismatched wake finds nobody
+                let (mismatched, matched): (fn(_, _) -> _, fn(_, _) -> _) = match scope {
+                    FutexScope::Shared => (futex_wake_private, futex_wake),
+                    FutexScope::Private => (futex_wake, futex_wake_private),
+                };
+                assert_eq!(mismatched(&word, WakeWaiters::All).unwrap(), 0);
+                assert!(!waiter.is_finished());
+                while matched(&word, WakeWaiters::All).unwrap() == 0 {
+                    std::thread::yield_now();
+                }
+                waiter.join().unwrap().unwrap();
+            });
+            ready.store(0, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_private_primitives() {
+        let m = crate::mutex::Mutex::new_private(0);
+        let cv = crate::cond_var::CondVar::new_private();
+        let sem = crate::semaphore::Semaphore::new_private(0);
+        let ring_buf = crate::ring_buffer::RingBuffer::<usize, 4>::builder()
+            .full_policy(crate::ring_buffer::FullPolicy::Block)
+            .private(true)
+            .build();
+        const ROUNDS: usize = 100;
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..ROUNDS {
+                    *m.lock() += 1;
+                    cv.notify_all();
+                    sem.signal();
+                    ring_buf.write(i).unwrap();
+                }
+            });
+            for i in 0..ROUNDS {
+                sem.wait();
+                let mut guard = m.lock();
+                while *guard <= i {
+                    guard = cv.wait(guard);
+                }
+                drop(guard);
+                assert_eq!(ring_buf.read().unwrap(), i);
+            }
+        });
+    }
+
+    #[test]
+    fn test_wake_bitset() {
+        const READERS: Bitset = Bitset::bit(0).unwrap();
+        const WRITERS: Bitset = Bitset::bit(1).unwrap();
+        let word = AtomicU32::new(0);
+        let ready = AtomicUsize::new(0);
+        let wait = |mask| {
+            ready.fetch_add(1, Ordering::SeqCst);
+            while word.load(Ordering::SeqCst) == 0 {
+                let _ = futex_wait_bitset(
+                    FutexWaitContext {
+                        word: &word,
+                        expected: 0,
+                        timeout: None,
+                        scope: FutexScope::Shared,
+                    },
+                    mask,
+                );
+            }
+        };
+        std::thread::scope(|s| {
+            let readers = [s.spawn(|| wait(READERS)), s.spawn(|| wait(READERS))];
+            let writer = s.spawn(|| wait(WRITERS));
+            while ready.load(Ordering::SeqCst) < 3 {
+                std::thread::yield_now();
+            }
+            std::thread::sleep(Duration::from_millis(50));
+            word.store(1, Ordering::SeqCst);
+
+            assert_eq!(
+                futex_wake_bitset(&word, WakeWaiters::All, READERS, FutexScope::Shared).unwrap(),
+                2
+            );
+            for reader in readers {
+                reader.join().unwrap();
+            }
+            assert!(!writer.is_finished());
+            assert_eq!(
+                futex_wake_bitset(&word, WakeWaiters::All, READERS, FutexScope::Shared).unwrap(),
+                0
+            );
+            assert_eq!(
+                futex_wake_bitset(&word, WakeWaiters::All, Bitset::ALL, FutexScope::Shared)
+                    .unwrap(),
+                1
+            );
+        });
+
+        let e = futex_wait_bitset(
+            FutexWaitContext {
+                word: &word,
+                expected: 1,
+                timeout: Some(FutexTimeout::For(
+                    Duration::from_millis(10),
+                    TimeoutMeasure::MonoTime,
+                )),
+                scope: FutexScope::Shared,
+            },
+            WRITERS,
+        )
+        .unwrap_err();
+        assert!(matches!(e, FutexError::TimedOut));
+        assert_eq!(Bitset::new(0), None);
+        assert_eq!(Bitset::bit(31).unwrap().get(), 1 << 31);
+        assert_eq!(Bitset::bit(32), None);
+    }
+
+    #[test]
+    fn test_wake_bitset_private() {
+        const MASK: Bitset = Bitset::bit(3).unwrap();
+        let word = AtomicU32::new(0);
+        let ready = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                ready.fetch_add(1, Ordering::SeqCst);
+                while word.load(Ordering::SeqCst) == 0 {
+                    let _ = futex_wait_bitset(
+                        FutexWaitContext {
+                            word: &word,
+                            expected: 0,
+                            timeout: None,
+                            scope: FutexScope::Private,
+                        },
+                        MASK,
+                    );
+                }
+            });
+            while ready.load(Ordering::SeqCst) < 1 {
+                std::thread::yield_now();
+            }
+            std::thread::sleep(Duration::from_millis(50));
+
+            // Keyed apart from the private waiter
+            assert_eq!(
+                futex_wake_bitset(&word, WakeWaiters::All, MASK, FutexScope::Shared).unwrap(),
+                0
+            );
+            word.store(1, Ordering::SeqCst);
+            assert_eq!(
+                futex_wake_bitset(&word, WakeWaiters::All, MASK, FutexScope::Private).unwrap(),
+                1
+            );
+            waiter.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_deadline_in_past() {
+        let word = AtomicU32::new(0);
+        let start = Instant::now();
+        for timeout in [
+            FutexTimeout::Until(start.checked_sub(Duration::from_secs(1)).unwrap_or(start)),
+            FutexTimeout::UntilSystemTime(SystemTime::UNIX_EPOCH),
+        ] {
+            let e = futex_wait(FutexWaitContext {
+                word: &word,
+                expected: 0,
+                timeout: Some(timeout),
+                scope: FutexScope::Shared,
+            })
+            .unwrap_err();
+            assert!(matches!(e, FutexError::TimedOut));
+            assert!(timeout.remaining().is_zero());
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_spurious_wakes_keep_deadline() {
+        const TIMEOUT: Duration = Duration::from_millis(100);
+        let word = AtomicU32::new(0);
+        let stop = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            // Wakes without a change of the value
+            s.spawn(|| {
+                while !stop.load(Ordering::SeqCst) {
+                    std::thread::sleep(Duration::from_millis(5));
+                    futex_wake(&word, WakeWaiters::All).unwrap();
+                }
+            });
+            let start = Instant::now();
+            let timeout = FutexTimeout::For(TIMEOUT, TimeoutMeasure::MonoTime).anchored();
+            let mut spurious = 0;
+            loop {
+                let res = resumed_futex_wait(FutexWaitContext {
+                    word: &word,
+                    expected: 0,
+                    timeout: Some(timeout),
+                    scope: FutexScope::Shared,
+                });
+                match res {
+                    Ok(()) => spurious += 1,
+                    Err(e) => {
+                        assert!(matches!(e, FutexError::TimedOut));
+                        break;
+                    }
+                }
+            }
+            stop.store(true, Ordering::SeqCst);
+            let elapsed = start.elapsed();
+            assert!(0 < spurious);
+            assert!(TIMEOUT <= elapsed);
+            // Far short of the `TIMEOUT` per wake-up that re-issuing the relative timeout would take
+            assert!(elapsed < TIMEOUT * 2, "{elapsed:?} after {spurious} wakes");
+        });
+    }
+
+    #[test]
+    fn test_requeue() {
+        let from = AtomicU32::new(0);
+        let to = AtomicU32::new(0);
+        let woken = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    let _ = futex_wait(FutexWaitContext {
+                        word: &from,
+                        expected: 0,
+                        timeout: None,
+                        scope: FutexScope::Shared,
+                    });
+                    woken.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+            // Catch each waiter once it has parked
+            let mut moved = 0;
+            while moved < 3 {
+                let zero = WakeWaiters::Amount(U31::new(0).unwrap());
+                moved += futex_requeue(&from, &to, zero, RequeueCount::All).unwrap();
+                std::thread::yield_now();
+            }
+            assert_eq!(futex_wake(&from, WakeWaiters::All).unwrap(), 0);
+            assert_eq!(woken.load(Ordering::SeqCst), 0);
+            // One woken, one moved back, one left
+            let one = U31::new(1).unwrap();
+            assert_eq!(
+                futex_requeue(
+                    &to,
+                    &from,
+                    WakeWaiters::Amount(one),
+                    RequeueCount::Amount(one)
+                )
+                .unwrap(),
+                2
+            );
+            assert_eq!(futex_wake(&to, WakeWaiters::All).unwrap(), 1);
+            assert_eq!(futex_wake(&from, WakeWaiters::All).unwrap(), 1);
+        });
+        assert_eq!(woken.into_inner(), 3);
+    }
+
+    #[test]
+    fn test_cmp_requeue_rejects_stale() {
+        let from = AtomicU32::new(0);
+        let to = AtomicU32::new(0);
+        let none = WakeWaiters::Amount(U31::new(0).unwrap());
+        std::thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    let _ = futex_wait(FutexWaitContext {
+                        word: &from,
+                        expected: 0,
+                        timeout: None,
+                        scope: FutexScope::Shared,
+                    });
+                });
+            }
+            let mut moved = 0;
+            while moved < 3 {
+                let res = futex_cmp_requeue(&from, &to, none, RequeueCount::All, 0).unwrap();
+                assert_eq!(res.woken, 0);
+                moved += res.requeued;
+                std::thread::yield_now();
+            }
+
+            // Another thread moves `to` on before the requeue back
+            s.spawn(|| to.store(1, Ordering::SeqCst)).join().unwrap();
+            let one = U31::new(1).unwrap();
+            let e = futex_cmp_requeue(&to, &from, WakeWaiters::Amount(one), RequeueCount::All, 0)
+                .unwrap_err();
+            assert!(matches!(e, FutexError::ValueMismatch));
+            // Nobody was touched
+            assert_eq!(futex_wake(&from, WakeWaiters::All).unwrap(), 0);
+
+            let res = futex_cmp_requeue(&to, &from, WakeWaiters::Amount(one), RequeueCount::All, 1);
+            assert_eq!(
+                res.unwrap(),
+                Requeued {
+                    woken: 1,
+                    requeued: 2
+                }
+            );
+            assert_eq!(futex_wake(&from, WakeWaiters::All).unwrap(), 2);
+        });
+    }
+
+    #[test]
+    fn test_wake_op_unlocks_and_signals() {
+        const UNLOCKED: u32 = 0;
+        const LOCKED: u32 = 1;
+        // Unlock, and wake a waiter of the lock if it was held
+        let unlock = WakeOp::new(
+            WakeOpKind::Set,
+            WakeOpArg::Value(UNLOCKED as i32),
+            WakeOpCmp::Eq,
+            LOCKED as i32,
+        )
+        .unwrap();
+        let signal = AtomicU32::new(0);
+        let lock = AtomicU32::new(LOCKED);
+        let ready = AtomicUsize::new(0);
+        let wait = |word: &AtomicU32, expected| {
+            ready.fetch_add(1, Ordering::SeqCst);
+            while word.load(Ordering::SeqCst) == expected {
+                let _ = futex_wait(FutexWaitContext {
+                    word,
+                    expected,
+                    timeout: None,
+                    scope: FutexScope::Shared,
+                });
+            }
+        };
+        let one = WakeWaiters::ONE;
+        std::thread::scope(|s| {
+            s.spawn(|| wait(&signal, 0));
+            s.spawn(|| wait(&lock, LOCKED));
+            while ready.load(Ordering::SeqCst) < 2 {
+                std::thread::yield_now();
+            }
+            std::thread::sleep(Duration::from_millis(50));
+            signal.store(1, Ordering::SeqCst);
+            assert_eq!(futex_wake_op(&signal, &lock, one, one, unlock).unwrap(), 2);
+        });
+        assert_eq!(lock.load(Ordering::SeqCst), UNLOCKED);
+
+        // Already unlocked, so the waiter of the lock word stays parked
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| wait(&lock, UNLOCKED));
+            while ready.load(Ordering::SeqCst) < 3 {
+                std::thread::yield_now();
+            }
+            std::thread::sleep(Duration::from_millis(50));
+            assert_eq!(futex_wake_op(&signal, &lock, one, one, unlock).unwrap(), 0);
+            assert!(!waiter.is_finished());
+            lock.store(LOCKED, Ordering::SeqCst);
+            futex_wake(&lock, WakeWaiters::All).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_wake_op_rejects_invalid_encodings() {
+        let op = |arg, cmp_arg| WakeOp::new(WakeOpKind::Or, arg, WakeOpCmp::Ge, cmp_arg);
+        assert!(op(WakeOpArg::Value(2047), -2048).is_some());
+        assert!(op(WakeOpArg::Value(2048), 0).is_none());
+        assert!(op(WakeOpArg::Value(-2049), 0).is_none());
+        assert!(op(WakeOpArg::Value(0), 2048).is_none());
+        assert!(op(WakeOpArg::Shift(31), 0).is_some());
+        assert!(op(WakeOpArg::Shift(32), 0).is_none());
+        // OR with `1 << 31`, compared against -1
+        assert_eq!(op(WakeOpArg::Shift(31), -1).unwrap().get(), 0xa501_ffff);
+    }
+
+    #[test]
+    fn test_uninterruptible_wait_under_signal_storm() {
+        extern "C" fn noop(_: libc::c_int) {}
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = noop as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            // No `SA_RESTART`
+            action.sa_flags = 0;
+            libc::sigemptyset(&mut action.sa_mask);
+            assert_eq!(
+                libc::sigaction(libc::SIGPROF, &action, std::ptr::null_mut()),
+                0
+            );
+        }
+
+        let word = AtomicU32::new(0);
+        let timeout = Duration::from_millis(100);
+        let cx = FutexWaitContext {
+            word: &word,
+            expected: 0,
+            timeout: Some(FutexTimeout::For(timeout, TimeoutMeasure::MonoTime)),
+            scope: FutexScope::Shared,
+        };
+        let done = AtomicBool::new(false);
+        let waiter = unsafe { libc::pthread_self() };
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    unsafe { libc::pthread_kill(waiter, libc::SIGPROF) };
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            });
+            let measure = |wait: fn(FutexWaitContext<'_>) -> Result<(), FutexError>| {
+                let before = WAIT_SYSCALLS.get();
+                let start = Instant::now();
+                let e = wait(cx).unwrap_err();
+                assert!(matches!(e, FutexError::TimedOut));
+                let elapsed = start.elapsed();
+                assert!(timeout <= elapsed);
+                assert!(elapsed < timeout * 2);
+                WAIT_SYSCALLS.get() - before
+            };
+            let interrupted = measure(resumed_futex_wait);
+            let uninterrupted = measure(futex_wait_uninterruptible);
+            done.store(true, Ordering::Relaxed);
+            assert_eq!(uninterrupted, 1);
+            assert!(10 < interrupted, "{interrupted}");
+        });
+
+        // The mask is restored
+        let mut mask = unsafe { std::mem::zeroed::<libc::sigset_t>() };
+        unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, std::ptr::null(), &mut mask) };
+        assert_eq!(unsafe { libc::sigismember(&mask, libc::SIGPROF) }, 0);
+    }
+
+    #[test]
+    fn test_futex_error_display() {
+        let e = FutexOpError {
+            context: FutexErrorContext {
+                op: FutexOp::Wait,
+                addr: 0x1000,
+                primitive: observer::Primitive::Semaphore,
+            },
+            error: std::io::Error::from_raw_os_error(libc::EINVAL).into(),
+        };
+        let msg = e.to_string();
+        assert!(msg.starts_with("Semaphore futex Wait on 0x1000: "));
+        assert_eq!(e.error.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn test_futex_error_conversions() {
+        let cases = [
+            (libc::EAGAIN, std::io::ErrorKind::WouldBlock),
+            (libc::ETIMEDOUT, std::io::ErrorKind::TimedOut),
+            (libc::EINTR, std::io::ErrorKind::Interrupted),
+            (libc::ENOSYS, std::io::ErrorKind::Unsupported),
+            (libc::EINVAL, std::io::ErrorKind::InvalidInput),
+        ];
+        for (errno, kind) in cases {
+            let e = FutexError::from(std::io::Error::from_raw_os_error(errno));
+            assert_eq!(e.raw_os_error(), Some(errno));
+            assert_eq!(errno == libc::EINVAL, matches!(e, FutexError::Os(_)));
+            assert_eq!(std::io::Error::from(e).kind(), kind);
+        }
+
+        let word = AtomicU32::new(1);
+        let e = futex_wait(FutexWaitContext {
+            word: &word,
+            expected: 0,
+            timeout: None,
+            scope: FutexScope::Private,
+        })
+        .unwrap_err();
+        assert!(matches!(e, FutexError::ValueMismatch));
+        let e = futex_wait(FutexWaitContext {
+            word: &word,
+            expected: 1,
+            timeout: Some(FutexTimeout::For(Duration::ZERO, TimeoutMeasure::MonoTime)),
+            scope: FutexScope::Private,
+        })
+        .unwrap_err();
+        assert!(matches!(e, FutexError::TimedOut));
     }
 
     #[test]
@@ -157,12 +2322,13 @@ mod tests {
                     word: &word.clone(),
                     expected: 0,
                     timeout: None,
+                    scope: FutexScope::Shared,
                 })
                 .unwrap();
             }
         });
         loop {
-            if futex_wake(&word, WakeWaiters::Amount(U31::new(1).unwrap())).unwrap() == 1 {
+            if futex_wake(&word, WakeWaiters::ONE).unwrap() == 1 {
                 break;
             }
         }
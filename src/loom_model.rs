@@ -0,0 +1,257 @@
+//! Models of the wake handshakes of [`Semaphore`](crate::semaphore::Semaphore) and [`CondVar`](crate::cond_var::CondVar), checked over every interleaving by loom.
+//!
+//! Both skip the wake syscall when no waiter is registered, which is only sound if a waiter registers and then reads the futex word, while the waker changes the word and then reads the waiters, each pair in the single total order of `SeqCst`.
+//! Otherwise both reads can miss the other side, and the waiter sleeps through the only wake meant for it.
+//!
+//! The primitives themselves run on std atomics and real futexes, so the handshakes are restated here on loom atomics over a futex stand-in.
+//! The counters are the per-thread slots of a private [`WaitersCounter`](crate::WaitersCounter), the mode where registering is a plain store.
+//! Loom weakens `SeqCst` accesses to `AcqRel`, so each one of the handshakes is followed by `fence(SeqCst)`, which loom does honor.
+//! A waiter left asleep is reported by loom as a deadlock, which aborts the test run, so the check that the model tells the orderings apart by itself runs on the handshake alone.
+//!
+//! Run with:
+//!
+//! ```sh
+//! RUSTFLAGS="--cfg loom" cargo test --release --lib loom_model
+//! ```
+
+use loom::{
+    sync::{
+        atomic::{fence, AtomicU32, AtomicU8, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+};
+
+/// The futex syscalls: the word is compared and the waiter parked under the same lock that wakes take, as in the kernel
+#[derive(Default)]
+struct Futex {
+    word: AtomicU32,
+    /// Parked waiters and the wakes handed out to them
+    queue: Mutex<(usize, usize)>,
+    wakes: Condvar,
+}
+impl Futex {
+    fn wait(&self, expected: u32) {
+        let mut queue = self.queue.lock().unwrap();
+        if self.word.load(Ordering::Relaxed) != expected {
+            return;
+        }
+        queue.0 += 1;
+        while queue.1 == 0 {
+            queue = self.wakes.wait(queue).unwrap();
+        }
+        queue.0 -= 1;
+        queue.1 -= 1;
+    }
+
+    fn wake(&self, n: usize) {
+        let mut queue = self.queue.lock().unwrap();
+        let (parked, woken) = *queue;
+        queue.1 += n.min(parked - woken);
+        self.wakes.notify_all();
+    }
+}
+
+/// The handshake orderings under test
+#[derive(Clone, Copy)]
+struct Handshake {
+    ordering: Ordering,
+    fenced: bool,
+}
+impl Handshake {
+    const SEQ_CST: Self = Self {
+        ordering: Ordering::SeqCst,
+        fenced: true,
+    };
+    const RELAXED: Self = Self {
+        ordering: Ordering::Relaxed,
+        fenced: false,
+    };
+
+    fn fence(self) {
+        if self.fenced {
+            fence(Ordering::SeqCst);
+        }
+    }
+}
+
+/// The per-thread slots of a private waiters counter
+#[derive(Default)]
+struct Waiters {
+    slots: [AtomicU8; 2],
+}
+impl Waiters {
+    fn register(&self, slot: usize, handshake: Handshake) {
+        self.slots[slot].store(1, handshake.ordering);
+        handshake.fence();
+    }
+
+    fn deregister(&self, slot: usize) {
+        self.slots[slot].store(0, Ordering::Relaxed);
+    }
+
+    fn load(&self, handshake: Handshake) -> u32 {
+        self.slots
+            .iter()
+            .map(|slot| u32::from(slot.load(handshake.ordering)))
+            .sum()
+    }
+}
+
+#[derive(Default)]
+struct Semaphore {
+    value: Futex,
+    waiters: Waiters,
+}
+impl Semaphore {
+    /// [`crate::semaphore::Semaphore::wait`] past its fast path
+    fn wait(&self, slot: usize, handshake: Handshake) {
+        self.waiters.register(slot, handshake);
+        loop {
+            let value = self.value.word.load(handshake.ordering);
+            if value == 0 {
+                self.value.wait(0);
+                continue;
+            }
+            if self
+                .value
+                .word
+                .compare_exchange(value, value - 1, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok()
+            {
+                break;
+            }
+        }
+        self.waiters.deregister(slot);
+    }
+
+    /// The deposit and wake of [`crate::semaphore::Semaphore::signal`]
+    fn signal(&self, handshake: Handshake) {
+        self.value.word.fetch_add(1, handshake.ordering);
+        handshake.fence();
+        if self.waiters.load(handshake) == 0 {
+            return;
+        }
+        self.value.wake(1);
+    }
+}
+
+#[derive(Default)]
+struct CondVar {
+    counter: Futex,
+    waiters: Waiters,
+}
+impl CondVar {
+    /// [`crate::cond_var::CondVar::wait`] in a loop on a flag
+    fn wait_until(&self, m: &Mutex<bool>, slot: usize, handshake: Handshake) {
+        let mut ready = m.lock().unwrap();
+        while !*ready {
+            self.waiters.register(slot, handshake);
+            let c = self.counter.word.load(handshake.ordering);
+            drop(ready);
+            self.counter.wait(c);
+            self.waiters.deregister(slot);
+            ready = m.lock().unwrap();
+        }
+    }
+
+    /// [`crate::cond_var::CondVar::notify_one`]
+    fn notify_one(&self, handshake: Handshake) {
+        self.counter.word.fetch_add(1, handshake.ordering);
+        handshake.fence();
+        if self.waiters.load(handshake) == 0 {
+            return;
+        }
+        self.counter.wake(1);
+    }
+}
+
+fn semaphore_signal_reaches_waiter(handshake: Handshake) {
+    loom::model(move || {
+        let sem = Arc::new(Semaphore::default());
+        let waiter = thread::spawn({
+            let sem = Arc::clone(&sem);
+            move || sem.wait(0, handshake)
+        });
+        sem.signal(handshake);
+        waiter.join().unwrap();
+    });
+}
+
+fn semaphore_signals_reach_waiters(handshake: Handshake) {
+    // Bounded, since two waiters retrying their CAS keep the full search running for more than ten minutes
+    let mut builder = loom::model::Builder::new();
+    builder.preemption_bound = Some(3);
+    builder.check(move || {
+        let sem = Arc::new(Semaphore::default());
+        let waiters = [0, 1].map(|slot| {
+            let sem = Arc::clone(&sem);
+            thread::spawn(move || sem.wait(slot, handshake))
+        });
+        sem.signal(handshake);
+        sem.signal(handshake);
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+    });
+}
+
+fn cond_var_notify_reaches_waiter(handshake: Handshake) {
+    loom::model(move || {
+        let cv = Arc::new(CondVar::default());
+        let m = Arc::new(Mutex::new(false));
+        let waiter = thread::spawn({
+            let cv = Arc::clone(&cv);
+            let m = Arc::clone(&m);
+            move || cv.wait_until(&m, 0, handshake)
+        });
+        *m.lock().unwrap() = true;
+        cv.notify_one(handshake);
+        waiter.join().unwrap();
+    });
+}
+
+#[test]
+fn test_semaphore_signal_reaches_waiter() {
+    semaphore_signal_reaches_waiter(Handshake::SEQ_CST);
+}
+
+#[test]
+fn test_semaphore_signals_reach_waiters() {
+    semaphore_signals_reach_waiters(Handshake::SEQ_CST);
+}
+
+#[test]
+fn test_cond_var_notify_reaches_waiter() {
+    cond_var_notify_reaches_waiter(Handshake::SEQ_CST);
+}
+
+/// The handshake alone, without parking, so that a lost wake fails an assertion instead of hanging the waiter
+fn handshake_never_loses_wake(handshake: Handshake) {
+    loom::model(move || {
+        let sem = Arc::new(Semaphore::default());
+        let waiter = thread::spawn({
+            let sem = Arc::clone(&sem);
+            move || {
+                sem.waiters.register(0, handshake);
+                sem.value.word.load(handshake.ordering) == 0
+            }
+        });
+        sem.value.word.fetch_add(1, handshake.ordering);
+        handshake.fence();
+        let skips_wake = sem.waiters.load(handshake) == 0;
+        let parks = waiter.join().unwrap();
+        assert!(!(parks && skips_wake));
+    });
+}
+
+#[test]
+fn test_handshake_never_loses_wake() {
+    handshake_never_loses_wake(Handshake::SEQ_CST);
+}
+
+#[test]
+#[should_panic]
+fn test_relaxed_handshake_loses_wake() {
+    handshake_never_loses_wake(Handshake::RELAXED);
+}
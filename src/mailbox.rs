@@ -0,0 +1,78 @@
+use crate::slot::{CellValue, SlotCell};
+
+/// Single-slot handoff where a new post overrides an untaken one, so the reader only ever sees the latest value.
+///
+/// The equivalent of a [`crate::ring_buffer::RingBuffer`] with one readable cell, without paying for three.
+#[derive(Debug)]
+pub struct Mailbox<T> {
+    slot: SlotCell<T>,
+}
+impl<T> Mailbox<T> {
+    pub fn new() -> Self {
+        Self {
+            slot: SlotCell::new(),
+        }
+    }
+
+    /// Return the untaken value being overridden if any.
+    pub fn post_override(&self, value: T) -> Option<T> {
+        let mut m = self.slot.write();
+        std::mem::replace(&mut **m.locked(), CellValue::Some(value)).take()
+    }
+
+    /// Block until a value is posted.
+    pub fn take(&self) -> T {
+        self.slot
+            .take_blocking(None)
+            .expect("a mailbox is never cancelled")
+    }
+
+    pub fn try_take(&self) -> Option<T> {
+        self.slot.try_take()
+    }
+}
+impl<T> Default for Mailbox<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_post_override() {
+        let mailbox = Mailbox::new();
+        assert_eq!(mailbox.try_take(), None);
+        assert_eq!(mailbox.post_override(1), None);
+        assert_eq!(mailbox.post_override(2), Some(1));
+        assert_eq!(mailbox.take(), 2);
+        assert_eq!(mailbox.try_take(), None);
+    }
+
+    #[test]
+    fn test_reader_sees_latest() {
+        const POSTS: usize = 1 << 16;
+        let mailbox = Mailbox::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mut prev = None;
+                loop {
+                    let n = mailbox.take();
+                    assert!(prev < Some(n));
+                    if n == POSTS {
+                        return;
+                    }
+                    prev = Some(n);
+                }
+            });
+            for i in 1..=POSTS {
+                mailbox.post_override(i);
+            }
+        });
+        assert_eq!(mailbox.try_take(), None);
+    }
+}
@@ -0,0 +1,608 @@
+//! A user-space stand-in for the futex syscalls, for asserting how the primitives wait and wake.
+//!
+//! [`futex_wait`](crate::futex_wait) and the wakes of the crate consult it first; a word inside a value passed to [`MockBackend::intercept`] is then served here instead of by the kernel.
+//! Other words, including those of tests running alongside, still go to the kernel.
+//!
+//! Like the kernel, the mock compares the word and enqueues the waiter under the same lock that wakes take, so a wake can never slip in between.
+//! It also keys the waiters by [`FutexScope`] as well as by address, so a wake only reaches the waiters of its own scope.
+//!
+//! Every futex syscall of the crate passes through here under test, intercepted or not, so this is also where they are counted and failed on purpose per thread.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    panic::AssertUnwindSafe,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::{Bitset, FutexScope, RequeueCount, WakeWaiters};
+
+static BACKENDS: Mutex<Vec<Arc<Shared>>> = Mutex::new(vec![]);
+
+thread_local! {
+    /// Wake syscalls issued by this thread
+    pub(crate) static WAKE_SYSCALLS: Cell<usize> = const { Cell::new(0) };
+    /// Wait syscalls issued by this thread
+    pub(crate) static WAIT_SYSCALLS: Cell<usize> = const { Cell::new(0) };
+    static FAIL_WAITS: Cell<bool> = const { Cell::new(false) };
+    static FAIL_WAKES: Cell<Option<i32>> = const { Cell::new(None) };
+    static AFTER_WAIT: RefCell<Option<Box<dyn Fn()>>> = const { RefCell::new(None) };
+}
+
+/// Run `f` with every futex wait on this thread failing with `EINVAL`, catching the resulting panic.
+pub(crate) fn with_failing_waits<R>(f: impl FnOnce() -> R) -> std::thread::Result<R> {
+    FAIL_WAITS.set(true);
+    let res = std::panic::catch_unwind(AssertUnwindSafe(f));
+    FAIL_WAITS.set(false);
+    res
+}
+
+/// Run `f` with every futex wake on this thread failing with `errno`, catching the resulting panic.
+pub(crate) fn with_failing_wakes<R>(errno: i32, f: impl FnOnce() -> R) -> std::thread::Result<R> {
+    FAIL_WAKES.set(Some(errno));
+    let res = std::panic::catch_unwind(AssertUnwindSafe(f));
+    FAIL_WAKES.set(None);
+    res
+}
+
+/// Run `f` with `hook` called right after each intercepted wait on this thread returns, e.g., to act as another thread getting in first.
+pub(crate) fn with_wait_hook<R>(hook: impl Fn() + 'static, f: impl FnOnce() -> R) -> R {
+    AFTER_WAIT.set(Some(Box::new(hook)));
+    let res = f();
+    AFTER_WAIT.set(None);
+    res
+}
+
+fn count_wait() -> Option<std::io::Result<()>> {
+    WAIT_SYSCALLS.set(WAIT_SYSCALLS.get() + 1);
+    FAIL_WAITS
+        .get()
+        .then(|| Err(std::io::Error::from_raw_os_error(libc::EINVAL)))
+}
+
+fn count_wake() -> Option<std::io::Error> {
+    WAKE_SYSCALLS.set(WAKE_SYSCALLS.get() + 1);
+    FAIL_WAKES.get().map(std::io::Error::from_raw_os_error)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct WakeRecord {
+    pub addr: usize,
+    /// [`None`] for [`WakeWaiters::All`]
+    pub requested: Option<u32>,
+    pub woken: usize,
+}
+
+/// What the next waits on intercepted words do instead of sleeping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Injection {
+    /// Return as if woken
+    Spurious,
+    /// Fail with `EINTR`
+    Interrupted,
+    /// Fail with `EPERM`, as under a seccomp profile denying the syscall
+    Denied,
+}
+
+/// How the kernel tells futex words apart
+type Key = (usize, FutexScope);
+
+#[derive(Debug, Default)]
+struct State {
+    /// Parked tickets and their masks per word, in arrival order
+    queues: HashMap<Key, VecDeque<(u64, Bitset)>>,
+    /// Tickets woken but not yet returned
+    woken: Vec<u64>,
+    next_ticket: u64,
+    wakes: Vec<WakeRecord>,
+    injections: VecDeque<Injection>,
+}
+impl State {
+    fn parked(&self) -> usize {
+        self.queues.values().map(|q| q.len()).sum()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    ranges: Mutex<Vec<(usize, usize)>>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+impl Shared {
+    fn covers(&self, addr: usize) -> bool {
+        self.ranges
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|&(start, end)| (start..end).contains(&addr))
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+/// Uninstalled on drop.
+#[derive(Debug)]
+pub(crate) struct MockBackend {
+    shared: Arc<Shared>,
+}
+impl MockBackend {
+    pub fn install() -> Self {
+        let shared = Arc::new(Shared::default());
+        BACKENDS.lock().unwrap().push(shared.clone());
+        Self { shared }
+    }
+
+    /// Serve every futex word inside `value` from this backend.
+    pub fn intercept<T>(&self, value: &T) {
+        let start = value as *const T as usize;
+        let end = start + std::mem::size_of_val(value);
+        self.shared.ranges.lock().unwrap().push((start, end));
+    }
+
+    /// Make the next intercepted waits, one per injection, return without sleeping.
+    pub fn inject(&self, injection: Injection) {
+        self.shared.state().injections.push_back(injection);
+    }
+
+    /// Waiters parked on any intercepted word.
+    ///
+    /// Only a snapshot.
+    pub fn parked(&self) -> usize {
+        self.shared.state().parked()
+    }
+
+    /// Block until `n` waiters are parked on intercepted words, so that the test can step the next action deterministically.
+    ///
+    /// # Panics
+    ///
+    /// If that takes longer than ten seconds.
+    pub fn wait_until_parked(&self, n: usize) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut state = self.shared.state();
+        while state.parked() < n {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            assert!(!remaining.is_zero(), "{n} waiters never parked");
+            state = self
+                .shared
+                .changed
+                .wait_timeout(state, remaining)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Every wake on intercepted words so far, in order.
+    pub fn wakes(&self) -> Vec<WakeRecord> {
+        self.shared.state().wakes.clone()
+    }
+
+    /// Forget the wakes so far, e.g., those of the setup.
+    pub fn clear_wakes(&self) {
+        self.shared.state().wakes.clear();
+    }
+}
+impl Drop for MockBackend {
+    fn drop(&mut self) {
+        BACKENDS
+            .lock()
+            .unwrap()
+            .retain(|shared| !Arc::ptr_eq(shared, &self.shared));
+    }
+}
+
+fn find(addr: usize) -> Option<Arc<Shared>> {
+    let backends = BACKENDS.lock().unwrap();
+    backends.iter().find(|shared| shared.covers(addr)).cloned()
+}
+
+/// Serve the wait if the word is intercepted.
+pub(crate) fn wait(cx: crate::FutexWaitContext<'_>) -> Option<std::io::Result<()>> {
+    wait_bitset(cx, Bitset::ALL)
+}
+
+/// Serve the wait if the word is intercepted, only to be woken by the wakes whose mask intersects `mask`.
+pub(crate) fn wait_bitset(
+    cx: crate::FutexWaitContext<'_>,
+    mask: Bitset,
+) -> Option<std::io::Result<()>> {
+    if let Some(failed) = count_wait() {
+        return Some(failed);
+    }
+    let res = serve_wait(cx, mask)?;
+    AFTER_WAIT.with_borrow(|hook| hook.as_ref().map(|hook| hook()));
+    Some(res)
+}
+
+fn serve_wait(cx: crate::FutexWaitContext<'_>, mask: Bitset) -> Option<std::io::Result<()>> {
+    let shared = find(cx.word.as_ptr() as usize)?;
+    let key = (cx.word.as_ptr() as usize, cx.scope);
+    let mut state = shared.state();
+    if cx.word.load(std::sync::atomic::Ordering::SeqCst) != cx.expected {
+        return Some(Err(std::io::Error::from_raw_os_error(libc::EAGAIN)));
+    }
+    match state.injections.pop_front() {
+        Some(Injection::Spurious) => return Some(Ok(())),
+        Some(Injection::Interrupted) => {
+            return Some(Err(std::io::Error::from_raw_os_error(libc::EINTR)))
+        }
+        Some(Injection::Denied) => {
+            return Some(Err(std::io::Error::from_raw_os_error(libc::EPERM)))
+        }
+        None => (),
+    }
+    let ticket = state.next_ticket;
+    state.next_ticket += 1;
+    state
+        .queues
+        .entry(key)
+        .or_default()
+        .push_back((ticket, mask));
+    shared.changed.notify_all();
+    let deadline = cx
+        .timeout
+        .map(|timeout| Instant::now() + timeout.remaining());
+    loop {
+        if let Some(i) = state.woken.iter().position(|&t| t == ticket) {
+            state.woken.swap_remove(i);
+            return Some(Ok(()));
+        }
+        state = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    // Possibly requeued elsewhere
+                    for queue in state.queues.values_mut() {
+                        queue.retain(|&(t, _)| t != ticket);
+                    }
+                    shared.changed.notify_all();
+                    return Some(Err(std::io::Error::from_raw_os_error(libc::ETIMEDOUT)));
+                }
+                shared.changed.wait_timeout(state, remaining).unwrap().0
+            }
+            None => shared.changed.wait(state).unwrap(),
+        };
+    }
+}
+
+/// Serve the wake if the word is intercepted.
+pub(crate) fn wake(
+    addr: *mut u32,
+    waiters: WakeWaiters,
+    scope: FutexScope,
+) -> Option<std::io::Result<usize>> {
+    wake_bitset(addr, waiters, Bitset::ALL, scope)
+}
+
+/// Serve the wake if the word is intercepted, only waking the waiters whose mask intersects `mask`.
+pub(crate) fn wake_bitset(
+    addr: *mut u32,
+    waiters: WakeWaiters,
+    mask: Bitset,
+    scope: FutexScope,
+) -> Option<std::io::Result<usize>> {
+    if let Some(e) = count_wake() {
+        return Some(Err(e));
+    }
+    serve_wake((addr as usize, scope), waiters, mask)
+}
+
+/// Count the wake of a kind the mock does not serve, e.g., `FUTEX_WAKE_OP`.
+pub(crate) fn wake_unserved() -> Option<std::io::Error> {
+    count_wake()
+}
+
+fn serve_wake(key: Key, waiters: WakeWaiters, mask: Bitset) -> Option<std::io::Result<usize>> {
+    let (addr, _) = key;
+    let shared = find(addr)?;
+    let mut state = shared.state();
+    let requested = match waiters {
+        WakeWaiters::Amount(n) => Some(n.get()),
+        WakeWaiters::All => None,
+    };
+    let queue = state.queues.entry(key).or_default();
+    let mut woken = vec![];
+    queue.retain(|&(ticket, waiting)| {
+        let wake =
+            waiting.get() & mask.get() != 0 && requested.is_none_or(|n| woken.len() < n as usize);
+        if wake {
+            woken.push(ticket);
+        }
+        !wake
+    });
+    state.woken.extend(&woken);
+    state.wakes.push(WakeRecord {
+        addr,
+        requested,
+        woken: woken.len(),
+    });
+    shared.changed.notify_all();
+    Some(Ok(woken.len()))
+}
+
+/// Serve the requeue if `from` is intercepted; `to` must then be intercepted by the same backend.
+pub(crate) fn requeue(
+    from: *mut u32,
+    to: *mut u32,
+    wake: WakeWaiters,
+    requeue: RequeueCount,
+) -> Option<std::io::Result<usize>> {
+    if let Some(e) = count_wake() {
+        return Some(Err(e));
+    }
+    serve_requeue(from, to, wake, requeue)
+}
+
+fn serve_requeue(
+    from: *mut u32,
+    to: *mut u32,
+    wake: WakeWaiters,
+    requeue: RequeueCount,
+) -> Option<std::io::Result<usize>> {
+    // The requeues of the crate are all shared
+    let (from, to) = (
+        (from as usize, FutexScope::Shared),
+        (to as usize, FutexScope::Shared),
+    );
+    let shared = find(from.0)?;
+    assert!(shared.covers(to.0));
+    let woken = serve_wake(from, wake, Bitset::ALL).unwrap().unwrap();
+    let mut state = shared.state();
+    let queue = state.queues.entry(from).or_default();
+    let n = match requeue {
+        RequeueCount::Amount(n) => queue.len().min(n.get() as usize),
+        RequeueCount::All => queue.len(),
+    };
+    let moved = queue.drain(..n).collect::<Vec<_>>();
+    state.queues.entry(to).or_default().extend(&moved);
+    shared.changed.notify_all();
+    Some(Ok(woken + moved.len()))
+}
+
+/// Serve the compared requeue if `from` is intercepted, comparing under the same lock as waits do.
+pub(crate) fn cmp_requeue(
+    from: &std::sync::atomic::AtomicU32,
+    to: &std::sync::atomic::AtomicU32,
+    wake: u32,
+    requeue: RequeueCount,
+    expected: u32,
+) -> Option<std::io::Result<usize>> {
+    if let Some(e) = count_wake() {
+        return Some(Err(e));
+    }
+    let shared = find(from.as_ptr() as usize)?;
+    let state = shared.state();
+    if from.load(std::sync::atomic::Ordering::SeqCst) != expected {
+        return Some(Err(std::io::Error::from_raw_os_error(libc::EAGAIN)));
+    }
+    drop(state);
+    let wake = WakeWaiters::Amount(crate::U31::new(wake).unwrap());
+    serve_requeue(from.as_ptr(), to.as_ptr(), wake, requeue)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::AtomicU32, thread};
+
+    use crate::{cond_var::CondVar, event::Event, mutex::Mutex, semaphore::Semaphore, U31};
+
+    use super::*;
+
+    #[test]
+    fn test_uncontended_unlock_never_wakes() {
+        let mock = MockBackend::install();
+        let m = Mutex::new(0);
+        mock.intercept(&m);
+        for _ in 0..10 {
+            *m.lock() += 1;
+        }
+        assert_eq!(mock.wakes(), []);
+    }
+
+    #[test]
+    fn test_contended_unlock_wakes_one() {
+        let mock = MockBackend::install();
+        let m = Mutex::new(0);
+        mock.intercept(&m);
+        let guard = m.lock();
+        thread::scope(|s| {
+            s.spawn(|| *m.lock() += 1);
+            mock.wait_until_parked(1);
+            drop(guard);
+        });
+        let wakes = mock.wakes();
+        assert_eq!((wakes[0].requested, wakes[0].woken), (Some(1), 1));
+        // The woken waiter relocks as contended, not knowing whether others still wait, so its unlock wakes in vain
+        assert_eq!(wakes.len(), 2);
+        assert_eq!(wakes[1].woken, 0);
+    }
+
+    #[test]
+    fn test_notify_all_wakes_every_waiter_at_once() {
+        let mock = MockBackend::install();
+        let m = Mutex::new(false);
+        let cv = CondVar::new();
+        mock.intercept(&m);
+        mock.intercept(&cv);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mut ready = m.lock();
+                    while !*ready {
+                        ready = cv.wait(ready);
+                    }
+                });
+            }
+            mock.wait_until_parked(4);
+            mock.clear_wakes();
+            *m.lock() = true;
+            cv.notify_all();
+            let wakes = mock.wakes();
+            assert_eq!(wakes.len(), 1);
+            assert_eq!((wakes[0].requested, wakes[0].woken), (None, 4));
+        });
+        // The woken waiters then contend on the mutex, one wake each at most
+        let mutex = m.futex_word().as_ptr() as usize;
+        let wakes = mock.wakes();
+        assert!(wakes[1..].iter().all(|w| w.addr == mutex));
+        assert!(wakes.len() <= 1 + 4, "{wakes:?}");
+    }
+
+    #[test]
+    fn test_notify_n_wakes_exactly_n() {
+        let mock = MockBackend::install();
+        let m = Mutex::new(0);
+        let cv = CondVar::new();
+        mock.intercept(&cv);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mut guard = m.lock();
+                    *guard += 1;
+                    drop(cv.wait(guard));
+                });
+            }
+            mock.wait_until_parked(4);
+            assert_eq!(cv.notify_n(U31::new(3).unwrap()), 3);
+            assert_eq!(mock.parked(), 1);
+            cv.notify_one();
+        });
+        assert_eq!(mock.parked(), 0);
+    }
+
+    #[test]
+    fn test_private_counters_wake_exactly() {
+        let mock = MockBackend::install();
+        let m = Mutex::new(0);
+        let cv = CondVar::new_private();
+        let sem = Semaphore::new_private(0);
+        mock.intercept(&cv);
+        mock.intercept(&sem);
+        // Nobody registered, so no syscall
+        cv.notify_all();
+        sem.signal();
+        sem.wait();
+        assert_eq!(mock.wakes(), []);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| drop(cv.wait(m.lock())));
+                s.spawn(|| sem.wait());
+            }
+            mock.wait_until_parked(8);
+            assert_eq!((cv.waiters(), sem.waiters()), (Some(4), Some(4)));
+            assert_eq!(cv.notify_n(U31::new(3).unwrap()), 3);
+            assert_eq!(sem.signal_many(3), 3);
+            assert_eq!(mock.parked(), 2);
+            cv.notify_one();
+            sem.signal();
+        });
+        assert_eq!((cv.waiters(), sem.waiters()), (Some(0), Some(0)));
+        mock.clear_wakes();
+        cv.notify_all();
+        sem.signal();
+        assert_eq!(mock.wakes(), []);
+    }
+
+    #[test]
+    fn test_interrupted_wait_resumes() {
+        let mock = MockBackend::install();
+        let sem = Semaphore::new(0);
+        mock.intercept(&sem);
+        mock.inject(Injection::Interrupted);
+        thread::scope(|s| {
+            s.spawn(|| sem.wait());
+            // Parked only after the injected `EINTR` is retried
+            mock.wait_until_parked(1);
+            sem.signal();
+        });
+        assert_eq!(sem.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_spurious_wakeup_rechecks() {
+        let mock = MockBackend::install();
+        let event = Event::new();
+        mock.intercept(&event);
+        mock.inject(Injection::Spurious);
+        thread::scope(|s| {
+            let waiter = s.spawn(|| event.wait());
+            mock.wait_until_parked(1);
+            assert!(!waiter.is_finished());
+            event.set();
+        });
+        assert_eq!(mock.wakes().len(), 1);
+    }
+
+    #[test]
+    fn test_requeue_moves_parked() {
+        let mock = MockBackend::install();
+        let words = [AtomicU32::new(0), AtomicU32::new(0)];
+        mock.intercept(&words);
+        thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    crate::futex_wait(crate::FutexWaitContext {
+                        word: &words[0],
+                        expected: 0,
+                        timeout: None,
+                        scope: FutexScope::Shared,
+                    })
+                    .unwrap()
+                });
+            }
+            mock.wait_until_parked(3);
+            let one = U31::new(1).unwrap();
+            let moved = crate::futex_requeue(
+                &words[0],
+                &words[1],
+                WakeWaiters::Amount(one),
+                crate::RequeueCount::All,
+            );
+            assert_eq!(moved.unwrap(), 3);
+            assert_eq!(crate::futex_wake(&words[0], WakeWaiters::All).unwrap(), 0);
+            assert_eq!(mock.parked(), 2);
+            assert_eq!(crate::futex_wake(&words[1], WakeWaiters::All).unwrap(), 2);
+        });
+    }
+
+    #[test]
+    fn test_wakes_reach_own_scope_and_mask() {
+        let mock = MockBackend::install();
+        let word = AtomicU32::new(0);
+        mock.intercept(&word);
+        let mask = Bitset::bit(1).unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                let cx = crate::FutexWaitContext {
+                    word: &word,
+                    expected: 0,
+                    timeout: None,
+                    scope: FutexScope::Private,
+                };
+                crate::futex_wait_bitset(cx, mask).unwrap()
+            });
+            mock.wait_until_parked(1);
+            assert_eq!(crate::futex_wake(&word, WakeWaiters::All).unwrap(), 0);
+            let other = Bitset::bit(2).unwrap();
+            let woken =
+                crate::futex_wake_bitset(&word, WakeWaiters::All, other, FutexScope::Private);
+            assert_eq!(woken.unwrap(), 0);
+            assert_eq!(mock.parked(), 1);
+            let woken =
+                crate::futex_wake_bitset(&word, WakeWaiters::All, mask, FutexScope::Private);
+            assert_eq!(woken.unwrap(), 1);
+        });
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mock = MockBackend::install();
+        let event = Event::new();
+        mock.intercept(&event);
+        assert!(!event.wait_timeout(Duration::from_millis(10)));
+        assert_eq!(mock.parked(), 0);
+    }
+}
@@ -1,212 +1,1192 @@
 use std::{
+    convert::Infallible,
+    marker::{PhantomData, PhantomPinned},
     ops::{Deref, DerefMut},
     sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+    time::{Duration, Instant},
 };
 
 use sync_unsafe_cell::SyncUnsafeCell;
 
-use crate::{futex_wake, resumed_futex_wait, FutexWaitContext, WakeWaiters, U31};
+use crate::{
+    deadline::TimedOut,
+    futex_enum::FutexEnum,
+    futex_wait,
+    idle::AdaptiveSpin,
+    observer::{futex_wake_from, observed_futex_wait, Primitive},
+    shutdown::{futex_wait_or_shutdown, Shutdown, ShutdownToken},
+    violation::{violation, ProtocolViolation},
+    Futex, FutexError, FutexScope, FutexTimeout, TimeoutMeasure, WaiterGuard, WaitersCounter,
+    WakeWaiters,
+};
 
-#[derive(Debug, Clone, Copy)]
-pub enum State {
-    Unlocked = 0,
-    Locked,
+crate::futex_enum! {
+    /// # Word encoding
+    ///
+    /// - [`State::Locked`] means no thread has gone to sleep on the word since it was locked, so unlocking needs no `FUTEX_WAKE`.
+    /// - [`State::Contended`] means some thread might be sleeping on the word; every thread does so only after setting it.
+    #[derive(Debug, Clone, Copy)]
+    pub enum State {
+        Unlocked = 0,
+        Locked,
+        Contended,
+    }
+}
+
+/// How often a priority-inheriting [`Mutex::lock_or_shutdown`] checks the token.
+const PI_SHUTDOWN_SLICE: Duration = Duration::from_millis(10);
+
+pub const fn new_unlocked_futex() -> AtomicU32 {
+    AtomicU32::new(State::Unlocked as u32)
+}
+
+/// Return [`LockResult::WouldBlock`] if it fails to lock in a nonblocking setting, or [`LockResult::TimedOut`] if the deadline passes first.
+///
+/// `waiters` is only maintained for introspection; whether to wake on [`unlock`] is decided by the word alone.
+///
+/// # Panic
+///
+/// If `futex` is not in any of the [`State`] (only checked in debug builds).
+#[inline]
+pub fn lock(
+    futex: &AtomicU32,
+    waiters: Option<&AtomicUsize>,
+    blocking: LockBlocking,
+) -> LockResult {
+    lock_inner(
+        futex,
+        || WaiterGuard::new(waiters, Ordering::Relaxed),
+        None,
+        None,
+        blocking,
+        FutexScope::Shared,
+    )
 }
-impl From<State> for u32 {
-    fn from(value: State) -> Self {
-        value as u32
+
+/// `register` counts the caller as a waiter until the returned guard drops.
+#[inline]
+fn lock_inner<'a>(
+    futex: &AtomicU32,
+    register: impl FnOnce() -> WaiterGuard<'a>,
+    holder: Option<&AtomicU32>,
+    spin: Option<&AdaptiveSpin>,
+    blocking: LockBlocking,
+    scope: FutexScope,
+) -> LockResult {
+    // Fast path: uncontended
+    if try_acquire(futex) {
+        return LockResult::Acquired;
     }
+    lock_contended(futex, register, holder, spin, blocking, |_, timeout| {
+        sleep_contended(futex, timeout, scope);
+        Ok::<_, Infallible>(())
+    })
+    .unwrap()
 }
-impl TryFrom<u32> for State {
-    type Error = ();
-    fn try_from(value: u32) -> Result<Self, Self::Error> {
-        if value == State::Locked.into() {
-            return Ok(State::Locked);
-        }
-        if value == State::Unlocked.into() {
-            return Ok(State::Unlocked);
+
+fn sleep_contended(futex: &AtomicU32, timeout: Option<Duration>, scope: FutexScope) {
+    if let Err(e) = observed_futex_wait(
+        Primitive::Mutex,
+        Futex::from_atomic(futex).context(
+            State::Contended.into(),
+            timeout.map(|t| FutexTimeout::For(t, TimeoutMeasure::MonoTime)),
+            scope,
+        ),
+    ) {
+        if !matches!(e.error, FutexError::ValueMismatch | FutexError::TimedOut) {
+            panic!("{e}");
         }
-        Err(())
     }
 }
 
-pub fn new_unlocked_futex() -> AtomicU32 {
-    AtomicU32::new(State::Unlocked.into())
+#[inline]
+fn try_acquire(futex: &AtomicU32) -> bool {
+    futex
+        .compare_exchange(
+            State::Unlocked.into(),
+            State::Locked.into(),
+            Ordering::Acquire,
+            Ordering::Relaxed,
+        )
+        .is_ok()
 }
 
-/// Return `false` if it fails to lock in a nonblocking setting.
+/// `sleep` sleeps on `futex` while it is [`State::Contended`], given the word's value before it was set so and the time left until the deadline of `blocking`; its error aborts the locking.
 ///
-/// # Panic
+/// If `holder` is given, yield once to whoever it names before going to sleep.
 ///
-/// If `futex` is not in any of the [`State`].
-pub fn lock(futex: &AtomicU32, waiters: Option<&AtomicUsize>, blocking: LockBlocking) -> bool {
-    const RETRIES: usize = 128;
-    loop {
-        // Assert `futex` is in valid state
-        let _ = locked(futex);
-
-        for _ in 0..RETRIES {
-            if futex
-                .compare_exchange(
-                    State::Unlocked.into(),
-                    State::Locked.into(),
-                    Ordering::Acquire,
-                    Ordering::Relaxed,
-                )
-                .is_ok()
-            {
-                return true;
+/// If `spin` is given, it sets how long to spin first and learns whether that paid off; otherwise spin a fixed number of times.
+#[cold]
+#[inline(never)]
+fn lock_contended<'a, E>(
+    futex: &AtomicU32,
+    register: impl FnOnce() -> WaiterGuard<'a>,
+    holder: Option<&AtomicU32>,
+    spin: Option<&AdaptiveSpin>,
+    blocking: LockBlocking,
+    mut sleep: impl FnMut(u32, Option<Duration>) -> Result<(), E>,
+) -> Result<LockResult, E> {
+    const RETRIES: u32 = 128;
+    debug_assert_valid_state(futex);
+
+    let budget = spin.map_or(RETRIES, AdaptiveSpin::budget);
+    for _ in 0..budget {
+        if try_acquire(futex) {
+            if let Some(spin) = spin {
+                spin.record(budget, true);
             }
-            std::hint::spin_loop();
+            return Ok(LockResult::Acquired);
         }
-        match blocking {
-            LockBlocking::Blocking => {
-                if let Some(waiters) = waiters {
-                    waiters.fetch_add(1, Ordering::Relaxed);
-                }
-                if let Err(e) = resumed_futex_wait(FutexWaitContext {
-                    word: futex,
-                    expected: State::Locked.into(),
-                    timeout: None,
-                }) {
-                    if !matches!(e.kind(), std::io::ErrorKind::WouldBlock) {
-                        panic!("{e}");
-                    }
-                }
-                if let Some(waiters) = waiters {
-                    waiters.fetch_sub(1, Ordering::Relaxed);
-                }
-            }
-            LockBlocking::Nonblocking => {
-                return false;
+        std::hint::spin_loop();
+    }
+    if let Some(spin) = spin {
+        spin.record(budget, false);
+    }
+    let deadline = match blocking {
+        LockBlocking::Blocking => None,
+        LockBlocking::Nonblocking => return Ok(LockResult::WouldBlock),
+        // Too far to represent is as good as no deadline
+        LockBlocking::For(timeout) => Instant::now().checked_add(timeout),
+        LockBlocking::Until(deadline) => Some(deadline),
+    };
+    if let Some(holder) = holder {
+        // The holder could be descheduled on an oversubscribed machine; hand over the rest of this timeslice before paying for a sleep
+        if holder.load(Ordering::Relaxed) != 0 {
+            std::thread::yield_now();
+            if try_acquire(futex) {
+                return Ok(LockResult::Acquired);
             }
         }
     }
+
+    let _waiter = register();
+    // Announce a potential sleeper before sleeping.
+    // Acquiring the lock this way leaves it contended, which at worst costs the next unlock a needless wake.
+    loop {
+        let prev = futex.swap(State::Contended.into(), Ordering::Acquire);
+        if State::Unlocked as u32 == prev {
+            return Ok(LockResult::Acquired);
+        }
+        let timeout = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    // The word stays contended, which at worst costs the holder's unlock a needless wake
+                    return Ok(LockResult::TimedOut);
+                }
+                Some(remaining)
+            }
+            None => None,
+        };
+        // The word stays contended on an abort, which is still correct for the other sleepers
+        sleep(prev, timeout)?;
+    }
 }
 #[derive(Debug, Clone, Copy)]
 pub enum LockBlocking {
     Blocking,
     Nonblocking,
+    /// Block for at most the duration, counted from after spinning.
+    For(Duration),
+    Until(Instant),
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockResult {
+    Acquired,
+    /// Not acquired with [`LockBlocking::Nonblocking`]
+    WouldBlock,
+    /// Not acquired before the deadline of [`LockBlocking::For`] or [`LockBlocking::Until`]
+    TimedOut,
+}
+impl LockResult {
+    pub fn is_acquired(self) -> bool {
+        matches!(self, Self::Acquired)
+    }
 }
 
+/// Wake a waiter only if the lock has been contended, so an uncontended unlock is a single atomic swap with no syscall, with or without `waiters`.
+///
 /// # Panic
 ///
-/// If `futex` is not in any of the [`State`].
-pub fn unlock(futex: &AtomicU32, waiters: Option<&AtomicUsize>) {
-    if !locked(futex) {
+/// Learn the policy from [`crate::violation`]:
+///
+/// - If `futex` is not locked.
+/// - If `futex` is not in any of the [`State`] (only checked in debug builds).
+#[inline]
+pub fn unlock(futex: &AtomicU32, _waiters: Option<&AtomicUsize>) {
+    unlock_in(futex, FutexScope::Shared);
+}
+
+/// [`unlock`] waking in `scope`.
+#[inline]
+fn unlock_in(futex: &AtomicU32, scope: FutexScope) {
+    debug_assert_valid_state(futex);
+    if futex.load(Ordering::Relaxed) == u32::from(State::Unlocked) {
+        violation!(DoubleUnlock, u32::from(State::Unlocked));
+    }
+    let prev = futex.swap(State::Unlocked.into(), Ordering::Release);
+    if prev != u32::from(State::Contended) {
         return;
     }
-    futex.store(State::Unlocked.into(), Ordering::Release);
-    if let Some(waiters) = waiters {
-        if 0 == waiters.load(Ordering::Relaxed) {
-            return;
-        }
+    futex_wake_from(Primitive::Mutex, futex, WakeWaiters::ONE, scope).unwrap();
+}
+
+/// Like [`lock`], but return [`ViolationKind::UnknownState`](crate::violation::ViolationKind::UnknownState) instead of blocking on a word that is not in any of the [`State`].
+///
+/// Meant for words in shared memory that another process could have corrupted.
+/// A corrupt value found while announcing contention has already been overwritten with [`State::Contended`] by the time it is returned.
+pub fn lock_checked(
+    futex: &AtomicU32,
+    waiters: Option<&AtomicUsize>,
+    blocking: LockBlocking,
+) -> Result<LockResult, ProtocolViolation> {
+    State::from_word(futex.load(Ordering::Relaxed))?;
+    if try_acquire(futex) {
+        return Ok(LockResult::Acquired);
+    }
+    let register = || WaiterGuard::new(waiters, Ordering::Relaxed);
+    lock_contended(futex, register, None, None, blocking, |prev, timeout| {
+        State::from_word(prev).map_err(ProtocolViolation::from)?;
+        sleep_contended(futex, timeout, FutexScope::Shared);
+        Ok(())
+    })
+}
+
+/// Like [`unlock`], but return [`ViolationKind::UnknownState`](crate::violation::ViolationKind::UnknownState) instead of panicking on a word that is not in any of the [`State`].
+///
+/// A corrupt value is left as is unless it shows up only after the check, in which case the word is unlocked anyway.
+///
+/// An unlocked word is reported according to [`crate::violation`].
+pub fn unlock_checked(
+    futex: &AtomicU32,
+    _waiters: Option<&AtomicUsize>,
+) -> Result<(), ProtocolViolation> {
+    let state = State::from_word(futex.load(Ordering::Relaxed))?;
+    if matches!(state, State::Unlocked) {
+        violation!(DoubleUnlock, u32::from(State::Unlocked), return);
+    }
+    let prev = State::from_word(futex.swap(State::Unlocked.into(), Ordering::Release))?;
+    if matches!(prev, State::Contended) {
+        futex_wake_from(
+            Primitive::Mutex,
+            futex,
+            WakeWaiters::ONE,
+            FutexScope::Shared,
+        )
+        .unwrap();
     }
-    futex_wake(futex, WakeWaiters::Amount(U31::new(1).unwrap())).unwrap();
+    Ok(())
 }
 
 /// # Panic
 ///
 /// If `futex` is not in any of the [`State`].
-fn locked(futex: &AtomicU32) -> bool {
-    let s: State = futex
-        .load(Ordering::Relaxed)
-        .try_into()
-        .expect("unknown state");
-    match s {
-        State::Unlocked => false,
-        State::Locked => true,
+#[inline]
+fn debug_assert_valid_state(futex: &AtomicU32) {
+    if cfg!(debug_assertions) {
+        let word = futex.load(Ordering::Relaxed);
+        if State::from_word(word).is_err() {
+            violation!(UnknownState, word);
+        }
+    }
+}
+
+/// Lock `futex` and unlock it with the same arguments on drop.
+///
+/// Works on any futex word following [`State`], including ones in shared memory.
+pub fn raw_guard<'a>(futex: &'a AtomicU32, waiters: Option<&'a AtomicUsize>) -> RawGuard<'a> {
+    lock(futex, waiters, LockBlocking::Blocking);
+    RawGuard { futex, waiters }
+}
+
+/// Learn more from [`raw_guard`].
+pub fn try_raw_guard<'a>(
+    futex: &'a AtomicU32,
+    waiters: Option<&'a AtomicUsize>,
+) -> Option<RawGuard<'a>> {
+    if !lock(futex, waiters, LockBlocking::Nonblocking).is_acquired() {
+        return None;
     }
+    Some(RawGuard { futex, waiters })
 }
 
+/// Return [`None`] on timeout.
+///
+/// Learn more from [`raw_guard`].
+pub fn raw_guard_timeout<'a>(
+    futex: &'a AtomicU32,
+    waiters: Option<&'a AtomicUsize>,
+    timeout: Duration,
+) -> Option<RawGuard<'a>> {
+    if !lock(futex, waiters, LockBlocking::For(timeout)).is_acquired() {
+        return None;
+    }
+    Some(RawGuard { futex, waiters })
+}
+
+#[derive(Debug)]
+#[must_use = "if unused the futex word will immediately unlock"]
+pub struct RawGuard<'a> {
+    futex: &'a AtomicU32,
+    waiters: Option<&'a AtomicUsize>,
+}
+impl RawGuard<'_> {
+    /// Leave the futex word locked, e.g., for another process to unlock.
+    pub fn forget(self) {
+        std::mem::forget(self);
+    }
+}
+impl Drop for RawGuard<'_> {
+    #[inline]
+    fn drop(&mut self) {
+        unlock(self.futex, self.waiters);
+    }
+}
+
+/// # Zero initialization
+///
+/// The all-zero bit pattern is an unlocked mutex holding a zeroed `T`, made as by [`Self::new`].
+#[repr(C)]
 pub struct Mutex<T> {
-    futex: AtomicU32,
-    waiters: Option<AtomicUsize>,
+    futex: Futex,
+    waiters: WaitersCounter,
+    holder: HolderHint,
+    /// Of the contended acquisitions, unless priority-inheriting
+    spin: AdaptiveSpin,
+    /// Whether `futex` follows the priority-inheritance protocol of [`crate::pi`] instead of [`State`]
+    pi: bool,
     value: SyncUnsafeCell<T>,
 }
+/// TID of the current holder, or `0`; only a hint, never authoritative.
+///
+/// Unlike `Option<AtomicU32>`, the all-zero bit pattern is valid: no hint kept.
+#[derive(Debug)]
+#[repr(C)]
+struct HolderHint {
+    tid: AtomicU32,
+    enabled: bool,
+}
+impl HolderHint {
+    const fn new() -> Self {
+        Self {
+            tid: AtomicU32::new(0),
+            enabled: true,
+        }
+    }
+
+    const fn disabled() -> Self {
+        Self {
+            tid: AtomicU32::new(0),
+            enabled: false,
+        }
+    }
+
+    fn as_ref(&self) -> Option<&AtomicU32> {
+        self.enabled.then_some(&self.tid)
+    }
+}
+// Like `std::sync::Mutex`: the value is only ever reached by the one thread holding the lock
+unsafe impl<T: Send> Sync for Mutex<T> {}
 impl<T> Mutex<T> {
-    pub fn new(value: T) -> Self {
+    pub const fn new(value: T) -> Self {
+        Self {
+            value: SyncUnsafeCell::new(value),
+            waiters: WaitersCounter::new(),
+            holder: HolderHint::disabled(),
+            spin: AdaptiveSpin::new(),
+            pi: false,
+            futex: Futex::new(State::Unlocked as u32),
+        }
+    }
+
+    pub const fn new_slow(value: T) -> Self {
+        Self {
+            value: SyncUnsafeCell::new(value),
+            waiters: WaitersCounter::disabled(),
+            holder: HolderHint::disabled(),
+            spin: AdaptiveSpin::new(),
+            pi: false,
+            futex: Futex::new(State::Unlocked as u32),
+        }
+    }
+
+    /// Register each contended waiter with a plain store to a byte of its thread instead of an RMW on a counter shared by all waiters; [`Self::waiters`] still counts them exactly.
+    ///
+    /// Also waits and wakes with [`FutexScope::Private`], sparing the kernel the lookup of the mapping on every contended lock and unlock.
+    /// Must not be shared between processes; learn more from there.
+    pub const fn new_private(value: T) -> Self {
+        Self {
+            value: SyncUnsafeCell::new(value),
+            waiters: WaitersCounter::private(),
+            holder: HolderHint::disabled(),
+            spin: AdaptiveSpin::new(),
+            pi: false,
+            futex: Futex::new(State::Unlocked as u32),
+        }
+    }
+
+    /// A waiter that runs out of spins while the lock is held yields its timeslice once before going to sleep, in case the holder is descheduled.
+    ///
+    /// Meant for oversubscribed machines.
+    /// Every acquisition and release additionally stores the holder's TID in a side word.
+    pub const fn new_yield_to_holder(value: T) -> Self {
         Self {
             value: SyncUnsafeCell::new(value),
-            waiters: Some(AtomicUsize::new(0)),
-            futex: new_unlocked_futex(),
+            waiters: WaitersCounter::new(),
+            holder: HolderHint::new(),
+            spin: AdaptiveSpin::new(),
+            pi: false,
+            futex: Futex::new(State::Unlocked as u32),
         }
     }
 
-    pub fn new_slow(value: T) -> Self {
+    /// Lock with the kernel's priority inheritance: a thread blocked on the lock lends its scheduling priority to the holder until the unlock, so a low-priority holder cannot be starved by medium-priority work while a high-priority thread waits.
+    ///
+    /// Every contended acquisition and release is a syscall, and the lock is handed to the highest-priority waiter.
+    /// [`Self::lock_interruptible`] never returns [`Interrupted`], and [`Self::holder`] reads the holder's TID off the futex word.
+    ///
+    /// Cannot back a [`crate::composite::WaitSource`].
+    #[cfg(feature = "pi")]
+    pub const fn new_pi(value: T) -> Self {
         Self {
             value: SyncUnsafeCell::new(value),
-            waiters: None,
-            futex: new_unlocked_futex(),
+            waiters: WaitersCounter::new(),
+            holder: HolderHint::disabled(),
+            spin: AdaptiveSpin::new(),
+            pi: true,
+            futex: Futex::new(State::Unlocked as u32),
         }
     }
 
+    #[inline]
     pub fn lock(&self) -> MutexGuard<'_, T> {
-        lock(&self.futex, self.waiters.as_ref(), LockBlocking::Blocking);
-        MutexGuard { og: self }
+        if self.pi {
+            self.lock_pi(None);
+            return self.guard();
+        }
+        lock_inner(
+            &self.futex,
+            || self.waiters.register(Ordering::Relaxed),
+            self.holder.as_ref(),
+            Some(&self.spin),
+            LockBlocking::Blocking,
+            self.waiters.scope(),
+        );
+        self.guard()
+    }
+
+    /// Give up with [`Shutdown`] once `token` trips, even if the lock is free by then.
+    pub fn lock_or_shutdown(&self, token: &ShutdownToken) -> Result<MutexGuard<'_, T>, Shutdown> {
+        if token.is_shutdown() {
+            return Err(Shutdown);
+        }
+        if self.pi {
+            // `FUTEX_LOCK_PI` cannot also sleep on the token
+            while !self.lock_pi(Some(Instant::now() + PI_SHUTDOWN_SLICE)) {
+                if token.is_shutdown() {
+                    return Err(Shutdown);
+                }
+            }
+            return Ok(self.guard());
+        }
+        if !try_acquire(&self.futex) {
+            lock_contended(
+                &self.futex,
+                || self.waiters.register(Ordering::Relaxed),
+                self.holder.as_ref(),
+                Some(&self.spin),
+                LockBlocking::Blocking,
+                |_, _| {
+                    futex_wait_or_shutdown(
+                        Primitive::Mutex,
+                        &self.futex,
+                        State::Contended.into(),
+                        self.waiters.scope(),
+                        token,
+                    )
+                },
+            )?;
+        }
+        Ok(self.guard())
+    }
+
+    /// Give up with [`Interrupted`] if a signal handler runs while sleeping, instead of going back to sleep.
+    ///
+    /// The sleep can also be interrupted without any handler of the caller's running (e.g., on `SIGSTOP` followed by `SIGCONT`), so callers must be prepared to retry.
+    /// A handler installed with `SA_RESTART` may have the sleep restarted by the kernel instead.
+    pub fn lock_interruptible(&self) -> Result<MutexGuard<'_, T>, Interrupted> {
+        if self.pi {
+            return Ok(self.lock());
+        }
+        if !try_acquire(&self.futex) {
+            lock_contended(
+                &self.futex,
+                || self.waiters.register(Ordering::Relaxed),
+                self.holder.as_ref(),
+                Some(&self.spin),
+                LockBlocking::Blocking,
+                |_, _| match futex_wait(self.futex.context(
+                    State::Contended.into(),
+                    None,
+                    self.waiters.scope(),
+                )) {
+                    Ok(()) | Err(FutexError::ValueMismatch) => Ok(()),
+                    Err(FutexError::Interrupted) => Err(Interrupted),
+                    Err(e) => panic!("{e}"),
+                },
+            )?;
+        }
+        Ok(self.guard())
+    }
+
+    /// [`Self::lock`] with the signals of the calling thread blocked while it sleeps, as in [`crate::futex_wait_uninterruptible`].
+    ///
+    /// For deployments where profilers or timers signal the thread often; the uncontended path leaves the mask alone.
+    pub fn lock_uninterruptible(&self) -> MutexGuard<'_, T> {
+        if let Some(guard) = self.try_lock() {
+            return guard;
+        }
+        let _masked = crate::signal::MaskedSignals::block();
+        self.lock()
     }
 
+    #[inline]
     pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        if self.pi {
+            return crate::pi::try_lock(&self.futex, current_tid()).then(|| self.guard());
+        }
         if !lock(
             &self.futex,
             self.waiters.as_ref(),
             LockBlocking::Nonblocking,
-        ) {
+        )
+        .is_acquired()
+        {
             return None;
         };
-        Some(MutexGuard { og: self })
+        Some(self.guard())
+    }
+
+    /// Return [`None`] on timeout.
+    ///
+    /// A `timeout` too far to represent as an [`Instant`] waits without one.
+    pub fn lock_for(&self, timeout: Duration) -> Option<MutexGuard<'_, T>> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.lock_until(deadline),
+            None => Some(self.lock()),
+        }
+    }
+
+    /// [`Self::lock_until`] reporting the timeout as [`TimedOut`], for chaining on one [`crate::deadline::Deadline`].
+    pub fn lock_deadline(
+        &self,
+        deadline: impl Into<Instant>,
+    ) -> Result<MutexGuard<'_, T>, TimedOut> {
+        self.lock_until(deadline.into()).ok_or(TimedOut(()))
+    }
+
+    /// Return [`None`] on timeout.
+    pub fn lock_until(&self, deadline: Instant) -> Option<MutexGuard<'_, T>> {
+        if self.pi {
+            return self.lock_pi(Some(deadline)).then(|| self.guard());
+        }
+        if !lock_inner(
+            &self.futex,
+            || self.waiters.register(Ordering::Relaxed),
+            self.holder.as_ref(),
+            Some(&self.spin),
+            LockBlocking::Until(deadline),
+            self.waiters.scope(),
+        )
+        .is_acquired()
+        {
+            return None;
+        }
+        Some(self.guard())
+    }
+
+    /// Only a snapshot.
+    ///
+    /// Return the TID of the thread holding the lock, if the mutex is made by [`Self::new_yield_to_holder`] or `Self::new_pi` and is held.
+    pub fn holder(&self) -> Option<u32> {
+        if self.pi {
+            let holder = self.futex.load(Ordering::Relaxed) & crate::pi::TID_MASK;
+            return (holder != 0).then_some(holder);
+        }
+        let holder = self.holder.as_ref()?.load(Ordering::Relaxed);
+        (holder != 0).then_some(holder)
+    }
+
+    pub(crate) fn futex_word(&self) -> &AtomicU32 {
+        &self.futex
+    }
+
+    pub(crate) fn is_pi(&self) -> bool {
+        self.pi
+    }
+
+    pub(crate) fn scope(&self) -> FutexScope {
+        self.waiters.scope()
+    }
+
+    /// Return `false` if `deadline` passes first.
+    fn lock_pi(&self, deadline: Option<Instant>) -> bool {
+        let tid = current_tid();
+        if crate::pi::try_lock(&self.futex, tid) {
+            return true;
+        }
+        let _waiter = self.waiters.register(Ordering::Relaxed);
+        crate::pi::lock(&self.futex, tid, deadline)
+    }
+
+    /// Must be called right after locking.
+    #[inline]
+    fn guard(&self) -> MutexGuard<'_, T> {
+        if let Some(holder) = self.holder.as_ref() {
+            holder.store(current_tid(), Ordering::Relaxed);
+        }
+        MutexGuard {
+            og: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    #[inline]
+    fn release(&self) {
+        if let Some(holder) = self.holder.as_ref() {
+            holder.store(0, Ordering::Relaxed);
+        }
+        if self.pi {
+            crate::pi::unlock(&self.futex, current_tid());
+            return;
+        }
+        unlock_in(&self.futex, self.waiters.scope());
     }
 
     pub fn into_inner(self) -> T {
         self.value.into_inner()
     }
+
+    /// No locking needed, since the borrow is exclusive.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Only a snapshot; the state could have changed by the time it returns.
+    pub fn is_locked(&self) -> bool {
+        self.futex.load(Ordering::Relaxed) != u32::from(State::Unlocked)
+    }
+
+    /// Only a snapshot.
+    ///
+    /// Return [`None`] if the mutex does not count its waiters.
+    pub fn waiters(&self) -> Option<usize> {
+        self.waiters.load(Ordering::Relaxed)
+    }
+
+    /// Only a snapshot.
+    ///
+    /// Return how many times a contended acquisition currently spins before sleeping, between `0` and `256`.
+    /// The budget shrinks while spinning keeps ending in a sleep anyway, e.g., under long hold times, and grows back while it pays off.
+    pub fn spin_budget(&self) -> u32 {
+        self.spin.snapshot()
+    }
 }
 impl<T: core::fmt::Debug> core::fmt::Debug for Mutex<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Mutex")
-            .field("futex", &self.futex)
-            .field("value", &unsafe { self.value.get().as_ref() })
-            .finish()
+        let mut d = f.debug_struct("Mutex");
+        d.field("futex", &self.futex);
+        match self.try_lock() {
+            Some(guard) => d.field("value", &&*guard),
+            None => d.field("value", &format_args!("<locked>")),
+        };
+        d.finish()
+    }
+}
+
+/// A [`Mutex`] that is `!Unpin`, for holders that reach it through [`Pin`](std::pin::Pin) so that it stays put while threads sleep on its futex word.
+///
+/// Otherwise the same as the [`Mutex`] it derefs to.
+#[derive(Debug)]
+#[repr(C)]
+pub struct PinnedMutex<T> {
+    mutex: Mutex<T>,
+    _pinned: PhantomPinned,
+}
+impl<T> PinnedMutex<T> {
+    /// Learn more from [`Mutex::new`].
+    pub const fn new(value: T) -> Self {
+        Self::pin(Mutex::new(value))
+    }
+
+    /// Learn more from [`Mutex::new_private`].
+    pub const fn new_private(value: T) -> Self {
+        Self::pin(Mutex::new_private(value))
+    }
+
+    /// Learn more from [`Mutex::new_pi`].
+    #[cfg(feature = "pi")]
+    pub const fn new_pi(value: T) -> Self {
+        Self::pin(Mutex::new_pi(value))
+    }
+
+    const fn pin(mutex: Mutex<T>) -> Self {
+        Self {
+            mutex,
+            _pinned: PhantomPinned,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.mutex.into_inner()
+    }
+}
+impl<T> Deref for PinnedMutex<T> {
+    type Target = Mutex<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.mutex
+    }
+}
+
+fn current_tid() -> u32 {
+    thread_local! {
+        static TID: u32 = rustix::thread::gettid().as_raw_nonzero().get().unsigned_abs();
+    }
+    TID.with(|tid| *tid)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupted;
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "interrupted by a signal while blocking")
     }
 }
+impl std::error::Error for Interrupted {}
 
+/// Neither [`Send`] nor, unless `T` is, [`Sync`]: the holder hint and a PI unlock both name the locking thread, so the guard must be dropped on it.
+///
+/// ```compile_fail
+/// use futex::mutex::Mutex;
+///
+/// let m = Mutex::new(0);
+/// let guard = m.lock();
+/// std::thread::scope(|s| {
+///     s.spawn(move || drop(guard));
+/// });
+/// ```
 pub struct MutexGuard<'a, T> {
     og: &'a Mutex<T>,
+    _not_send: PhantomData<*const ()>,
 }
+unsafe impl<T: Sync> Sync for MutexGuard<'_, T> {}
 impl<'a, T> MutexGuard<'a, T> {
+    /// Unlock and hand back the mutex, e.g., to lock it again after sleeping on a [`crate::cond_var::CondVar`].
+    ///
+    /// # Between the unlock and the relock
+    ///
+    /// The mutex is free for any thread to lock meanwhile, so nothing can be assumed about the value until it is locked again.
+    /// The returned reference is an ordinary `&Mutex<T>`: it can be relocked from this thread or handed to another one.
+    ///
+    /// The unlock is a `Release` and every lock an `Acquire` of the same word, so the locks form a chain:
+    ///
+    /// - Whoever locks next sees every write made under the guard unlocked here.
+    /// - The relock sees every write made under any lock taken in between, by whichever thread.
+    ///
+    /// ```
+    /// use futex::mutex::Mutex;
+    ///
+    /// let m = Mutex::new(vec![1]);
+    /// let m = m.lock().unlock();
+    /// std::thread::scope(|s| {
+    ///     s.spawn(|| m.lock().push(2));
+    /// });
+    /// assert_eq!(*m.lock(), [1, 2]);
+    /// ```
+    #[inline]
     pub fn unlock(self) -> &'a Mutex<T> {
-        unlock(&self.og.futex, self.og.waiters.as_ref());
-        self.og
+        let og = self.og;
+        // Skip the drop, which would release the lock a second time, maybe from under the next holder
+        std::mem::forget(self);
+        og.release();
+        og
+    }
+
+    /// Same as [`Self::unlock`], named for handing the guard back in exchange for the mutex.
+    #[inline]
+    pub fn into_mutex(self) -> &'a Mutex<T> {
+        self.unlock()
     }
 }
 impl<T> Drop for MutexGuard<'_, T> {
+    #[inline]
     fn drop(&mut self) {
-        unlock(&self.og.futex, self.og.waiters.as_ref());
+        self.og.release();
     }
 }
 impl<T> Deref for MutexGuard<'_, T> {
     type Target = T;
 
+    #[inline]
     fn deref(&self) -> &Self::Target {
         unsafe { self.og.value.get().as_ref() }.unwrap()
     }
 }
 impl<T> DerefMut for MutexGuard<'_, T> {
+    #[inline]
     fn deref_mut(&mut self) -> &mut Self::Target {
         unsafe { self.og.value.get().as_mut() }.unwrap()
     }
 }
+impl<T: core::fmt::Debug> core::fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        core::fmt::Debug::fmt(&**self, f)
+    }
+}
+impl<T: core::fmt::Display> core::fmt::Display for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        core::fmt::Display::fmt(&**self, f)
+    }
+}
+impl<T: PartialEq> PartialEq<T> for MutexGuard<'_, T> {
+    fn eq(&self, other: &T) -> bool {
+        **self == *other
+    }
+}
+impl<T: PartialEq> PartialEq for MutexGuard<'_, T> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+impl<T: Eq> Eq for MutexGuard<'_, T> {}
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize> serde::Serialize for MutexGuard<'_, T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (**self).serialize(serializer)
+    }
+}
+/// Lock for the duration of the serialization.
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize> serde::Serialize for Mutex<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.lock().serialize(serializer)
+    }
+}
+/// Into a fresh unlocked mutex.
+#[cfg(feature = "serde")]
+impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for Mutex<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Self::new)
+    }
+}
+/// Learn more from the zero initialization of [`Mutex`].
+#[cfg(feature = "bytemuck")]
+unsafe impl<T: bytemuck::Zeroable> bytemuck::Zeroable for Mutex<T> {}
 
 #[cfg(test)]
 mod tests {
     use std::sync::{atomic::AtomicBool, Arc};
 
+    use crate::{
+        futex_enum::UnknownState,
+        mock_backend::{with_failing_waits, WAIT_SYSCALLS, WAKE_SYSCALLS},
+    };
+
     use super::*;
 
+    #[test]
+    fn test_lock_interruptible() {
+        extern "C" fn noop(_: libc::c_int) {}
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = noop as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            // No `SA_RESTART`
+            action.sa_flags = 0;
+            libc::sigemptyset(&mut action.sa_mask);
+            assert_eq!(
+                libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()),
+                0
+            );
+        }
+
+        let mutex = Arc::new(Mutex::new(()));
+        let held = mutex.lock();
+        let waiter = std::thread::spawn({
+            let mutex = mutex.clone();
+            move || mutex.lock_interruptible().map(|_| ())
+        });
+        while mutex.waiters() != Some(1) {
+            std::thread::yield_now();
+        }
+        // A signal delivered before the waiter sleeps does not interrupt it, so keep signaling
+        while !waiter.is_finished() {
+            let thread = std::os::unix::thread::JoinHandleExt::as_pthread_t(&waiter);
+            unsafe { libc::pthread_kill(thread, libc::SIGUSR1) };
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(waiter.join().unwrap(), Err(Interrupted));
+        assert!(mutex.is_locked());
+        drop(held);
+        assert!(!mutex.is_locked());
+        drop(mutex.lock_interruptible().unwrap());
+    }
+
+    #[test]
+    fn test_lock_uninterruptible() {
+        let mutex = Mutex::new(0);
+        let held = mutex.lock();
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                *mutex.lock_uninterruptible() += 1;
+                // The mask is restored
+                let mut mask = unsafe { std::mem::zeroed::<libc::sigset_t>() };
+                unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, std::ptr::null(), &mut mask) };
+                unsafe { libc::sigismember(&mask, libc::SIGPROF) }
+            });
+            while mutex.waiters() != Some(1) {
+                std::thread::yield_now();
+            }
+            drop(held);
+            assert_eq!(waiter.join().unwrap(), 0);
+        });
+        assert_eq!(*mutex.lock_uninterruptible(), 1);
+    }
+
+    #[test]
+    fn test_corrupt_word() {
+        let word = AtomicU32::new(7);
+        let corrupt = ProtocolViolation::from(UnknownState(7));
+        assert_eq!(
+            lock_checked(&word, None, LockBlocking::Blocking),
+            Err(corrupt)
+        );
+        assert_eq!(
+            lock_checked(&word, None, LockBlocking::Nonblocking),
+            Err(corrupt)
+        );
+        assert_eq!(unlock_checked(&word, None), Err(corrupt));
+        assert_eq!(word.load(Ordering::SeqCst), 7);
+
+        let word = new_unlocked_futex();
+        assert_eq!(
+            lock_checked(&word, None, LockBlocking::Blocking),
+            Ok(LockResult::Acquired)
+        );
+        assert_eq!(
+            lock_checked(&word, None, LockBlocking::Nonblocking),
+            Ok(LockResult::WouldBlock)
+        );
+        assert_eq!(
+            lock_checked(&word, None, LockBlocking::For(Duration::from_millis(10))),
+            Ok(LockResult::TimedOut)
+        );
+        assert_eq!(unlock_checked(&word, None), Ok(()));
+    }
+
+    #[test]
+    fn test_unrepresentable_timeout_waits_untimed() {
+        let m = Mutex::new(());
+        let locked = std::sync::Barrier::new(2);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let _guard = m.lock();
+                locked.wait();
+                std::thread::sleep(Duration::from_millis(20));
+            });
+            locked.wait();
+            assert!(m.lock_for(Duration::MAX).is_some());
+        });
+        let word = new_unlocked_futex();
+        lock(&word, None, LockBlocking::Blocking);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(20));
+                unlock(&word, None);
+            });
+            assert_eq!(
+                lock(&word, None, LockBlocking::For(Duration::MAX)),
+                LockResult::Acquired
+            );
+        });
+    }
+
+    #[test]
+    fn test_lock_for_shared_word() {
+        let word = new_unlocked_futex();
+        lock(&word, None, LockBlocking::Blocking);
+        let timeout = Duration::from_millis(50);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let start = Instant::now();
+                assert_eq!(
+                    lock(&word, None, LockBlocking::For(timeout)),
+                    LockResult::TimedOut
+                );
+                let elapsed = start.elapsed();
+                assert!(timeout <= elapsed && elapsed < timeout * 20);
+                assert_eq!(
+                    lock(&word, None, LockBlocking::Until(Instant::now())),
+                    LockResult::TimedOut
+                );
+            });
+        });
+        // The holder still holds the lock and can release it
+        assert_ne!(word.load(Ordering::Relaxed), u32::from(State::Unlocked));
+        unlock(&word, None);
+        assert_eq!(word.load(Ordering::Relaxed), u32::from(State::Unlocked));
+
+        let mutex = Mutex::new(());
+        let guard = mutex.lock();
+        std::thread::scope(|s| {
+            s.spawn(|| assert!(mutex.lock_for(Duration::from_millis(10)).is_none()));
+        });
+        drop(guard);
+        assert!(mutex.lock_for(Duration::from_millis(10)).is_some());
+    }
+
+    /// The unlock and relock pattern of [`crate::cond_var::CondVar::wait`], with the value only written under the lock and never atomically.
+    #[test]
+    fn test_relock_observes_writes_in_between() {
+        const ROUNDS: usize = 1000;
+        let mutex = Mutex::new((0, vec![]));
+        let turn = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            // Takes the odd turns
+            s.spawn(|| {
+                for round in 0..ROUNDS {
+                    while turn.load(Ordering::Relaxed) != 2 * round + 1 {
+                        std::thread::yield_now();
+                    }
+                    let mut guard = mutex.lock();
+                    assert_eq!(guard.0, 2 * round + 1);
+                    guard.0 += 1;
+                    guard.1.push(round);
+                    drop(guard);
+                    turn.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+            let mut guard = mutex.lock();
+            for round in 0..ROUNDS {
+                guard.0 += 1;
+                let m = match round % 2 {
+                    0 => guard.unlock(),
+                    _ => guard.into_mutex(),
+                };
+                // The turn only hands over the order; `Relaxed` carries no data
+                turn.fetch_add(1, Ordering::Relaxed);
+                while turn.load(Ordering::Relaxed) != 2 * round + 2 {
+                    std::thread::yield_now();
+                }
+                guard = m.lock();
+                assert_eq!(guard.0, 2 * round + 2);
+                assert_eq!(guard.1.len(), round + 1);
+                assert_eq!(guard.1.last(), Some(&round));
+            }
+        });
+    }
+
+    #[test]
+    fn test_unlock_releases_once() {
+        let mutex = Mutex::new(());
+        let inside = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..20_000 {
+                        let guard = mutex.lock();
+                        // A second release by the previous holder would let another thread in here
+                        assert!(!inside.swap(true, Ordering::Relaxed));
+                        for _ in 0..16 {
+                            std::hint::spin_loop();
+                        }
+                        inside.store(false, Ordering::Relaxed);
+                        guard.unlock();
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_yield_to_holder() {
+        let mutex = Mutex::new_yield_to_holder(0);
+        assert_eq!(mutex.holder(), None);
+        let guard = mutex.lock();
+        assert_eq!(mutex.holder(), Some(current_tid()));
+        let mutex = guard.unlock();
+        assert_eq!(mutex.holder(), None);
+        assert!(!mutex.is_locked());
+
+        // More threads than cores so that holders get descheduled
+        let threads = std::thread::available_parallelism().map_or(4, |n| n.get() * 2);
+        std::thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let mut guard = mutex.lock();
+                        assert_eq!(mutex.holder(), Some(current_tid()));
+                        *guard += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*mutex.lock(), threads * 1000);
+        assert_eq!(mutex.holder(), None);
+        assert_eq!(Mutex::new(()).holder(), None);
+    }
+
+    #[test]
+    fn test_unwinding_wait_deregisters() {
+        let mutex = Mutex::new(());
+        let guard = mutex.lock();
+        assert!(with_failing_waits(|| mutex.lock_for(Duration::from_secs(5))).is_err());
+        assert_eq!(mutex.waiters(), Some(0));
+        drop(guard);
+        assert!(mutex.try_lock().is_some());
+    }
+
+    #[test]
+    fn test_raw_guard() {
+        let word = new_unlocked_futex();
+        let waiters = AtomicUsize::new(0);
+
+        let early_return = |fail: bool| -> Result<(), ()> {
+            let _guard = raw_guard(&word, Some(&waiters));
+            if fail {
+                return Err(());
+            }
+            Ok(())
+        };
+        assert!(early_return(true).is_err());
+        assert!(early_return(false).is_ok());
+
+        let panicked = std::panic::catch_unwind(|| {
+            let _guard = raw_guard(&word, Some(&waiters));
+            panic!();
+        });
+        assert!(panicked.is_err());
+
+        std::thread::scope(|s| {
+            s.spawn(|| drop(raw_guard(&word, Some(&waiters))))
+                .join()
+                .unwrap();
+        });
+
+        raw_guard(&word, Some(&waiters)).forget();
+        assert!(try_raw_guard(&word, Some(&waiters)).is_none());
+        assert!(raw_guard_timeout(&word, Some(&waiters), Duration::from_millis(10)).is_none());
+        assert_eq!(waiters.load(Ordering::SeqCst), 0);
+        unlock(&word, Some(&waiters));
+        assert!(raw_guard_timeout(&word, Some(&waiters), Duration::from_millis(10)).is_some());
+    }
+
     #[test]
     fn test_unlock() {
         let word = new_unlocked_futex();
+        lock(&word, None, LockBlocking::Blocking);
         unlock(&word, None);
+        assert_eq!(word.load(Ordering::Relaxed), u32::from(State::Unlocked));
     }
 
     #[test]
@@ -228,6 +1208,47 @@ mod tests {
         waiting.join().unwrap();
     }
 
+    #[test]
+    fn test_uncontended_unlock_skips_wake() {
+        let (waits, wakes) = (WAIT_SYSCALLS.get(), WAKE_SYSCALLS.get());
+        let word = new_unlocked_futex();
+        for _ in 0..u16::MAX {
+            assert_eq!(
+                lock(&word, None, LockBlocking::Blocking),
+                LockResult::Acquired
+            );
+            // A later `unlock` only wakes from the contended state
+            assert_eq!(word.load(Ordering::Relaxed), u32::from(State::Locked));
+            unlock(&word, None);
+        }
+        assert_eq!(word.load(Ordering::Relaxed), u32::from(State::Unlocked));
+        let mutex = Mutex::new(0);
+        for _ in 0..u16::MAX {
+            *mutex.lock() += 1;
+        }
+        // Counted per thread, so tests running alongside cannot add to them
+        assert_eq!(WAIT_SYSCALLS.get(), waits);
+        assert_eq!(WAKE_SYSCALLS.get(), wakes);
+    }
+
+    #[test]
+    fn test_contended_unlock_wakes() {
+        let word = new_unlocked_futex();
+        lock(&word, None, LockBlocking::Blocking);
+        std::thread::scope(|s| {
+            let waiting = s.spawn(|| {
+                lock(&word, None, LockBlocking::Blocking);
+                unlock(&word, None);
+            });
+            while word.load(Ordering::Relaxed) != u32::from(State::Contended) {
+                std::thread::yield_now();
+            }
+            assert!(!waiting.is_finished());
+            unlock(&word, None);
+        });
+        assert_eq!(word.load(Ordering::Relaxed), u32::from(State::Unlocked));
+    }
+
     #[test]
     fn test_atomics_mutex() {
         let m = Mutex::new(0);
@@ -246,4 +1267,55 @@ mod tests {
             }
         })
     }
+
+    #[test]
+    fn test_spin_budget_backs_off() {
+        const ROUNDS: usize = 40;
+        let m = Mutex::new(0);
+        assert_eq!(m.spin_budget(), AdaptiveSpin::MAX_SPINS);
+        std::thread::scope(|s| {
+            for _ in 0..ROUNDS {
+                let mut guard = m.lock();
+                let waiter = s.spawn(|| *m.lock() += 1);
+                while m.waiters() != Some(1) {
+                    std::thread::yield_now();
+                }
+                // Held well past any spinning
+                std::thread::sleep(Duration::from_millis(2));
+                *guard += 1;
+                drop(guard);
+                waiter.join().unwrap();
+            }
+        });
+        assert_eq!(*m.lock(), ROUNDS * 2);
+        assert!(
+            m.spin_budget() < AdaptiveSpin::MAX_SPINS / 16,
+            "{}",
+            m.spin_budget()
+        );
+    }
+
+    #[test]
+    fn test_guard_forwarding() {
+        let mutex = Mutex::new(String::from("a"));
+        let guard = mutex.lock();
+        assert_eq!(format!("{guard}"), "a");
+        assert_eq!(format!("{guard:?}"), "\"a\"");
+        assert_eq!(guard, String::from("a"));
+        assert_ne!(guard, String::from("b"));
+        let other = Mutex::new(String::from("a"));
+        assert_eq!(guard, other.lock());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let mutex = Mutex::new(vec![1, 2, 3]);
+        assert_eq!(serde_json::to_string(&mutex.lock()).unwrap(), "[1,2,3]");
+        let json = serde_json::to_string(&mutex).unwrap();
+        assert!(!mutex.is_locked());
+        let de: Mutex<Vec<i32>> = serde_json::from_str(&json).unwrap();
+        assert!(!de.is_locked());
+        assert_eq!(de.lock(), vec![1, 2, 3]);
+    }
 }
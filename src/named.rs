@@ -0,0 +1,122 @@
+//! Process-wide primitives addressable by a string key, for components that cannot share types.
+//!
+//! Entries are created on first use and live for the process lifetime.
+
+use std::collections::BTreeMap;
+
+use crate::{mutex::Mutex, semaphore::Semaphore};
+
+static MUTEXES: Mutex<BTreeMap<String, &'static Mutex<()>>> = Mutex::new(BTreeMap::new());
+static SEMAPHORES: Mutex<BTreeMap<String, NamedSemaphore>> = Mutex::new(BTreeMap::new());
+
+struct NamedSemaphore {
+    initial_value: u32,
+    semaphore: &'static Semaphore,
+}
+
+/// Return the mutex registered under `key`, creating it on first use.
+pub fn mutex(key: &str) -> &'static Mutex<()> {
+    let mut mutexes = MUTEXES.lock();
+    if let Some(mutex) = mutexes.get(key) {
+        return mutex;
+    }
+    let mutex = Box::leak(Box::new(Mutex::new(())));
+    mutexes.insert(key.to_owned(), mutex);
+    mutex
+}
+
+/// Return the semaphore registered under `key`, creating it with `initial_value` on first use.
+///
+/// The first caller decides the initial value; later callers passing a different one get [`InitialValueMismatch`].
+pub fn semaphore(
+    key: &str,
+    initial_value: u32,
+) -> Result<&'static Semaphore, InitialValueMismatch> {
+    let mut semaphores = SEMAPHORES.lock();
+    if let Some(named) = semaphores.get(key) {
+        if named.initial_value != initial_value {
+            return Err(InitialValueMismatch {
+                registered: named.initial_value,
+            });
+        }
+        return Ok(named.semaphore);
+    }
+    let semaphore = Box::leak(Box::new(Semaphore::new(initial_value)));
+    semaphores.insert(
+        key.to_owned(),
+        NamedSemaphore {
+            initial_value,
+            semaphore,
+        },
+    );
+    Ok(semaphore)
+}
+
+/// The key was first registered with another initial value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InitialValueMismatch {
+    pub registered: u32,
+}
+impl std::fmt::Display for InitialValueMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "registered with initial value {}", self.registered)
+    }
+}
+impl std::error::Error for InitialValueMismatch {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    mod plugin_a {
+        pub fn lock() -> &'static crate::mutex::Mutex<()> {
+            super::mutex("test-cache-lock")
+        }
+    }
+    mod plugin_b {
+        pub fn lock() -> &'static crate::mutex::Mutex<()> {
+            super::mutex("test-cache-lock")
+        }
+    }
+
+    #[test]
+    fn test_same_key_same_mutex() {
+        assert!(std::ptr::eq(plugin_a::lock(), plugin_b::lock()));
+        assert!(!std::ptr::eq(plugin_a::lock(), mutex("test-other-lock")));
+
+        let in_critical = AtomicU32::new(0);
+        std::thread::scope(|s| {
+            for lock in [plugin_a::lock as fn() -> _, plugin_b::lock] {
+                for _ in 0..4 {
+                    let in_critical = &in_critical;
+                    s.spawn(move || {
+                        for _ in 0..1000 {
+                            let _guard = lock().lock();
+                            assert_eq!(in_critical.fetch_add(1, Ordering::SeqCst), 0);
+                            in_critical.fetch_sub(1, Ordering::SeqCst);
+                        }
+                    });
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_semaphore_first_caller_wins() {
+        let a = semaphore("test-db-conns", 2).unwrap();
+        let b = semaphore("test-db-conns", 2).unwrap();
+        assert!(std::ptr::eq(a, b));
+        assert_eq!(
+            semaphore("test-db-conns", 32).unwrap_err(),
+            InitialValueMismatch { registered: 2 }
+        );
+
+        a.wait();
+        b.wait();
+        assert_eq!(a.try_acquire_many(1), Err(0));
+        a.signal_many(2);
+        assert_eq!(b.available_permits(), 2);
+    }
+}
@@ -0,0 +1,176 @@
+//! Process-wide hook reporting how long primitives sleep in the kernel.
+
+use std::{
+    sync::atomic::{AtomicPtr, AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    futex_wake_in, resumed_futex_wait, FutexError, FutexErrorContext, FutexOp, FutexOpError,
+    FutexScope, FutexWaitContext, WakeWaiters,
+};
+
+type Observer = Box<dyn Fn(WaitEvent) + Send + Sync>;
+
+static OBSERVER: AtomicPtr<Observer> = AtomicPtr::new(std::ptr::null_mut());
+
+/// Call `observer` after each futex wait of the crate's primitives completes.
+///
+/// Waits that can be cut short by a [`crate::shutdown::ShutdownToken`] are not reported.
+///
+/// The observer is called on the waiting thread while it holds none of the primitive's internal locks.
+/// A replaced observer is leaked, since other threads could still be calling it.
+pub fn set_wait_observer(observer: impl Fn(WaitEvent) + Send + Sync + 'static) {
+    let observer: Observer = Box::new(observer);
+    OBSERVER.store(Box::into_raw(Box::new(observer)), Ordering::Release);
+}
+
+/// Stop reporting waits.
+///
+/// The removed observer is leaked, since other threads could still be calling it.
+pub fn clear_wait_observer() {
+    OBSERVER.store(std::ptr::null_mut(), Ordering::Release);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitEvent {
+    pub primitive: Primitive,
+    /// Time spent in the wait, including any retries on [`crate::FutexError::Interrupted`]
+    pub duration: Duration,
+    pub outcome: WaitOutcome,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Primitive {
+    Barrier,
+    CondVar,
+    Debouncer,
+    Event,
+    Lazy,
+    Mutex,
+    Once,
+    PersistentCounter,
+    PingPong,
+    RingBuffer,
+    RwLock,
+    Semaphore,
+    SharedCell,
+    SignalSafeEvent,
+    StateMachine,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitOutcome {
+    /// Returned without an error, which can still be a spurious wake-up.
+    Woken,
+    TimedOut,
+    /// The futex word's value was not `expected`, so the thread did not sleep.
+    Spurious,
+}
+
+/// [`resumed_futex_wait`] reporting to the observer if there is one.
+pub(crate) fn observed_futex_wait(
+    primitive: Primitive,
+    cx: FutexWaitContext<'_>,
+) -> Result<(), FutexOpError> {
+    crate::wake_scope::flush();
+    let observer = OBSERVER.load(Ordering::Acquire);
+    if observer.is_null() {
+        return resumed_futex_wait(cx).map_err(|e| error(FutexOp::Wait, cx.word, primitive, e));
+    }
+    // Never freed once set
+    let observer = unsafe { &*observer };
+    let start = Instant::now();
+    let res = resumed_futex_wait(cx);
+    let duration = start.elapsed();
+    let outcome = match &res {
+        Ok(()) => Some(WaitOutcome::Woken),
+        Err(FutexError::TimedOut) => Some(WaitOutcome::TimedOut),
+        Err(FutexError::ValueMismatch) => Some(WaitOutcome::Spurious),
+        Err(_) => None,
+    };
+    if let Some(outcome) = outcome {
+        observer(WaitEvent {
+            primitive,
+            duration,
+            outcome,
+        });
+    }
+    res.map_err(|e| error(FutexOp::Wait, cx.word, primitive, e))
+}
+
+/// [`crate::futex_wake`] in `scope`, attaching the context to the error.
+///
+/// Deferred inside a [`crate::wake_scope`], returning `0`.
+/// An `EFAULT` during a [`crate::teardown::begin_teardown`] is also ignored, returning `0`.
+pub(crate) fn futex_wake_from(
+    primitive: Primitive,
+    addr: &AtomicU32,
+    waiters: WakeWaiters,
+    scope: FutexScope,
+) -> Result<usize, FutexOpError> {
+    if crate::wake_scope::defer(addr, waiters, scope) {
+        return Ok(0);
+    }
+    match futex_wake_in(addr, waiters, scope) {
+        Ok(woken) => Ok(woken),
+        // The mapping holding the word is being unmapped
+        Err(e) if crate::teardown::ignore_wake_error(&e) => Ok(0),
+        Err(e) => Err(error(FutexOp::Wake, addr, primitive, e)),
+    }
+}
+
+fn error(op: FutexOp, word: &AtomicU32, primitive: Primitive, error: FutexError) -> FutexOpError {
+    FutexOpError {
+        context: FutexErrorContext {
+            op,
+            addr: word.as_ptr() as usize,
+            primitive,
+        },
+        error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{mutex::Mutex, semaphore::Semaphore};
+
+    use super::*;
+
+    #[test]
+    fn test_recording_observer() {
+        let events = Arc::new(Mutex::new(vec![]));
+        set_wait_observer({
+            let events = events.clone();
+            move |event| events.lock().push(event)
+        });
+
+        let timeout = Duration::from_millis(50);
+        let sem = Semaphore::new(0);
+        assert!(!sem.acquire_many_timeout(1, timeout));
+
+        let mutex = Mutex::new(());
+        std::thread::scope(|s| {
+            let guard = mutex.lock();
+            s.spawn(|| drop(mutex.lock()));
+            while mutex.waiters() != Some(1) {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            std::thread::sleep(timeout);
+            drop(guard);
+        });
+        clear_wait_observer();
+
+        // Other tests run concurrently, so only look for the events of this one
+        let events = events.lock();
+        assert!(events.iter().any(|e| e.primitive == Primitive::Semaphore
+            && e.outcome == WaitOutcome::TimedOut
+            && timeout <= e.duration
+            && e.duration < Duration::from_secs(5)));
+        assert!(events.iter().any(|e| e.primitive == Primitive::Mutex
+            && e.outcome == WaitOutcome::Woken
+            && e.duration < Duration::from_secs(5)));
+    }
+}
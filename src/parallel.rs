@@ -0,0 +1,185 @@
+//! Run jobs on scoped threads with at most a fixed number in flight.
+
+use std::{
+    any::Any,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+};
+
+use crate::{semaphore::Semaphore, workers};
+
+/// Run each job on its own scoped thread, admitting a new one only while fewer than `limit` are running.
+///
+/// Return the results in the order of `jobs`.
+///
+/// # Panics
+///
+/// If `limit` is zero.
+///
+/// Once a job panics, no further job is started; the jobs in flight run to completion, then the panic of the earliest of the failed jobs in input order is resumed.
+pub fn run_limited<T, F>(limit: usize, jobs: impl IntoIterator<Item = F>) -> Vec<T>
+where
+    T: Send,
+    F: FnOnce() -> T + Send,
+{
+    let mut results = vec![];
+    let mut first_panic = None;
+    for result in run(limit, jobs, true) {
+        match result {
+            Ok(value) => results.push(value),
+            Err(JobPanicked(payload)) => {
+                first_panic.get_or_insert(payload);
+            }
+        }
+    }
+    if let Some(payload) = first_panic {
+        std::panic::resume_unwind(payload);
+    }
+    results
+}
+
+/// [`run_limited`] that runs every job regardless of panics and reports each one's outcome in the order of `jobs`.
+///
+/// # Panics
+///
+/// If `limit` is zero.
+pub fn try_run_limited<T, F>(
+    limit: usize,
+    jobs: impl IntoIterator<Item = F>,
+) -> Vec<Result<T, JobPanicked>>
+where
+    T: Send,
+    F: FnOnce() -> T + Send,
+{
+    run(limit, jobs, false)
+}
+
+fn run<T, F>(
+    limit: usize,
+    jobs: impl IntoIterator<Item = F>,
+    stop_on_panic: bool,
+) -> Vec<Result<T, JobPanicked>>
+where
+    T: Send,
+    F: FnOnce() -> T + Send,
+{
+    assert!(limit != 0, "limit must be positive");
+    let admission = Semaphore::new(limit.min(u32::MAX as usize) as u32);
+    let panicked = AtomicBool::new(false);
+    thread::scope(|s| {
+        let mut handles = vec![];
+        for job in jobs {
+            let permit = admission.permit();
+            if stop_on_panic && panicked.load(Ordering::Acquire) {
+                break;
+            }
+            let panicked = &panicked;
+            handles.push(s.spawn(move || {
+                // Dropped before the permit, so that the flag is up by the time the next job is admitted
+                let _permit = permit;
+                let _flag = PanicFlag(panicked);
+                job()
+            }));
+        }
+        handles
+            .into_iter()
+            .map(|handle| handle.join().map_err(JobPanicked))
+            .collect()
+    })
+}
+
+/// Raise the flag if dropped while unwinding.
+struct PanicFlag<'a>(&'a AtomicBool);
+impl Drop for PanicFlag<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.store(true, Ordering::Release);
+        }
+    }
+}
+
+/// A job of [`try_run_limited`] panicked; carries the payload, e.g., for [`std::panic::resume_unwind`].
+#[derive(Debug)]
+pub struct JobPanicked(pub Box<dyn Any + Send>);
+impl std::fmt::Display for JobPanicked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "job panicked: {}", workers::message(&*self.0))
+    }
+}
+impl std::error::Error for JobPanicked {}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_bounded_and_ordered() {
+        let running = AtomicUsize::new(0);
+        let high_water = AtomicUsize::new(0);
+        let jobs = (0..100).map(|i| {
+            let running = &running;
+            let high_water = &high_water;
+            move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                high_water.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(1));
+                running.fetch_sub(1, Ordering::SeqCst);
+                i
+            }
+        });
+        let results = run_limited(4, jobs);
+        assert_eq!(results, (0..100).collect::<Vec<_>>());
+        assert!(high_water.load(Ordering::SeqCst) <= 4);
+    }
+
+    #[test]
+    fn test_panic_after_in_flight() {
+        let finished = AtomicUsize::new(0);
+        let panic = std::panic::catch_unwind(|| {
+            let jobs = (0..100).map(|i| {
+                let finished = &finished;
+                move || {
+                    if i == 2 {
+                        panic!("job {i}");
+                    }
+                    thread::sleep(Duration::from_millis(20));
+                    finished.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+            run_limited(4, jobs)
+        })
+        .unwrap_err();
+        assert_eq!(workers::message(&*panic), "job 2");
+        let finished = finished.load(Ordering::SeqCst);
+        // The jobs in flight when job 2 panicked, but none started after
+        assert!((3..=6).contains(&finished), "{finished}");
+    }
+
+    #[test]
+    fn test_try_partial_results() {
+        let jobs = (0..10).map(|i| {
+            move || {
+                if i % 3 == 0 {
+                    panic!("job {i}");
+                }
+                i
+            }
+        });
+        let results = try_run_limited(2, jobs);
+        assert_eq!(results.len(), 10);
+        for (i, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(value) => assert_eq!(value, i),
+                Err(e) => {
+                    assert_eq!(i % 3, 0);
+                    assert_eq!(e.to_string(), format!("job panicked: job {i}"));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,305 @@
+//! A counter in a file-backed shared mapping, surviving crashes and waited on across processes.
+//!
+//! # Futexes on file-backed mappings
+//!
+//! A futex word in a `MAP_SHARED` file mapping is keyed by the file and offset, so every process mapping the same file sleeps on the same futex, however it mapped it.
+//!
+//! If the file is truncated below the word, touching the word raises `SIGBUS` and futex calls on it fail with `EFAULT`.
+//! [`PersistentCounter`] checks the file length before each access and reports [`PersistentCounterError::Truncated`] instead, and maps an `EFAULT` from a racing truncation to the same error.
+//! A truncation landing between the check and the access can still raise `SIGBUS`; the check narrows the hazard, and only an agreement between the processes never to truncate the file closes it.
+//!
+//! # Persistence
+//!
+//! Stores reach the page cache right away, so other processes see them even if the writer crashes.
+//! They reach the disk only when the kernel writes the page back or on [`PersistentCounter::flush`].
+
+use std::{
+    fs::{File, OpenOptions},
+    path::Path,
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use rustix::mm::{MapFlags, MsyncFlags, ProtFlags};
+
+use crate::{
+    observer::{futex_wake_from, observed_futex_wait, Primitive},
+    probe::Unsupported,
+    FutexError, FutexOpError, FutexScope, FutexTimeout, FutexWaitContext, TimeoutMeasure,
+    WakeWaiters,
+};
+
+const WORD_SIZE: usize = std::mem::size_of::<AtomicU32>();
+/// How long a waiter sleeps at most before checking the file length again, since a truncation wakes no one.
+const TRUNCATION_CHECK: Duration = Duration::from_millis(100);
+
+/// A `u32` counter stored in the first four bytes of a file.
+///
+/// Saturates at [`u32::MAX`].
+#[derive(Debug)]
+pub struct PersistentCounter {
+    file: File,
+    word: NonNull<AtomicU32>,
+}
+unsafe impl Send for PersistentCounter {}
+unsafe impl Sync for PersistentCounter {}
+impl PersistentCounter {
+    /// Map the counter in the file at `path`, creating the file with a zero counter if it is missing or shorter than the counter.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, PersistentCounterError> {
+        crate::probe::require_shared()?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if file.metadata()?.len() < WORD_SIZE as u64 {
+            file.set_len(WORD_SIZE as u64)?;
+        }
+        let ptr = unsafe {
+            rustix::mm::mmap(
+                std::ptr::null_mut(),
+                WORD_SIZE,
+                ProtFlags::READ | ProtFlags::WRITE,
+                MapFlags::SHARED,
+                &file,
+                0,
+            )
+        }
+        .map_err(std::io::Error::from)?;
+        let word = NonNull::new(ptr.cast()).unwrap();
+        Ok(Self { file, word })
+    }
+
+    pub fn get(&self) -> Result<u32, PersistentCounterError> {
+        Ok(self.word()?.load(Ordering::Acquire))
+    }
+
+    /// Add `n` and wake every waiter, in any process; return the new value.
+    pub fn advance(&self, n: u32) -> Result<u32, PersistentCounterError> {
+        let word = self.word()?;
+        let prev = word
+            .fetch_update(Ordering::SeqCst, Ordering::Relaxed, |v| {
+                Some(v.saturating_add(n))
+            })
+            .unwrap();
+        // Waiters in other processes cannot be counted reliably across crashes, so always wake
+        futex_wake_from(
+            Primitive::PersistentCounter,
+            word,
+            WakeWaiters::All,
+            FutexScope::Shared,
+        )
+        .map_err(|e| self.futex_error(e))?;
+        Ok(prev.saturating_add(n))
+    }
+
+    /// Block until the counter reaches `value`; return `false` on `timeout`.
+    pub fn wait_for_at_least(
+        &self,
+        value: u32,
+        timeout: Option<Duration>,
+    ) -> Result<bool, PersistentCounterError> {
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        loop {
+            let word = self.word()?;
+            let sample = word.load(Ordering::Acquire);
+            if value <= sample {
+                return Ok(true);
+            }
+            let slice = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Ok(false);
+                    }
+                    remaining.min(TRUNCATION_CHECK)
+                }
+                None => TRUNCATION_CHECK,
+            };
+            if let Err(e) = observed_futex_wait(
+                Primitive::PersistentCounter,
+                FutexWaitContext {
+                    word,
+                    expected: sample,
+                    timeout: Some(FutexTimeout::For(slice, TimeoutMeasure::MonoTime)),
+                    scope: FutexScope::Shared,
+                },
+            ) {
+                if !matches!(e.error, FutexError::ValueMismatch | FutexError::TimedOut) {
+                    return Err(self.futex_error(e));
+                }
+            }
+        }
+    }
+
+    /// Write the counter back to the file and wait for the disk.
+    pub fn flush(&self) -> Result<(), PersistentCounterError> {
+        self.word()?;
+        unsafe { rustix::mm::msync(self.word.as_ptr().cast(), WORD_SIZE, MsyncFlags::SYNC) }
+            .map_err(std::io::Error::from)?;
+        Ok(())
+    }
+
+    /// The word, if the file still covers it.
+    fn word(&self) -> Result<&AtomicU32, PersistentCounterError> {
+        let len = self.file.metadata()?.len();
+        if len < WORD_SIZE as u64 {
+            return Err(PersistentCounterError::Truncated { len });
+        }
+        Ok(unsafe { self.word.as_ref() })
+    }
+
+    fn futex_error(&self, e: FutexOpError) -> PersistentCounterError {
+        if e.error.raw_os_error() == Some(libc::EFAULT) {
+            if let Err(truncated) = self.word() {
+                return truncated;
+            }
+        }
+        PersistentCounterError::Futex(e)
+    }
+}
+impl Drop for PersistentCounter {
+    fn drop(&mut self) {
+        let _ = unsafe { rustix::mm::munmap(self.word.as_ptr().cast(), WORD_SIZE) };
+    }
+}
+
+#[derive(Debug)]
+pub enum PersistentCounterError {
+    Io(std::io::Error),
+    /// The file no longer covers the counter; `len` is its length
+    Truncated {
+        len: u64,
+    },
+    Futex(FutexOpError),
+    /// The environment denies what the counter needs; carries what the probe found
+    Unsupported(Unsupported),
+}
+impl From<std::io::Error> for PersistentCounterError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+impl From<Unsupported> for PersistentCounterError {
+    fn from(value: Unsupported) -> Self {
+        Self::Unsupported(value)
+    }
+}
+impl std::fmt::Display for PersistentCounterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::Truncated { len } => {
+                write!(f, "file truncated to {len} bytes under the counter")
+            }
+            Self::Futex(e) => write!(f, "{e}"),
+            Self::Unsupported(e) => write!(f, "{e}"),
+        }
+    }
+}
+impl std::error::Error for PersistentCounterError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Truncated { .. } => None,
+            Self::Futex(e) => Some(e),
+            Self::Unsupported(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// Removed on drop
+    struct TempPath(PathBuf);
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("futex-{name}-{}.counter", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+    }
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_across_processes() {
+        use nix::{
+            sys::wait::{waitpid, WaitStatus},
+            unistd::{fork, ForkResult},
+        };
+
+        let path = TempPath::new("processes");
+        let counter = PersistentCounter::open(&path.0).unwrap();
+        assert_eq!(counter.get().unwrap(), 0);
+
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                // Mapped anew, sharing nothing with the parent but the file
+                let ok = std::panic::catch_unwind(|| {
+                    let counter = PersistentCounter::open(&path.0).unwrap();
+                    for i in 1..=10 {
+                        let reached =
+                            counter.wait_for_at_least(2 * i - 1, Some(Duration::from_secs(10)));
+                        assert!(reached.unwrap());
+                        counter.advance(1).unwrap();
+                    }
+                });
+                unsafe { libc::_exit(i32::from(ok.is_err())) };
+            }
+            ForkResult::Parent { child } => {
+                for i in 1..=10 {
+                    counter.advance(1).unwrap();
+                    let reached = counter.wait_for_at_least(2 * i, Some(Duration::from_secs(10)));
+                    assert!(reached.unwrap());
+                }
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+        counter.flush().unwrap();
+        drop(counter);
+
+        // Survives the mappings
+        let counter = PersistentCounter::open(&path.0).unwrap();
+        assert_eq!(counter.get().unwrap(), 20);
+        assert!(!counter
+            .wait_for_at_least(21, Some(Duration::from_millis(10)))
+            .unwrap());
+    }
+
+    #[test]
+    fn test_truncated() {
+        let path = TempPath::new("truncated");
+        let counter = PersistentCounter::open(&path.0).unwrap();
+        assert_eq!(counter.advance(3).unwrap(), 3);
+
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| counter.wait_for_at_least(4, None));
+            std::thread::sleep(Duration::from_millis(20));
+            counter.file.set_len(0).unwrap();
+            // Noticed without any wake
+            assert!(matches!(
+                waiter.join().unwrap(),
+                Err(PersistentCounterError::Truncated { len: 0 })
+            ));
+        });
+        assert!(matches!(
+            counter.advance(1),
+            Err(PersistentCounterError::Truncated { len: 0 })
+        ));
+        assert_eq!(
+            counter.get().unwrap_err().to_string(),
+            "file truncated to 0 bytes under the counter"
+        );
+    }
+}
@@ -0,0 +1,418 @@
+//! The priority-inheritance protocol of the kernel, backing [`Mutex::new_pi`](crate::mutex::Mutex::new_pi).
+//!
+//! # Word encoding
+//!
+//! Fixed by the kernel:
+//!
+//! - `0` means unlocked.
+//! - Otherwise, the low 30 bits hold the TID of the holder.
+//! - The top bit, `FUTEX_WAITERS`, is set by the kernel while some thread sleeps on the word, so unlocking has to go through `FUTEX_UNLOCK_PI`, which hands the lock to the highest-priority waiter.
+//! - The next bit, `FUTEX_OWNER_DIED`, is set by the kernel if the holder exits with the word on its robust list; the next locker takes over with the bit kept.
+//!
+//! While a thread sleeps in `FUTEX_LOCK_PI`, the kernel boosts the holder to the waiter's priority, so a low-priority holder preempted by medium-priority work cannot stall a high-priority waiter indefinitely.
+
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Instant, SystemTime},
+};
+
+/// `FUTEX_TID_MASK` from `linux/futex.h`
+pub(crate) const TID_MASK: u32 = 0x3fff_ffff;
+/// `FUTEX_OWNER_DIED` from `linux/futex.h`
+#[cfg(feature = "pi")]
+pub(crate) const OWNER_DIED: u32 = 0x4000_0000;
+
+#[inline]
+pub(crate) fn try_lock(futex: &AtomicU32, tid: u32) -> bool {
+    futex
+        .compare_exchange(0, tid, Ordering::Acquire, Ordering::Relaxed)
+        .is_ok()
+}
+
+/// Return `false` if `deadline` passes first.
+///
+/// # Panic
+///
+/// If the calling thread already holds the lock.
+pub(crate) fn lock(futex: &AtomicU32, tid: u32, deadline: Option<Instant>) -> bool {
+    lock_kernel(futex, tid, deadline).unwrap_or_else(|e| panic!("{e}"))
+}
+
+/// [`lock`], failing instead of panicking.
+///
+/// Fail with [`rustix::io::Errno::DEADLK`] if the calling thread already holds the lock.
+pub(crate) fn lock_kernel(
+    futex: &AtomicU32,
+    tid: u32,
+    deadline: Option<Instant>,
+) -> std::io::Result<bool> {
+    if try_lock(futex, tid) {
+        return Ok(true);
+    }
+    loop {
+        // The timeout of `FUTEX_LOCK_PI` is absolute on the realtime clock
+        let timeout = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Ok(false);
+                }
+                let at = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    + remaining;
+                Some(rustix::thread::Timespec {
+                    tv_sec: at.as_secs() as i64,
+                    tv_nsec: i64::from(at.subsec_nanos()),
+                })
+            }
+            None => None,
+        };
+        #[cfg(test)]
+        tests::PI_SYSCALLS.set(tests::PI_SYSCALLS.get() + 1);
+        let res = unsafe {
+            rustix::thread::futex(
+                futex.as_ptr(),
+                rustix::thread::FutexOperation::LockPi,
+                rustix::thread::FutexFlags::empty(),
+                0, // ignored
+                timeout
+                    .as_ref()
+                    .map_or(std::ptr::null(), |t| t as *const rustix::thread::Timespec),
+                std::ptr::null_mut(), // ignored
+                0,                    // ignored
+            )
+        };
+        match res {
+            Ok(_) => return Ok(true),
+            Err(rustix::io::Errno::TIMEDOUT) => return Ok(false),
+            // Retried by the kernel for handlers with `SA_RESTART`; the others land here
+            Err(rustix::io::Errno::INTR) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// [`try_lock`], falling back on `FUTEX_TRYLOCK_PI`, which also takes over from a holder that died; return `false` if the lock is held.
+///
+/// Fail with [`rustix::io::Errno::DEADLK`] if the calling thread already holds the lock.
+#[cfg(feature = "pi")]
+pub(crate) fn try_lock_kernel(futex: &AtomicU32, tid: u32) -> std::io::Result<bool> {
+    if try_lock(futex, tid) {
+        return Ok(true);
+    }
+    #[cfg(test)]
+    tests::PI_SYSCALLS.set(tests::PI_SYSCALLS.get() + 1);
+    let res = unsafe {
+        rustix::thread::futex(
+            futex.as_ptr(),
+            rustix::thread::FutexOperation::TrylockPi,
+            rustix::thread::FutexFlags::empty(),
+            0,                    // ignored
+            std::ptr::null(),     // ignored
+            std::ptr::null_mut(), // ignored
+            0,                    // ignored
+        )
+    };
+    match res {
+        Ok(_) => Ok(true),
+        Err(rustix::io::Errno::AGAIN) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// # Panic
+///
+/// If the calling thread does not hold the lock.
+#[inline]
+pub(crate) fn unlock(futex: &AtomicU32, tid: u32) {
+    if let Err(e) = unlock_kernel(futex, tid) {
+        panic!("{e}");
+    }
+}
+
+/// [`unlock`], failing instead of panicking.
+///
+/// Fail with [`rustix::io::Errno::PERM`] if the calling thread does not hold the lock.
+#[inline]
+pub(crate) fn unlock_kernel(futex: &AtomicU32, tid: u32) -> std::io::Result<()> {
+    if futex
+        .compare_exchange(tid, 0, Ordering::Release, Ordering::Relaxed)
+        .is_ok()
+    {
+        return Ok(());
+    }
+    // Waiters are queued in the kernel
+    #[cfg(test)]
+    tests::PI_SYSCALLS.set(tests::PI_SYSCALLS.get() + 1);
+    unsafe {
+        rustix::thread::futex(
+            futex.as_ptr(),
+            rustix::thread::FutexOperation::UnlockPi,
+            rustix::thread::FutexFlags::empty(),
+            0,                    // ignored
+            std::ptr::null(),     // ignored
+            std::ptr::null_mut(), // ignored
+            0,                    // ignored
+        )
+    }?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::cell::Cell;
+
+    #[cfg(feature = "pi")]
+    use super::*;
+
+    thread_local! {
+        /// `FUTEX_LOCK_PI`, `FUTEX_TRYLOCK_PI`, and `FUTEX_UNLOCK_PI` syscalls issued by this thread
+        pub(crate) static PI_SYSCALLS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// `FUTEX_WAITERS` from `linux/futex.h`
+    #[cfg(feature = "pi")]
+    const WAITERS: u32 = 0x8000_0000;
+
+    #[cfg(feature = "pi")]
+    #[test]
+    fn test_contention_goes_through_kernel() {
+        let m = crate::mutex::Mutex::new_pi(0);
+        let guard = m.lock();
+        let tid = rustix::thread::gettid()
+            .as_raw_nonzero()
+            .get()
+            .unsigned_abs();
+        assert_eq!(m.holder(), Some(tid));
+        let before = PI_SYSCALLS.get();
+        let locked_in_kernel = std::thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                let mut guard = m.lock();
+                *guard += 1;
+                // The kernel may leave the waiters bit set on handing over, making this unlock a syscall too
+                PI_SYSCALLS.get()
+            });
+            // The kernel marks the word once the waiter is queued
+            while m.futex_word().load(Ordering::Relaxed) & WAITERS == 0 {
+                std::thread::yield_now();
+            }
+            drop(guard);
+            waiter.join().unwrap()
+        });
+        assert_eq!(locked_in_kernel, 1);
+        assert_eq!(PI_SYSCALLS.get() - before, 1);
+        assert_eq!(*m.lock(), 1);
+        assert!(!m.is_locked());
+    }
+
+    #[cfg(feature = "pi")]
+    #[test]
+    fn test_trylock_takes_over_from_dead_owner() {
+        use crate::{futex_trylock_pi, OwnerDied};
+
+        /// `struct robust_list_head` from `linux/futex.h`, with its one entry
+        #[repr(C)]
+        struct RobustList {
+            next: *const RobustList,
+            futex_offset: libc::c_long,
+            list_op_pending: *const RobustList,
+            entry: Entry,
+        }
+        #[repr(C)]
+        struct Entry {
+            next: *const RobustList,
+            word: AtomicU32,
+        }
+        let tid = || {
+            rustix::thread::gettid()
+                .as_raw_nonzero()
+                .get()
+                .unsigned_abs()
+        };
+
+        // Read by the kernel as the owner exits, so it must outlive the owner
+        let list = Box::leak(Box::new(RobustList {
+            next: std::ptr::null(),
+            futex_offset: std::mem::offset_of!(Entry, word) as libc::c_long,
+            list_op_pending: std::ptr::null(),
+            entry: Entry {
+                next: std::ptr::null(),
+                word: AtomicU32::new(0),
+            },
+        }));
+        let head = list as *const RobustList;
+        list.entry.next = head;
+        let head = head as usize;
+        list.next = std::ptr::addr_of!(list.entry).cast();
+        let list = &*list;
+        let word = &list.entry.word;
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let ret = unsafe {
+                    libc::syscall(
+                        libc::SYS_set_robust_list,
+                        head,
+                        std::mem::size_of::<usize>() * 3,
+                    )
+                };
+                assert_eq!(ret, 0);
+                assert!(futex_trylock_pi(word).unwrap());
+                // Exit without unlocking
+            });
+        });
+        assert_eq!(word.load(Ordering::Relaxed), OwnerDied::FLAG);
+
+        let e = futex_trylock_pi(word).unwrap_err();
+        assert!(e.get_ref().unwrap().downcast_ref::<OwnerDied>().is_some());
+        assert_eq!(word.load(Ordering::Relaxed), OwnerDied::FLAG | tid());
+        // Repaired
+        word.store(tid(), Ordering::Relaxed);
+
+        let held = std::thread::scope(|s| s.spawn(|| futex_trylock_pi(word)).join().unwrap());
+        assert!(!held.unwrap());
+        assert_eq!(
+            futex_trylock_pi(word).unwrap_err().raw_os_error(),
+            Some(libc::EDEADLK)
+        );
+        unlock(word, tid());
+        assert_eq!(word.load(Ordering::Relaxed), 0);
+    }
+
+    #[cfg(feature = "pi")]
+    #[test]
+    fn test_requeue_pi_cond_var() {
+        use std::{sync::atomic::AtomicUsize, time::Duration};
+
+        use crate::{
+            futex_cmp_requeue_pi, futex_wait_requeue_pi, RequeueCount, RequeuePiWait, WakeWaiters,
+        };
+
+        let tid = || {
+            rustix::thread::gettid()
+                .as_raw_nonzero()
+                .get()
+                .unsigned_abs()
+        };
+        let cond = AtomicU32::new(0);
+        let pi = AtomicU32::new(0);
+        // Guarded by `pi`
+        let tickets = AtomicU32::new(0);
+        let ready = AtomicUsize::new(0);
+        let done = AtomicUsize::new(0);
+        let notify = |n: u32, requeue: RequeueCount| {
+            let tid = tid();
+            assert!(lock(&pi, tid, None));
+            tickets.fetch_add(n, Ordering::Relaxed);
+            let seq = cond.fetch_add(1, Ordering::Relaxed) + 1;
+            let one = WakeWaiters::Amount(crate::U31::new(1).unwrap());
+            let moved = futex_cmp_requeue_pi(&cond, seq, &pi, one, requeue).unwrap();
+            unlock(&pi, tid);
+            moved
+        };
+
+        std::thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    let tid = tid();
+                    assert!(lock(&pi, tid, None));
+                    ready.fetch_add(1, Ordering::SeqCst);
+                    while tickets.load(Ordering::Relaxed) == 0 {
+                        let seq = cond.load(Ordering::Relaxed);
+                        unlock(&pi, tid);
+                        match futex_wait_requeue_pi(&cond, seq, &pi, None).unwrap() {
+                            RequeuePiWait::Locked => (),
+                            RequeuePiWait::WouldBlock => assert!(lock(&pi, tid, None)),
+                            RequeuePiWait::TimedOut => unreachable!(),
+                        }
+                        // Holding the lock either way
+                        assert_eq!(pi.load(Ordering::Relaxed) & TID_MASK, tid);
+                    }
+                    tickets.fetch_sub(1, Ordering::Relaxed);
+                    done.fetch_add(1, Ordering::SeqCst);
+                    unlock(&pi, tid);
+                });
+            }
+            while ready.load(Ordering::SeqCst) < 3 {
+                std::thread::yield_now();
+            }
+            std::thread::sleep(Duration::from_millis(50));
+
+            // Signal: the lock is held, so the waiter is queued on it until the unlock
+            assert_eq!(
+                notify(1, RequeueCount::Amount(crate::U31::new(0).unwrap())),
+                1
+            );
+            std::thread::sleep(Duration::from_millis(50));
+            assert_eq!(done.load(Ordering::SeqCst), 1);
+
+            // Broadcast
+            assert_eq!(notify(2, RequeueCount::All), 2);
+        });
+        assert_eq!(done.load(Ordering::SeqCst), 3);
+        assert_eq!(pi.load(Ordering::Relaxed), 0);
+
+        let timeout = Some(Duration::from_millis(10));
+        let seq = cond.load(Ordering::Relaxed);
+        let waited = futex_wait_requeue_pi(&cond, seq, &pi, timeout).unwrap();
+        assert_eq!(waited, RequeuePiWait::TimedOut);
+        let waited = futex_wait_requeue_pi(&cond, seq + 1, &pi, timeout).unwrap();
+        assert_eq!(waited, RequeuePiWait::WouldBlock);
+    }
+
+    #[cfg(feature = "pi")]
+    #[test]
+    fn test_public_lock_unlock() {
+        use std::time::Duration;
+
+        use crate::{futex_lock_pi, futex_unlock_pi};
+
+        let word = AtomicU32::new(0);
+        // Uncontended: CAS only
+        let before = PI_SYSCALLS.get();
+        futex_lock_pi(&word, None).unwrap();
+        futex_unlock_pi(&word).unwrap();
+        assert_eq!(PI_SYSCALLS.get() - before, 0);
+
+        futex_lock_pi(&word, None).unwrap();
+        let e = futex_lock_pi(&word, None).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EDEADLK));
+        let in_kernel = std::thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                let before = PI_SYSCALLS.get();
+                futex_lock_pi(&word, None).unwrap();
+                futex_unlock_pi(&word).unwrap();
+                PI_SYSCALLS.get() - before
+            });
+            while word.load(Ordering::Relaxed) & WAITERS == 0 {
+                std::thread::yield_now();
+            }
+            let before = PI_SYSCALLS.get();
+            futex_unlock_pi(&word).unwrap();
+            assert_eq!(PI_SYSCALLS.get() - before, 1);
+            waiter.join().unwrap()
+        });
+        // Slept in the kernel, and maybe unlocked through it if the kernel left the waiters bit set
+        assert!((1..=2).contains(&in_kernel));
+        assert_eq!(word.load(Ordering::Relaxed), 0);
+
+        futex_lock_pi(&word, None).unwrap();
+        std::thread::scope(|s| {
+            let e = s
+                .spawn(|| futex_lock_pi(&word, Some(Duration::from_millis(10))))
+                .join()
+                .unwrap()
+                .unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+            let e = s
+                .spawn(|| futex_unlock_pi(&word))
+                .join()
+                .unwrap()
+                .unwrap_err();
+            assert_eq!(e.raw_os_error(), Some(libc::EPERM));
+        });
+        futex_unlock_pi(&word).unwrap();
+        assert_eq!(word.load(Ordering::Relaxed), 0);
+    }
+}
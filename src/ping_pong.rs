@@ -0,0 +1,283 @@
+//! Two parties taking turns, in one process or across two sharing memory.
+//!
+//! ```
+//! use std::thread;
+//!
+//! use futex::ping_pong::PingPong;
+//!
+//! let (a, b) = PingPong::pair();
+//! let b = thread::spawn(move || {
+//!     for _ in 0..3 {
+//!         b.wait_turn(None);
+//!         print!("pong ");
+//!         b.pass_turn();
+//!     }
+//! });
+//! for _ in 0..3 {
+//!     a.wait_turn(None);
+//!     print!("ping ");
+//!     a.pass_turn();
+//! }
+//! b.join().unwrap();
+//! ```
+
+use std::{
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    observer::{futex_wake_from, observed_futex_wait, Primitive},
+    FutexError, FutexScope, FutexTimeout, FutexWaitContext, TimeoutMeasure, WakeWaiters,
+};
+
+crate::futex_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Turn {
+        Unavailable = 0,
+        Available,
+    }
+}
+
+const A: usize = 0;
+const B: usize = 1;
+
+/// A baton passed back and forth between side A and side B, with side A holding it first.
+///
+/// # Word layout
+///
+/// One word per side, `1` while that side may take its turn and `0` otherwise.
+/// Waiting for a turn swaps the own word back to `0`; passing the turn makes the other side's word available and wakes it.
+/// A side sleeps only on a word it has seen unavailable, so a pass landing in between makes the sleep return right away instead of being lost.
+///
+/// # Shared memory
+///
+/// The layout is `#[repr(C)]` and holds no pointers, so a value written into memory shared between processes can be driven from both through [`Self::side_a`] and [`Self::side_b`].
+/// Each process must drive only one side.
+#[derive(Debug)]
+#[repr(C)]
+pub struct PingPong {
+    words: [AtomicU32; 2],
+}
+impl PingPong {
+    pub fn new() -> Self {
+        Self {
+            words: [
+                AtomicU32::new(Turn::Available.into()),
+                AtomicU32::new(Turn::Unavailable.into()),
+            ],
+        }
+    }
+
+    /// Both sides of a new baton for use in one process.
+    pub fn pair() -> (Side<Arc<Self>>, Side<Arc<Self>>) {
+        let ping_pong = Arc::new(Self::new());
+        (
+            Side {
+                ping_pong: ping_pong.clone(),
+                side: A,
+            },
+            Side { ping_pong, side: B },
+        )
+    }
+
+    pub fn side_a(&self) -> Side<&Self> {
+        Side {
+            ping_pong: self,
+            side: A,
+        }
+    }
+
+    pub fn side_b(&self) -> Side<&Self> {
+        Side {
+            ping_pong: self,
+            side: B,
+        }
+    }
+}
+impl Default for PingPong {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One side of a [`PingPong`].
+#[derive(Debug)]
+pub struct Side<P: Deref<Target = PingPong>> {
+    ping_pong: P,
+    side: usize,
+}
+impl<P: Deref<Target = PingPong>> Side<P> {
+    /// Block until the other side passes the turn, or return `false` on `timeout`.
+    ///
+    /// Side A's first call returns right away.
+    pub fn wait_turn(&self, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        let word = &self.ping_pong.words[self.side];
+        loop {
+            if word
+                .compare_exchange(
+                    Turn::Available.into(),
+                    Turn::Unavailable.into(),
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                return true;
+            }
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return false;
+                    }
+                    Some(FutexTimeout::For(remaining, TimeoutMeasure::MonoTime))
+                }
+                None => None,
+            };
+            if let Err(e) = observed_futex_wait(
+                Primitive::PingPong,
+                FutexWaitContext {
+                    word,
+                    expected: Turn::Unavailable.into(),
+                    timeout,
+                    scope: FutexScope::Shared,
+                },
+            ) {
+                if !matches!(e.error, FutexError::ValueMismatch | FutexError::TimedOut) {
+                    panic!("{e}");
+                }
+            }
+        }
+    }
+
+    /// Hand the turn to the other side.
+    ///
+    /// A no-op if the other side already holds an unclaimed turn, i.e., if this side passes without having waited for its own turn.
+    pub fn pass_turn(&self) {
+        let other = &self.ping_pong.words[1 - self.side];
+        if other
+            .compare_exchange(
+                Turn::Unavailable.into(),
+                Turn::Available.into(),
+                Ordering::Release,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            return;
+        }
+        // The other side has no waiters counter across processes, so always wake
+        futex_wake_from(
+            Primitive::PingPong,
+            other,
+            WakeWaiters::ONE,
+            FutexScope::Shared,
+        )
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_strict_alternation() {
+        const TURNS: u32 = 10_000;
+        let (a, b) = PingPong::pair();
+        let last = AtomicU32::new(u32::MAX);
+        thread::scope(|s| {
+            for (side, parity) in [(a, 0), (b, 1)] {
+                let last = &last;
+                s.spawn(move || {
+                    for i in 0..TURNS {
+                        side.wait_turn(None);
+                        let turn = 2 * i + parity;
+                        assert_eq!(last.swap(turn, Ordering::Relaxed), turn.wrapping_sub(1));
+                        side.pass_turn();
+                    }
+                });
+            }
+        });
+        assert_eq!(last.into_inner(), 2 * TURNS - 1);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let ping_pong = PingPong::new();
+        let (a, b) = (ping_pong.side_a(), ping_pong.side_b());
+        assert!(!b.wait_turn(Some(Duration::from_millis(10))));
+        assert!(a.wait_turn(Some(Duration::ZERO)));
+        assert!(!a.wait_turn(Some(Duration::from_millis(10))));
+        a.pass_turn();
+        // Passing twice does not hand out a second turn
+        a.pass_turn();
+        assert!(b.wait_turn(Some(Duration::ZERO)));
+        assert!(!b.wait_turn(Some(Duration::ZERO)));
+    }
+
+    #[test]
+    fn test_across_fork() {
+        use nix::{
+            sys::wait::{waitpid, WaitStatus},
+            unistd::{fork, ForkResult},
+        };
+        use rustix::mm::{mmap_anonymous, munmap, MapFlags, ProtFlags};
+
+        #[repr(C)]
+        struct Shared {
+            ping_pong: PingPong,
+            last: AtomicU32,
+        }
+        const TURNS: u32 = 100;
+        let size = std::mem::size_of::<Shared>();
+        let ptr = unsafe {
+            mmap_anonymous(
+                std::ptr::null_mut(),
+                size,
+                ProtFlags::READ | ProtFlags::WRITE,
+                MapFlags::SHARED,
+            )
+        }
+        .unwrap();
+        let shared = unsafe {
+            ptr.cast::<Shared>().write(Shared {
+                ping_pong: PingPong::new(),
+                last: AtomicU32::new(u32::MAX),
+            });
+            &*ptr.cast::<Shared>()
+        };
+        let take_turns = |side: Side<&PingPong>, parity: u32| {
+            for i in 0..TURNS {
+                side.wait_turn(None);
+                let turn = 2 * i + parity;
+                assert_eq!(
+                    shared.last.swap(turn, Ordering::Relaxed),
+                    turn.wrapping_sub(1)
+                );
+                side.pass_turn();
+            }
+        };
+
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let ok = std::panic::catch_unwind(|| take_turns(shared.ping_pong.side_b(), 1));
+                unsafe { libc::_exit(i32::from(ok.is_err())) };
+            }
+            ForkResult::Parent { child } => {
+                take_turns(shared.ping_pong.side_a(), 0);
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+        assert_eq!(shared.last.load(Ordering::Relaxed), 2 * TURNS - 1);
+        unsafe { munmap(ptr, size) }.unwrap();
+    }
+}
@@ -0,0 +1,207 @@
+//! Checks that the kernel lets this process use what the primitives shared between processes rely on.
+//!
+//! Container runtimes can deny syscalls through seccomp, which then fail with `EPERM` or `ENOSYS`.
+//! Without a probe, that surfaces as a panic at the first wait, deep inside a primitive; the constructors of shared primitives run [`probe`] first and fail with [`Unsupported`] instead.
+//!
+//! ```
+//! let report = futex::probe::probe();
+//! println!("{report}");
+//! if let Err(e) = report.require_shared() {
+//!     eprintln!("{e}");
+//! }
+//! ```
+
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use rustix::mm::{MapFlags, ProtFlags};
+
+use crate::{
+    futex_wait, futex_wake, FutexError, FutexScope, FutexTimeout, FutexWaitContext, TimeoutMeasure,
+    WakeWaiters,
+};
+
+thread_local! {
+    /// Per thread, so that a test can deny it to its own thread alone
+    static PROBE_WORD: AtomicU32 = const { AtomicU32::new(0) };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    Allowed,
+    /// Carries the `errno` of the refused syscall
+    Denied(i32),
+}
+impl Capability {
+    pub fn is_allowed(self) -> bool {
+        matches!(self, Self::Allowed)
+    }
+
+    fn from_result<T>(res: std::io::Result<T>) -> Self {
+        match res {
+            Ok(_) => Self::Allowed,
+            Err(e) => Self::Denied(e.raw_os_error().unwrap_or(0)),
+        }
+    }
+}
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Allowed => write!(f, "allowed"),
+            Self::Denied(errno) => {
+                write!(f, "denied: {}", std::io::Error::from_raw_os_error(*errno))
+            }
+        }
+    }
+}
+
+/// What [`probe`] found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeReport {
+    /// `FUTEX_WAIT` and `FUTEX_WAKE` without `FUTEX_PRIVATE_FLAG`, which every primitive of this crate issues
+    pub shared_futex: Capability,
+    /// Anonymous `MAP_SHARED` mappings, which back memory shared with forked children
+    pub mmap_shared: Capability,
+    /// `futex_waitv`, which [`crate::composite::composite_wait`] prefers but can do without
+    pub futex_waitv: Capability,
+}
+impl ProbeReport {
+    /// Fail unless every capability the shared primitives cannot do without is allowed.
+    pub fn require_shared(self) -> Result<Self, Unsupported> {
+        if !self.shared_futex.is_allowed() || !self.mmap_shared.is_allowed() {
+            return Err(Unsupported { report: self });
+        }
+        Ok(self)
+    }
+}
+impl std::fmt::Display for ProbeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "shared futex {}; mmap shared {}; futex_waitv {}",
+            self.shared_futex, self.mmap_shared, self.futex_waitv
+        )
+    }
+}
+
+/// The environment denies something the shared primitives need.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unsupported {
+    pub report: ProbeReport,
+}
+impl std::fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "shared primitives unsupported here, check the seccomp profile for `futex` and `mmap` ({})",
+            self.report
+        )
+    }
+}
+impl std::error::Error for Unsupported {}
+
+/// Try each capability with a syscall that has no effect.
+///
+/// Not cached, so that a changed profile is picked up; each call costs a handful of syscalls.
+pub fn probe() -> ProbeReport {
+    ProbeReport {
+        shared_futex: probe_shared_futex(),
+        mmap_shared: probe_mmap_shared(),
+        futex_waitv: probe_futex_waitv(),
+    }
+}
+
+/// [`probe`] and [`ProbeReport::require_shared`] in one.
+pub fn require_shared() -> Result<ProbeReport, Unsupported> {
+    probe().require_shared()
+}
+
+fn probe_shared_futex() -> Capability {
+    PROBE_WORD.with(|word| {
+        let waited = futex_wait(FutexWaitContext {
+            word,
+            expected: word.load(Ordering::Relaxed),
+            timeout: Some(FutexTimeout::For(Duration::ZERO, TimeoutMeasure::MonoTime)),
+            scope: FutexScope::Shared,
+        });
+        match waited {
+            Ok(())
+            | Err(FutexError::TimedOut | FutexError::ValueMismatch | FutexError::Interrupted) => {
+                Capability::from_result(
+                    futex_wake(word, WakeWaiters::All).map_err(std::io::Error::from),
+                )
+            }
+            Err(e) => Capability::from_result(Err::<(), _>(std::io::Error::from(e))),
+        }
+    })
+}
+
+fn probe_mmap_shared() -> Capability {
+    let len = std::mem::size_of::<AtomicU32>();
+    let mapped = unsafe {
+        rustix::mm::mmap_anonymous(
+            std::ptr::null_mut(),
+            len,
+            ProtFlags::READ | ProtFlags::WRITE,
+            MapFlags::SHARED,
+        )
+    };
+    Capability::from_result(
+        mapped
+            .and_then(|ptr| unsafe { rustix::mm::munmap(ptr, len) })
+            .map_err(std::io::Error::from),
+    )
+}
+
+fn probe_futex_waitv() -> Capability {
+    // No waiters is refused with `EINVAL` once the syscall itself is let through
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_futex_waitv,
+            std::ptr::null::<u8>(),
+            0 as libc::c_uint,
+            0 as libc::c_uint,
+            std::ptr::null::<libc::timespec>(),
+            libc::CLOCK_MONOTONIC,
+        )
+    };
+    let e = std::io::Error::last_os_error();
+    match (ret, e.raw_os_error()) {
+        (0.., _) | (_, Some(libc::EINVAL)) => Capability::Allowed,
+        _ => Capability::from_result(Err::<(), _>(e)),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::mock_backend::{Injection, MockBackend};
+
+    use super::*;
+
+    /// Make the next shared futex probe on this thread fail with `EPERM`.
+    ///
+    /// The returned backend must stay installed until then.
+    pub(crate) fn deny_shared_futex() -> MockBackend {
+        let mock = MockBackend::install();
+        PROBE_WORD.with(|word| mock.intercept(word));
+        mock.inject(Injection::Denied);
+        mock
+    }
+
+    #[test]
+    fn test_probe() {
+        let report = probe();
+        assert_eq!(report.shared_futex, Capability::Allowed);
+        assert_eq!(report.mmap_shared, Capability::Allowed);
+        assert_eq!(require_shared(), Ok(report));
+
+        let _mock = deny_shared_futex();
+        let e = require_shared().unwrap_err();
+        assert_eq!(e.report.shared_futex, Capability::Denied(libc::EPERM));
+        assert!(e.to_string().contains("seccomp"), "{e}");
+        // Only the next probe
+        assert!(require_shared().is_ok());
+    }
+}
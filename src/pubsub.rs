@@ -0,0 +1,249 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use sync_unsafe_cell::SyncUnsafeCell;
+
+use crate::{
+    ring_buffer::{FullPolicy, RecvError, RingBuffer, WriteError},
+    rw_lock::RawFutexRwLock,
+    shutdown::ShutdownToken,
+};
+
+/// One publisher fanning every message out to independent subscribers, each reading from a [`RingBuffer`] of its own with its own [`FullPolicy`].
+///
+/// A publish read-locks the list of subscribers and clones the message into each buffer in turn; subscribing and detaching write-lock it.
+///
+/// # Backpressure
+///
+/// A subscriber under [`FullPolicy::Block`] stalls the publish until it reads, which delays the delivery to the subscribers after it but never drops their messages.
+/// Subscribers under [`FullPolicy::Override`] and [`FullPolicy::Reject`] never stall a publish; they lose messages instead, counted in [`Subscriber::dropped`].
+pub struct Topic<T, const N: usize> {
+    lock: RawFutexRwLock,
+    /// Only reached under `lock`
+    subscribers: SyncUnsafeCell<Vec<Arc<Inbox<T, N>>>>,
+}
+// The list is only reached under `lock`, and the buffers are shared with the subscribers on other threads
+unsafe impl<T: Send, const N: usize> Sync for Topic<T, N> {}
+impl<T, const N: usize> Topic<T, N> {
+    pub fn new() -> Self {
+        Self {
+            lock: RawFutexRwLock::new(),
+            subscribers: SyncUnsafeCell::new(vec![]),
+        }
+    }
+
+    /// Receive every message published from now on until the subscriber drops.
+    ///
+    /// # Panic
+    ///
+    /// Same as [`RingBuffer::new`].
+    pub fn subscribe(&self, full_policy: FullPolicy) -> Subscriber<'_, T, N> {
+        let inbox = Arc::new(Inbox {
+            buf: RingBuffer::builder().full_policy(full_policy).build(),
+            detached: ShutdownToken::new(),
+            dropped: AtomicU64::new(0),
+        });
+        self.lock.lock_exclusive();
+        unsafe { &mut *self.subscribers.get() }.push(inbox.clone());
+        unsafe { self.lock.unlock_exclusive() };
+        Subscriber { topic: self, inbox }
+    }
+
+    /// Clone `message` into the buffer of every subscriber, blocking on each one under [`FullPolicy::Block`] until it has room or detaches.
+    pub fn publish(&self, message: T)
+    where
+        T: Clone,
+    {
+        self.lock.lock_shared();
+        let _unlock = SharedUnlock(&self.lock);
+        let subscribers = unsafe { &*self.subscribers.get() };
+        for inbox in subscribers {
+            inbox.push(message.clone());
+        }
+    }
+
+    /// Only a snapshot.
+    pub fn subscribers(&self) -> usize {
+        self.lock.lock_shared();
+        let _unlock = SharedUnlock(&self.lock);
+        unsafe { &*self.subscribers.get() }.len()
+    }
+}
+impl<T, const N: usize> core::fmt::Debug for Topic<T, N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Topic")
+            .field("subscribers", &self.subscribers())
+            .finish()
+    }
+}
+impl<T, const N: usize> Default for Topic<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Releases the shared lock of a publish even if a clone unwinds.
+struct SharedUnlock<'a>(&'a RawFutexRwLock);
+impl Drop for SharedUnlock<'_> {
+    fn drop(&mut self) {
+        unsafe { self.0.unlock_shared() };
+    }
+}
+
+#[derive(Debug)]
+struct Inbox<T, const N: usize> {
+    buf: RingBuffer<T, N>,
+    /// Tripped once the subscriber drops, releasing a publish blocked on `buf`
+    detached: ShutdownToken,
+    dropped: AtomicU64,
+}
+impl<T, const N: usize> Inbox<T, N> {
+    fn push(&self, message: T) {
+        // Only a snapshot, since the reader can make room meanwhile
+        if self.buf.full_policy() == FullPolicy::Override && self.buf.len() == N - 1 {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        match self.buf.write_or_shutdown(message, &self.detached) {
+            Ok(()) | Err(WriteError::Shutdown(_)) => (),
+            Err(WriteError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(WriteError::TimedOut(_)) => {
+                unreachable!("a write without a timeout never times out")
+            }
+        }
+    }
+}
+
+/// The reading end of a [`Topic`], from [`Topic::subscribe`].
+///
+/// Dropping it detaches it from the topic, releasing a publish blocked on its buffer.
+/// The drop then waits for the publish in progress to finish, which may wait in turn on the other subscribers under [`FullPolicy::Block`]; a thread must not drop one subscriber while it is the one meant to read another.
+#[derive(Debug)]
+pub struct Subscriber<'a, T, const N: usize> {
+    topic: &'a Topic<T, N>,
+    inbox: Arc<Inbox<T, N>>,
+}
+impl<T, const N: usize> Subscriber<'_, T, N> {
+    /// Block until a message is readable.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.inbox.buf.read()
+    }
+
+    /// Learn more from [`RingBuffer::try_read`].
+    pub fn try_recv(&self) -> Result<T, RecvError> {
+        self.inbox.buf.try_read()
+    }
+
+    /// Learn more from [`RingBuffer::read_timeout`].
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvError> {
+        self.inbox.buf.read_timeout(timeout)
+    }
+
+    pub fn full_policy(&self) -> FullPolicy {
+        self.inbox.buf.full_policy()
+    }
+
+    /// Learn more from [`RingBuffer::lag`].
+    pub fn lag(&self) -> usize {
+        self.inbox.buf.lag()
+    }
+
+    /// Learn more from [`RingBuffer::max_lag_since_reset`].
+    pub fn max_lag_since_reset(&self) -> usize {
+        self.inbox.buf.max_lag_since_reset()
+    }
+
+    /// Messages this subscriber lost to a full buffer: rejected under [`FullPolicy::Reject`], or overwritten unread under [`FullPolicy::Override`].
+    ///
+    /// Only a snapshot; an override racing a read may be counted even though the read made room in time.
+    pub fn dropped(&self) -> u64 {
+        self.inbox.dropped.load(Ordering::Relaxed)
+    }
+}
+impl<T, const N: usize> Drop for Subscriber<'_, T, N> {
+    fn drop(&mut self) {
+        // A publish blocked on the buffer holds the shared lock; release it before taking the exclusive one
+        self.inbox.detached.shutdown();
+        self.topic.lock.lock_exclusive();
+        let subscribers = unsafe { &mut *self.topic.subscribers.get() };
+        subscribers.retain(|inbox| !Arc::ptr_eq(inbox, &self.inbox));
+        unsafe { self.topic.lock.unlock_exclusive() };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_fanout_with_mixed_policies() {
+        const MESSAGES: usize = 10_000;
+        const N: usize = 16;
+        let topic = Topic::<usize, N>::new();
+        let blocking = topic.subscribe(FullPolicy::Block);
+        let overriding = topic.subscribe(FullPolicy::Override);
+        let rejecting = topic.subscribe(FullPolicy::Reject);
+        assert_eq!(topic.subscribers(), 3);
+        thread::scope(|s| {
+            let publisher = s.spawn(|| {
+                for i in 0..MESSAGES {
+                    topic.publish(i);
+                }
+            });
+            // Only the blocking subscriber reads while publishing, so it alone paces the publisher
+            for i in 0..MESSAGES {
+                assert_eq!(blocking.recv().unwrap(), i);
+            }
+            publisher.join().unwrap();
+        });
+        assert_eq!(blocking.dropped(), 0);
+
+        // The newest messages survive an override, the oldest a rejection
+        let kept = N - 1;
+        for i in MESSAGES - kept..MESSAGES {
+            assert_eq!(overriding.try_recv().unwrap(), i);
+        }
+        assert_eq!(overriding.dropped(), (MESSAGES - kept) as u64);
+        for i in 0..kept {
+            assert_eq!(rejecting.try_recv().unwrap(), i);
+        }
+        assert_eq!(rejecting.dropped(), (MESSAGES - kept) as u64);
+        assert_eq!(rejecting.max_lag_since_reset(), kept);
+        assert_eq!(rejecting.try_recv(), Err(RecvError::Empty));
+    }
+
+    #[test]
+    fn test_drop_detaches_mid_publish() {
+        let topic = Topic::<usize, 4>::new();
+        let stalled = topic.subscribe(FullPolicy::Block);
+        let reader = topic.subscribe(FullPolicy::Block);
+        thread::scope(|s| {
+            let publisher = s.spawn(|| {
+                for i in 0..100 {
+                    topic.publish(i);
+                }
+            });
+            // Fill the stalled buffer so that the publisher blocks on it
+            while stalled.lag() < 3 {
+                assert!(reader.recv().is_ok());
+            }
+            thread::sleep(Duration::from_millis(50));
+            assert!(!publisher.is_finished());
+            // Dropped elsewhere, since the drop waits for the publish that may be blocked on `reader`
+            s.spawn(move || drop(stalled));
+            for i in reader.recv().unwrap() + 1..100 {
+                assert_eq!(reader.recv().unwrap(), i);
+            }
+            publisher.join().unwrap();
+        });
+        assert_eq!(topic.subscribers(), 1);
+    }
+}
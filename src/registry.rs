@@ -0,0 +1,255 @@
+//! Opt-in registry of live primitives for dumping their states when a process wedges.
+
+use std::{
+    fmt::{self, Write},
+    io,
+};
+
+use crate::{
+    compat::{self, Recoverable},
+    cond_var, mutex, ring_buffer, semaphore,
+};
+
+static REGISTRY: mutex::Mutex<Vec<Entry>> = mutex::Mutex::new(Vec::new());
+
+/// A primitive whose state can be reported by [`dump`].
+pub trait Inspect: Sync {
+    fn kind(&self) -> &'static str;
+    fn snapshot(&self, f: &mut dyn fmt::Write) -> fmt::Result;
+}
+impl<T> Inspect for mutex::Mutex<T>
+where
+    Self: Sync,
+{
+    fn kind(&self) -> &'static str {
+        "Mutex"
+    }
+
+    fn snapshot(&self, f: &mut dyn fmt::Write) -> fmt::Result {
+        write!(f, "locked: {}", self.is_locked())?;
+        write_waiters(f, self.waiters())
+    }
+}
+impl Inspect for cond_var::CondVar {
+    fn kind(&self) -> &'static str {
+        "CondVar"
+    }
+
+    fn snapshot(&self, f: &mut dyn fmt::Write) -> fmt::Result {
+        write!(f, "-")?;
+        write_waiters(f, self.waiters())
+    }
+}
+impl Inspect for semaphore::Semaphore {
+    fn kind(&self) -> &'static str {
+        "Semaphore"
+    }
+
+    fn snapshot(&self, f: &mut dyn fmt::Write) -> fmt::Result {
+        write!(f, "permits: {}", self.available_permits())?;
+        write_waiters(f, self.waiters())
+    }
+}
+impl<T, const N: usize> Inspect for ring_buffer::RingBuffer<T, N>
+where
+    Self: Sync,
+{
+    fn kind(&self) -> &'static str {
+        "RingBuffer"
+    }
+
+    fn snapshot(&self, f: &mut dyn fmt::Write) -> fmt::Result {
+        write!(f, "len: {}/{}", self.len(), N - 1)
+    }
+}
+impl<T> Inspect for compat::Mutex<T>
+where
+    Self: Sync,
+{
+    fn kind(&self) -> &'static str {
+        "compat::Mutex"
+    }
+
+    fn snapshot(&self, f: &mut dyn fmt::Write) -> fmt::Result {
+        write!(f, "locked: {}", self.is_locked())?;
+        write_poisoned(f, self)
+    }
+}
+impl<T> Inspect for compat::RwLock<T>
+where
+    Self: Sync,
+{
+    fn kind(&self) -> &'static str {
+        "compat::RwLock"
+    }
+
+    fn snapshot(&self, f: &mut dyn fmt::Write) -> fmt::Result {
+        write!(f, "locked: {}", self.is_locked())?;
+        write_poisoned(f, self)
+    }
+}
+impl Inspect for compat::Once {
+    fn kind(&self) -> &'static str {
+        "compat::Once"
+    }
+
+    fn snapshot(&self, f: &mut dyn fmt::Write) -> fmt::Result {
+        write!(f, "completed: {}", self.is_completed())?;
+        write_poisoned(f, self)
+    }
+}
+fn write_poisoned(f: &mut dyn fmt::Write, primitive: &impl Recoverable) -> fmt::Result {
+    write!(f, ", poisoned: {}", primitive.is_poisoned())
+}
+fn write_waiters(f: &mut dyn fmt::Write, waiters: Option<usize>) -> fmt::Result {
+    match waiters {
+        Some(waiters) => write!(f, ", waiters: {waiters}"),
+        None => Ok(()),
+    }
+}
+
+struct Entry {
+    addr: usize,
+    name: Option<&'static str>,
+    /// Only dereferenced while the owning [`Registration`] is alive
+    primitive: *const (dyn Inspect + 'static),
+}
+// The pointee is `Sync`
+unsafe impl Send for Entry {}
+unsafe impl Sync for Entry {}
+
+/// Keep the primitive in the registry until dropped.
+///
+/// The borrow prevents the primitive from moving while it is registered.
+#[must_use]
+pub struct Registration<'a> {
+    addr: usize,
+    _primitive: &'a dyn Inspect,
+}
+impl Registration<'_> {
+    /// Label the entry in the dump.
+    pub fn with_name(self, name: &'static str) -> Self {
+        let mut registry = REGISTRY.lock();
+        let entry = registry.iter_mut().find(|e| e.addr == self.addr).unwrap();
+        entry.name = Some(name);
+        drop(registry);
+        self
+    }
+}
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let mut registry = REGISTRY.lock();
+        let i = registry.iter().position(|e| e.addr == self.addr).unwrap();
+        registry.swap_remove(i);
+    }
+}
+
+/// # Panic
+///
+/// If `primitive` is already registered.
+pub fn register(primitive: &dyn Inspect) -> Registration<'_> {
+    let addr = primitive as *const dyn Inspect as *const () as usize;
+    let ptr: *const (dyn Inspect + '_) = primitive;
+    // Erase the lifetime; the `Registration` deregisters the entry before the borrow ends
+    let ptr: *const (dyn Inspect + 'static) = unsafe { std::mem::transmute(ptr) };
+    let mut registry = REGISTRY.lock();
+    assert!(
+        registry.iter().all(|e| e.addr != addr),
+        "already registered"
+    );
+    registry.push(Entry {
+        addr,
+        name: None,
+        primitive: ptr,
+    });
+    drop(registry);
+    Registration {
+        addr,
+        _primitive: primitive,
+    }
+}
+
+/// Write one line per registered primitive.
+pub fn dump(w: &mut impl io::Write) -> io::Result<()> {
+    let mut out = String::new();
+    {
+        let registry = REGISTRY.lock();
+        for entry in registry.iter() {
+            let primitive = unsafe { &*entry.primitive };
+            let name = entry.name.unwrap_or("-");
+            let addr = entry.addr;
+            let kind = primitive.kind();
+            write!(out, "{kind} {name} @{addr:#x}: ").unwrap();
+            primitive.snapshot(&mut out).unwrap();
+            out.push('\n');
+        }
+    }
+    w.write_all(out.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn test_dump() {
+        let m = mutex::Mutex::new(());
+        let sem = semaphore::Semaphore::new(0);
+        let ring_buf: ring_buffer::RingBuffer<usize, 4> = ring_buffer::RingBuffer::new();
+        let _m_reg = register(&m).with_name("test-dump-lock");
+        let _sem_reg = register(&sem).with_name("test-dump-sem");
+        let _ring_buf_reg = register(&ring_buf).with_name("test-dump-ring");
+        ring_buf.write_override(1);
+        ring_buf.write_override(2);
+
+        thread::scope(|s| {
+            let guard = m.lock();
+            s.spawn(|| {
+                sem.wait();
+            });
+            while sem.waiters() != Some(1) {
+                thread::sleep(Duration::from_millis(1));
+            }
+
+            let mut out = vec![];
+            dump(&mut out).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            assert!(out.contains("Mutex test-dump-lock"));
+            assert!(out.contains("locked: true, waiters: 0"));
+            assert!(out.contains("Semaphore test-dump-sem"));
+            assert!(out.contains("permits: 0, waiters: 1"));
+            assert!(out.contains("RingBuffer test-dump-ring"));
+            assert!(out.contains("len: 2/3"));
+
+            drop(guard);
+            sem.signal();
+        });
+
+        drop(_m_reg);
+        let mut out = vec![];
+        dump(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(!out.contains("test-dump-lock"));
+    }
+
+    #[test]
+    fn test_dump_poisoned() {
+        let m = compat::Mutex::new(());
+        let _m_reg = register(&m).with_name("test-dump-poisoned");
+        let _ = thread::scope(|s| {
+            s.spawn(|| {
+                let _guard = m.lock();
+                panic!();
+            })
+            .join()
+        });
+
+        let mut out = vec![];
+        dump(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("compat::Mutex test-dump-poisoned"));
+        assert!(out.contains("locked: false, poisoned: true"));
+    }
+}
@@ -0,0 +1,54 @@
+//! The process-local side table behind the [relocation](crate::WaitersCounter#relocation) check of the waiters counters.
+//!
+//! Keyed by the address of a counter, it holds how many threads of this process registered with the counter there.
+//! A counter holding more registrations than the table has seen at its current address was moved while they waited.
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+/// Registrations of this process per address of a counter
+static REGISTERED: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+/// Record one registration at `address`, before the counter itself counts it.
+///
+/// # Panic
+///
+/// If the counter already holds registrations made at another address; `registered` reads them.
+pub(crate) fn enter(address: usize, registered: impl FnOnce() -> usize) {
+    let mut table = REGISTERED.lock().unwrap_or_else(|e| e.into_inner());
+    let seen = table.entry(address).or_insert(0);
+    let moved = registered().saturating_sub(*seen);
+    *seen += 1;
+    drop(table);
+    assert_unmoved(address, moved);
+}
+
+/// Forget one registration at `address`, after the counter itself dropped it.
+pub(crate) fn leave(address: usize) {
+    let mut table = REGISTERED.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(seen) = table.get_mut(&address) else {
+        return;
+    };
+    *seen -= 1;
+    if *seen == 0 {
+        table.remove(&address);
+    }
+}
+
+/// # Panic
+///
+/// If the counter at `address` holds registrations made at another address; `registered` reads them.
+pub(crate) fn check(address: usize, registered: impl FnOnce() -> usize) {
+    let table = REGISTERED.lock().unwrap_or_else(|e| e.into_inner());
+    let seen = table.get(&address).copied().unwrap_or(0);
+    // Read under the lock: a registration the counter already counts entered the table before, and one it no longer counts may not have left yet
+    let moved = registered().saturating_sub(seen);
+    drop(table);
+    assert_unmoved(address, moved);
+}
+
+fn assert_unmoved(address: usize, moved: usize) {
+    debug_assert!(
+        moved == 0,
+        "primitive moved to {address:#x} while {moved} threads wait on it at its old address"
+    );
+}
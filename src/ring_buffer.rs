@@ -1,15 +1,35 @@
 use std::{
+    marker::PhantomData,
     mem::MaybeUninit,
     ops::{Deref, DerefMut},
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
 };
 
-use crate::{cond_var, mutex};
+pub use crate::slot::CellValue;
+use crate::{
+    idle::{IdleStrategy, RetryBudget},
+    observer::{futex_wake_from, observed_futex_wait, Primitive},
+    shared_cell::{SharedCell, SharedCellError, SharedSafe},
+    shutdown::{futex_wait_or_shutdown, Shutdown, ShutdownToken},
+    slot::SlotCell,
+    FutexError, FutexTimeout, FutexWaitContext, TimeoutMeasure, WaitersCounter, WakeWaiters,
+};
 
 /// Multiple writers; single reader.
+///
+/// # Re-entrancy
+///
+/// The callbacks of [`Self::peek`], [`Self::read_if`] and [`Self::read_with`], and the drop of an element evicted by a write, run with a cell locked.
+/// A call into the same buffer from them would block on that cell for good, so it panics instead in debug builds.
 #[derive(Debug)]
 pub struct RingBuffer<T, const N: usize> {
-    buf: [Cell<T>; N],
+    buf: [SlotCell<T>; N],
+    header: Header,
+}
+/// Every field of a [`RingBuffer`] but the cells, so that [`RingBuffer::init`] writes them all at once.
+#[derive(Debug)]
+struct Header {
     /// Points to the next cell to read.
     /// The pointed cell is unavailable if `write_ptr` is also pointing to the same one.
     ///
@@ -23,6 +43,45 @@ pub struct RingBuffer<T, const N: usize> {
     ///
     /// Never proactively share cells with `read_ptr`.
     write_ptr: AtomicUsize,
+    /// Highest [`RingBuffer::lag`] seen by a write since the last [`RingBuffer::reset_lag_stats`]
+    max_lag: AtomicUsize,
+    /// Set by [`RingBuffer::close`]; the reader only checks it while the buffer is empty
+    closed: AtomicBool,
+    full_policy: FullPolicy,
+    /// Whether writes maintain `max_lag`
+    stats: bool,
+    /// Of the blocking reads without a timeout
+    idle: Option<IdleStrategy>,
+    /// Bumped once `read_ptr` moves under [`FullPolicy::Block`]; the futex word blocked writers sleep on
+    reads: AtomicU32,
+    blocked_writers: WaitersCounter,
+    /// Write credits left; the futex word writers out of credits sleep on
+    credits: AtomicU32,
+    credit_waiters: WaitersCounter,
+    /// Credits the reader grants at once, or `0` without [`RingBufferBuilder::with_credits`]
+    credit_batch: usize,
+    /// Elements consumed since the last automatic grant; only touched by the reader
+    consumed: AtomicUsize,
+}
+impl Header {
+    /// The state of [`RingBuffer::new`].
+    fn new() -> Self {
+        Self {
+            read_ptr: AtomicUsize::new(0),
+            write_ptr: AtomicUsize::new(0),
+            max_lag: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            full_policy: FullPolicy::Override,
+            stats: true,
+            idle: None,
+            reads: AtomicU32::new(0),
+            blocked_writers: WaitersCounter::new(),
+            credits: AtomicU32::new(0),
+            credit_waiters: WaitersCounter::new(),
+            credit_batch: 0,
+            consumed: AtomicUsize::new(0),
+        }
+    }
 }
 impl<T, const N: usize> RingBuffer<T, N> {
     /// # Panic
@@ -30,20 +89,95 @@ impl<T, const N: usize> RingBuffer<T, N> {
     /// - If `N` does not reach `3`.
     /// - If `N` reaches [`usize::MAX`].
     pub fn new() -> Self {
+        Self::with_cells(SlotCell::new)
+    }
+
+    /// Start from the options of [`Self::new`].
+    pub fn builder() -> RingBufferBuilder<T, N> {
+        RingBufferBuilder {
+            full_policy: FullPolicy::Override,
+            stats: true,
+            idle: None,
+            private: false,
+            credit_batch: None,
+            #[cfg(feature = "pi")]
+            pi: false,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Keep every cell and the blocked writers in [`FutexScope::Private`](crate::FutexScope::Private), sparing the kernel the lookup of the mapping on every wait and wake.
+    ///
+    /// Must not be shared between processes; learn more from [`FutexScope::Private`](crate::FutexScope::Private).
+    ///
+    /// # Panic
+    ///
+    /// Same as [`Self::new`].
+    pub fn new_private() -> Self {
+        let mut ring_buf = Self::with_cells(SlotCell::new_private);
+        ring_buf.header.blocked_writers = WaitersCounter::private();
+        ring_buf.header.credit_waiters = WaitersCounter::private();
+        ring_buf
+    }
+
+    /// Guard each cell with a priority-inheriting mutex, so that a high-priority reader blocked on a cell lends its priority to the preempted low-priority writer filling it.
+    ///
+    /// Behaves the same as [`Self::new`] otherwise; learn more from [`crate::mutex::Mutex::new_pi`].
+    ///
+    /// # Panic
+    ///
+    /// Same as [`Self::new`].
+    #[cfg(feature = "pi")]
+    pub fn new_pi() -> Self {
+        Self::with_cells(SlotCell::new_pi)
+    }
+
+    /// Build on the heap without ever holding the buffer on the stack, which [`Self::new`] does for a moment; fail if the allocator does.
+    ///
+    /// For buffers too large for the stack of the constructing thread, e.g., `RingBuffer<[u8; 4096], 1024>` on a thread with the default 2 MiB stack.
+    ///
+    /// # Panic
+    ///
+    /// Same as [`Self::new`].
+    pub fn try_new_boxed() -> Result<Box<Self>, AllocError> {
+        // Before allocating, so that a panic leaks nothing
+        Self::assert_capacity();
+        let layout = std::alloc::Layout::new::<Self>();
+        let ptr = unsafe { std::alloc::alloc(layout) }.cast::<Self>();
+        if ptr.is_null() {
+            return Err(AllocError);
+        }
+        unsafe {
+            Self::init(ptr, SlotCell::new);
+            Ok(Box::from_raw(ptr))
+        }
+    }
+
+    fn with_cells(new_cell: fn() -> SlotCell<T>) -> Self {
+        Self::assert_capacity();
+        let mut this = MaybeUninit::<Self>::uninit();
+        unsafe {
+            Self::init(this.as_mut_ptr(), new_cell);
+            this.assume_init()
+        }
+    }
+
+    /// Write the cells in place one at a time, and then the header.
+    ///
+    /// # Safety
+    ///
+    /// `this` must be valid for writes and aligned.
+    unsafe fn init(this: *mut Self, new_cell: fn() -> SlotCell<T>) {
+        let buf = std::ptr::addr_of_mut!((*this).buf).cast::<SlotCell<T>>();
+        for i in 0..N {
+            buf.add(i).write(new_cell());
+        }
+        std::ptr::addr_of_mut!((*this).header).write(Header::new());
+    }
+
+    fn assert_capacity() {
         assert!(3 <= N);
         assert!(N != usize::MAX);
-        let buf = {
-            let mut buf: [MaybeUninit<Cell<T>>; N] = unsafe { MaybeUninit::uninit().assume_init() };
-            for cell in buf.iter_mut() {
-                *cell = MaybeUninit::new(Cell::new());
-            }
-            unsafe { std::mem::transmute_copy::<_, [Cell<T>; N]>(&buf) }
-        };
-        Self {
-            buf,
-            read_ptr: AtomicUsize::new(0),
-            write_ptr: AtomicUsize::new(0),
-        }
     }
 
     fn positive_distance(&self, src: usize, dst: usize) -> usize {
@@ -55,18 +189,105 @@ impl<T, const N: usize> RingBuffer<T, N> {
         new_dst - new_src
     }
 
+    /// Write past a full buffer by dropping the oldest element, whatever the [`FullPolicy`].
+    ///
+    /// Takes no write credit.
     pub fn write_override(&self, new: T) {
+        let _ = self.write_inner(new, FullPolicy::Override, None, None);
+    }
+
+    /// Write as the [`FullPolicy`] of the buffer says; return the value back if it is rejected.
+    pub fn write(&self, new: T) -> Result<(), T> {
+        self.write_inner(new, self.header.full_policy, None, None)
+            .map_err(WriteError::into_inner)
+    }
+
+    /// Like [`Self::write`], but a write blocked under [`FullPolicy::Block`] gives up with [`WriteError::TimedOut`] after `timeout`.
+    ///
+    /// Learn more from [`Self::write_or_shutdown`] about giving up.
+    pub fn write_timeout(&self, new: T, timeout: Duration) -> Result<(), WriteError<T>> {
+        self.write_inner(
+            new,
+            self.header.full_policy,
+            Instant::now().checked_add(timeout),
+            None,
+        )
+    }
+
+    /// Like [`Self::write`], but a write blocked under [`FullPolicy::Block`] gives up with [`WriteError::Shutdown`] once `token` trips.
+    ///
+    /// # Cancellation
+    ///
+    /// A write takes no cell while it waits for room.
+    /// It claims the cell at `write_ptr` only once there is room, and fills it right after while keeping the cell locked.
+    /// A write that gives up has therefore claimed nothing, so it costs the buffer no capacity.
+    /// The order of the elements written is the order of their claims.
+    /// The other blocked writers need no wake handed over, since a read wakes them all.
+    pub fn write_or_shutdown(&self, new: T, token: &ShutdownToken) -> Result<(), WriteError<T>> {
+        if token.is_shutdown() {
+            return Err(WriteError::Shutdown(new));
+        }
+        self.write_inner(new, self.header.full_policy, None, Some(token))
+    }
+
+    pub fn full_policy(&self) -> FullPolicy {
+        self.header.full_policy
+    }
+
+    /// Only a write under [`FullPolicy::Block`] observes `deadline` and `token`.
+    fn write_inner(
+        &self,
+        new: T,
+        full_policy: FullPolicy,
+        deadline: Option<Instant>,
+        token: Option<&ShutdownToken>,
+    ) -> Result<(), WriteError<T>> {
+        self.check_reentry();
+        let credited = self.header.credit_batch != 0 && full_policy != FullPolicy::Override;
+        if credited {
+            if let Err(stop) = self.take_credit(full_policy, deadline, token) {
+                return Err(stop.with(new));
+            }
+        }
+        let res = self.claim_and_fill(new, full_policy, deadline, token);
+        if credited && res.is_err() {
+            // Nothing was written, so the credit goes to another writer
+            self.grant(1);
+        }
+        res
+    }
+
+    fn claim_and_fill(
+        &self,
+        new: T,
+        full_policy: FullPolicy,
+        deadline: Option<Instant>,
+        token: Option<&ShutdownToken>,
+    ) -> Result<(), WriteError<T>> {
+        let mut budget = RetryBudget::new();
         let mut new = Some(new);
         while new.is_some() {
             // Override
             let write_ptr = loop {
-                let write_ptr = self.write_ptr.load(Ordering::SeqCst);
-                let read_ptr = self.read_ptr.load(Ordering::SeqCst);
+                let write_ptr = self.header.write_ptr.load(Ordering::SeqCst);
+                let read_ptr = self.header.read_ptr.load(Ordering::SeqCst);
 
                 if self.positive_distance(write_ptr, read_ptr) == 1 {
+                    match full_policy {
+                        FullPolicy::Override => (),
+                        FullPolicy::Block => {
+                            // Nothing is claimed yet, so giving up here leaves the buffer as it was
+                            if let Err(stop) = self.wait_for_read(read_ptr, deadline, token) {
+                                return Err(stop.with(new.take().unwrap()));
+                            }
+                            continue;
+                        }
+                        FullPolicy::Reject => return Err(WriteError::Full(new.take().unwrap())),
+                    }
                     let cell = &self.buf[read_ptr];
                     let mut m = cell.write();
                     if self
+                        .header
                         .read_ptr
                         .compare_exchange(
                             read_ptr,
@@ -77,9 +298,15 @@ impl<T, const N: usize> RingBuffer<T, N> {
                         .is_err()
                     {
                         // In case
+                        budget.retry();
                         continue;
                     }
+                    self.notify_read();
+                    // `read_ptr` is advanced before marking, so an interruption in between leaves a stale value that the reader has already moved past
+                    let _held = self.hold_cell();
+                    // Runs the drop of the evicted element
                     *m.locked().deref_mut() = CellValue::Cancelled;
+                    // Dropping `m` wakes any reader parked on this cell so it moves on to the new `read_ptr`
                 }
                 break write_ptr;
             };
@@ -87,6 +314,7 @@ impl<T, const N: usize> RingBuffer<T, N> {
             let cell = &self.buf[write_ptr];
             let mut m = cell.write();
             if self
+                .header
                 .write_ptr
                 .compare_exchange(
                     write_ptr,
@@ -96,37 +324,469 @@ impl<T, const N: usize> RingBuffer<T, N> {
                 )
                 .is_err()
             {
+                budget.retry();
                 continue;
             }
             **m.locked() = CellValue::Some(new.take().unwrap());
+            if !self.header.stats {
+                continue;
+            }
+            let read_ptr = self.header.read_ptr.load(Ordering::Relaxed);
+            let lag = self.positive_distance(read_ptr, (write_ptr + 1) % self.buf.len());
+            // At most one RMW, and only while the lag is climbing
+            if self.header.max_lag.load(Ordering::Relaxed) < lag {
+                self.header.max_lag.fetch_max(lag, Ordering::Relaxed);
+            }
         }
+        Ok(())
     }
 
-    pub fn read(&self) -> T {
+    /// Take one write credit, sleeping under [`FullPolicy::Block`] until the reader grants some.
+    ///
+    /// Fail with [`WriteError::Full`] under [`FullPolicy::Reject`] if none is left, or once `deadline` passes or `token` trips.
+    fn take_credit(
+        &self,
+        full_policy: FullPolicy,
+        deadline: Option<Instant>,
+        token: Option<&ShutdownToken>,
+    ) -> Result<(), WriteError<()>> {
         loop {
-            let read_ptr = self.read_ptr.load(Ordering::SeqCst);
-            let cell = &self.buf[read_ptr];
-            let m = cell.read(|| read_ptr == self.read_ptr.load(Ordering::SeqCst));
-            let Some(mut m) = m else {
+            let credits = self.header.credits.load(Ordering::SeqCst);
+            if credits != 0 {
+                if self
+                    .header
+                    .credits
+                    .compare_exchange_weak(credits, credits - 1, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+                {
+                    return Ok(());
+                }
                 continue;
+            }
+            if full_policy == FullPolicy::Reject {
+                return Err(WriteError::Full(()));
+            }
+            let _waiter = self.header.credit_waiters.register_shared(Ordering::SeqCst);
+            self.sleep_on(&self.header.credits, 0, deadline, token)?;
+        }
+    }
+
+    /// Hand `n` more write credits to the writers, waking up to `n` of those out of credits in a single syscall.
+    ///
+    /// The reader grants the batch of [`RingBufferBuilder::with_credits`] on its own once it has consumed as many elements; granting more lets writers run into a full buffer, where the [`FullPolicy`] applies again.
+    ///
+    /// # Panic
+    ///
+    /// If the buffer was not built [with credits](RingBufferBuilder::with_credits).
+    pub fn grant(&self, n: usize) {
+        assert!(self.header.credit_batch != 0);
+        if n == 0 {
+            return;
+        }
+        let n = u32::try_from(n).unwrap_or(u32::MAX);
+        let _ = self
+            .header
+            .credits
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |credits| {
+                Some(credits.saturating_add(n))
+            });
+        if self
+            .header
+            .credit_waiters
+            .as_ref()
+            .is_some_and(|n| 0 < n.load(Ordering::SeqCst))
+        {
+            futex_wake_from(
+                Primitive::RingBuffer,
+                &self.header.credits,
+                WakeWaiters::at_most(n as usize),
+                self.header.blocked_writers.scope(),
+            )
+            .unwrap();
+        }
+    }
+
+    /// Write credits left, or [`None`] without [`RingBufferBuilder::with_credits`].
+    ///
+    /// Only a snapshot.
+    pub fn credits(&self) -> Option<usize> {
+        if self.header.credit_batch == 0 {
+            return None;
+        }
+        Some(self.header.credits.load(Ordering::SeqCst) as usize)
+    }
+
+    /// Sleep until `read_ptr` may have moved away from `read_ptr`.
+    ///
+    /// Fail only once `deadline` passes or `token` trips.
+    fn wait_for_read(
+        &self,
+        read_ptr: usize,
+        deadline: Option<Instant>,
+        token: Option<&ShutdownToken>,
+    ) -> Result<(), WriteError<()>> {
+        let _waiter = self
+            .header
+            .blocked_writers
+            .register_shared(Ordering::SeqCst);
+        let reads = self.header.reads.load(Ordering::SeqCst);
+        if self.header.read_ptr.load(Ordering::SeqCst) != read_ptr {
+            return Ok(());
+        }
+        self.sleep_on(&self.header.reads, reads, deadline, token)
+    }
+
+    /// Sleep on `word` while it holds `expected`, in the scope of `blocked_writers`.
+    ///
+    /// Fail only once `deadline` passes or `token` trips.
+    fn sleep_on(
+        &self,
+        word: &AtomicU32,
+        expected: u32,
+        deadline: Option<Instant>,
+        token: Option<&ShutdownToken>,
+    ) -> Result<(), WriteError<()>> {
+        if let Some(token) = token {
+            return futex_wait_or_shutdown(
+                Primitive::RingBuffer,
+                word,
+                expected,
+                self.header.blocked_writers.scope(),
+                token,
+            )
+            .map_err(|_| WriteError::Shutdown(()));
+        }
+        let timeout = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(WriteError::TimedOut(()));
+                }
+                Some(FutexTimeout::For(remaining, TimeoutMeasure::MonoTime))
+            }
+            None => None,
+        };
+        if let Err(e) = observed_futex_wait(
+            Primitive::RingBuffer,
+            FutexWaitContext {
+                word,
+                expected,
+                timeout,
+                scope: self.header.blocked_writers.scope(),
+            },
+        ) {
+            if !matches!(e.error, FutexError::ValueMismatch | FutexError::TimedOut) {
+                panic!("{e}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Wake the writers blocked in [`Self::wait_for_read`] after `read_ptr` moved.
+    fn notify_read(&self) {
+        if self.header.full_policy != FullPolicy::Block {
+            return;
+        }
+        self.header.reads.fetch_add(1, Ordering::SeqCst);
+        if self
+            .header
+            .blocked_writers
+            .as_ref()
+            .is_some_and(|n| 0 < n.load(Ordering::SeqCst))
+        {
+            futex_wake_from(
+                Primitive::RingBuffer,
+                &self.header.reads,
+                WakeWaiters::All,
+                self.header.blocked_writers.scope(),
+            )
+            .unwrap();
+        }
+    }
+
+    /// Block until an element is readable; fail only with [`RecvError::Disconnected`].
+    pub fn read(&self) -> Result<T, RecvError> {
+        self.recv_inner(None)
+    }
+
+    /// Read without blocking; fail with [`RecvError::Empty`] or [`RecvError::Disconnected`].
+    pub fn try_read(&self) -> Result<T, RecvError> {
+        self.recv_inner(Some(Instant::now())).map_err(|e| match e {
+            RecvError::TimedOut => RecvError::Empty,
+            e => e,
+        })
+    }
+
+    /// Fail with [`RecvError::TimedOut`] if the buffer stays empty for `timeout`, or with [`RecvError::Disconnected`].
+    pub fn read_timeout(&self, timeout: Duration) -> Result<T, RecvError> {
+        self.recv_inner(Instant::now().checked_add(timeout))
+    }
+
+    /// Wait for the oldest element and hand it to `f` without consuming it.
+    ///
+    /// The element stays readable unless a writer overrides it in the meantime.
+    pub fn peek<R>(&self, f: impl FnOnce(&T) -> R) -> Result<R, RecvError> {
+        self.visit_head(None, None, None, |value| match value {
+            CellValue::Some(value) => f(value),
+            _ => unreachable!(),
+        })
+        .unwrap()
+    }
+
+    /// Wait for the oldest element and consume it only if `predicate` accepts it.
+    ///
+    /// A rejected element stays at the head, ahead of all the others.
+    pub fn read_if(&self, predicate: impl FnOnce(&T) -> bool) -> Result<Option<T>, RecvError> {
+        self.visit_head(None, None, None, |value| match value {
+            CellValue::Some(v) => predicate(v).then(|| value.take().unwrap()),
+            _ => unreachable!(),
+        })
+        .unwrap()
+    }
+
+    /// Wait for the oldest element and hand it to `f` in place, without moving it out of its cell.
+    ///
+    /// After [`Verdict::Consume`], the element is dropped in its cell and the cell freed.
+    /// After [`Verdict::Keep`], the element stays at the head with whatever changes `f` made to it.
+    /// The head cell stays locked for the duration of `f`, so a writer overriding a full buffer waits for `f` to return.
+    /// A kept element can still be evicted by such a writer once `f` returns, as can any element not yet read.
+    ///
+    /// Return whether the element was consumed.
+    pub fn read_with(&self, f: impl FnOnce(&mut T) -> Verdict) -> Result<bool, RecvError> {
+        self.visit_head(None, None, None, |value| {
+            let verdict = match value {
+                CellValue::Some(v) => f(v),
+                _ => unreachable!(),
             };
-            let write_ptr = self.write_ptr.load(Ordering::SeqCst);
-            if read_ptr == write_ptr {
-                continue;
+            match verdict {
+                Verdict::Consume => {
+                    *value = CellValue::Vacant;
+                    true
+                }
+                Verdict::Keep => false,
             }
-            if self
-                .read_ptr
-                .compare_exchange(
-                    read_ptr,
-                    (read_ptr + 1) % self.buf.len(),
-                    Ordering::SeqCst,
-                    Ordering::SeqCst,
-                )
-                .is_err()
-            {
-                continue;
+        })
+        .unwrap()
+    }
+
+    /// Read and drop `n` elements, waiting for each.
+    pub fn skip(&self, n: usize) -> Result<(), RecvError> {
+        for _ in 0..n {
+            self.read()?;
+        }
+        Ok(())
+    }
+
+    /// Idle with `idle` instead of parking right away while the buffer is empty.
+    pub fn read_idle(&self, idle: IdleStrategy) -> Result<T, RecvError> {
+        self.visit_head(None, Some(idle), None, |value| value.take().unwrap())
+            .unwrap()
+    }
+
+    /// Give up with [`Shutdown`] once `token` trips, even if an element is readable by then.
+    ///
+    /// A closed and drained buffer counts as shut down.
+    pub fn read_or_shutdown(&self, token: &ShutdownToken) -> Result<T, Shutdown> {
+        if token.is_shutdown() {
+            return Err(Shutdown);
+        }
+        self.visit_head(Some(token), None, None, |value| value.take().unwrap())?
+            .map_err(|_| Shutdown)
+    }
+
+    /// Tell the reader that no more writes are coming.
+    ///
+    /// The reader still reads the elements left in the buffer, and then fails with [`RecvError::Disconnected`] instead of blocking.
+    /// Writes after the close are not rejected, but the reader may or may not see them.
+    pub fn close(&self) {
+        self.check_reentry();
+        self.header.closed.store(true, Ordering::SeqCst);
+        // The reader checks the flag with the head cell locked, so it either sees the flag or is parked in time for this wake
+        let read_ptr = self.header.read_ptr.load(Ordering::SeqCst);
+        drop(self.buf[read_ptr].write());
+    }
+
+    /// Only a snapshot.
+    pub fn is_closed(&self) -> bool {
+        self.header.closed.load(Ordering::SeqCst)
+    }
+
+    /// Read up to `max_items` at a time, waiting at most `first_timeout` for the first and then lingering at most `linger` for the rest.
+    ///
+    /// Learn more from [`Batches`].
+    pub fn batches(
+        &self,
+        max_items: usize,
+        first_timeout: Duration,
+        linger: Duration,
+    ) -> Batches<'_, T, N> {
+        Batches {
+            buf: self,
+            max_items,
+            first_timeout,
+            linger,
+            token: None,
+        }
+    }
+
+    /// The single path of every consuming read.
+    ///
+    /// Elements readable by `deadline` are returned even past it.
+    fn recv_inner(&self, deadline: Option<Instant>) -> Result<T, RecvError> {
+        let idle = match deadline {
+            Some(_) => None,
+            None => self.header.idle,
+        };
+        self.visit_head(None, idle, deadline, |value| value.take().unwrap())
+            .unwrap()
+    }
+
+    /// Wait for a readable head cell and hand it to `visit` while it is locked.
+    ///
+    /// `visit` either takes the value, consuming the element, or leaves it in place.
+    ///
+    /// Only fail with [`Shutdown`] given a `token`.
+    fn visit_head<R>(
+        &self,
+        token: Option<&ShutdownToken>,
+        mut idle: Option<IdleStrategy>,
+        deadline: Option<Instant>,
+        visit: impl FnOnce(&mut CellValue<T>) -> R,
+    ) -> Result<Result<R, RecvError>, Shutdown> {
+        self.check_reentry();
+        let mut budget = RetryBudget::new();
+        loop {
+            let read_ptr = self.header.read_ptr.load(Ordering::SeqCst);
+            let cell = &self.buf[read_ptr];
+            let mut m = cell.lock();
+            // `read_ptr` only moves away from a cell while that cell is locked
+            loop {
+                if read_ptr != self.header.read_ptr.load(Ordering::SeqCst) {
+                    // Cancelled by an overriding writer
+                    budget.retry();
+                    break;
+                }
+                match m.deref() {
+                    CellValue::Some(_) => {
+                        let visited = {
+                            let _held = self.hold_cell();
+                            visit(&mut m)
+                        };
+                        if m.is_vacant() {
+                            self.advance_read_ptr(read_ptr);
+                        }
+                        return Ok(Ok(visited));
+                    }
+                    CellValue::Cancelled => {
+                        // The value is gone; reclaim the cell so that it never gets stuck in this state
+                        *m = CellValue::Vacant;
+                    }
+                    CellValue::Vacant => {
+                        if read_ptr == self.header.write_ptr.load(Ordering::SeqCst) {
+                            // Empty
+                            if self.header.closed.load(Ordering::SeqCst) {
+                                return Ok(Err(RecvError::Disconnected));
+                            }
+                            m = match (token, &mut idle) {
+                                (Some(token), _) => cell.wait_or_shutdown(m, token)?,
+                                (None, Some(idle)) => cell.wait_idle(m, idle),
+                                (None, None) => match deadline {
+                                    Some(deadline) => {
+                                        let remaining =
+                                            deadline.saturating_duration_since(Instant::now());
+                                        if remaining.is_zero() {
+                                            return Ok(Err(RecvError::TimedOut));
+                                        }
+                                        cell.wait_timeout(m, remaining)
+                                    }
+                                    None => cell.wait(m),
+                                },
+                            };
+                            continue;
+                        }
+                        // Left behind by a writer that stopped between claiming the cell and filling it
+                        self.advance_read_ptr(read_ptr);
+                        break;
+                    }
+                }
             }
-            return m.take().unwrap();
+        }
+    }
+
+    /// The cell at `read_ptr` must be locked.
+    fn advance_read_ptr(&self, read_ptr: usize) {
+        self.header
+            .read_ptr
+            .compare_exchange(
+                read_ptr,
+                (read_ptr + 1) % self.buf.len(),
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .expect("`read_ptr` moved while its cell was locked");
+        self.notify_read();
+        if self.header.credit_batch == 0 {
+            return;
+        }
+        // Every cell passed, read or abandoned, was written with a credit
+        let consumed = self.header.consumed.load(Ordering::Relaxed) + 1;
+        if consumed < self.header.credit_batch {
+            self.header.consumed.store(consumed, Ordering::Relaxed);
+            return;
+        }
+        self.header.consumed.store(0, Ordering::Relaxed);
+        self.grant(self.header.credit_batch);
+    }
+
+    /// Number of readable elements.
+    ///
+    /// Only a snapshot.
+    pub fn len(&self) -> usize {
+        let read_ptr = self.header.read_ptr.load(Ordering::SeqCst);
+        let write_ptr = self.header.write_ptr.load(Ordering::SeqCst);
+        self.positive_distance(read_ptr, write_ptr)
+    }
+
+    /// Only a snapshot.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many elements the reader is behind the writers: the same as [`Self::len`].
+    ///
+    /// Only a snapshot.
+    pub fn lag(&self) -> usize {
+        self.len()
+    }
+
+    /// The highest [`Self::lag`] right after a write since the last [`Self::reset_lag_stats`], at most `N - 1`.
+    ///
+    /// Stays `0` if [`RingBufferBuilder::stats`] is off.
+    ///
+    /// Only a snapshot.
+    pub fn max_lag_since_reset(&self) -> usize {
+        self.header.max_lag.load(Ordering::Relaxed)
+    }
+
+    /// Start a new interval for [`Self::max_lag_since_reset`] from the current lag.
+    pub fn reset_lag_stats(&self) {
+        self.header.max_lag.store(self.lag(), Ordering::Relaxed);
+    }
+
+    /// Mark a cell of this buffer held by the current thread, around the user code run with it locked.
+    fn hold_cell(&self) -> HeldCell {
+        HeldCell::new(self.buf.as_ptr() as usize)
+    }
+
+    /// Catch a call from user code run with a cell of this buffer locked, which would otherwise block on that cell for good.
+    ///
+    /// # Panic
+    ///
+    /// In debug builds, if the current thread holds a cell of this buffer.
+    fn check_reentry(&self) {
+        #[cfg(debug_assertions)]
+        if HELD_CELLS.with_borrow(|held| held.contains(&(self.buf.as_ptr() as usize))) {
+            panic!("re-entered a ring buffer from a callback run with one of its cells locked");
         }
     }
 }
@@ -136,106 +796,449 @@ impl<T, const N: usize> Default for RingBuffer<T, N> {
     }
 }
 
-pub struct DebugRingBuffer<T: core::fmt::Debug, const N: usize>(pub RingBuffer<T, N>);
-impl<T: core::fmt::Debug, const N: usize> DebugRingBuffer<T, N> {
-    pub fn get(&self) -> &RingBuffer<T, N> {
-        &self.0
+#[cfg(debug_assertions)]
+thread_local! {
+    /// The buffers of which the current thread holds a cell, by address
+    static HELD_CELLS: std::cell::RefCell<Vec<usize>> = const { std::cell::RefCell::new(vec![]) };
+}
+
+/// Listed in [`HELD_CELLS`] until dropped; nothing in release builds.
+struct HeldCell {
+    #[cfg(debug_assertions)]
+    buf: usize,
+}
+impl HeldCell {
+    fn new(buf: usize) -> Self {
+        #[cfg(debug_assertions)]
+        HELD_CELLS.with_borrow_mut(|held| held.push(buf));
+        #[cfg(not(debug_assertions))]
+        let _ = buf;
+        Self {
+            #[cfg(debug_assertions)]
+            buf,
+        }
     }
 }
-impl<T: core::fmt::Debug, const N: usize> Drop for DebugRingBuffer<T, N> {
+#[cfg(debug_assertions)]
+impl Drop for HeldCell {
     fn drop(&mut self) {
-        dbg!(&self.0);
+        HELD_CELLS.with_borrow_mut(|held| {
+            let i = held.iter().rposition(|&buf| buf == self.buf).unwrap();
+            held.swap_remove(i);
+        });
     }
 }
 
+/// What [`RingBuffer::write`] does when the buffer is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FullPolicy {
+    /// Drop the oldest element, as [`RingBuffer::write_override`] always does
+    #[default]
+    Override,
+    /// Sleep until the reader makes room
+    Block,
+    /// Hand the value back
+    Reject,
+}
+
+/// The options of a [`RingBuffer`], from [`RingBuffer::builder`].
+///
+/// The capacity is the `N` of the type.
 #[derive(Debug)]
-struct Cell<T> {
-    cond_var: cond_var::CondVar,
-    mutex: mutex::Mutex<CellValue<T>>,
+pub struct RingBufferBuilder<T, const N: usize> {
+    full_policy: FullPolicy,
+    stats: bool,
+    idle: Option<IdleStrategy>,
+    private: bool,
+    credit_batch: Option<usize>,
+    #[cfg(feature = "pi")]
+    pi: bool,
+    _marker: PhantomData<fn() -> T>,
 }
-impl<T> Cell<T> {
-    pub fn new() -> Self {
-        Self {
-            cond_var: cond_var::CondVar::new(),
-            mutex: mutex::Mutex::new(CellValue::Vacant),
+impl<T, const N: usize> RingBufferBuilder<T, N> {
+    /// [`FullPolicy::Override`] by default.
+    pub fn full_policy(mut self, full_policy: FullPolicy) -> Self {
+        self.full_policy = full_policy;
+        self
+    }
+
+    /// Whether writes keep [`RingBuffer::max_lag_since_reset`] up to date, at the cost of a load and at times an RMW each; on by default.
+    pub fn stats(mut self, stats: bool) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Idle with `idle` in blocking reads without a timeout, as [`RingBuffer::read_idle`] does; parking right away by default.
+    pub fn idle(mut self, idle: IdleStrategy) -> Self {
+        self.idle = Some(idle);
+        self
+    }
+
+    /// Learn more from [`RingBuffer::new_private`]; off by default.
+    ///
+    /// Combined with `pi`, the cells stay priority-inheriting and only the blocked writers are private.
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Pace the writers with write credits instead of letting them find the buffer full one element at a time; off by default.
+    ///
+    /// The buffer starts with a credit for every free cell, `N - 1`.
+    /// Each write other than [`RingBuffer::write_override`] takes one first, and a writer out of credits acts on its [`FullPolicy`]: it sleeps on the credits under [`FullPolicy::Block`] or is rejected under [`FullPolicy::Reject`].
+    /// The reader grants `batch` credits back once it has consumed `batch` elements, waking the writers out of credits once per batch rather than once per element.
+    ///
+    /// # Panic
+    ///
+    /// On [`Self::build`]:
+    ///
+    /// - If `batch` is `0` or beyond `N - 1`.
+    /// - Under [`FullPolicy::Override`], which never waits for room.
+    pub fn with_credits(mut self, batch: usize) -> Self {
+        self.credit_batch = Some(batch);
+        self
+    }
+
+    /// Learn more from [`RingBuffer::new_pi`]; off by default.
+    #[cfg(feature = "pi")]
+    pub fn pi(mut self, pi: bool) -> Self {
+        self.pi = pi;
+        self
+    }
+
+    /// # Panic
+    ///
+    /// - Same as [`RingBuffer::new`].
+    /// - Learn more from [`Self::with_credits`].
+    pub fn build(self) -> RingBuffer<T, N> {
+        let new_cell: fn() -> SlotCell<T> = match self.private {
+            true => SlotCell::new_private,
+            false => SlotCell::new,
+        };
+        #[cfg(feature = "pi")]
+        let new_cell = match self.pi {
+            true => SlotCell::new_pi,
+            false => new_cell,
+        };
+        let mut ring_buf = RingBuffer::with_cells(new_cell);
+        if self.private {
+            ring_buf.header.blocked_writers = WaitersCounter::private();
+            ring_buf.header.credit_waiters = WaitersCounter::private();
+        }
+        ring_buf.header.full_policy = self.full_policy;
+        ring_buf.header.stats = self.stats;
+        ring_buf.header.idle = self.idle;
+        if let Some(batch) = self.credit_batch {
+            assert!(batch != 0);
+            assert!(batch < N);
+            assert!(self.full_policy != FullPolicy::Override);
+            ring_buf.header.credit_batch = batch;
+            ring_buf.header.credits = AtomicU32::new(u32::try_from(N - 1).unwrap_or(u32::MAX));
         }
+        ring_buf
     }
 
-    pub fn write(&self) -> WriteGuard<'_, T> {
-        let m = self.mutex.lock();
-        WriteGuard {
-            locked: m,
-            cond_var: &self.cond_var,
+    /// Stamp each element with a sequence number.
+    ///
+    /// Learn more from [`SequencedRingBuffer`].
+    pub fn build_sequenced(self) -> SequencedRingBuffer<T, N> {
+        SequencedRingBuffer {
+            inner: RingBufferBuilder {
+                full_policy: self.full_policy,
+                stats: self.stats,
+                idle: self.idle,
+                private: self.private,
+                credit_batch: self.credit_batch,
+                #[cfg(feature = "pi")]
+                pi: self.pi,
+                _marker: PhantomData,
+            }
+            .build(),
+            next_seq: AtomicU64::new(0),
         }
     }
 
-    /// Instructions in the `confirmed` closure are protected by a mutex
-    pub fn read(
-        &self,
-        mut confirmed: impl FnMut() -> bool,
-    ) -> Option<mutex::MutexGuard<'_, CellValue<T>>> {
-        let m = self.mutex.lock();
-        if !confirmed() {
+    /// Build the buffer into memory shared between processes, as by [`SharedCell::create`]; others attach with [`SharedCell::open`].
+    ///
+    /// # Safety
+    ///
+    /// Same as [`SharedCell::create`].
+    ///
+    /// # Panic
+    ///
+    /// If the buffer is [private](Self::private).
+    pub unsafe fn build_shared<'a>(
+        self,
+        region: *mut [u8],
+        offset: usize,
+    ) -> Result<&'a RingBuffer<T, N>, SharedCellError>
+    where
+        T: SharedSafe + Send,
+    {
+        assert!(!self.private);
+        SharedCell::create(region, offset, self.build())
+    }
+}
+
+/// An endless iterator of batches read from a [`RingBuffer`], from [`RingBuffer::batches`].
+///
+/// Each batch holds at most `max_items` elements:
+///
+/// - If the buffer stays empty for `first_timeout`, the batch is empty.
+/// - Otherwise, the batch is returned `linger` after its first element was read at the latest, or as soon as it is full.
+///
+/// The iteration ends once the buffer is closed and drained.
+#[derive(Debug)]
+pub struct Batches<'a, T, const N: usize> {
+    buf: &'a RingBuffer<T, N>,
+    max_items: usize,
+    first_timeout: Duration,
+    linger: Duration,
+    token: Option<&'a ShutdownToken>,
+}
+impl<'a, T, const N: usize> Batches<'a, T, N> {
+    /// End the iteration once `token` trips, noticed within `first_timeout` or `linger`.
+    pub fn until_shutdown(mut self, token: &'a ShutdownToken) -> Self {
+        self.token = Some(token);
+        self
+    }
+}
+impl<T, const N: usize> Iterator for Batches<'_, T, N> {
+    type Item = Vec<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.token.is_some_and(|token| token.is_shutdown()) {
             return None;
         }
-        match m.deref() {
-            CellValue::Some(_) => Some(m),
-            CellValue::Vacant => {
-                self.cond_var.wait(m);
-                None
+        let mut batch = Vec::new();
+        if self.max_items == 0 {
+            return Some(batch);
+        }
+        match self.buf.read_timeout(self.first_timeout) {
+            Ok(first) => batch.push(first),
+            Err(RecvError::Disconnected) => return None,
+            Err(_) => return Some(batch),
+        }
+        // Lingering past what `Instant` can hold is lingering until the batch is full
+        let deadline = Instant::now().checked_add(self.linger);
+        while batch.len() < self.max_items {
+            match self.buf.recv_inner(deadline) {
+                Ok(value) => batch.push(value),
+                Err(_) => break,
             }
-            CellValue::Cancelled => None,
         }
+        Some(batch)
+    }
+}
+
+/// A [`RingBuffer`] stamping each element with a sequence number, so that the reader can tell where [`Self::write_override`] dropped elements and how many.
+///
+/// The sequence is a `u64` incremented once per write; it would take centuries of back-to-back writes to wrap around.
+///
+/// With a single writer, the sequences read only increase, and any jump is a gap.
+/// With multiple writers, two writes can land in the opposite order of their sequences, so a jump can also be a reordering.
+#[derive(Debug)]
+pub struct SequencedRingBuffer<T, const N: usize> {
+    inner: RingBuffer<(u64, T), N>,
+    next_seq: AtomicU64,
+}
+impl<T, const N: usize> SequencedRingBuffer<T, N> {
+    /// Learn more from [`RingBuffer::new`].
+    pub fn new() -> Self {
+        Self {
+            inner: RingBuffer::new(),
+            next_seq: AtomicU64::new(0),
+        }
+    }
+
+    /// Return the sequence of the element.
+    pub fn write_override(&self, new: T) -> u64 {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        self.inner.write_override((seq, new));
+        seq
+    }
+
+    /// Learn more from [`RingBuffer::write`].
+    ///
+    /// A rejected element still takes its sequence, so the reader sees it as a gap like an overridden one.
+    pub fn write(&self, new: T) -> Result<u64, T> {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        self.inner.write((seq, new)).map_err(|(_, new)| new)?;
+        Ok(seq)
+    }
+
+    /// Learn more from [`RingBuffer::read`].
+    pub fn read_seq(&self) -> Result<(u64, T), RecvError> {
+        self.inner.read()
+    }
+
+    /// Learn more from [`RingBuffer::try_read`].
+    pub fn try_read_seq(&self) -> Result<(u64, T), RecvError> {
+        self.inner.try_read()
+    }
+
+    /// Learn more from [`RingBuffer::read_timeout`].
+    pub fn read_seq_timeout(&self, timeout: Duration) -> Result<(u64, T), RecvError> {
+        self.inner.read_timeout(timeout)
+    }
+
+    /// Learn more from [`RingBuffer::peek`].
+    pub fn peek_seq<R>(&self, f: impl FnOnce(u64, &T) -> R) -> Result<R, RecvError> {
+        self.inner.peek(|(seq, value)| f(*seq, value))
+    }
+
+    /// Learn more from [`RingBuffer::read_if`].
+    pub fn read_seq_if(
+        &self,
+        predicate: impl FnOnce(&T) -> bool,
+    ) -> Result<Option<(u64, T)>, RecvError> {
+        self.inner.read_if(|(_, value)| predicate(value))
+    }
+
+    /// Read and drop `n` elements, waiting for each.
+    ///
+    /// Return the sequence of the last one, if any, to resume gap detection from.
+    pub fn skip(&self, n: usize) -> Result<Option<u64>, RecvError> {
+        let mut last = None;
+        for _ in 0..n {
+            last = Some(self.read_seq()?.0);
+        }
+        Ok(last)
+    }
+
+    /// Learn more from [`RingBuffer::read_or_shutdown`].
+    pub fn read_seq_or_shutdown(&self, token: &ShutdownToken) -> Result<(u64, T), Shutdown> {
+        self.inner.read_or_shutdown(token)
+    }
+
+    /// Learn more from [`RingBuffer::close`].
+    pub fn close(&self) {
+        self.inner.close()
+    }
+
+    /// Only a snapshot.
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+
+    /// Only a snapshot.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Only a snapshot.
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Learn more from [`RingBuffer::lag`].
+    pub fn lag(&self) -> usize {
+        self.inner.lag()
+    }
+
+    /// Learn more from [`RingBuffer::max_lag_since_reset`].
+    pub fn max_lag_since_reset(&self) -> usize {
+        self.inner.max_lag_since_reset()
+    }
+
+    /// Learn more from [`RingBuffer::reset_lag_stats`].
+    pub fn reset_lag_stats(&self) {
+        self.inner.reset_lag_stats()
     }
 }
-impl<T> Default for Cell<T> {
+impl<T, const N: usize> Default for SequencedRingBuffer<T, N> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-#[derive(Debug)]
-pub enum CellValue<T> {
-    Vacant,
-    Some(T),
-    Cancelled,
+/// What [`RingBuffer::read_with`] does with the element it visited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Drop the element and free its cell
+    Consume,
+    /// Leave the element at the head for a later read
+    Keep,
+}
+
+/// Why a read of a [`RingBuffer`] returned no element.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// Only from the non-blocking reads
+    Empty,
+    /// Only from the reads with a timeout
+    TimedOut,
+    /// The buffer is closed and drained
+    Disconnected,
 }
-impl<T> CellValue<T> {
-    pub fn take(&mut self) -> Option<T> {
+impl std::fmt::Display for RecvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            CellValue::Vacant => return None,
-            CellValue::Some(_) => (),
-            CellValue::Cancelled => return None,
+            RecvError::Empty => write!(f, "ring buffer empty"),
+            RecvError::TimedOut => write!(f, "timed out waiting for the ring buffer"),
+            RecvError::Disconnected => write!(f, "ring buffer closed"),
         }
-        let a = std::mem::replace(self, Self::Vacant);
-        match a {
-            CellValue::Vacant => unreachable!(),
-            CellValue::Some(v) => Some(v),
-            CellValue::Cancelled => unreachable!(),
+    }
+}
+impl std::error::Error for RecvError {}
+
+/// Why a write of a [`RingBuffer`] handed the value back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteError<T> {
+    /// The buffer is full under [`FullPolicy::Reject`]
+    Full(T),
+    /// Only from [`RingBuffer::write_timeout`]
+    TimedOut(T),
+    /// Only from [`RingBuffer::write_or_shutdown`]
+    Shutdown(T),
+}
+impl<T> WriteError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            WriteError::Full(value) | WriteError::TimedOut(value) | WriteError::Shutdown(value) => {
+                value
+            }
         }
     }
 
-    pub fn is_vacant(&self) -> bool {
+    fn with<U>(self, value: U) -> WriteError<U> {
+        match self {
+            WriteError::Full(_) => WriteError::Full(value),
+            WriteError::TimedOut(_) => WriteError::TimedOut(value),
+            WriteError::Shutdown(_) => WriteError::Shutdown(value),
+        }
+    }
+}
+impl<T> std::fmt::Display for WriteError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            CellValue::Vacant => true,
-            CellValue::Some(_) => false,
-            CellValue::Cancelled => false,
+            WriteError::Full(_) => write!(f, "ring buffer full"),
+            WriteError::TimedOut(_) => write!(f, "timed out waiting for room in the ring buffer"),
+            WriteError::Shutdown(_) => write!(f, "shut down while blocking"),
         }
     }
 }
+impl<T: std::fmt::Debug> std::error::Error for WriteError<T> {}
 
-pub struct WriteGuard<'a, T> {
-    locked: mutex::MutexGuard<'a, CellValue<T>>,
-    cond_var: &'a cond_var::CondVar,
+/// The allocator failed to provide the memory of [`RingBuffer::try_new_boxed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError;
+impl std::fmt::Display for AllocError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "failed to allocate the ring buffer")
+    }
 }
-impl<'a, T> WriteGuard<'a, T> {
-    pub fn locked(&mut self) -> &mut mutex::MutexGuard<'a, CellValue<T>> {
-        &mut self.locked
+impl std::error::Error for AllocError {}
+
+pub struct DebugRingBuffer<T: core::fmt::Debug, const N: usize>(pub RingBuffer<T, N>);
+impl<T: core::fmt::Debug, const N: usize> DebugRingBuffer<T, N> {
+    pub fn get(&self) -> &RingBuffer<T, N> {
+        &self.0
     }
 }
-impl<T> Drop for WriteGuard<'_, T> {
+impl<T: core::fmt::Debug, const N: usize> Drop for DebugRingBuffer<T, N> {
     fn drop(&mut self) {
-        self.cond_var.notify_all();
+        dbg!(&self.0);
     }
 }
 
@@ -244,6 +1247,7 @@ mod tests {
     use std::sync::Arc;
 
     use super::*;
+    use crate::mock_backend::WAKE_SYSCALLS;
 
     #[test]
     fn test_1() {
@@ -267,7 +1271,7 @@ mod tests {
                 move || {
                     let mut prev = writes;
                     loop {
-                        let n = ring_buf.read();
+                        let n = ring_buf.read().unwrap();
                         dbg!(n);
                         assert!(n < prev);
                         if n == 0 {
@@ -289,4 +1293,787 @@ mod tests {
             });
         });
     }
+
+    #[test]
+    fn test_parked_reader_with_writer_burst() {
+        const BUF_SIZE: usize = 3;
+        const WRITES: usize = 1 << 14;
+        for _ in 0..16 {
+            let ring_buf: RingBuffer<usize, BUF_SIZE> = RingBuffer::new();
+            std::thread::scope(|s| {
+                s.spawn(|| {
+                    let mut prev = None;
+                    loop {
+                        let n = ring_buf.read().unwrap();
+                        assert!(prev < Some(n));
+                        if n == WRITES {
+                            return;
+                        }
+                        prev = Some(n);
+                    }
+                });
+
+                // Let the reader park on the empty buffer
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                for i in 1..=WRITES {
+                    ring_buf.write_override(i);
+                }
+            });
+        }
+    }
+
+    #[cfg(feature = "pi")]
+    #[test]
+    fn test_pi_cells() {
+        const WRITERS: usize = 3;
+        const WRITES: usize = 1 << 12;
+        for ring_buf in [RingBuffer::<_, 4>::new(), RingBuffer::new_pi()] {
+            let done = AtomicUsize::new(0);
+            let mut read = 0;
+            std::thread::scope(|s| {
+                for w in 0..WRITERS {
+                    let (ring_buf, done) = (&ring_buf, &done);
+                    s.spawn(move || {
+                        for i in 0..WRITES {
+                            ring_buf.write_override((w, i));
+                        }
+                        done.fetch_add(1, Ordering::Release);
+                    });
+                }
+                // Overridden elements are lost, but each writer's survivors arrive in order
+                let mut prev = [None; WRITERS];
+                loop {
+                    let finished = done.load(Ordering::Acquire) == WRITERS;
+                    let Ok((w, i)) = ring_buf.read_timeout(Duration::from_millis(10)) else {
+                        if finished {
+                            break;
+                        }
+                        continue;
+                    };
+                    assert!(prev[w] < Some(i));
+                    prev[w] = Some(i);
+                    read += 1;
+                }
+            });
+            assert!(0 < read);
+        }
+    }
+
+    #[test]
+    fn test_read_idle() {
+        const WRITES: usize = 1 << 12;
+        let strategies = [
+            IdleStrategy::BusySpin,
+            IdleStrategy::Yielding { spins: 64 },
+            IdleStrategy::Parking {
+                spins: 64,
+                yields: 8,
+                park: std::time::Duration::from_millis(1),
+            },
+        ];
+        for strategy in strategies {
+            let ring_buf: RingBuffer<usize, 4> = RingBuffer::new();
+            std::thread::scope(|s| {
+                s.spawn(|| {
+                    let mut prev = None;
+                    loop {
+                        let n = ring_buf.read_idle(strategy).unwrap();
+                        assert!(prev < Some(n));
+                        if n == WRITES {
+                            return;
+                        }
+                        prev = Some(n);
+                    }
+                });
+                for i in 1..=WRITES {
+                    ring_buf.write_override(i);
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn test_reader_parks_on_cancelled_head() {
+        let ring_buf: RingBuffer<usize, 3> = RingBuffer::new();
+        // Fill and override once so that the next cell to write is a cancelled one
+        for i in 0..3 {
+            ring_buf.write_override(i);
+        }
+        assert_eq!(ring_buf.read(), Ok(1));
+        assert_eq!(ring_buf.read(), Ok(2));
+        let read_ptr = ring_buf.header.read_ptr.load(Ordering::SeqCst);
+        assert_eq!(read_ptr, ring_buf.header.write_ptr.load(Ordering::SeqCst));
+        assert!(matches!(
+            *ring_buf.buf[read_ptr].lock(),
+            CellValue::Cancelled
+        ));
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                assert_eq!(ring_buf.read(), Ok(3));
+            });
+            while ring_buf.buf[read_ptr].waiters() != Some(1) {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            ring_buf.write_override(3);
+        });
+    }
+
+    #[test]
+    fn test_reader_reclaims_interrupted_cancellation() {
+        let ring_buf: RingBuffer<usize, 4> = RingBuffer::new();
+        for i in 0..3 {
+            ring_buf.write_override(i);
+        }
+        // Simulate a writer that marked the head cancelled but stopped before advancing `read_ptr`
+        let read_ptr = ring_buf.header.read_ptr.load(Ordering::SeqCst);
+        *ring_buf.buf[read_ptr].lock() = CellValue::Cancelled;
+        assert_eq!(ring_buf.read(), Ok(1));
+
+        // Simulate a writer that advanced `write_ptr` but stopped before filling the cell
+        let write_ptr = ring_buf.header.write_ptr.load(Ordering::SeqCst);
+        ring_buf
+            .header
+            .write_ptr
+            .store((write_ptr + 1) % 4, Ordering::SeqCst);
+        ring_buf.write_override(3);
+        assert_eq!(ring_buf.read(), Ok(2));
+        assert_eq!(ring_buf.read(), Ok(3));
+        assert!(ring_buf.is_empty());
+    }
+
+    #[test]
+    fn test_sequenced_gaps() {
+        let ring_buf: SequencedRingBuffer<char, 4> = SequencedRingBuffer::new();
+        let mut written = 0;
+        let mut read = vec![];
+        // Overload the 3 readable cells in bursts, reading a little in between
+        for (burst, reads) in [(5, 2), (1, 1), (7, 1)] {
+            for _ in 0..burst {
+                ring_buf.write_override(char::from(b'a' + written));
+                written += 1;
+            }
+            for _ in 0..reads {
+                read.push(ring_buf.read_seq().unwrap());
+            }
+        }
+        while !ring_buf.is_empty() {
+            read.push(ring_buf.read_seq().unwrap());
+        }
+
+        let mut gaps = vec![];
+        let mut expected = 0;
+        for &(seq, value) in &read {
+            assert_eq!(value, char::from(b'a' + u8::try_from(seq).unwrap()));
+            if expected < seq {
+                gaps.push(expected..seq);
+            }
+            expected = seq + 1;
+        }
+        assert_eq!(gaps, [0..2, 5..10]);
+        let evicted = gaps.iter().map(|gap| gap.end - gap.start).sum::<u64>();
+        assert_eq!(evicted + read.len() as u64, u64::from(written));
+    }
+
+    #[test]
+    fn test_recv_errors() {
+        type Read = fn(&RingBuffer<usize, 4>) -> Result<usize, RecvError>;
+        let blocking: [(&str, Read); 6] = [
+            ("read", |b| b.read()),
+            ("read_idle", |b| {
+                b.read_idle(IdleStrategy::Parking {
+                    spins: 0,
+                    yields: 0,
+                    park: Duration::from_secs(10),
+                })
+            }),
+            ("peek", |b| b.peek(|v| *v)),
+            ("read_if", |b| b.read_if(|_| true).map(Option::unwrap)),
+            ("skip", |b| b.skip(1).map(|()| 7)),
+            ("read_or_shutdown", |b| {
+                b.read_or_shutdown(&ShutdownToken::new())
+                    .map_err(|Shutdown| RecvError::Disconnected)
+            }),
+        ];
+        let timed: [(&str, Read, RecvError); 2] = [
+            ("try_read", |b| b.try_read(), RecvError::Empty),
+            (
+                "read_timeout",
+                |b| b.read_timeout(Duration::from_millis(10)),
+                RecvError::TimedOut,
+            ),
+        ];
+        let buffer = |readable: bool, closed: bool| {
+            let ring_buf = RingBuffer::new();
+            if readable {
+                ring_buf.write_override(7);
+            }
+            if closed {
+                ring_buf.close();
+            }
+            ring_buf
+        };
+
+        for (name, read, empty) in timed {
+            assert_eq!(read(&buffer(true, false)), Ok(7), "{name}");
+            assert_eq!(read(&buffer(false, false)), Err(empty), "{name}");
+            assert_eq!(read(&buffer(true, true)), Ok(7), "{name}");
+            assert_eq!(
+                read(&buffer(false, true)),
+                Err(RecvError::Disconnected),
+                "{name}"
+            );
+        }
+        for (name, read) in blocking {
+            assert_eq!(read(&buffer(true, false)), Ok(7), "{name}");
+            assert_eq!(read(&buffer(true, true)), Ok(7), "{name}");
+            assert_eq!(
+                read(&buffer(false, true)),
+                Err(RecvError::Disconnected),
+                "{name}"
+            );
+            // Closing wakes a reader parked on the empty buffer
+            let ring_buf = buffer(false, false);
+            std::thread::scope(|s| {
+                let reader = s.spawn(|| read(&ring_buf));
+                while ring_buf.buf[0].waiters() != Some(1) {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                ring_buf.close();
+                assert_eq!(
+                    reader.join().unwrap(),
+                    Err(RecvError::Disconnected),
+                    "{name}"
+                );
+            });
+        }
+
+        // Batches end once drained
+        let ring_buf = buffer(true, true);
+        let mut batches = ring_buf.batches(8, Duration::from_secs(10), Duration::ZERO);
+        assert_eq!(batches.next(), Some(vec![7]));
+        assert_eq!(batches.next(), None);
+    }
+
+    #[test]
+    fn test_builder() {
+        // The defaults are those of `new`
+        let ring_buf = RingBuffer::<usize, 3>::builder().build();
+        assert_eq!(ring_buf.full_policy(), FullPolicy::Override);
+        (0..3).for_each(|i| ring_buf.write(i).unwrap());
+        assert_eq!(ring_buf.max_lag_since_reset(), 2);
+        assert_eq!(ring_buf.read(), Ok(1));
+
+        let ring_buf = RingBuffer::<usize, 3>::builder()
+            .full_policy(FullPolicy::Reject)
+            .stats(false)
+            .build();
+        (0..2).for_each(|i| ring_buf.write(i).unwrap());
+        assert_eq!(ring_buf.write(2), Err(2));
+        assert_eq!(ring_buf.max_lag_since_reset(), 0);
+        // Still overridable on purpose
+        ring_buf.write_override(3);
+        assert_eq!(ring_buf.read(), Ok(1));
+        ring_buf.write(4).unwrap();
+        assert_eq!(ring_buf.read(), Ok(3));
+
+        let ring_buf = RingBuffer::<usize, 3>::builder()
+            .full_policy(FullPolicy::Block)
+            .idle(IdleStrategy::Yielding { spins: 16 })
+            .build();
+        (0..2).for_each(|i| ring_buf.write(i).unwrap());
+        std::thread::scope(|s| {
+            let writer = s.spawn(|| ring_buf.write(2));
+            while ring_buf
+                .header
+                .blocked_writers
+                .as_ref()
+                .unwrap()
+                .load(Ordering::SeqCst)
+                == 0
+            {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            assert!(!writer.is_finished());
+            assert_eq!(ring_buf.read(), Ok(0));
+            assert_eq!(writer.join().unwrap(), Ok(()));
+        });
+        assert_eq!(ring_buf.read(), Ok(1));
+        assert_eq!(ring_buf.read(), Ok(2));
+
+        // A rejected write leaves a gap
+        let ring_buf = RingBuffer::<char, 3>::builder()
+            .full_policy(FullPolicy::Reject)
+            .build_sequenced();
+        assert_eq!(ring_buf.write('a'), Ok(0));
+        assert_eq!(ring_buf.write('b'), Ok(1));
+        assert_eq!(ring_buf.write('c'), Err('c'));
+        assert_eq!(ring_buf.read_seq(), Ok((0, 'a')));
+        assert_eq!(ring_buf.write('d'), Ok(3));
+        assert_eq!(ring_buf.read_seq(), Ok((1, 'b')));
+        assert_eq!(ring_buf.read_seq(), Ok((3, 'd')));
+
+        #[cfg(feature = "pi")]
+        {
+            let ring_buf = RingBuffer::<usize, 3>::builder().pi(true).build();
+            ring_buf.write(1).unwrap();
+            assert_eq!(ring_buf.read(), Ok(1));
+        }
+    }
+
+    #[test]
+    fn test_cancelled_writes_keep_capacity() {
+        const WRITERS: usize = 4;
+        const WRITES: usize = 200;
+        let ring_buf = RingBuffer::<(usize, usize), 4>::builder()
+            .full_policy(FullPolicy::Block)
+            .build();
+        let token = ShutdownToken::new();
+        let written = AtomicUsize::new(0);
+        let read = std::thread::scope(|s| {
+            let reader = s.spawn(|| {
+                let mut last = [None; WRITERS];
+                let mut read = 0;
+                while let Ok((writer, i)) = ring_buf.read() {
+                    // FIFO among the writes of each writer, whatever gave up in between
+                    assert!(last[writer] < Some(i));
+                    last[writer] = Some(i);
+                    read += 1;
+                    if read % 8 == 0 {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                }
+                read
+            });
+            let writers = (0..WRITERS)
+                .map(|writer| {
+                    let (ring_buf, token, written) = (&ring_buf, &token, &written);
+                    s.spawn(move || {
+                        for i in 0..WRITES {
+                            let res = match i % 3 {
+                                0 => ring_buf.write_or_shutdown((writer, i), token),
+                                1 => ring_buf.write_timeout((writer, i), Duration::ZERO),
+                                _ => {
+                                    ring_buf.write_timeout((writer, i), Duration::from_micros(100))
+                                }
+                            };
+                            match res {
+                                Ok(()) => {
+                                    written.fetch_add(1, Ordering::Relaxed);
+                                }
+                                Err(e) => assert_eq!(e.into_inner(), (writer, i)),
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            // Strand the writers blocked until shutdown
+            std::thread::sleep(Duration::from_millis(20));
+            token.shutdown();
+            writers.into_iter().for_each(|w| w.join().unwrap());
+            ring_buf.close();
+            reader.join().unwrap()
+        });
+        assert_eq!(read, written.into_inner());
+        assert!(0 < read);
+
+        // Every cell is still usable
+        for i in 0..3 {
+            ring_buf.write_timeout((0, i), Duration::ZERO).unwrap();
+        }
+        assert_eq!(
+            ring_buf.write_timeout((0, 3), Duration::from_millis(10)),
+            Err(WriteError::TimedOut((0, 3)))
+        );
+        assert_eq!(
+            ring_buf.write_or_shutdown((0, 3), &token),
+            Err(WriteError::Shutdown((0, 3)))
+        );
+        assert_eq!(ring_buf.read(), Ok((0, 0)));
+        assert_eq!(
+            ring_buf.write_or_shutdown((0, 3), &ShutdownToken::new()),
+            Ok(())
+        );
+        assert_eq!(ring_buf.len(), 3);
+    }
+
+    #[test]
+    fn test_try_new_boxed_beyond_stack() {
+        type Big = RingBuffer<[u8; 4096], 1024>;
+        // Over 4 MiB, while the thread only has a fraction of that
+        assert!(4 << 20 < std::mem::size_of::<Big>());
+        std::thread::Builder::new()
+            .stack_size(256 << 10)
+            .spawn(|| {
+                let ring_buf = Big::try_new_boxed().unwrap();
+                for i in 0..2048_u16 {
+                    let mut page = [0; 4096];
+                    page[..2].copy_from_slice(&i.to_le_bytes());
+                    ring_buf.write_override(page);
+                }
+                assert_eq!(ring_buf.len(), 1023);
+                let page = ring_buf.read().unwrap();
+                assert_eq!(u16::from_le_bytes([page[0], page[1]]), 2048 - 1023);
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_build_shared() {
+        let mut region = vec![0_u64; 128];
+        let region = std::ptr::slice_from_raw_parts_mut(region.as_mut_ptr().cast::<u8>(), 128 * 8);
+        let created = unsafe {
+            RingBuffer::<u64, 4>::builder()
+                .full_policy(FullPolicy::Reject)
+                .build_shared(region, 0)
+        }
+        .unwrap();
+        let opened = unsafe { SharedCell::<RingBuffer<u64, 4>>::open(region, 0, None) }.unwrap();
+        (0..3).for_each(|i| created.write(i).unwrap());
+        assert_eq!(opened.write(3), Err(3));
+        assert_eq!(opened.read(), Ok(0));
+        assert!(matches!(
+            unsafe { RingBuffer::<u64, 4>::builder().build_shared(region, 0) },
+            Err(SharedCellError::AlreadyCreated)
+        ));
+    }
+
+    #[test]
+    fn test_read_if_keeps_order() {
+        let ring_buf: RingBuffer<usize, 8> = RingBuffer::new();
+        (0..6).for_each(|i| ring_buf.write_override(i));
+        let mut accepted = vec![];
+        let mut reject = false;
+        while !ring_buf.is_empty() {
+            assert_eq!(ring_buf.peek(|v| *v), Ok(accepted.len()));
+            // Alternate, so each element is rejected once before being accepted
+            reject = !reject;
+            match ring_buf.read_if(|_| !reject).unwrap() {
+                Some(v) => accepted.push(v),
+                None => assert_eq!(ring_buf.len(), 6 - accepted.len()),
+            }
+        }
+        assert_eq!(accepted, (0..6).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_read_with_in_place() {
+        type Payload = [u64; 1024];
+        let ring_buf: Box<RingBuffer<Payload, 4>> = RingBuffer::try_new_boxed().unwrap();
+        (0..3).for_each(|i| ring_buf.write_override([i; 1024]));
+        let mut consumed = vec![];
+        while !ring_buf.is_empty() {
+            let mut id = 0;
+            let consume = ring_buf
+                .read_with(|payload| {
+                    id = payload[1];
+                    // Keep each element once, marking it in place
+                    if payload[0] == u64::MAX {
+                        return Verdict::Consume;
+                    }
+                    payload[0] = u64::MAX;
+                    Verdict::Keep
+                })
+                .unwrap();
+            if consume {
+                consumed.push(id);
+            } else {
+                assert_eq!(ring_buf.peek(|payload| payload[0]), Ok(u64::MAX));
+            }
+        }
+        assert_eq!(consumed, [0, 1, 2]);
+
+        // A kept head is evicted like any unread element
+        (3..6).for_each(|i| ring_buf.write_override([i; 1024]));
+        assert_eq!(ring_buf.read_with(|_| Verdict::Keep), Ok(false));
+        ring_buf.write_override([6; 1024]);
+        assert_eq!(ring_buf.peek(|payload| payload[0]), Ok(4));
+        (4..7).for_each(|i| assert_eq!(ring_buf.read().unwrap()[0], i));
+
+        ring_buf.close();
+        assert_eq!(
+            ring_buf.read_with(|_| Verdict::Consume),
+            Err(RecvError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn test_read_with_keep_races_eviction() {
+        type Payload = [u64; 512];
+        const WRITES: u64 = 2000;
+        let ring_buf: Box<RingBuffer<Payload, 4>> = RingBuffer::try_new_boxed().unwrap();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 1..=WRITES {
+                    ring_buf.write_override([i; 512]);
+                }
+                ring_buf.close();
+            });
+            let mut kept = None;
+            let mut last = 0;
+            loop {
+                let mut id = 0;
+                let res = ring_buf.read_with(|payload| {
+                    // Never torn by a writer while visited
+                    assert!(payload[1..].iter().all(|&x| x == payload[1]));
+                    id = payload[1];
+                    if payload[0] != payload[1] {
+                        return Verdict::Consume;
+                    }
+                    payload[0] = 0;
+                    Verdict::Keep
+                });
+                match res {
+                    Ok(consume) => {
+                        assert!(last < id || kept == Some(id));
+                        last = id;
+                        kept = (!consume).then_some(id);
+                    }
+                    Err(e) => {
+                        assert_eq!(e, RecvError::Disconnected);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_reentry_panics() {
+        use std::{panic::AssertUnwindSafe, sync::OnceLock};
+
+        fn message(panic: Box<dyn std::any::Any + Send>) -> &'static str {
+            panic.downcast_ref::<&str>().unwrap()
+        }
+
+        let ring_buf: RingBuffer<usize, 3> = RingBuffer::new();
+        ring_buf.write_override(1);
+        let panic = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            ring_buf.read_with(|_| {
+                let _ = ring_buf.try_read();
+                Verdict::Keep
+            })
+        }))
+        .unwrap_err();
+        assert!(message(panic).starts_with("re-entered"));
+        // Unlocked by the unwind
+        assert_eq!(ring_buf.try_read(), Ok(1));
+
+        /// Reads its buffer from its drop if told to
+        #[derive(Debug)]
+        struct Reenter(bool);
+        impl Drop for Reenter {
+            fn drop(&mut self) {
+                if self.0 {
+                    let _ = EVICTING.get().unwrap().try_read();
+                }
+            }
+        }
+        static EVICTING: OnceLock<RingBuffer<Reenter, 3>> = OnceLock::new();
+        let ring_buf = EVICTING.get_or_init(RingBuffer::new);
+        ring_buf.write_override(Reenter(true));
+        ring_buf.write_override(Reenter(false));
+        let panic =
+            std::panic::catch_unwind(AssertUnwindSafe(|| ring_buf.write_override(Reenter(false))))
+                .unwrap_err();
+        assert!(message(panic).starts_with("re-entered"));
+        assert!(!ring_buf.try_read().unwrap().0);
+    }
+
+    #[test]
+    fn test_skip_gaps() {
+        let ring_buf: SequencedRingBuffer<char, 4> = SequencedRingBuffer::new();
+        // Evicts `a` and `b`
+        "abcde".chars().for_each(|c| {
+            ring_buf.write_override(c);
+        });
+        assert_eq!(ring_buf.skip(0), Ok(None));
+        assert_eq!(ring_buf.peek_seq(|seq, c| (seq, *c)), Ok((2, 'c')));
+        assert_eq!(ring_buf.skip(2), Ok(Some(3)));
+        assert_eq!(ring_buf.read_seq_if(|c| *c == 'e'), Ok(Some((4, 'e'))));
+        assert!(ring_buf.is_empty());
+
+        // Skipped elements are not mistaken for dropped ones
+        let mut expected = 4 + 1;
+        let mut evicted = 0;
+        "fghijk".chars().for_each(|c| {
+            ring_buf.write_override(c);
+        });
+        let last = ring_buf.skip(1).unwrap().unwrap();
+        evicted += last - expected;
+        expected = last + 1;
+        while !ring_buf.is_empty() {
+            let (seq, _) = ring_buf.read_seq().unwrap();
+            evicted += seq - expected;
+            expected = seq + 1;
+        }
+        assert_eq!(evicted, 3);
+    }
+
+    #[test]
+    fn test_lag() {
+        let ring_buf: SequencedRingBuffer<usize, 8> = SequencedRingBuffer::new();
+        // The consumer is paused
+        (0..5).for_each(|i| {
+            ring_buf.write_override(i);
+        });
+        assert_eq!(ring_buf.lag(), 5);
+        assert_eq!(ring_buf.max_lag_since_reset(), 5);
+
+        // Catching up leaves the high-water mark
+        while !ring_buf.is_empty() {
+            ring_buf.read_seq().unwrap();
+        }
+        assert_eq!(ring_buf.lag(), 0);
+        assert_eq!(ring_buf.max_lag_since_reset(), 5);
+
+        // Bounded by the readable cells once writes override
+        (0..20).for_each(|i| {
+            ring_buf.write_override(i);
+        });
+        assert_eq!(ring_buf.lag(), 7);
+        assert_eq!(ring_buf.max_lag_since_reset(), 7);
+
+        ring_buf.read_seq().unwrap();
+        ring_buf.reset_lag_stats();
+        assert_eq!(ring_buf.max_lag_since_reset(), 6);
+        (0..4).for_each(|_| {
+            ring_buf.read_seq().unwrap();
+        });
+        ring_buf.write_override(0);
+        assert_eq!(ring_buf.max_lag_since_reset(), 6);
+    }
+
+    #[test]
+    fn test_batches_latency() {
+        const ITEMS: usize = 60;
+        let ring_buf: RingBuffer<Instant, 128> = RingBuffer::new();
+        let token = ShutdownToken::new();
+        let linger = Duration::from_millis(5);
+        let first_timeout = Duration::from_millis(50);
+        // Generous against scheduling noise, yet well below the pacing of a whole run
+        let slack = Duration::from_millis(30);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..ITEMS {
+                    ring_buf.write_override(Instant::now());
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            });
+            let mut batches = ring_buf
+                .batches(8, first_timeout, linger)
+                .until_shutdown(&token);
+            let mut read = 0;
+            let mut count = 0;
+            while read < ITEMS {
+                let batch = batches.next().unwrap();
+                let returned = Instant::now();
+                assert!(!batch.is_empty());
+                assert!(batch.len() <= 8);
+                // Written, read, then lingered on
+                assert!(returned - batch[0] < linger + slack);
+                read += batch.len();
+                count += 1;
+            }
+            assert!(count < ITEMS);
+
+            let start = Instant::now();
+            assert_eq!(batches.next().unwrap(), []);
+            assert!(first_timeout <= start.elapsed());
+            assert!(start.elapsed() < first_timeout + slack);
+            token.shutdown();
+            assert!(batches.next().is_none());
+        });
+    }
+
+    #[test]
+    fn test_batches_max_items() {
+        let ring_buf: RingBuffer<usize, 32> = RingBuffer::new();
+        (0..20).for_each(|i| ring_buf.write_override(i));
+        let sizes = ring_buf
+            .batches(8, Duration::ZERO, Duration::from_millis(10))
+            .take(3)
+            .map(|batch| batch.len())
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, [8, 8, 4]);
+        assert_eq!(
+            ring_buf.read_timeout(Duration::from_millis(10)),
+            Err(RecvError::TimedOut)
+        );
+    }
+
+    #[test]
+    fn test_batches_unbounded_linger() {
+        let ring_buf: RingBuffer<usize, 16> = RingBuffer::new();
+        (0..8).for_each(|i| ring_buf.write_override(i));
+        let batches = ring_buf
+            .batches(4, Duration::ZERO, Duration::MAX)
+            .take(2)
+            .collect::<Vec<_>>();
+        assert_eq!(batches, [vec![0, 1, 2, 3], vec![4, 5, 6, 7]]);
+    }
+
+    #[test]
+    fn test_credits_gate_writes() {
+        let ring_buf = RingBuffer::<usize, 8>::builder()
+            .full_policy(FullPolicy::Reject)
+            .with_credits(4)
+            .build();
+        assert_eq!(ring_buf.credits(), Some(7));
+        (0..7).for_each(|i| ring_buf.write(i).unwrap());
+        assert_eq!(ring_buf.write(7), Err(7));
+        // Credits only come back a batch at a time
+        (0..3).for_each(|i| assert_eq!(ring_buf.read(), Ok(i)));
+        assert_eq!(ring_buf.credits(), Some(0));
+        assert_eq!(ring_buf.write(7), Err(7));
+        assert_eq!(ring_buf.read(), Ok(3));
+        assert_eq!(ring_buf.credits(), Some(4));
+        (7..11).for_each(|i| ring_buf.write(i).unwrap());
+        assert_eq!(ring_buf.write(11), Err(11));
+        // An extra grant runs into the full buffer
+        ring_buf.grant(1);
+        assert_eq!(ring_buf.write(11), Err(11));
+        assert_eq!(ring_buf.credits(), Some(1));
+        assert_eq!(RingBuffer::<usize, 8>::new().credits(), None);
+    }
+
+    #[test]
+    fn test_credit_wakes_scale_with_batches() {
+        const WRITERS: usize = 4;
+        const ITEMS: usize = 4_000;
+        const BATCH: usize = 16;
+        const N: usize = 64;
+        let ring_buf = RingBuffer::<(usize, usize), N>::builder()
+            .full_policy(FullPolicy::Block)
+            .with_credits(BATCH)
+            .build();
+        let wakes = std::thread::scope(|s| {
+            for w in 0..WRITERS {
+                let ring_buf = &ring_buf;
+                s.spawn(move || {
+                    for i in 0..ITEMS / WRITERS {
+                        ring_buf.write((w, i)).unwrap();
+                    }
+                });
+            }
+            let before = WAKE_SYSCALLS.get();
+            let mut next = [0; WRITERS];
+            for _ in 0..ITEMS {
+                // Only the reader returns credits, and writers take one before they claim a cell
+                let len = ring_buf.len();
+                let credits = ring_buf.credits().unwrap();
+                let consumed = ring_buf.header.consumed.load(Ordering::Relaxed);
+                assert!(len + credits + consumed < N);
+                let (w, i) = ring_buf.read().unwrap();
+                assert_eq!(next[w], i);
+                next[w] += 1;
+            }
+            WAKE_SYSCALLS.get() - before
+        });
+        assert!(wakes <= ITEMS / BATCH, "{wakes}");
+        assert_eq!(ring_buf.credits(), Some(N - 1));
+    }
 }
@@ -0,0 +1,449 @@
+//! Robust futexes: locks shared between processes that survive a holder dying with them held.
+//!
+//! Each thread hands the kernel a list of the robust locks it holds with `set_robust_list`.
+//! As the thread exits, however it exits, the kernel walks the list, marks every lock still held by it with `FUTEX_OWNER_DIED`, and wakes a waiter of each.
+//! The next locker then acquires the lock along with the news that the state it protects may be half-updated.
+//!
+//! # Word encoding
+//!
+//! Fixed by the kernel:
+//!
+//! - The low 30 bits hold the TID of the holder, or `0` if unlocked.
+//! - The next bit, `FUTEX_OWNER_DIED`, is set by the kernel as the holder dies, and kept by the lock until a holder [marks the state consistent](RobustGuard::mark_consistent).
+//! - The top bit, `FUTEX_WAITERS`, is set by a waiter before it sleeps, so that the unlock, or the kernel on the death of the holder, wakes one.
+//!
+//! # Displacing libc
+//!
+//! A thread has one robust list, and glibc registers its own for the robust `pthread_mutex_t`s.
+//! The first robust lock of a thread replaces that list with the one of this module, so the same thread must not also rely on robust `pthread_mutex_t`s.
+
+use std::{
+    cell::Cell,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        LockResult, Once, PoisonError, TryLockError, TryLockResult,
+    },
+    time::Instant,
+};
+
+use crate::{
+    compat::Recoverable, deadline::TimedOut, pi::TID_MASK, FutexError, FutexScope, FutexTimeout,
+    FutexWaitContext, WakeWaiters,
+};
+
+/// `FUTEX_OWNER_DIED` from `linux/futex.h`
+const OWNER_DIED: u32 = 0x4000_0000;
+/// `FUTEX_WAITERS` from `linux/futex.h`
+const WAITERS: u32 = 0x8000_0000;
+
+/// A lock word the kernel can recover from a dead holder, along with its node on the robust list of the holder.
+///
+/// Place it in memory shared between the processes, e.g., with [`crate::ipc::SharedMapping`].
+///
+/// # Zero initialization
+///
+/// The all-zero bit pattern is an unlocked word, made as by [`Self::new`].
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct RobustMutexWord {
+    /// `struct robust_list`: the next node on the robust list of the holder, or the head of that list
+    ///
+    /// Only meaningful in the address space of the holder, and only touched by it.
+    next: AtomicUsize,
+    word: AtomicU32,
+}
+impl RobustMutexWord {
+    pub const fn new() -> Self {
+        Self {
+            next: AtomicUsize::new(0),
+            word: AtomicU32::new(0),
+        }
+    }
+
+    /// Block until the calling thread holds the lock.
+    ///
+    /// Fail with a [`PoisonError`] carrying the guard if a holder died with the lock held since a holder last [marked the state consistent](RobustGuard::mark_consistent).
+    ///
+    /// # Panic
+    ///
+    /// If the calling thread already holds the lock, or if the kernel refuses the robust list.
+    pub fn lock(&self) -> LockResult<RobustGuard<'_>> {
+        self.lock_until(None).unwrap()
+    }
+
+    /// [`Self::lock`] without blocking.
+    pub fn try_lock(&self) -> TryLockResult<RobustGuard<'_>> {
+        match self.lock_until(Some(Instant::now())) {
+            Ok(res) => Ok(res?),
+            Err(TimedOut(())) => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Only a snapshot.
+    pub fn is_locked(&self) -> bool {
+        self.word.load(Ordering::Relaxed) & TID_MASK != 0
+    }
+
+    fn lock_until(
+        &self,
+        deadline: Option<Instant>,
+    ) -> Result<LockResult<RobustGuard<'_>>, TimedOut> {
+        RobustList::with(|list| {
+            // The kernel also recovers the word if the thread dies in the middle of the acquisition
+            list.head.pending.set(self.node());
+            let acquired = self.acquire(list.tid.get(), deadline);
+            if acquired.is_ok() {
+                list.push(self);
+            }
+            list.head.pending.set(0);
+            acquired
+        })?;
+        let guard = RobustGuard {
+            lock: self,
+            _thread: PhantomData,
+        };
+        Ok(match self.word.load(Ordering::Relaxed) & OWNER_DIED {
+            0 => Ok(guard),
+            _ => Err(PoisonError::new(guard)),
+        })
+    }
+
+    fn acquire(&self, tid: u32, deadline: Option<Instant>) -> Result<(), TimedOut> {
+        let timeout = deadline.map(FutexTimeout::Until);
+        // Once asleep, this locker cannot tell whether others still are, so it keeps asking for a wake
+        let mut waiters = 0;
+        loop {
+            let word = self.word.load(Ordering::Relaxed);
+            let holder = word & TID_MASK;
+            if holder == 0 {
+                let new = tid | (word & (OWNER_DIED | WAITERS)) | waiters;
+                if self
+                    .word
+                    .compare_exchange(word, new, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    return Ok(());
+                }
+                continue;
+            }
+            if timeout.is_some_and(|t| t.remaining().is_zero()) {
+                return Err(TimedOut(()));
+            }
+            assert_ne!(holder, tid, "already held by the calling thread");
+            if word & WAITERS == 0
+                && self
+                    .word
+                    .compare_exchange(word, word | WAITERS, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_err()
+            {
+                continue;
+            }
+            waiters = WAITERS;
+            let cx = FutexWaitContext {
+                word: &self.word,
+                expected: word | WAITERS,
+                timeout,
+                scope: FutexScope::Shared,
+            };
+            if let Err(e) = crate::futex_wait(cx) {
+                if !matches!(
+                    e,
+                    FutexError::ValueMismatch | FutexError::Interrupted | FutexError::TimedOut
+                ) {
+                    panic!("{e}");
+                }
+            }
+        }
+    }
+
+    fn node(&self) -> usize {
+        self as *const Self as usize
+    }
+}
+/// A recovery locks the word, runs the closure, and marks the state consistent once it returns.
+impl Recoverable for RobustMutexWord {
+    type Inner = ();
+
+    fn is_poisoned(&self) -> bool {
+        self.word.load(Ordering::Relaxed) & OWNER_DIED != 0
+    }
+
+    fn clear_poison(&self) {
+        self.word.fetch_and(!OWNER_DIED, Ordering::Relaxed);
+    }
+
+    fn recover_with(&self, f: impl FnOnce(&mut ())) {
+        let mut guard = self.lock().unwrap_or_else(PoisonError::into_inner);
+        f(&mut ());
+        guard.mark_consistent();
+    }
+
+    fn recover_until(&self, deadline: Instant, f: impl FnOnce(&mut ())) -> Result<(), TimedOut> {
+        let mut guard = self
+            .lock_until(Some(deadline))?
+            .unwrap_or_else(PoisonError::into_inner);
+        f(&mut ());
+        guard.mark_consistent();
+        Ok(())
+    }
+}
+
+/// Unlocks on drop, keeping the owner-died indication unless [`Self::mark_consistent`] was called.
+///
+/// Tied to the thread that locked, whose robust list holds the lock.
+#[must_use]
+#[derive(Debug)]
+pub struct RobustGuard<'a> {
+    lock: &'a RobustMutexWord,
+    _thread: PhantomData<*const ()>,
+}
+impl RobustGuard<'_> {
+    /// Whether no holder died with the lock held since the state was last marked consistent.
+    pub fn is_consistent(&self) -> bool {
+        self.lock.word.load(Ordering::Relaxed) & OWNER_DIED == 0
+    }
+
+    /// Declare the protected state repaired, so that the next locker acquires the lock without the owner-died indication.
+    pub fn mark_consistent(&mut self) {
+        self.lock.word.fetch_and(!OWNER_DIED, Ordering::Relaxed);
+    }
+}
+impl Drop for RobustGuard<'_> {
+    fn drop(&mut self) {
+        let word = RobustList::with(|list| {
+            // The kernel also recovers the word if the thread dies in the middle of the release
+            list.head.pending.set(self.lock.node());
+            list.remove(self.lock);
+            let kept = self.lock.word.load(Ordering::Relaxed) & OWNER_DIED;
+            let word = self.lock.word.swap(kept, Ordering::Release);
+            list.head.pending.set(0);
+            word
+        });
+        if word & WAITERS != 0 {
+            crate::futex_wake(&self.lock.word, WakeWaiters::ONE).unwrap();
+        }
+    }
+}
+
+/// `struct robust_list_head` from `linux/futex.h`
+#[repr(C)]
+struct Head {
+    /// The first node, or the head itself if the list is empty
+    next: Cell<usize>,
+    /// From a node to its lock word
+    futex_offset: libc::c_long,
+    /// A node being locked or unlocked, or `0`
+    pending: Cell<usize>,
+}
+
+/// The robust list of the calling thread, registered with the kernel on the first robust lock of the thread.
+pub struct RobustList {
+    head: Head,
+    /// Of the thread in the process the list was registered in, refreshed in a forked child
+    tid: Cell<u32>,
+    /// [`FORKS`] as of the registration, or [`u32::MAX`] if never registered
+    forks: Cell<u32>,
+}
+thread_local! {
+    static LIST: RobustList = const {
+        RobustList {
+            head: Head {
+                next: Cell::new(0),
+                futex_offset: std::mem::offset_of!(RobustMutexWord, word) as libc::c_long,
+                pending: Cell::new(0),
+            },
+            tid: Cell::new(0),
+            forks: Cell::new(u32::MAX),
+        }
+    };
+}
+/// Bumped in every child forked, whose threads start out with no robust list and a new TID.
+static FORKS: AtomicU32 = AtomicU32::new(0);
+impl RobustList {
+    /// Register the robust list of the calling thread with the kernel unless already registered in this process.
+    ///
+    /// The robust locks register it themselves; call this up front to fail early instead of panicking in the first lock.
+    pub fn register() -> std::io::Result<()> {
+        static AT_FORK: Once = Once::new();
+        AT_FORK.call_once(|| {
+            extern "C" fn child() {
+                FORKS.fetch_add(1, Ordering::Relaxed);
+            }
+            let ret = unsafe { libc::pthread_atfork(None, None, Some(child)) };
+            assert_eq!(ret, 0);
+        });
+        LIST.with(|list| {
+            let forks = FORKS.load(Ordering::Relaxed);
+            if list.forks.get() == forks {
+                return Ok(());
+            }
+            // Whatever a forked child inherited is held by the parent
+            let head = &list.head as *const Head as usize;
+            list.head.next.set(head);
+            list.head.pending.set(0);
+            let ret = unsafe {
+                libc::syscall(libc::SYS_set_robust_list, head, std::mem::size_of::<Head>())
+            };
+            if ret != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let tid = rustix::thread::gettid().as_raw_nonzero().get();
+            list.tid.set(tid.unsigned_abs());
+            list.forks.set(forks);
+            Ok(())
+        })
+    }
+
+    /// # Panic
+    ///
+    /// If the registration fails.
+    fn with<R>(f: impl FnOnce(&Self) -> R) -> R {
+        Self::register().unwrap();
+        LIST.with(f)
+    }
+
+    fn push(&self, lock: &RobustMutexWord) {
+        lock.next.store(self.head.next.get(), Ordering::Relaxed);
+        self.head.next.set(lock.node());
+    }
+
+    fn remove(&self, lock: &RobustMutexWord) {
+        let head = &self.head as *const Head as usize;
+        let next = lock.next.load(Ordering::Relaxed);
+        if self.head.next.get() == lock.node() {
+            self.head.next.set(next);
+            return;
+        }
+        let mut node = self.head.next.get();
+        while node != head {
+            // Every node on the list is a lock held by this thread
+            let held = unsafe { &*(node as *const RobustMutexWord) };
+            if held.next.load(Ordering::Relaxed) == lock.node() {
+                held.next.store(next, Ordering::Relaxed);
+                return;
+            }
+            node = held.next.load(Ordering::Relaxed);
+        }
+        unreachable!("a held robust lock is missing from the robust list");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::ipc::SharedMapping;
+
+    #[test]
+    fn test_lock_unlock_across_threads() {
+        let lock = RobustMutexWord::new();
+        let counter = AtomicU32::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..500 {
+                        let guard = lock.lock().unwrap();
+                        let n = counter.load(Ordering::Relaxed);
+                        counter.store(n + 1, Ordering::Relaxed);
+                        drop(guard);
+                    }
+                });
+            }
+        });
+        assert_eq!(counter.load(Ordering::Relaxed), 2000);
+        assert_eq!(lock.word.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_nested_locks_unlinked_out_of_order() {
+        let locks: [RobustMutexWord; 3] = Default::default();
+        let a = locks[0].lock().unwrap();
+        let b = locks[1].lock().unwrap();
+        let c = locks[2].lock().unwrap();
+        assert!(matches!(locks[1].try_lock(), Err(TryLockError::WouldBlock)));
+        drop(b);
+        drop(a);
+        let b = locks[1].lock().unwrap();
+        drop(c);
+        drop(b);
+        LIST.with(|list| {
+            let head = &list.head as *const Head as usize;
+            assert_eq!(list.head.next.get(), head);
+        });
+        assert!(locks.iter().all(|lock| !lock.is_locked()));
+    }
+
+    #[test]
+    fn test_thread_death_recovered() {
+        let lock = RobustMutexWord::new();
+        std::thread::scope(|s| {
+            // Joined only once the kernel has walked its robust list
+            s.spawn(|| std::mem::forget(lock.lock().unwrap()))
+                .join()
+                .unwrap();
+        });
+        assert!(lock.is_poisoned());
+        assert!(!lock.is_locked());
+
+        // Not marked consistent, so the next locker hears of it too
+        drop(lock.lock().unwrap_err().into_inner());
+        lock.recover_with(|()| ());
+        assert!(!lock.is_poisoned());
+        assert!(lock.lock().unwrap().is_consistent());
+    }
+
+    #[test]
+    fn test_child_dies_holding_lock() {
+        use nix::{
+            sys::wait::{waitpid, WaitStatus},
+            unistd::{fork, ForkResult},
+        };
+
+        let lock = SharedMapping::new(RobustMutexWord::new()).unwrap();
+        // Registered before the fork, so the child has to register anew
+        drop(lock.lock().unwrap());
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let ok = std::panic::catch_unwind(|| std::mem::forget(lock.lock().unwrap()));
+                unsafe { libc::_exit(i32::from(ok.is_err())) };
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut guard = match lock.lock_until(Some(deadline)).unwrap() {
+            Ok(_) => panic!("the owner-died indication is lost"),
+            Err(e) => e.into_inner(),
+        };
+        assert!(!guard.is_consistent());
+        guard.mark_consistent();
+        drop(guard);
+        assert!(lock.lock().unwrap().is_consistent());
+    }
+
+    #[test]
+    fn test_waiter_woken_by_death() {
+        let lock = RobustMutexWord::new();
+        let held = std::sync::Barrier::new(2);
+        let release = std::sync::Barrier::new(2);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let guard = lock.lock().unwrap();
+                held.wait();
+                release.wait();
+                std::mem::forget(guard);
+            });
+            held.wait();
+            let waiter = s.spawn(|| lock.lock().is_err());
+            while lock.word.load(Ordering::Relaxed) & WAITERS == 0 {
+                std::thread::yield_now();
+            }
+            release.wait();
+            assert!(waiter.join().unwrap());
+        });
+    }
+}
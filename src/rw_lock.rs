@@ -0,0 +1,1038 @@
+use std::{
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::Instant,
+};
+
+use crate::{
+    observer::{futex_wake_from, observed_futex_wait, Primitive},
+    violation::violation,
+    FutexError, FutexScope, FutexTimeout, FutexWaitContext, TimeoutMeasure, WakeWaiters,
+};
+
+const WRITER: u64 = 1 << 0;
+const UPGRADABLE: u64 = 1 << 1;
+const UPGRADING: u64 = 1 << 2;
+const READERS_SHIFT: u32 = 3;
+const PARKED_READERS_SHIFT: u32 = 23;
+const PARKED_WRITERS_SHIFT: u32 = 39;
+const PARKED_UPGRADABLES_SHIFT: u32 = 51;
+const GENERATION_SHIFT: u32 = 57;
+const READERS_MASK: u64 = field_mask(READERS_SHIFT, PARKED_READERS_SHIFT);
+const PARKED_READERS_MASK: u64 = field_mask(PARKED_READERS_SHIFT, PARKED_WRITERS_SHIFT);
+const PARKED_WRITERS_MASK: u64 = field_mask(PARKED_WRITERS_SHIFT, PARKED_UPGRADABLES_SHIFT);
+const PARKED_UPGRADABLES_MASK: u64 = field_mask(PARKED_UPGRADABLES_SHIFT, GENERATION_SHIFT);
+const ONE_READER: u64 = 1 << READERS_SHIFT;
+const ONE_PARKED_READER: u64 = 1 << PARKED_READERS_SHIFT;
+const ONE_PARKED_WRITER: u64 = 1 << PARKED_WRITERS_SHIFT;
+const ONE_PARKED_UPGRADABLE: u64 = 1 << PARKED_UPGRADABLES_SHIFT;
+const ONE_GENERATION: u64 = 1 << GENERATION_SHIFT;
+
+const fn field_mask(shift: u32, next_shift: u32) -> u64 {
+    ((1 << (next_shift - shift)) - 1) << shift
+}
+
+/// Who gets the lock next when both readers and writers are waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RwLockPolicy {
+    /// Readers get in whenever no writer holds the lock, so writers can starve.
+    ReaderPreference,
+    /// New readers queue behind any waiting writer, and a released writer hands over to the next waiting writer first, so readers can starve.
+    #[default]
+    WriterPreference,
+    /// New readers queue behind any waiting writer, but a released writer hands over to all the queued readers first.
+    ///
+    /// Reading and writing phases alternate while both sides are waiting, so neither side starves.
+    PhaseFair,
+}
+
+/// The raw reader-writer lock state behind [`RwLock`].
+///
+/// # State layout
+///
+/// - Bit 0: an exclusive lock is held.
+/// - Bit 1: an upgradable lock is held; it shares access with plain readers but excludes other upgradable and exclusive locks.
+/// - Bit 2: the upgradable lock holder is waiting in [`Self::upgrade`].
+/// - Bits 3..23: the number of shared locks held, excluding the upgradable one.
+/// - Bits 23..39: the number of parked readers.
+/// - Bits 39..51: the number of parked writers.
+/// - Bits 51..57: the number of threads parked in [`Self::lock_upgradable`].
+/// - Bits 57..64: the reader generation, bumped whenever the parked readers are granted the lock.
+///
+/// The generation has 7 bits, so it comes back around after `1 << 7` grants.
+/// A granted reader that has yet to check the generation then finds it unchanged, and sleeps on holding its shared lock until another grant comes, if ever.
+/// Since it holds that lock all along, no writer gets in to grant the readers parked after it on release; only a writer giving up on a timeout does, so this takes a reader descheduled across `1 << 7` writers timing out, each with readers parked behind it.
+///
+/// # Waking
+///
+/// Each kind of waiter sleeps on its own futex word, so a release wakes exactly who the policy picks:
+///
+/// - Parked readers never retry; a release grants all of them the lock in the same atomic update and then wakes them all.
+///   A parked reader only checks that the generation has moved on.
+/// - Parked writers retry; a release that lets a writer in wakes one of them.
+/// - Threads parked in [`Self::lock_upgradable`] retry; a release that lets one in wakes all of them.
+/// - The upgrader is woken by the last reader to leave.
+///
+/// A waiter registers itself in the state before sampling its futex word, and a releaser bumps the futex word after updating the state, so no wake-up is lost.
+#[derive(Debug)]
+pub struct RawFutexRwLock {
+    state: AtomicU64,
+    readers_word: AtomicU32,
+    writers_word: AtomicU32,
+    upgradables_word: AtomicU32,
+    upgrader_word: AtomicU32,
+    policy: RwLockPolicy,
+}
+impl RawFutexRwLock {
+    /// Use [`RwLockPolicy::WriterPreference`].
+    pub const fn new() -> Self {
+        Self::with_policy(RwLockPolicy::WriterPreference)
+    }
+
+    pub const fn with_policy(policy: RwLockPolicy) -> Self {
+        Self {
+            state: AtomicU64::new(0),
+            readers_word: AtomicU32::new(0),
+            writers_word: AtomicU32::new(0),
+            upgradables_word: AtomicU32::new(0),
+            upgrader_word: AtomicU32::new(0),
+            policy,
+        }
+    }
+
+    pub fn policy(&self) -> RwLockPolicy {
+        self.policy
+    }
+
+    pub fn lock_shared(&self) {
+        self.lock_shared_deadline(None);
+    }
+
+    pub fn try_lock_shared(&self) -> bool {
+        self.update(|s| {
+            if self.blocks_readers(s) {
+                return None;
+            }
+            Some(add(s, ONE_READER, READERS_MASK))
+        })
+        .is_ok()
+    }
+
+    /// Return `false` on timeout.
+    pub fn try_lock_shared_until(&self, deadline: Instant) -> bool {
+        self.lock_shared_deadline(Some(deadline))
+    }
+
+    fn lock_shared_deadline(&self, deadline: Option<Instant>) -> bool {
+        // Take a shared lock or register as a parked reader
+        let prev = self
+            .update(|s| {
+                if self.blocks_readers(s) {
+                    return Some(add(s, ONE_PARKED_READER, PARKED_READERS_MASK));
+                }
+                Some(add(s, ONE_READER, READERS_MASK))
+            })
+            .unwrap();
+        if !self.blocks_readers(prev) {
+            return true;
+        }
+        let generation = prev >> GENERATION_SHIFT;
+
+        loop {
+            let seq = self.readers_word.load(Ordering::SeqCst);
+            if self.state.load(Ordering::SeqCst) >> GENERATION_SHIFT != generation {
+                // Granted
+                return true;
+            }
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        // Deregister unless granted in the meantime
+                        return self
+                            .update(|s| {
+                                if s >> GENERATION_SHIFT != generation {
+                                    return None;
+                                }
+                                Some(s - ONE_PARKED_READER)
+                            })
+                            .is_err();
+                    }
+                    Some(FutexTimeout::For(remaining, TimeoutMeasure::MonoTime))
+                }
+                None => None,
+            };
+            sleep(&self.readers_word, seq, timeout);
+        }
+    }
+
+    /// # Safety
+    ///
+    /// A shared lock must be held by the caller.
+    pub unsafe fn unlock_shared(&self) {
+        let prev = self.state.fetch_sub(ONE_READER, Ordering::SeqCst);
+        debug_assert!(prev & READERS_MASK != 0);
+        let s = prev - ONE_READER;
+        if s & READERS_MASK != 0 {
+            return;
+        }
+        // The last reader
+        if s & UPGRADING != 0 {
+            self.wake(Wake::Upgrader);
+        } else if s & UPGRADABLE == 0 && s & PARKED_WRITERS_MASK != 0 {
+            self.wake(Wake::Writer);
+        }
+    }
+
+    pub fn lock_exclusive(&self) {
+        self.lock_exclusive_deadline(None);
+    }
+
+    pub fn try_lock_exclusive(&self) -> bool {
+        self.update(|s| {
+            if !is_free_for_writer(s) {
+                return None;
+            }
+            Some(s | WRITER)
+        })
+        .is_ok()
+    }
+
+    /// Return `false` on timeout.
+    pub fn try_lock_exclusive_until(&self, deadline: Instant) -> bool {
+        self.lock_exclusive_deadline(Some(deadline))
+    }
+
+    fn lock_exclusive_deadline(&self, deadline: Option<Instant>) -> bool {
+        // Take the exclusive lock or register as a parked writer
+        let prev = self
+            .update(|s| {
+                if !is_free_for_writer(s) {
+                    return Some(add(s, ONE_PARKED_WRITER, PARKED_WRITERS_MASK));
+                }
+                Some(s | WRITER)
+            })
+            .unwrap();
+        if is_free_for_writer(prev) {
+            return true;
+        }
+
+        loop {
+            let seq = self.writers_word.load(Ordering::SeqCst);
+            let acquired = self.update(|s| {
+                if !is_free_for_writer(s) {
+                    return None;
+                }
+                Some((s | WRITER) - ONE_PARKED_WRITER)
+            });
+            if acquired.is_ok() {
+                return true;
+            }
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        self.deregister_writer();
+                        return false;
+                    }
+                    Some(FutexTimeout::For(remaining, TimeoutMeasure::MonoTime))
+                }
+                None => None,
+            };
+            sleep(&self.writers_word, seq, timeout);
+        }
+    }
+
+    fn deregister_writer(&self) {
+        let mut wakes = [None; 3];
+        self.update(|s| {
+            wakes = [None; 3];
+            let mut s = s - ONE_PARKED_WRITER;
+            // Readers might have been held back only by this writer
+            if s & PARKED_WRITERS_MASK == 0 && !self.blocks_readers(s) {
+                s = grant_readers(s, &mut wakes[0]);
+                wakes[1] = self.upgradables_to_wake(s);
+            }
+            // This writer might have absorbed a wake-up meant for another one
+            if s & PARKED_WRITERS_MASK != 0 && is_free_for_writer(s) {
+                wakes[2] = Some(Wake::Writer);
+            }
+            Some(s)
+        })
+        .unwrap();
+        wakes.into_iter().flatten().for_each(|w| self.wake(w));
+    }
+
+    /// # Safety
+    ///
+    /// An exclusive lock must be held by the caller.
+    pub unsafe fn unlock_exclusive(&self) {
+        let mut wake = None;
+        let mut upgradables = None;
+        self.update(|s| {
+            wake = None;
+            debug_assert!(s & WRITER != 0);
+            let mut s = s & !WRITER;
+            let parked_readers = s & PARKED_READERS_MASK != 0;
+            let parked_writers = s & PARKED_WRITERS_MASK != 0;
+            match self.policy {
+                RwLockPolicy::ReaderPreference | RwLockPolicy::PhaseFair => {
+                    if parked_readers {
+                        s = grant_readers(s, &mut wake);
+                    } else if parked_writers {
+                        wake = Some(Wake::Writer);
+                    }
+                }
+                RwLockPolicy::WriterPreference => {
+                    if parked_writers {
+                        wake = Some(Wake::Writer);
+                    } else if parked_readers {
+                        s = grant_readers(s, &mut wake);
+                    }
+                }
+            }
+            upgradables = self.upgradables_to_wake(s);
+            Some(s)
+        })
+        .unwrap();
+        [wake, upgradables]
+            .into_iter()
+            .flatten()
+            .for_each(|w| self.wake(w));
+    }
+
+    /// Turn the held exclusive lock into a shared one, letting in the parked readers the policy allows.
+    ///
+    /// # Safety
+    ///
+    /// An exclusive lock must be held by the caller.
+    pub unsafe fn downgrade(&self) {
+        let mut wake = None;
+        let mut upgradables = None;
+        self.update(|s| {
+            wake = None;
+            debug_assert!(s & WRITER != 0);
+            let mut s = (s & !WRITER) + ONE_READER;
+            let admits_parked_readers = match self.policy {
+                RwLockPolicy::ReaderPreference | RwLockPolicy::PhaseFair => true,
+                RwLockPolicy::WriterPreference => s & PARKED_WRITERS_MASK == 0,
+            };
+            if admits_parked_readers {
+                s = grant_readers(s, &mut wake);
+            }
+            upgradables = self.upgradables_to_wake(s);
+            Some(s)
+        })
+        .unwrap();
+        [wake, upgradables]
+            .into_iter()
+            .flatten()
+            .for_each(|w| self.wake(w));
+    }
+
+    pub fn lock_upgradable(&self) {
+        self.lock_upgradable_deadline(None);
+    }
+
+    pub fn try_lock_upgradable(&self) -> bool {
+        self.update(|s| {
+            if !self.admits_upgradable(s) {
+                return None;
+            }
+            Some(s | UPGRADABLE)
+        })
+        .is_ok()
+    }
+
+    /// Return `false` on timeout.
+    pub fn try_lock_upgradable_until(&self, deadline: Instant) -> bool {
+        self.lock_upgradable_deadline(Some(deadline))
+    }
+
+    fn lock_upgradable_deadline(&self, deadline: Option<Instant>) -> bool {
+        // Take the upgradable lock or register as a parked one
+        let prev = self
+            .update(|s| {
+                if !self.admits_upgradable(s) {
+                    return Some(add(s, ONE_PARKED_UPGRADABLE, PARKED_UPGRADABLES_MASK));
+                }
+                Some(s | UPGRADABLE)
+            })
+            .unwrap();
+        if self.admits_upgradable(prev) {
+            return true;
+        }
+
+        loop {
+            let seq = self.upgradables_word.load(Ordering::SeqCst);
+            let acquired = self.update(|s| {
+                if !self.admits_upgradable(s) {
+                    return None;
+                }
+                Some((s | UPGRADABLE) - ONE_PARKED_UPGRADABLE)
+            });
+            if acquired.is_ok() {
+                return true;
+            }
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        // The others are woken all at once, so no wake-up can have been absorbed
+                        self.state
+                            .fetch_sub(ONE_PARKED_UPGRADABLE, Ordering::SeqCst);
+                        return false;
+                    }
+                    Some(FutexTimeout::For(remaining, TimeoutMeasure::MonoTime))
+                }
+                None => None,
+            };
+            sleep(&self.upgradables_word, seq, timeout);
+        }
+    }
+
+    /// # Safety
+    ///
+    /// An upgradable lock must be held by the caller.
+    pub unsafe fn unlock_upgradable(&self) {
+        let prev = self.state.fetch_and(!UPGRADABLE, Ordering::SeqCst);
+        debug_assert!(prev & UPGRADABLE != 0);
+        let s = prev & !UPGRADABLE;
+        if s & PARKED_WRITERS_MASK != 0 && is_free_for_writer(s) {
+            self.wake(Wake::Writer);
+        }
+        if let Some(wake) = self.upgradables_to_wake(s) {
+            self.wake(wake);
+        }
+    }
+
+    /// Block until the other readers are gone.
+    ///
+    /// Under every policy but [`RwLockPolicy::ReaderPreference`], new readers queue up in the meantime.
+    ///
+    /// # Safety
+    ///
+    /// An upgradable lock must be held by the caller.
+    pub unsafe fn upgrade(&self) {
+        if self.try_upgrade() {
+            return;
+        }
+        self.state.fetch_or(UPGRADING, Ordering::SeqCst);
+        loop {
+            let seq = self.upgrader_word.load(Ordering::SeqCst);
+            let upgraded = self.update(|s| {
+                if s & READERS_MASK != 0 {
+                    return None;
+                }
+                Some((s & !(UPGRADABLE | UPGRADING)) | WRITER)
+            });
+            if upgraded.is_ok() {
+                return;
+            }
+            sleep(&self.upgrader_word, seq, None);
+        }
+    }
+
+    /// # Safety
+    ///
+    /// An upgradable lock must be held by the caller.
+    pub unsafe fn try_upgrade(&self) -> bool {
+        self.update(|s| {
+            debug_assert!(s & UPGRADABLE != 0);
+            if s & READERS_MASK != 0 {
+                return None;
+            }
+            Some((s & !UPGRADABLE) | WRITER)
+        })
+        .is_ok()
+    }
+
+    /// Only a snapshot.
+    pub fn is_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & (WRITER | UPGRADABLE | READERS_MASK) != 0
+    }
+
+    /// Only a snapshot.
+    pub fn is_locked_exclusive(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & WRITER != 0
+    }
+
+    /// Only a snapshot.
+    pub fn parked_readers(&self) -> usize {
+        ((self.state.load(Ordering::Relaxed) & PARKED_READERS_MASK) >> PARKED_READERS_SHIFT)
+            as usize
+    }
+
+    /// Only a snapshot.
+    pub fn parked_writers(&self) -> usize {
+        ((self.state.load(Ordering::Relaxed) & PARKED_WRITERS_MASK) >> PARKED_WRITERS_SHIFT)
+            as usize
+    }
+
+    fn blocks_readers(&self, s: u64) -> bool {
+        if s & WRITER != 0 {
+            return true;
+        }
+        match self.policy {
+            RwLockPolicy::ReaderPreference => false,
+            RwLockPolicy::WriterPreference | RwLockPolicy::PhaseFair => {
+                s & (UPGRADING | PARKED_WRITERS_MASK) != 0
+            }
+        }
+    }
+
+    fn admits_upgradable(&self, s: u64) -> bool {
+        s & (WRITER | UPGRADABLE) == 0 && !self.blocks_readers(s)
+    }
+
+    fn upgradables_to_wake(&self, s: u64) -> Option<Wake> {
+        if s & PARKED_UPGRADABLES_MASK == 0 || !self.admits_upgradable(s) {
+            return None;
+        }
+        Some(Wake::Upgradables)
+    }
+
+    fn update(&self, f: impl FnMut(u64) -> Option<u64>) -> Result<u64, u64> {
+        self.state
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, f)
+    }
+
+    fn wake(&self, wake: Wake) {
+        let (word, amount) = match wake {
+            Wake::Readers => (&self.readers_word, WakeWaiters::All),
+            Wake::Writer => (&self.writers_word, WakeWaiters::ONE),
+            Wake::Upgradables => (&self.upgradables_word, WakeWaiters::All),
+            Wake::Upgrader => (&self.upgrader_word, WakeWaiters::All),
+        };
+        word.fetch_add(1, Ordering::SeqCst);
+        futex_wake_from(Primitive::RwLock, word, amount, FutexScope::Shared).unwrap();
+    }
+}
+impl Default for RawFutexRwLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Wake {
+    Readers,
+    Writer,
+    Upgradables,
+    Upgrader,
+}
+
+fn is_free_for_writer(s: u64) -> bool {
+    s & (WRITER | UPGRADABLE | READERS_MASK) == 0
+}
+
+/// Turn all parked readers into holders.
+fn grant_readers(s: u64, wake: &mut Option<Wake>) -> u64 {
+    let parked = (s & PARKED_READERS_MASK) >> PARKED_READERS_SHIFT;
+    if parked == 0 {
+        return s;
+    }
+    *wake = Some(Wake::Readers);
+    let s = add(
+        s & !PARKED_READERS_MASK,
+        parked << READERS_SHIFT,
+        READERS_MASK,
+    );
+    s.wrapping_add(ONE_GENERATION)
+}
+
+/// # Panic
+///
+/// If the field overflows; learn the policy from [`crate::violation`].
+fn add(s: u64, n: u64, mask: u64) -> u64 {
+    if mask - (s & mask) < n {
+        violation!(Overflow, s);
+    }
+    s + n
+}
+
+fn sleep(word: &AtomicU32, expected: u32, timeout: Option<FutexTimeout>) {
+    if let Err(e) = observed_futex_wait(
+        Primitive::RwLock,
+        FutexWaitContext {
+            word,
+            expected,
+            timeout,
+            scope: FutexScope::Shared,
+        },
+    ) {
+        if !matches!(e.error, FutexError::ValueMismatch | FutexError::TimedOut) {
+            panic!("{e}");
+        }
+    }
+}
+
+#[cfg(feature = "lock_api")]
+mod lock_api_impl {
+    use std::time::Duration;
+
+    use super::*;
+
+    unsafe impl lock_api::RawRwLock for RawFutexRwLock {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const INIT: Self = Self::new();
+        type GuardMarker = lock_api::GuardSend;
+
+        fn lock_shared(&self) {
+            RawFutexRwLock::lock_shared(self)
+        }
+
+        fn try_lock_shared(&self) -> bool {
+            RawFutexRwLock::try_lock_shared(self)
+        }
+
+        unsafe fn unlock_shared(&self) {
+            RawFutexRwLock::unlock_shared(self)
+        }
+
+        fn lock_exclusive(&self) {
+            RawFutexRwLock::lock_exclusive(self)
+        }
+
+        fn try_lock_exclusive(&self) -> bool {
+            RawFutexRwLock::try_lock_exclusive(self)
+        }
+
+        unsafe fn unlock_exclusive(&self) {
+            RawFutexRwLock::unlock_exclusive(self)
+        }
+
+        fn is_locked(&self) -> bool {
+            RawFutexRwLock::is_locked(self)
+        }
+
+        fn is_locked_exclusive(&self) -> bool {
+            RawFutexRwLock::is_locked_exclusive(self)
+        }
+    }
+
+    unsafe impl lock_api::RawRwLockTimed for RawFutexRwLock {
+        type Duration = Duration;
+        type Instant = Instant;
+
+        fn try_lock_shared_for(&self, timeout: Self::Duration) -> bool {
+            match Instant::now().checked_add(timeout) {
+                Some(deadline) => self.try_lock_shared_until(deadline),
+                None => {
+                    RawFutexRwLock::lock_shared(self);
+                    true
+                }
+            }
+        }
+
+        fn try_lock_shared_until(&self, timeout: Self::Instant) -> bool {
+            RawFutexRwLock::try_lock_shared_until(self, timeout)
+        }
+
+        fn try_lock_exclusive_for(&self, timeout: Self::Duration) -> bool {
+            match Instant::now().checked_add(timeout) {
+                Some(deadline) => self.try_lock_exclusive_until(deadline),
+                None => {
+                    RawFutexRwLock::lock_exclusive(self);
+                    true
+                }
+            }
+        }
+
+        fn try_lock_exclusive_until(&self, timeout: Self::Instant) -> bool {
+            RawFutexRwLock::try_lock_exclusive_until(self, timeout)
+        }
+    }
+
+    unsafe impl lock_api::RawRwLockDowngrade for RawFutexRwLock {
+        unsafe fn downgrade(&self) {
+            RawFutexRwLock::downgrade(self)
+        }
+    }
+
+    unsafe impl lock_api::RawRwLockUpgrade for RawFutexRwLock {
+        fn lock_upgradable(&self) {
+            RawFutexRwLock::lock_upgradable(self)
+        }
+
+        fn try_lock_upgradable(&self) -> bool {
+            RawFutexRwLock::try_lock_upgradable(self)
+        }
+
+        unsafe fn unlock_upgradable(&self) {
+            RawFutexRwLock::unlock_upgradable(self)
+        }
+
+        unsafe fn upgrade(&self) {
+            RawFutexRwLock::upgrade(self)
+        }
+
+        unsafe fn try_upgrade(&self) -> bool {
+            RawFutexRwLock::try_upgrade(self)
+        }
+    }
+}
+
+#[cfg(feature = "lock_api")]
+pub type RwLock<T> = lock_api::RwLock<RawFutexRwLock, T>;
+#[cfg(feature = "lock_api")]
+pub type RwLockReadGuard<'a, T> = lock_api::RwLockReadGuard<'a, RawFutexRwLock, T>;
+#[cfg(feature = "lock_api")]
+pub type RwLockWriteGuard<'a, T> = lock_api::RwLockWriteGuard<'a, RawFutexRwLock, T>;
+#[cfg(feature = "lock_api")]
+pub type RwLockUpgradableReadGuard<'a, T> =
+    lock_api::RwLockUpgradableReadGuard<'a, RawFutexRwLock, T>;
+/// Made by [`RwLockReadGuard::map`] or [`RwLockReadGuard::try_map`], e.g., to hand out one entry of a read-mostly map:
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// use futex::rw_lock::{RwLock, RwLockReadGuard};
+///
+/// let lock = RwLock::new(HashMap::from([("a", 1)]));
+/// let a = RwLockReadGuard::map(lock.read(), |m| &m["a"]);
+/// assert_eq!(*a, 1);
+/// // Other readers are still let in
+/// assert!(lock.try_read().is_some());
+/// assert!(lock.try_write().is_none());
+///
+/// // A failed projection returns the original guard
+/// let guard = RwLockReadGuard::try_map(lock.read(), |m| m.get("b")).unwrap_err();
+/// assert_eq!(guard.len(), 1);
+/// ```
+///
+/// The lock is released exactly once, when the mapped guard is dropped.
+#[cfg(feature = "lock_api")]
+pub type MappedRwLockReadGuard<'a, T> = lock_api::MappedRwLockReadGuard<'a, RawFutexRwLock, T>;
+/// Made by [`RwLockWriteGuard::map`] or [`RwLockWriteGuard::try_map`], e.g., to hand out one element of a vector:
+///
+/// ```
+/// use futex::rw_lock::{RwLock, RwLockWriteGuard};
+///
+/// let lock = RwLock::new(vec![1, 2, 3]);
+/// let mut second = RwLockWriteGuard::map(lock.write(), |v| &mut v[1]);
+/// *second = 20;
+/// assert!(lock.try_read().is_none());
+/// drop(second);
+/// assert_eq!(*lock.read(), [1, 20, 3]);
+/// ```
+///
+/// It cannot be downgraded, since the projection could be invalidated by whatever the shared access it downgrades to is allowed to observe.
+/// Downgrade the [`RwLockWriteGuard`] before mapping instead.
+#[cfg(feature = "lock_api")]
+pub type MappedRwLockWriteGuard<'a, T> = lock_api::MappedRwLockWriteGuard<'a, RawFutexRwLock, T>;
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use crate::mock_backend::MockBackend;
+
+    use super::*;
+
+    #[test]
+    fn test_readers_exclude_writer() {
+        let lock = RawFutexRwLock::new();
+        lock.lock_shared();
+        assert!(lock.try_lock_shared());
+        assert!(!lock.try_lock_exclusive());
+        let mock = MockBackend::install();
+        mock.intercept(&lock);
+        thread::scope(|s| {
+            let writer = s.spawn(|| {
+                lock.lock_exclusive();
+                unsafe { lock.unlock_exclusive() };
+            });
+            mock.wait_until_parked(1);
+            assert!(!writer.is_finished());
+            unsafe { lock.unlock_shared() };
+            // One reader left, so the writer stays parked without a wake
+            assert_eq!(mock.parked(), 1);
+            assert_eq!(mock.wakes(), []);
+            assert!(!writer.is_finished());
+            unsafe { lock.unlock_shared() };
+        });
+        assert!(!lock.is_locked());
+    }
+
+    #[test]
+    fn test_upgrade_downgrade() {
+        let lock = RawFutexRwLock::new();
+        lock.lock_upgradable();
+        assert!(!lock.try_lock_upgradable());
+        assert!(lock.try_lock_shared());
+        assert!(!unsafe { lock.try_upgrade() });
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                unsafe { lock.unlock_shared() };
+            });
+            unsafe { lock.upgrade() };
+        });
+        assert!(lock.is_locked_exclusive());
+        assert!(!lock.try_lock_shared());
+
+        unsafe { lock.downgrade() };
+        assert!(!lock.is_locked_exclusive());
+        assert!(lock.try_lock_shared());
+        unsafe { lock.unlock_shared() };
+        unsafe { lock.unlock_shared() };
+        assert!(!lock.is_locked());
+    }
+
+    #[test]
+    fn test_timed_exclusive() {
+        let lock = RawFutexRwLock::new();
+        lock.lock_shared();
+        let start = Instant::now();
+        assert!(!lock.try_lock_exclusive_until(start + Duration::from_millis(50)));
+        assert!(Duration::from_millis(50) <= start.elapsed());
+        unsafe { lock.unlock_shared() };
+        assert!(lock.try_lock_exclusive_until(Instant::now() + Duration::from_millis(50)));
+        unsafe { lock.unlock_exclusive() };
+    }
+
+    const POLICIES: [RwLockPolicy; 3] = [
+        RwLockPolicy::ReaderPreference,
+        RwLockPolicy::WriterPreference,
+        RwLockPolicy::PhaseFair,
+    ];
+
+    #[test]
+    fn test_stress() {
+        for policy in POLICIES {
+            let lock = RawFutexRwLock::with_policy(policy);
+            let mut value: usize = 0;
+            let value_ptr = &mut value as *mut usize as usize;
+            thread::scope(|s| {
+                for _ in 0..8 {
+                    s.spawn(|| {
+                        for i in 0..1000 {
+                            match i % 8 {
+                                0 => {
+                                    lock.lock_exclusive();
+                                    unsafe { *(value_ptr as *mut usize) += 1 };
+                                    unsafe { lock.unlock_exclusive() };
+                                }
+                                1 => {
+                                    lock.lock_upgradable();
+                                    unsafe { lock.upgrade() };
+                                    unsafe { *(value_ptr as *mut usize) += 1 };
+                                    unsafe { lock.downgrade() };
+                                    unsafe { lock.unlock_shared() };
+                                }
+                                2 => {
+                                    let deadline = Instant::now() + Duration::from_micros(50);
+                                    if lock.try_lock_exclusive_until(deadline) {
+                                        unsafe { lock.unlock_exclusive() };
+                                    }
+                                }
+                                3 => {
+                                    let deadline = Instant::now() + Duration::from_micros(50);
+                                    if lock.try_lock_shared_until(deadline) {
+                                        unsafe { lock.unlock_shared() };
+                                    }
+                                }
+                                4 => {
+                                    let deadline = Instant::now() + Duration::from_micros(50);
+                                    if lock.try_lock_upgradable_until(deadline) {
+                                        unsafe { lock.unlock_upgradable() };
+                                    }
+                                }
+                                _ => {
+                                    lock.lock_shared();
+                                    let _ = unsafe { *(value_ptr as *const usize) };
+                                    unsafe { lock.unlock_shared() };
+                                }
+                            }
+                        }
+                    });
+                }
+            });
+            assert_eq!(value, 8 * 250, "{policy:?}");
+            assert!(!lock.is_locked());
+            assert_eq!(lock.parked_readers(), 0);
+            assert_eq!(lock.parked_writers(), 0);
+        }
+    }
+
+    #[test]
+    fn test_reader_preference_admits_new_readers() {
+        let lock = RawFutexRwLock::with_policy(RwLockPolicy::ReaderPreference);
+        lock.lock_shared();
+        thread::scope(|s| {
+            s.spawn(|| {
+                lock.lock_exclusive();
+                unsafe { lock.unlock_exclusive() };
+            });
+            while lock.parked_writers() != 1 {
+                thread::yield_now();
+            }
+            assert!(lock.try_lock_shared());
+            unsafe { lock.unlock_shared() };
+            unsafe { lock.unlock_shared() };
+        });
+    }
+
+    /// A writer holds the lock while a reader and then a writer queue up; return who gets in first after the release.
+    fn first_after_writer_release(policy: RwLockPolicy) -> &'static str {
+        let lock = RawFutexRwLock::with_policy(policy);
+        let order = std::sync::Mutex::new(vec![]);
+        lock.lock_exclusive();
+        thread::scope(|s| {
+            s.spawn(|| {
+                lock.lock_shared();
+                order.lock().unwrap().push("reader");
+                thread::sleep(Duration::from_millis(20));
+                unsafe { lock.unlock_shared() };
+            });
+            while lock.parked_readers() != 1 {
+                thread::yield_now();
+            }
+            s.spawn(|| {
+                lock.lock_exclusive();
+                order.lock().unwrap().push("writer");
+                thread::sleep(Duration::from_millis(20));
+                unsafe { lock.unlock_exclusive() };
+            });
+            while lock.parked_writers() != 1 {
+                thread::yield_now();
+            }
+            if policy != RwLockPolicy::ReaderPreference {
+                // New readers queue behind the waiting writer
+                assert!(!lock.try_lock_shared());
+            }
+            unsafe { lock.unlock_exclusive() };
+        });
+        let order = order.into_inner().unwrap();
+        assert_eq!(order.len(), 2);
+        order[0]
+    }
+
+    #[test]
+    fn test_writer_release_hand_over() {
+        assert_eq!(
+            first_after_writer_release(RwLockPolicy::WriterPreference),
+            "writer"
+        );
+        assert_eq!(
+            first_after_writer_release(RwLockPolicy::PhaseFair),
+            "reader"
+        );
+        assert_eq!(
+            first_after_writer_release(RwLockPolicy::ReaderPreference),
+            "reader"
+        );
+    }
+
+    #[test]
+    fn test_phase_fair_alternates() {
+        let lock = RawFutexRwLock::with_policy(RwLockPolicy::PhaseFair);
+        let order = std::sync::Mutex::new(vec![]);
+        lock.lock_shared();
+        thread::scope(|s| {
+            // A writer waits for the current readers
+            s.spawn(|| {
+                lock.lock_exclusive();
+                order.lock().unwrap().push("writer");
+                thread::sleep(Duration::from_millis(20));
+                unsafe { lock.unlock_exclusive() };
+            });
+            while lock.parked_writers() != 1 {
+                thread::yield_now();
+            }
+            // A new reader queues behind it
+            s.spawn(|| {
+                lock.lock_shared();
+                order.lock().unwrap().push("reader");
+                thread::sleep(Duration::from_millis(20));
+                unsafe { lock.unlock_shared() };
+            });
+            while lock.parked_readers() != 1 {
+                thread::yield_now();
+            }
+            // So does another writer, but the reader goes first either way
+            s.spawn(|| {
+                lock.lock_exclusive();
+                order.lock().unwrap().push("writer");
+                unsafe { lock.unlock_exclusive() };
+            });
+            while lock.parked_writers() != 2 {
+                thread::yield_now();
+            }
+            unsafe { lock.unlock_shared() };
+        });
+        assert_eq!(order.into_inner().unwrap(), ["writer", "reader", "writer"]);
+    }
+
+    #[cfg(feature = "lock_api")]
+    #[test]
+    fn test_lock_api() {
+        let lock = RwLock::new(1);
+        {
+            let a = lock.read();
+            let b = lock.read();
+            assert_eq!(*a + *b, 2);
+        }
+        {
+            let upgradable = lock.upgradable_read();
+            let mut w = RwLockUpgradableReadGuard::upgrade(upgradable);
+            *w += 1;
+            let r = RwLockWriteGuard::downgrade(w);
+            assert_eq!(*r, 2);
+            assert!(lock.try_read().is_some());
+            assert!(lock.try_write().is_none());
+        }
+        let r = lock.read();
+        assert!(lock.try_write_for(Duration::from_millis(20)).is_none());
+        drop(r);
+        *lock.try_write_for(Duration::from_millis(20)).unwrap() += 1;
+        assert_eq!(*lock.read(), 3);
+    }
+
+    #[cfg(feature = "lock_api")]
+    #[test]
+    fn test_mapped_guards_release_once() {
+        let lock = RwLock::new((1, vec![1, 2, 3]));
+
+        let other = lock.read();
+        let mapped = RwLockReadGuard::map(lock.read(), |(_, v)| &v[..]);
+        drop(mapped);
+        // The other reader still holds the lock; a double release would have tripped the debug assertion
+        assert!(lock.is_locked());
+        assert!(lock.try_write().is_none());
+        drop(other);
+        assert!(!lock.is_locked());
+
+        let unmapped = RwLockReadGuard::try_map(lock.read(), |(_, v)| v.get(3)).unwrap_err();
+        assert!(lock.is_locked());
+        drop(unmapped);
+        assert!(!lock.is_locked());
+
+        let mut first = RwLockWriteGuard::map(lock.write(), |(n, _)| n);
+        *first += 1;
+        assert!(lock.is_locked_exclusive());
+        drop(first);
+        assert!(!lock.is_locked());
+
+        let unmapped = RwLockWriteGuard::try_map(lock.write(), |(_, v)| v.get_mut(3)).unwrap_err();
+        assert!(lock.is_locked_exclusive());
+        drop(unmapped);
+        assert!(!lock.is_locked());
+        assert_eq!(lock.read().0, 2);
+    }
+
+    /// `lock_api` forwards the traits of the guards; the `serde` feature enables its `Serialize` and `Deserialize` for the lock itself.
+    #[cfg(all(feature = "lock_api", feature = "serde"))]
+    #[test]
+    fn test_serde_round_trip() {
+        let lock = RwLock::new(vec![1, 2, 3]);
+        let read = lock.read();
+        assert_eq!(format!("{read:?}"), "[1, 2, 3]");
+        assert_eq!(serde_json::to_string(&*read).unwrap(), "[1,2,3]");
+        drop(read);
+        let json = serde_json::to_string(&lock).unwrap();
+        let de: RwLock<Vec<i32>> = serde_json::from_str(&json).unwrap();
+        assert!(!de.is_locked());
+        assert_eq!(*de.read(), [1, 2, 3]);
+    }
+}
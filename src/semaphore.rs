@@ -1,91 +1,713 @@
-use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::{
+    marker::PhantomPinned,
+    ops::Deref,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
 
-use crate::{futex_wake, resumed_futex_wait, FutexWaitContext, WakeWaiters, U31};
+use crate::{
+    deadline::TimedOut,
+    idle::{AdaptiveSpin, RetryBudget},
+    observer::{futex_wake_from, observed_futex_wait, Primitive},
+    shutdown::{futex_wait_or_shutdown, Shutdown, ShutdownToken},
+    violation::violation,
+    Futex, FutexError, FutexTimeout, TimeoutMeasure, WaiterGuard, WaitersCounter, WakeWaiters,
+};
+
+/// Wake-ups a [`Semaphore::wait`] caller may find its permit taken by another thread before reserving the next one.
+const BYPASS_LIMIT: u32 = 4;
 
 /// A semaphore is an integer whose value is never allowed to fall below zero.
+///
+/// # Fairness
+///
+/// Wake-ups are not handed over: a released permit can be taken by a newly arriving thread before the waiter woken for it gets to run.
+/// A [`Self::wait`] caller is bypassed this way at most [`BYPASS_LIMIT`] times, after which it reserves the next permit deposited.
+/// A thread already past its check of the reservation when it is made can still take one permit, so each thread racing the reservation can bypass the holder once more.
+/// [`Self::acquire_many`] callers get no such bound, since they can always be bypassed by smaller acquisitions.
+///
+/// # Zero initialization
+///
+/// The all-zero bit pattern is a semaphore with no permits, made as by [`Self::new`].
 #[derive(Debug)]
+#[repr(C)]
 pub struct Semaphore {
-    value: AtomicU32,
-    waiters: Option<AtomicUsize>,
+    value: Futex,
+    /// `1` while a waiter bypassed [`BYPASS_LIMIT`] times holds the reservation, so that no other thread takes a permit meanwhile; the futex word the others sleep on until it is given up
+    reserved: Futex,
+    waiters: WaitersCounter,
+    /// Parked [`Self::acquire_many`] callers; signals wake all waiters while there are any.
+    ///
+    /// Unlike `waiters`, it is needed for correctness: a wake-up absorbed by a caller short of permits would otherwise strand a waiter that can use them.
+    many_waiters: AtomicUsize,
+    /// Cap on `waiters` enforced by [`Self::wait_or_reject`], plus one and wrapping, so that zero means unbounded
+    max_waiters: usize,
+    /// Of [`Self::wait`] and the like before their first sleep
+    spin: AdaptiveSpin,
 }
 impl Semaphore {
     pub fn new(value: u32) -> Self {
         Self {
-            value: AtomicU32::new(value),
-            waiters: Some(AtomicUsize::new(0)),
+            value: Futex::new(value),
+            reserved: Futex::new(0),
+            waiters: WaitersCounter::new(),
+            many_waiters: AtomicUsize::new(0),
+            max_waiters: 0,
+            spin: AdaptiveSpin::new(),
+        }
+    }
+
+    /// A semaphore whose [`Self::wait_or_reject`] fails fast with [`QueueFull`] instead of queuing a waiter beyond `max_waiters`.
+    ///
+    /// Learn more from [`Self::new`].
+    pub fn with_max_waiters(value: u32, max_waiters: usize) -> Self {
+        Self {
+            max_waiters: max_waiters.wrapping_add(1),
+            ..Self::new(value)
+        }
+    }
+
+    /// Register each contended waiter with a plain store to a byte of its thread instead of an RMW on a counter shared by all waiters; [`Self::waiters`] still counts them exactly.
+    ///
+    /// Must not be shared between processes; learn more from [`FutexScope::Private`](crate::FutexScope::Private).
+    ///
+    /// Learn more from [`Self::new`].
+    pub fn new_private(value: u32) -> Self {
+        Self {
+            waiters: WaitersCounter::private(),
+            ..Self::new(value)
         }
     }
 
+    /// Learn more from [`Self::new`].
     pub fn new_slow(value: u32) -> Self {
         Self {
-            value: AtomicU32::new(value),
-            waiters: None,
+            value: Futex::new(value),
+            reserved: Futex::new(0),
+            waiters: WaitersCounter::disabled(),
+            many_waiters: AtomicUsize::new(0),
+            max_waiters: 0,
+            spin: AdaptiveSpin::new(),
         }
     }
 
     /// Decrement the semaphore value by one.
     /// If the semaphore value is currently zero, then it will block until the value becomes greater than zero.
+    #[inline]
     pub fn wait(&self) {
+        if self.take_one() {
+            return;
+        }
+        self.wait_contended(None, None).unwrap();
+    }
+
+    /// [`Self::wait`] with the signals of the calling thread blocked while it sleeps, as in [`crate::futex_wait_uninterruptible`].
+    ///
+    /// For deployments where profilers or timers signal the thread often; taking an available permit leaves the mask alone.
+    pub fn wait_uninterruptible(&self) {
+        if self.take_one() {
+            return;
+        }
+        let _masked = crate::signal::MaskedSignals::block();
+        self.wait_contended(None, None).unwrap();
+    }
+
+    /// [`Self::wait`], unless as many threads as the cap of [`Self::with_max_waiters`] are already waiting.
+    ///
+    /// The cap counts every waiter, but only this call enforces it: [`Self::wait`] and the other blocking calls always queue, possibly beyond the cap.
+    pub fn wait_or_reject(&self) -> Result<(), QueueFull> {
+        if self.take_one() {
+            return Ok(());
+        }
+        let waiter = self
+            .waiters
+            .try_register(self.max_waiters.wrapping_sub(1), Ordering::SeqCst)
+            .ok_or(QueueFull)?;
+        self.wait_contended(None, Some(waiter)).unwrap();
+        Ok(())
+    }
+
+    /// Fast path of the waits: take a permit unless none is available or a waiter holds the reservation.
+    ///
+    /// Driven by the CAS alone: the first guesses a single permit, and a miss reports the value for the second.
+    #[inline]
+    fn take_one(&self) -> bool {
+        let mut value = 1;
+        for _ in 0..2 {
+            match self.value.compare_exchange(
+                value,
+                value - 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return self.keep_one(),
+                Err(0) => return false,
+                Err(actual) => value = actual,
+            }
+        }
+        false
+    }
+
+    /// Give the permit just taken back if a waiter holds the reservation.
+    #[inline]
+    fn keep_one(&self) -> bool {
+        if self.reserved.load(Ordering::Relaxed) == 0 {
+            return true;
+        }
+        self.signal();
+        false
+    }
+
+    /// Give up with [`Shutdown`] once `token` trips, even if a permit is available by then.
+    pub fn wait_or_shutdown(&self, token: &ShutdownToken) -> Result<(), Shutdown> {
+        if token.is_shutdown() {
+            return Err(Shutdown);
+        }
+        self.wait_contended(Some(token), None)
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn wait_contended(
+        &self,
+        token: Option<&ShutdownToken>,
+        waiter: Option<WaiterGuard<'_>>,
+    ) -> Result<(), Shutdown> {
+        // Counted for the whole call, so that a woken waiter going back to sleep cannot lose its place under the cap
+        // Paired with the check in `wake`, which must not miss a waiter that then misses the deposit
+        let _waiter = waiter.unwrap_or_else(|| self.waiters.register(Ordering::SeqCst));
+        let mut budget = RetryBudget::new();
+        let mut bypassed = 0;
+        let mut woken = false;
+        // Spin on an empty semaphore only until the first sleep
+        let spin_budget = self.spin.budget();
+        let mut spins = 0;
+        let mut slept = false;
         loop {
-            let value = self.value.load(Ordering::Relaxed);
+            if self.reserved.load(Ordering::SeqCst) != 0 {
+                // Held back for the holder; checked before the value word, so that the holder letting go cannot slip in between
+                self.park_on(&self.reserved, 1, token)?;
+                continue;
+            }
+            let value = self.value.load(Ordering::SeqCst);
             if 0 < value {
                 if self
                     .value
                     .compare_exchange(value, value - 1, Ordering::Acquire, Ordering::Relaxed)
                     .is_ok()
                 {
-                    return;
+                    if !slept {
+                        self.spin.record(spin_budget, true);
+                    }
+                    return Ok(());
                 }
+                budget.retry();
                 continue;
             }
-            if let Some(waiters) = &self.waiters {
-                waiters.fetch_add(1, Ordering::Relaxed);
+            if woken {
+                // Whatever woke this waiter is gone
+                bypassed += 1;
+                woken = false;
             }
-            if let Err(e) = resumed_futex_wait(FutexWaitContext {
-                word: &self.value,
-                expected: 0,
-                timeout: None,
-            }) {
-                if !matches!(e.kind(), std::io::ErrorKind::WouldBlock) {
+            if BYPASS_LIMIT <= bypassed && value == 0 {
+                if self
+                    .reserved
+                    .compare_exchange(0, 1, Ordering::SeqCst, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    return self.wait_reserved(token);
+                }
+                continue;
+            }
+            if !slept {
+                if spins < spin_budget {
+                    spins += 1;
+                    std::hint::spin_loop();
+                    continue;
+                }
+                self.spin.record(spin_budget, false);
+                slept = true;
+            }
+            self.park_on(&self.value, value, token)?;
+            woken = true;
+        }
+    }
+
+    /// Take the next permit deposited while holding the reservation.
+    #[cold]
+    fn wait_reserved(&self, token: Option<&ShutdownToken>) -> Result<(), Shutdown> {
+        /// Give up the reservation once done, on shutdown, or on unwinding
+        struct Reservation<'a>(&'a Semaphore);
+        impl Drop for Reservation<'_> {
+            fn drop(&mut self) {
+                self.0.reserved.store(0, Ordering::SeqCst);
+                // The others held back sleep on the reservation word and take what is left
+                futex_wake_from(
+                    Primitive::Semaphore,
+                    &self.0.reserved,
+                    WakeWaiters::All,
+                    self.0.waiters.scope(),
+                )
+                .unwrap();
+            }
+        }
+
+        let _reservation = Reservation(self);
+        loop {
+            // Paired with the deposit's check of the reservation
+            let value = self.value.load(Ordering::SeqCst);
+            if 0 < value {
+                // Only deposits, forgets, and threads racing the reservation race with this
+                if self
+                    .value
+                    .compare_exchange(value, value - 1, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    return Ok(());
+                }
+                continue;
+            }
+            // Still counted by `wait_contended`
+            self.park_on(&self.value, value, token)?;
+        }
+    }
+
+    /// Sleep unless `word` moved from `expected`.
+    fn park_on(
+        &self,
+        word: &Futex,
+        expected: u32,
+        token: Option<&ShutdownToken>,
+    ) -> Result<(), Shutdown> {
+        match token {
+            Some(token) => futex_wait_or_shutdown(
+                Primitive::Semaphore,
+                word,
+                expected,
+                self.waiters.scope(),
+                token,
+            ),
+            None => {
+                if let Err(e) = observed_futex_wait(
+                    Primitive::Semaphore,
+                    word.context(expected, None, self.waiters.scope()),
+                ) {
+                    if !matches!(e.error, FutexError::ValueMismatch) {
+                        panic!("{e}");
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Decrement the semaphore value by `n` at once, blocking until it is at least `n`.
+    pub fn acquire_many(&self, n: u32) {
+        self.acquire_many_until(n, None);
+    }
+
+    /// Return `false` on timeout, having taken no permits.
+    pub fn acquire_many_timeout(&self, n: u32, timeout: Duration) -> bool {
+        self.acquire_many_until(n, Instant::now().checked_add(timeout))
+    }
+
+    /// [`Self::wait`] giving up with [`TimedOut`] once `deadline` has passed.
+    pub fn wait_deadline(&self, deadline: impl Into<Instant>) -> Result<(), TimedOut> {
+        self.acquire_many_deadline(1, deadline)
+    }
+
+    /// Return [`TimedOut`] once `deadline` has passed, having taken no permits.
+    pub fn acquire_many_deadline(
+        &self,
+        n: u32,
+        deadline: impl Into<Instant>,
+    ) -> Result<(), TimedOut> {
+        if !self.acquire_many_until(n, Some(deadline.into())) {
+            return Err(TimedOut(()));
+        }
+        Ok(())
+    }
+
+    /// Take all `n` permits or none.
+    ///
+    /// Return the number of permits available at the time if it is less than `n`.
+    pub fn try_acquire_many(&self, n: u32) -> Result<(), u32> {
+        self.try_take(n).map_err(|short| match short {
+            Short::Reserved => 0,
+            Short::Value(value) => value,
+        })
+    }
+
+    /// Never sleep; the blocking callers do that themselves.
+    fn try_take(&self, n: u32) -> Result<(), Short> {
+        let mut budget = RetryBudget::spinning();
+        if self.reserved.load(Ordering::SeqCst) != 0 {
+            return Err(Short::Reserved);
+        }
+        let mut value = self.value.load(Ordering::SeqCst);
+        loop {
+            if value < n {
+                return Err(Short::Value(value));
+            }
+            match self.value.compare_exchange(
+                value,
+                value - n,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(actual) => {
+                    value = actual;
+                    budget.retry();
+                }
+            }
+        }
+    }
+
+    fn acquire_many_until(&self, n: u32, deadline: Option<Instant>) -> bool {
+        if self.try_take(n).is_ok() {
+            return true;
+        }
+
+        let _many_waiter = WaiterGuard::new(Some(&self.many_waiters), Ordering::SeqCst);
+        if 0 < self.available(self.value.load(Ordering::SeqCst)) {
+            // A signal that missed the registration woke only as many waiters as it deposited permits, possibly this one instead of one that can use them
+            futex_wake_from(
+                Primitive::Semaphore,
+                &self.value,
+                WakeWaiters::All,
+                self.waiters.scope(),
+            )
+            .unwrap();
+        }
+        loop {
+            let (word, expected) = match self.try_take(n) {
+                Ok(()) => return true,
+                Err(Short::Reserved) => (&self.reserved, 1),
+                Err(Short::Value(value)) => (&self.value, value),
+            };
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return false;
+                    }
+                    Some(FutexTimeout::For(remaining, TimeoutMeasure::MonoTime))
+                }
+                None => None,
+            };
+            let _waiter = self.waiters.register(Ordering::SeqCst);
+            if let Err(e) = observed_futex_wait(
+                Primitive::Semaphore,
+                word.context(expected, timeout, self.waiters.scope()),
+            ) {
+                if !matches!(e.error, FutexError::ValueMismatch | FutexError::TimedOut) {
                     panic!("{e}");
                 }
             }
-            if let Some(waiters) = &self.waiters {
-                waiters.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Take one permit from each of `sems`, all or none.
+    ///
+    /// The semaphores are acquired in the order of their addresses, so callers passing the same semaphores in different orders cannot deadlock each other.
+    /// On timeout, the permits taken so far are given back before returning.
+    ///
+    /// A semaphore listed more than once gives one permit per listing, all taken at once like [`Self::acquire_many`], so that it is never held in part while waiting for the rest.
+    ///
+    /// The permits are returned in the order of `sems`.
+    pub fn acquire_all<'a>(
+        sems: &[&'a Semaphore],
+        timeout: Option<Duration>,
+    ) -> Result<Vec<SemaphorePermit<'a>>, AcquireAllError> {
+        let deadline = timeout.and_then(|t| Instant::now().checked_add(t));
+        let mut order = (0..sems.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| std::ptr::from_ref(sems[i]));
+        let mut permits = sems.iter().map(|_| None).collect::<Vec<_>>();
+        for listings in order.chunk_by(|&i, &j| std::ptr::eq(sems[i], sems[j])) {
+            let semaphore = sems[listings[0]];
+            if !semaphore.acquire_many_until(listings.len() as u32, deadline) {
+                // Dropping `permits` gives back the ones taken so far
+                return Err(AcquireAllError);
+            }
+            for &i in listings {
+                permits[i] = Some(SemaphorePermit { semaphore });
             }
         }
+        Ok(permits.into_iter().map(Option::unwrap).collect())
+    }
+
+    /// [`Self::wait`] for a permit given back on drop.
+    pub(crate) fn permit(&self) -> SemaphorePermit<'_> {
+        self.wait();
+        SemaphorePermit { semaphore: self }
     }
 
     /// Increment the semaphore value by one.
     pub fn signal(&self) {
+        self.signal_many(1);
+    }
+
+    /// Increment the semaphore value by `n` and wake up to `n` waiters with a single syscall.
+    ///
+    /// Return the number of waiters that were woken up.
+    pub fn signal_many(&self, n: u32) -> usize {
+        if n == 0 {
+            return 0;
+        }
+        self.deposit(n);
+        self.wake(n)
+    }
+
+    /// Defer the wake-ups of a burst of signals until [`SignalBatch::flush`].
+    pub fn batch(&self) -> SignalBatch<'_> {
+        SignalBatch {
+            semaphore: self,
+            pending: 0,
+        }
+    }
+
+    /// Alias of [`Self::signal_many`], the counterpart of [`Self::forget_permits`] when resizing a pool at runtime.
+    pub fn add_permits(&self, n: u32) -> usize {
+        self.signal_many(n)
+    }
+
+    /// Take up to `n` available permits out of circulation without blocking.
+    ///
+    /// Return the number of permits removed, which falls short of `n` if fewer are available; waiters keep waiting for [`Self::add_permits`].
+    pub fn forget_permits(&self, n: u32) -> u32 {
+        let mut budget = RetryBudget::spinning();
+        let mut value = self.value.load(Ordering::Relaxed);
+        loop {
+            let removed = value.min(n);
+            if removed == 0 {
+                return 0;
+            }
+            match self.value.compare_exchange(
+                value,
+                value - removed,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return removed,
+                Err(actual) => {
+                    value = actual;
+                    budget.retry();
+                }
+            }
+        }
+    }
+
+    fn deposit(&self, n: u32) {
+        let mut budget = RetryBudget::new();
         loop {
             let value = self.value.load(Ordering::Relaxed);
             if self
                 .value
                 .compare_exchange(
                     value,
-                    value.checked_add(1).expect("`u32` addition overflow"),
-                    Ordering::Release,
+                    value
+                        .checked_add(n)
+                        .unwrap_or_else(|| violation!(Overflow, value)),
+                    // Paired with the registration in `acquire_many_until`
+                    Ordering::SeqCst,
                     Ordering::Relaxed,
                 )
                 .is_err()
             {
+                budget.retry();
                 continue;
             }
             break;
         }
-        if let Some(waiters) = &self.waiters {
-            if 0 == waiters.load(Ordering::Relaxed) {
-                return;
-            }
+    }
+
+    fn wake(&self, n: u32) -> usize {
+        // The reservation holder could be passed over by a wake of `n`; it alone gives the reservation up, so it is still held if the holder sleeps
+        if 0 < self.many_waiters.load(Ordering::SeqCst) || self.reserved.load(Ordering::SeqCst) != 0
+        {
+            return futex_wake_from(
+                Primitive::Semaphore,
+                &self.value,
+                WakeWaiters::All,
+                self.waiters.scope(),
+            )
+            .unwrap();
+        }
+        // After the deposit in the single total order, so a waiter registered too late to be seen here sees the deposit instead
+        if self.waiters.load(Ordering::SeqCst) == Some(0) {
+            return 0;
+        }
+        futex_wake_from(
+            Primitive::Semaphore,
+            &self.value,
+            WakeWaiters::at_most(n as usize),
+            self.waiters.scope(),
+        )
+        .unwrap()
+    }
+
+    /// Permits in `value` takeable by a thread not holding the reservation.
+    fn available(&self, value: u32) -> u32 {
+        if self.reserved.load(Ordering::Relaxed) != 0 {
+            return 0;
         }
-        futex_wake(&self.value, WakeWaiters::Amount(U31::new(1).unwrap())).unwrap();
+        value
+    }
+
+    /// Only a snapshot.
+    ///
+    /// Includes a permit held back for a waiter's reservation.
+    pub fn available_permits(&self) -> u32 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    /// Only a snapshot.
+    ///
+    /// Learn more from [`crate::mutex::Mutex::spin_budget`]; only [`Self::wait`] and the like spin, and only before their first sleep.
+    pub fn spin_budget(&self) -> u32 {
+        self.spin.snapshot()
+    }
+
+    #[cfg(all(test, feature = "flight-recorder"))]
+    pub(crate) fn futex_word(&self) -> &std::sync::atomic::AtomicU32 {
+        &self.value
+    }
+
+    /// Only a snapshot.
+    ///
+    /// Return [`None`] if the semaphore does not count its waiters.
+    pub fn waiters(&self) -> Option<usize> {
+        self.waiters.load(Ordering::Relaxed)
+    }
+}
+/// Learn more from the zero initialization of [`Semaphore`].
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Zeroable for Semaphore {}
+
+/// A [`Semaphore`] that is `!Unpin`; learn more from [`crate::mutex::PinnedMutex`].
+#[derive(Debug)]
+#[repr(C)]
+pub struct PinnedSemaphore {
+    semaphore: Semaphore,
+    _pinned: PhantomPinned,
+}
+impl PinnedSemaphore {
+    /// Learn more from [`Semaphore::new`].
+    pub fn new(value: u32) -> Self {
+        Self::pin(Semaphore::new(value))
+    }
+
+    /// Learn more from [`Semaphore::new_private`].
+    pub fn new_private(value: u32) -> Self {
+        Self::pin(Semaphore::new_private(value))
+    }
+
+    fn pin(semaphore: Semaphore) -> Self {
+        Self {
+            semaphore,
+            _pinned: PhantomPinned,
+        }
+    }
+}
+impl Deref for PinnedSemaphore {
+    type Target = Semaphore;
+
+    fn deref(&self) -> &Self::Target {
+        &self.semaphore
+    }
+}
+
+/// Why [`Semaphore::try_take`] took nothing
+enum Short {
+    /// A waiter holds the reservation.
+    Reserved,
+    /// Fewer permits than asked for are in the value word.
+    Value(u32),
+}
+
+/// [`Semaphore::wait_or_reject`] found the waiter cap reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFull;
+impl std::fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "semaphore waiter queue full")
+    }
+}
+impl std::error::Error for QueueFull {}
+
+/// A permit taken by [`Semaphore::acquire_all`] or [`crate::parallel::run_limited`], given back on drop.
+#[must_use]
+#[derive(Debug)]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+impl<'a> SemaphorePermit<'a> {
+    pub fn semaphore(&self) -> &'a Semaphore {
+        self.semaphore
+    }
+
+    /// Keep the permit taken.
+    pub fn forget(self) {
+        std::mem::forget(self);
+    }
+}
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.signal();
+    }
+}
+
+/// [`Semaphore::acquire_all`] timed out before taking every permit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcquireAllError;
+impl std::fmt::Display for AcquireAllError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "timed out acquiring all permits")
+    }
+}
+impl std::error::Error for AcquireAllError {}
+
+/// Permits are deposited immediately, but waiters are only woken on [`Self::flush`] or drop.
+///
+/// Parked waiters could stay parked while permits are available until then, so keep the batch short-lived.
+#[derive(Debug)]
+pub struct SignalBatch<'a> {
+    semaphore: &'a Semaphore,
+    pending: u32,
+}
+impl SignalBatch<'_> {
+    /// Increment the semaphore value by one.
+    pub fn signal(&mut self) {
+        self.semaphore.deposit(1);
+        self.pending += 1;
+    }
+
+    /// Wake up to as many waiters as the signals since the last flush with a single syscall.
+    ///
+    /// Return the number of waiters that were woken up.
+    pub fn flush(&mut self) -> usize {
+        let pending = std::mem::take(&mut self.pending);
+        if pending == 0 {
+            return 0;
+        }
+        self.semaphore.wake(pending)
+    }
+}
+impl Drop for SignalBatch<'_> {
+    fn drop(&mut self) {
+        self.flush();
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::sync::atomic::AtomicU32;
+
+    use crate::{
+        mock_backend::{with_failing_waits, with_wait_hook, MockBackend},
+        workers::ScopedWorkers,
+    };
 
     use super::*;
 
@@ -93,30 +715,349 @@ mod tests {
     fn test_wait_signal() {
         let sem = Semaphore::new(1);
         sem.wait();
-        let sem = Arc::new(sem);
         let n = 10;
-        let mut waiters = vec![];
-        for i in 0..n {
-            let waiter = std::thread::spawn({
-                let sem = sem.clone();
-                move || {
-                    sem.wait();
-                    dbg!(i);
-                }
+        crate::scope(|s| {
+            let waiters = ScopedWorkers::spawn_n(s, n, |i| {
+                sem.wait();
+                dbg!(i);
             });
-            waiters.push(waiter);
-        }
+            while sem.waiters() != Some(n) {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
 
-        for waiter in &waiters {
-            assert!(!waiter.is_finished());
-        }
+            for _ in 0..n {
+                sem.signal();
+            }
+
+            waiters.join_timeout(std::time::Duration::from_secs(10));
+        });
+    }
+
+    #[test]
+    fn test_max_waiters() {
+        let sem = Semaphore::with_max_waiters(0, 2);
+        std::thread::scope(|s| {
+            let parked = [
+                s.spawn(|| sem.wait_or_reject()),
+                s.spawn(|| sem.wait_or_reject()),
+            ];
+            while sem.waiters() != Some(2) {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            let start = std::time::Instant::now();
+            assert_eq!(sem.wait_or_reject(), Err(QueueFull));
+            assert!(start.elapsed() < std::time::Duration::from_millis(100));
 
-        for _ in 0..waiters.len() {
             sem.signal();
-        }
+            while sem.waiters() != Some(1) {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            let admitted = s.spawn(|| sem.wait_or_reject());
+            while sem.waiters() != Some(2) {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            assert_eq!(sem.wait_or_reject(), Err(QueueFull));
+
+            sem.signal_many(2);
+            for waiter in parked.into_iter().chain([admitted]) {
+                assert_eq!(waiter.join().unwrap(), Ok(()));
+            }
+        });
+        assert_eq!(sem.waiters(), Some(0));
+    }
+
+    #[test]
+    fn test_signal_batch() {
+        let sem = Semaphore::new(0);
+        let n = 100;
+        std::thread::scope(|s| {
+            for _ in 0..n {
+                s.spawn(|| sem.wait());
+            }
+            while sem.waiters() != Some(n) {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            // Let the waiters get queued in the kernel
+            std::thread::sleep(std::time::Duration::from_millis(100));
+
+            let mut batch = sem.batch();
+            for _ in 0..n {
+                batch.signal();
+            }
+            // No wake-up issued yet
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            assert_eq!(sem.waiters(), Some(n));
+
+            // One syscall for the whole burst
+            assert_eq!(batch.flush(), n);
+            assert_eq!(batch.flush(), 0);
+        });
+        assert_eq!(sem.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_signal_many_wakes_at_most_parked() {
+        let sem = Semaphore::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| sem.wait());
+            }
+            while sem.waiters() != Some(3) {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            // Let the waiters get queued in the kernel
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            assert_eq!(sem.signal_many(u32::MAX - 1), 3);
+        });
+        assert_eq!(sem.available_permits(), u32::MAX - 4);
+    }
 
-        for waiter in waiters.into_iter() {
-            waiter.join().unwrap();
+    #[test]
+    fn test_try_acquire_many() {
+        let sem = Semaphore::new(3);
+        assert_eq!(sem.try_acquire_many(5), Err(3));
+        assert_eq!(sem.available_permits(), 3);
+        assert_eq!(sem.try_acquire_many(3), Ok(()));
+        assert_eq!(sem.try_acquire_many(1), Err(0));
+        assert!(!sem.acquire_many_timeout(2, std::time::Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn test_acquire_many_no_over_commit() {
+        const PERMITS: u32 = 10;
+        let sem = Semaphore::new(PERMITS);
+        let in_use = AtomicU32::new(0);
+        std::thread::scope(|s| {
+            for i in 0..8 {
+                let sem = &sem;
+                let in_use = &in_use;
+                s.spawn(move || {
+                    let n = i % 4 + 1;
+                    for round in 0..1000 {
+                        if round % 2 == 0 {
+                            sem.acquire_many(n);
+                        } else if sem.try_acquire_many(n).is_err() {
+                            continue;
+                        }
+                        let total = in_use.fetch_add(n, Ordering::SeqCst) + n;
+                        assert!(total <= PERMITS);
+                        in_use.fetch_sub(n, Ordering::SeqCst);
+                        sem.signal_many(n);
+                    }
+                });
+            }
+            // Single-permit waiters must not be stranded by the multi-permit ones
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        sem.wait();
+                        sem.signal();
+                    }
+                });
+            }
+        });
+        assert_eq!(sem.available_permits(), PERMITS);
+    }
+
+    #[test]
+    fn test_acquire_all_opposite_orders() {
+        let sems = [Semaphore::new(1), Semaphore::new(1), Semaphore::new(1)];
+        let [a, b, c] = &sems;
+        let forward = [a, b, c];
+        let backward = [c, b, a];
+        std::thread::scope(|s| {
+            for order in [forward, backward] {
+                s.spawn(move || {
+                    for _ in 0..1000 {
+                        let permits = Semaphore::acquire_all(&order, None).unwrap();
+                        for (permit, sem) in permits.iter().zip(order) {
+                            assert!(std::ptr::eq(permit.semaphore(), sem));
+                            assert_eq!(sem.available_permits(), 0);
+                        }
+                    }
+                });
+            }
+        });
+        for sem in &sems {
+            assert_eq!(sem.available_permits(), 1);
         }
     }
+
+    #[test]
+    fn test_acquire_all_duplicates() {
+        let mock = MockBackend::install();
+        let sem = Semaphore::new(1);
+        mock.intercept(&sem);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let permits =
+                    Semaphore::acquire_all(&[&sem, &sem], Some(Duration::from_secs(5))).unwrap();
+                assert_eq!(permits.len(), 2);
+            });
+            mock.wait_until_parked(1);
+            // Taking the two permits one by one, the waiter would hold this one while waiting for the other, as would another caller doing the same
+            assert_eq!(sem.try_acquire_many(1), Ok(()));
+            sem.signal_many(2);
+        });
+        assert_eq!(sem.forget_permits(2), 2);
+
+        sem.add_permits(1);
+        let timeout = Duration::from_millis(10);
+        assert!(Semaphore::acquire_all(&[&sem, &sem], Some(timeout)).is_err());
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_unrepresentable_timeout_waits_untimed() {
+        let sem = Semaphore::new(0);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(20));
+                sem.signal_many(2);
+            });
+            assert!(sem.acquire_many_timeout(1, Duration::MAX));
+            let permits = Semaphore::acquire_all(&[&sem], Some(Duration::MAX)).unwrap();
+            assert_eq!(permits.len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_acquire_all_timeout_gives_back() {
+        let sems = [Semaphore::new(1), Semaphore::new(0), Semaphore::new(2)];
+        let [a, b, c] = &sems;
+        let start = Instant::now();
+        let timeout = Duration::from_millis(50);
+        assert_eq!(
+            Semaphore::acquire_all(&[a, b, c], Some(timeout)).unwrap_err(),
+            AcquireAllError
+        );
+        assert!(timeout <= start.elapsed());
+        assert_eq!(a.available_permits(), 1);
+        assert_eq!(b.available_permits(), 0);
+        assert_eq!(c.available_permits(), 2);
+
+        b.signal();
+        let permits = Semaphore::acquire_all(&[a, b, c], Some(timeout)).unwrap();
+        assert_eq!(c.available_permits(), 1);
+        permits.into_iter().for_each(SemaphorePermit::forget);
+        assert_eq!(a.available_permits(), 0);
+        assert_eq!(b.available_permits(), 0);
+        assert_eq!(c.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_unwinding_wait_deregisters() {
+        let sem = Semaphore::new(0);
+        assert!(with_failing_waits(|| sem.wait()).is_err());
+        assert!(with_failing_waits(|| sem.acquire_many(2)).is_err());
+        assert_eq!(sem.waiters(), Some(0));
+        assert_eq!(sem.many_waiters.load(Ordering::Relaxed), 0);
+        // Nobody is left registered to take the permit
+        sem.signal();
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_resize_with_parked_waiters() {
+        let sem = Semaphore::new(3);
+        assert_eq!(sem.forget_permits(5), 3);
+        assert_eq!(sem.available_permits(), 0);
+        std::thread::scope(|s| {
+            for _ in 0..6 {
+                s.spawn(|| sem.wait());
+            }
+            // Parked short of permits even though one is available
+            s.spawn(|| sem.acquire_many(2));
+            while sem.waiters() != Some(7) {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            sem.signal();
+            std::thread::sleep(Duration::from_millis(50));
+            // Taken by a `wait` caller or removed here
+            let removed = sem.forget_permits(1);
+            assert!(removed <= 1);
+            assert_eq!(sem.forget_permits(1), 0);
+
+            sem.add_permits(4 + removed);
+            std::thread::sleep(Duration::from_millis(50));
+            sem.add_permits(3);
+        });
+        // 3 + 1 + 4 + 3 deposited, 3 + removed forgotten, 6 + 2 taken
+        assert_eq!(sem.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_bounded_bypass() {
+        let mock = MockBackend::install();
+        let sem: &'static Semaphore = Box::leak(Box::new(Semaphore::new(0)));
+        mock.intercept(sem);
+        let stolen: &'static AtomicU32 = Box::leak(Box::new(AtomicU32::new(0)));
+        let acquired = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                // A barging thread takes every permit before the woken waiter gets to it
+                let barge = || {
+                    if sem.try_acquire_many(1).is_ok() {
+                        stolen.fetch_add(1, Ordering::SeqCst);
+                    }
+                };
+                with_wait_hook(barge, || sem.wait());
+                acquired.store(true, Ordering::SeqCst);
+            });
+            while !acquired.load(Ordering::SeqCst) {
+                if sem.waiters() == Some(1) && sem.available_permits() == 0 {
+                    // Let it fall asleep
+                    std::thread::sleep(Duration::from_millis(5));
+                    sem.signal();
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        });
+        // Without the bound, the waiter would have been starved for good
+        assert_eq!(stolen.load(Ordering::SeqCst), BYPASS_LIMIT);
+        assert_eq!(sem.value.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_bypass_stress() {
+        let sem = Semaphore::new(2);
+        let in_use = AtomicU32::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..2000 {
+                        sem.wait();
+                        assert!(in_use.fetch_add(1, Ordering::SeqCst) < 2);
+                        in_use.fetch_sub(1, Ordering::SeqCst);
+                        sem.signal();
+                    }
+                });
+            }
+            // Reservations must not strand the bulk acquirer
+            s.spawn(|| {
+                for _ in 0..100 {
+                    sem.acquire_many(2);
+                    sem.signal_many(2);
+                }
+            });
+        });
+        assert_eq!(sem.value.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_reservation_given_up_on_shutdown() {
+        let sem = Semaphore::new(0);
+        let token = ShutdownToken::new();
+        // As if this waiter had reserved
+        sem.reserved.store(1, Ordering::Relaxed);
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| sem.wait_reserved(Some(&token)));
+            std::thread::sleep(Duration::from_millis(50));
+            token.shutdown();
+            assert_eq!(waiter.join().unwrap(), Err(Shutdown));
+        });
+        assert_eq!(sem.reserved.load(Ordering::Relaxed), 0);
+        sem.signal();
+        sem.wait();
+    }
 }
@@ -0,0 +1,281 @@
+use std::{
+    num::NonZeroUsize,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+
+use crate::{
+    idle::RetryBudget,
+    observer::{futex_wake_from, observed_futex_wait, Primitive},
+    violation::violation,
+    FutexError, FutexScope, FutexWaitContext, WakeWaiters,
+};
+
+/// A [`crate::semaphore::Semaphore`] whose permits are spread over per-shard words, for signal and wait rates at which a single value word bounces between cores.
+///
+/// Each thread has a home shard; [`Self::signal`] deposits there, and [`Self::wait`] takes from there first before stealing from the other shards.
+/// Only when every shard is empty does a waiter sleep, on an overflow word of its own, which signals touch only while a waiter is registered.
+///
+/// # Protocol
+///
+/// The permits are conserved: each one sits in exactly one shard until a single CAS takes it out, so none is lost or granted twice.
+/// A waiter never sleeps through a deposit:
+///
+/// 1. The waiter registers in `sleepers`, samples `overflow`, and scans every shard, all `SeqCst`.
+/// 1. The signaler deposits with a `SeqCst` RMW and then loads `sleepers`.
+/// 1. So either the scan finds the permit, or the signaler finds the registration and bumps `overflow` before waking, failing the sleep of the waiter if it comes after the bump.
+///
+/// # Fairness
+///
+/// None: a woken waiter can find its permit taken by a thread that never slept.
+#[derive(Debug)]
+pub struct ShardedSemaphore {
+    shards: Box<[Shard]>,
+    /// Bumped before each wake, so that a waiter registered before it never sleeps through it
+    overflow: AtomicU32,
+    /// Waiters between their registration and the end of their sleep
+    sleepers: AtomicUsize,
+}
+/// On a cache line of its own, so that the shards do not contend.
+#[derive(Debug)]
+#[repr(align(128))]
+struct Shard {
+    permits: AtomicU32,
+}
+impl ShardedSemaphore {
+    /// One shard per core available to the process.
+    ///
+    /// Learn more from [`Self::with_shards`].
+    pub fn new(value: u32) -> Self {
+        let shards = std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN);
+        Self::with_shards(value, shards.get())
+    }
+
+    /// `value` is spread evenly over the shards.
+    ///
+    /// # Panic
+    ///
+    /// If `shards` is zero.
+    pub fn with_shards(value: u32, shards: usize) -> Self {
+        assert!(0 < shards);
+        let base = value / shards as u32;
+        let extra = value % shards as u32;
+        let shards = (0..shards)
+            .map(|i| Shard {
+                permits: AtomicU32::new(base + u32::from((i as u32) < extra)),
+            })
+            .collect();
+        Self {
+            shards,
+            overflow: AtomicU32::new(0),
+            sleepers: AtomicUsize::new(0),
+        }
+    }
+
+    /// Take one permit, sleeping until one is signaled if every shard is empty.
+    pub fn wait(&self) {
+        if self.try_wait() {
+            return;
+        }
+        self.wait_contended();
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn wait_contended(&self) {
+        loop {
+            self.sleepers.fetch_add(1, Ordering::SeqCst);
+            let seq = self.overflow.load(Ordering::SeqCst);
+            if self.try_wait() {
+                self.sleepers.fetch_sub(1, Ordering::Relaxed);
+                return;
+            }
+            if let Err(e) = observed_futex_wait(
+                Primitive::Semaphore,
+                FutexWaitContext {
+                    word: &self.overflow,
+                    expected: seq,
+                    timeout: None,
+                    scope: FutexScope::Private,
+                },
+            ) {
+                if !matches!(e.error, FutexError::ValueMismatch) {
+                    panic!("{e}");
+                }
+            }
+            self.sleepers.fetch_sub(1, Ordering::Relaxed);
+            if self.try_wait() {
+                return;
+            }
+        }
+    }
+
+    /// Take one permit from the home shard, or else steal one from another shard, without blocking.
+    ///
+    /// Return `false` if every shard was found empty.
+    pub fn try_wait(&self) -> bool {
+        let home = self.home();
+        (home..self.shards.len())
+            .chain(0..home)
+            .any(|i| self.shards[i].take())
+    }
+
+    /// Deposit one permit into the home shard.
+    pub fn signal(&self) {
+        self.signal_many(1);
+    }
+
+    /// Deposit `n` permits into the home shard and wake up to `n` waiters with a single syscall.
+    ///
+    /// Return the number of waiters that were woken up.
+    pub fn signal_many(&self, n: u32) -> usize {
+        if n == 0 {
+            return 0;
+        }
+        self.shards[self.home()].deposit(n);
+        if self.sleepers.load(Ordering::SeqCst) == 0 {
+            return 0;
+        }
+        self.overflow.fetch_add(1, Ordering::SeqCst);
+        futex_wake_from(
+            Primitive::Semaphore,
+            &self.overflow,
+            WakeWaiters::at_most(n as usize),
+            FutexScope::Private,
+        )
+        .unwrap()
+    }
+
+    /// Only a snapshot; the sum of the shards, each read at a different time.
+    pub fn available_permits(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.permits.load(Ordering::Relaxed) as usize)
+            .sum()
+    }
+
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn home(&self) -> usize {
+        HOME.with(|home| *home) % self.shards.len()
+    }
+}
+impl Shard {
+    fn take(&self) -> bool {
+        let mut budget = RetryBudget::spinning();
+        let mut permits = self.permits.load(Ordering::SeqCst);
+        while 0 < permits {
+            match self.permits.compare_exchange(
+                permits,
+                permits - 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => {
+                    permits = actual;
+                    budget.retry();
+                }
+            }
+        }
+        false
+    }
+
+    fn deposit(&self, n: u32) {
+        // Paired with the registration in `ShardedSemaphore::wait_contended`
+        self.permits
+            .fetch_update(Ordering::SeqCst, Ordering::Relaxed, |permits| {
+                Some(
+                    permits
+                        .checked_add(n)
+                        .unwrap_or_else(|| violation!(Overflow, permits)),
+                )
+            })
+            .unwrap();
+    }
+}
+
+/// Threads are handed out home shards round-robin, in the order they first touch any sharded semaphore.
+static NEXT_HOME: AtomicUsize = AtomicUsize::new(0);
+thread_local! {
+    static HOME: usize = NEXT_HOME.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_initial_permits_spread() {
+        let sem = ShardedSemaphore::with_shards(10, 4);
+        let permits: Vec<_> = sem
+            .shards
+            .iter()
+            .map(|shard| shard.permits.load(Ordering::Relaxed))
+            .collect();
+        assert_eq!(permits, [3, 3, 2, 2]);
+        assert_eq!(sem.available_permits(), 10);
+    }
+
+    #[test]
+    fn test_steal_from_other_shards() {
+        let sem = ShardedSemaphore::with_shards(0, 4);
+        sem.signal_many(3);
+        std::thread::scope(|s| {
+            // Most likely on another home shard
+            s.spawn(|| {
+                for _ in 0..3 {
+                    assert!(sem.try_wait());
+                }
+                assert!(!sem.try_wait());
+            });
+        });
+        assert_eq!(sem.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_wait_sleeps_until_signal() {
+        let sem = ShardedSemaphore::with_shards(0, 4);
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| sem.wait());
+            while sem.sleepers.load(Ordering::Relaxed) == 0 {
+                std::thread::yield_now();
+            }
+            std::thread::sleep(Duration::from_millis(10));
+            assert!(!waiter.is_finished());
+            sem.signal();
+        });
+        assert_eq!(sem.available_permits(), 0);
+        assert_eq!(sem.sleepers.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_permits_conserved_under_stress() {
+        const PERMITS: u32 = 3;
+        const THREADS: usize = 8;
+        const ROUNDS: usize = 2000;
+        let sem = ShardedSemaphore::with_shards(PERMITS, 4);
+        let held = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for i in 0..ROUNDS {
+                        sem.wait();
+                        let now = held.fetch_add(1, Ordering::SeqCst) + 1;
+                        assert!(now <= PERMITS as usize, "permit granted twice");
+                        if i.is_multiple_of(16) {
+                            std::thread::yield_now();
+                        }
+                        held.fetch_sub(1, Ordering::SeqCst);
+                        sem.signal();
+                    }
+                });
+            }
+        });
+        assert_eq!(sem.available_permits(), PERMITS as usize);
+        assert_eq!(sem.sleepers.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,526 @@
+//! A primitive placed in memory shared between processes, initialized once by a creator and attached to by any number of openers.
+//!
+//! ```
+//! use futex::{mutex::Mutex, shared_cell::SharedCell};
+//!
+//! // Shared memory is zero-filled when first mapped
+//! let mut region = vec![0_u64; 16];
+//! let region = std::ptr::slice_from_raw_parts_mut(region.as_mut_ptr().cast::<u8>(), 16 * 8);
+//!
+//! let created = unsafe { SharedCell::create(region, 8, Mutex::new(0_u64)) }.unwrap();
+//! *created.lock() += 1;
+//! let opened = unsafe { SharedCell::<Mutex<u64>>::open(region, 8, None) }.unwrap();
+//! assert_eq!(*opened.lock(), 1);
+//! ```
+
+use std::{
+    mem::{align_of, size_of},
+    sync::atomic::{
+        AtomicBool, AtomicI16, AtomicI32, AtomicI64, AtomicI8, AtomicU16, AtomicU32, AtomicU64,
+        AtomicU8, Ordering,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cond_var::CondVar,
+    event::Event,
+    futex_enum::{FutexEnum, UnknownState},
+    mutex::Mutex,
+    observer::{futex_wake_from, observed_futex_wait, Primitive},
+    ping_pong::PingPong,
+    probe::Unsupported,
+    ring_buffer::RingBuffer,
+    robust::RobustMutexWord,
+    semaphore::Semaphore,
+    shared_ring_buffer::SharedRingBuffer,
+    FutexError, FutexScope, FutexTimeout, FutexWaitContext, TimeoutMeasure, WakeWaiters,
+};
+
+const MAGIC: u64 = u64::from_le_bytes(*b"FUTEXSHM");
+/// Bumped whenever the layout of [`SharedHeader`] changes.
+pub const VERSION: u32 = 1;
+
+crate::futex_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum State {
+        /// The zero-filled region before any creator
+        Uninit = 0,
+        Initializing,
+        Ready,
+    }
+}
+
+/// A type valid to place in memory shared between processes.
+///
+/// # Safety
+///
+/// The type must hold no pointers, references, or other process-local handles, and must be usable at any address and from any process mapping it.
+pub unsafe trait SharedSafe: Sync {}
+macro_rules! impl_shared_safe {
+    ($($ty:ty),* $(,)?) => {
+        $(unsafe impl SharedSafe for $ty {})*
+    };
+}
+impl_shared_safe!(
+    u8,
+    u16,
+    u32,
+    u64,
+    i8,
+    i16,
+    i32,
+    i64,
+    f32,
+    f64,
+    bool,
+    AtomicU8,
+    AtomicU16,
+    AtomicU32,
+    AtomicU64,
+    AtomicI8,
+    AtomicI16,
+    AtomicI32,
+    AtomicI64,
+    AtomicBool,
+    Semaphore,
+    Event,
+    CondVar,
+    PingPong,
+    RobustMutexWord,
+);
+unsafe impl<T: SharedSafe, const N: usize> SharedSafe for [T; N] {}
+unsafe impl<T: SharedSafe + Send> SharedSafe for Mutex<T> {}
+unsafe impl<T: SharedSafe + Copy + Send, const N: usize> SharedSafe for SharedRingBuffer<T, N> {}
+unsafe impl<T: SharedSafe + Send, const N: usize> SharedSafe for RingBuffer<T, N> {}
+
+/// A [`SharedSafe`] type whose all-zero bit pattern is a ready-to-use value, e.g., in a freshly mapped zero-filled region.
+///
+/// # Safety
+///
+/// The all-zero bit pattern must be a valid value, equivalent to one made by the type's plain constructor.
+pub unsafe trait ZeroInit: SharedSafe {
+    /// Panic if the zeroed value is still unusable for a reason only known at runtime.
+    fn assert_zero_valid() {}
+}
+macro_rules! impl_zero_init {
+    ($($ty:ty),* $(,)?) => {
+        $(unsafe impl ZeroInit for $ty {})*
+    };
+}
+impl_zero_init!(
+    u8, u16, u32, u64, i8, i16, i32, i64, f32, f64, bool, AtomicU8, AtomicU16, AtomicU32,
+    AtomicU64, AtomicI8, AtomicI16, AtomicI32, AtomicI64, AtomicBool, Semaphore, Event, CondVar,
+);
+unsafe impl<T: ZeroInit, const N: usize> ZeroInit for [T; N] {
+    fn assert_zero_valid() {
+        T::assert_zero_valid();
+    }
+}
+unsafe impl<T: ZeroInit + Send> ZeroInit for Mutex<T> {
+    fn assert_zero_valid() {
+        T::assert_zero_valid();
+    }
+}
+unsafe impl<T: SharedSafe + Copy + Send, const N: usize> ZeroInit for SharedRingBuffer<T, N> {
+    fn assert_zero_valid() {
+        SharedRingBuffer::<T, N>::assert_capacity();
+    }
+}
+
+/// Use the zero-filled memory at `offset` in `region` as a `T` as is, with no [`SharedCell`] header or init handshake.
+///
+/// Every process attaching this way sees the same primitive, as if made by its plain constructor; nothing may have been written to the memory but zeros and the primitive's own operations.
+///
+/// # Safety
+///
+/// `region` must be valid for reads and writes, zero-filled wherever no primitive has been used yet, and stay mapped for `'a`.
+pub unsafe fn from_zeroed_region<'a, T: ZeroInit>(
+    region: *mut [u8],
+    offset: usize,
+) -> Result<&'a T, SharedCellError> {
+    T::assert_zero_valid();
+    crate::probe::require_shared()?;
+    Ok(&*locate::<T>(region, offset)?)
+}
+
+/// Precedes the value of a [`SharedCell`] in the region.
+///
+/// The other fields are only meaningful once `state` reads [`State::Ready`].
+#[derive(Debug)]
+#[repr(C)]
+pub struct SharedHeader {
+    magic: u64,
+    /// Hash of the name, size, and alignment of the contained type
+    layout: u64,
+    version: u32,
+    /// Also the futex word openers sleep on
+    state: AtomicU32,
+}
+
+/// The layout of a shared primitive in the region: a [`SharedHeader`] followed by the value.
+///
+/// Both sides must be built from the same version of this crate with the same compiler, since [`SharedHeader`] only detects layout differences through the type's name, size, and alignment.
+#[derive(Debug)]
+#[repr(C)]
+pub struct SharedCell<T> {
+    header: SharedHeader,
+    value: T,
+}
+impl<T: SharedSafe> SharedCell<T> {
+    /// Move `value` into `region` at `offset` and publish it to openers.
+    ///
+    /// # Safety
+    ///
+    /// `region` must be valid for reads and writes, zero-filled wherever no cell has been created yet, and stay mapped for `'a`.
+    pub unsafe fn create<'a>(
+        region: *mut [u8],
+        offset: usize,
+        value: T,
+    ) -> Result<&'a T, SharedCellError> {
+        let cell = locate::<Self>(region, offset)?;
+        // Before claiming the region, so that it is left for a creator in a less restricted process
+        crate::probe::require_shared()?;
+        let state = &*std::ptr::addr_of!((*cell).header.state);
+        if let Err(word) = state.compare_exchange(
+            State::Uninit.into(),
+            State::Initializing.into(),
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            return Err(match State::from_word(word) {
+                Ok(_) => SharedCellError::AlreadyCreated,
+                Err(e) => SharedCellError::UnknownState(e),
+            });
+        }
+        std::ptr::addr_of_mut!((*cell).value).write(value);
+        std::ptr::addr_of_mut!((*cell).header.magic).write(MAGIC);
+        std::ptr::addr_of_mut!((*cell).header.layout).write(layout_hash::<T>());
+        std::ptr::addr_of_mut!((*cell).header.version).write(VERSION);
+        state.store(State::Ready.into(), Ordering::Release);
+        futex_wake_from(
+            Primitive::SharedCell,
+            state,
+            WakeWaiters::All,
+            FutexScope::Shared,
+        )
+        .unwrap();
+        Ok(&*std::ptr::addr_of!((*cell).value))
+    }
+
+    /// Wait for the creator to publish the cell at `offset` in `region`, then check that it holds a `T`.
+    ///
+    /// Wait forever if `timeout` is [`None`].
+    ///
+    /// # Safety
+    ///
+    /// `region` must be valid for reads and writes, zero-filled wherever no cell has been created yet, and stay mapped for `'a`.
+    pub unsafe fn open<'a>(
+        region: *mut [u8],
+        offset: usize,
+        timeout: Option<Duration>,
+    ) -> Result<&'a T, SharedCellError> {
+        let cell = locate::<Self>(region, offset)?;
+        crate::probe::require_shared()?;
+        let state = &*std::ptr::addr_of!((*cell).header.state);
+        let deadline = timeout.and_then(|t| Instant::now().checked_add(t));
+        loop {
+            let word = state.load(Ordering::Acquire);
+            match State::from_word(word).map_err(SharedCellError::UnknownState)? {
+                State::Ready => break,
+                State::Uninit | State::Initializing => (),
+            }
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(SharedCellError::TimedOut);
+                    }
+                    Some(FutexTimeout::For(remaining, TimeoutMeasure::MonoTime))
+                }
+                None => None,
+            };
+            if let Err(e) = observed_futex_wait(
+                Primitive::SharedCell,
+                FutexWaitContext {
+                    word: state,
+                    expected: word,
+                    timeout,
+                    scope: FutexScope::Shared,
+                },
+            ) {
+                if !matches!(e.error, FutexError::ValueMismatch | FutexError::TimedOut) {
+                    panic!("{e}");
+                }
+            }
+        }
+
+        let header = &*std::ptr::addr_of!((*cell).header);
+        if header.magic != MAGIC {
+            return Err(SharedCellError::BadMagic(header.magic));
+        }
+        if header.version != VERSION {
+            return Err(SharedCellError::VersionMismatch(header.version));
+        }
+        if header.layout != layout_hash::<T>() {
+            return Err(SharedCellError::LayoutMismatch(header.layout));
+        }
+        Ok(&*std::ptr::addr_of!((*cell).value))
+    }
+}
+
+/// Bounds- and alignment-check a `T` at `offset` in `region`.
+fn locate<T>(region: *mut [u8], offset: usize) -> Result<*mut T, SharedCellError> {
+    let fits = offset
+        .checked_add(size_of::<T>())
+        .is_some_and(|end| end <= region.len());
+    if !fits {
+        return Err(SharedCellError::OutOfBounds);
+    }
+    let ptr = region.cast::<u8>().wrapping_add(offset).cast::<T>();
+    if !ptr.is_aligned() {
+        return Err(SharedCellError::Misaligned);
+    }
+    Ok(ptr)
+}
+
+/// FNV-1a over the type's name, size, and alignment.
+fn layout_hash<T>() -> u64 {
+    let name = std::any::type_name::<T>().bytes();
+    let size = (size_of::<T>() as u64).to_le_bytes();
+    let align = (align_of::<T>() as u64).to_le_bytes();
+    name.chain(size)
+        .chain(align)
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SharedCellError {
+    /// The value would extend past the end of the region.
+    OutOfBounds,
+    /// The offset does not satisfy the alignment of the value.
+    Misaligned,
+    /// Another creator got there first.
+    AlreadyCreated,
+    /// The creator did not publish the cell in time.
+    TimedOut,
+    /// The header's init-state word holds none of its states.
+    UnknownState(UnknownState),
+    /// The region does not hold a cell at the offset; carries the value found.
+    BadMagic(u64),
+    /// The cell was created by an incompatible version of this crate; carries the version found.
+    VersionMismatch(u32),
+    /// The cell holds a different type; carries the layout hash found.
+    LayoutMismatch(u64),
+    /// The environment denies what shared primitives need; carries what the probe found.
+    Unsupported(Unsupported),
+}
+impl From<Unsupported> for SharedCellError {
+    fn from(value: Unsupported) -> Self {
+        Self::Unsupported(value)
+    }
+}
+impl std::fmt::Display for SharedCellError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OutOfBounds => write!(f, "shared cell out of bounds of the region"),
+            Self::Misaligned => write!(f, "shared cell misaligned"),
+            Self::AlreadyCreated => write!(f, "shared cell already created"),
+            Self::TimedOut => write!(f, "timed out waiting for the shared cell to be created"),
+            Self::UnknownState(e) => write!(f, "shared cell {e}"),
+            Self::BadMagic(magic) => write!(f, "bad shared cell magic: {magic:#x}"),
+            Self::VersionMismatch(version) => {
+                write!(f, "shared cell version {version}, expected {VERSION}")
+            }
+            Self::LayoutMismatch(layout) => {
+                write!(f, "shared cell layout hash {layout:#x} mismatched")
+            }
+            Self::Unsupported(e) => write!(f, "{e}"),
+        }
+    }
+}
+impl std::error::Error for SharedCellError {}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn region(words: &mut [u64]) -> *mut [u8] {
+        std::ptr::slice_from_raw_parts_mut(words.as_mut_ptr().cast::<u8>(), size_of_val(words))
+    }
+
+    #[test]
+    fn test_open_before_create() {
+        let mut words = vec![0_u64; 16];
+        let region = region(&mut words);
+        // Raw pointers are not `Send`
+        let addr = region.cast::<u8>() as usize;
+        let len = region.len();
+        thread::scope(|s| {
+            let opener = s.spawn(move || {
+                let region = std::ptr::slice_from_raw_parts_mut(addr as *mut u8, len);
+                let sem = unsafe {
+                    SharedCell::<Semaphore>::open(region, 0, Some(Duration::from_secs(5)))
+                }
+                .unwrap();
+                sem.wait();
+            });
+            thread::sleep(Duration::from_millis(50));
+            assert!(!opener.is_finished());
+            let sem = unsafe { SharedCell::create(region, 0, Semaphore::new(0)) }.unwrap();
+            sem.signal();
+        });
+
+        assert_eq!(
+            unsafe { SharedCell::create(region, 0, Semaphore::new(0)) }.unwrap_err(),
+            SharedCellError::AlreadyCreated
+        );
+    }
+
+    #[test]
+    // Waited on from two processes, which the process-local relocation check cannot follow
+    #[cfg(not(feature = "relocation-check"))]
+    fn test_attach_zeroed_region() {
+        use std::mem::offset_of;
+
+        use nix::{
+            sys::wait::{waitpid, WaitStatus},
+            unistd::{fork, ForkResult},
+        };
+        use rustix::mm::{mmap_anonymous, munmap, MapFlags, ProtFlags};
+
+        /// Where the primitives sit in the region, with the offsets and padding of a plain struct
+        #[repr(C)]
+        struct Layout {
+            m: Mutex<u64>,
+            sem: Semaphore,
+            event: Event,
+            buf: SharedRingBuffer<u64, 4>,
+        }
+        const SIZE: usize = 4096;
+        const { assert!(size_of::<Layout>() <= SIZE) };
+        // Zero-filled, like a fresh `memfd` or `shm` mapping
+        let ptr = unsafe {
+            mmap_anonymous(
+                std::ptr::null_mut(),
+                SIZE,
+                ProtFlags::READ | ProtFlags::WRITE,
+                MapFlags::SHARED,
+            )
+        }
+        .unwrap();
+        let region = std::ptr::slice_from_raw_parts_mut(ptr.cast::<u8>(), SIZE);
+        let attach = || unsafe {
+            (
+                from_zeroed_region::<Mutex<u64>>(region, offset_of!(Layout, m)).unwrap(),
+                from_zeroed_region::<Semaphore>(region, offset_of!(Layout, sem)).unwrap(),
+                from_zeroed_region::<Event>(region, offset_of!(Layout, event)).unwrap(),
+                from_zeroed_region::<SharedRingBuffer<u64, 4>>(region, offset_of!(Layout, buf))
+                    .unwrap(),
+            )
+        };
+        let (m, sem, event, buf) = attach();
+        assert_eq!(*m.lock(), 0);
+        assert_eq!(sem.available_permits(), 0);
+        assert!(!event.is_set());
+        assert!(buf.is_empty());
+
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                // Attached anew, with no init on either side
+                let (m, sem, event, buf) = attach();
+                // Bounded, so that a failed parent cannot leave the child blocked forever
+                let deadline = Instant::now() + Duration::from_secs(10);
+                for i in 0..8 {
+                    *m.lock() += 1;
+                    // The child is the only writer
+                    while unsafe { buf.try_write(i) }.is_err() {
+                        if deadline < Instant::now() {
+                            unsafe { libc::_exit(1) };
+                        }
+                        thread::yield_now();
+                    }
+                }
+                sem.signal();
+                let set = event.wait_timeout(deadline.saturating_duration_since(Instant::now()));
+                unsafe { libc::_exit(if set { 0 } else { 1 }) };
+            }
+            ForkResult::Parent { child } => {
+                // The parent is the only reader
+                let mut reader = unsafe { buf.attach_reader() }.unwrap();
+                for i in 0..8 {
+                    assert_eq!(reader.read(), i);
+                    reader.commit();
+                }
+                sem.wait();
+                assert_eq!(*m.lock(), 8);
+                event.set();
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+
+        assert_eq!(
+            unsafe { from_zeroed_region::<Semaphore>(region, offset_of!(Layout, sem) + 4) }
+                .unwrap_err(),
+            SharedCellError::Misaligned
+        );
+        unsafe { munmap(ptr, SIZE) }.unwrap();
+    }
+
+    #[test]
+    fn test_open_rejects_mismatches() {
+        let mut words = vec![0_u64; 16];
+        let region = region(&mut words);
+        assert_eq!(
+            unsafe { SharedCell::<AtomicU32>::open(region, 8, Some(Duration::from_millis(10))) }
+                .unwrap_err(),
+            SharedCellError::TimedOut
+        );
+        unsafe { SharedCell::create(region, 8, AtomicU32::new(3)) }.unwrap();
+        assert_eq!(
+            unsafe { SharedCell::<AtomicU64>::open(region, 8, None) }.unwrap_err(),
+            SharedCellError::LayoutMismatch(layout_hash::<AtomicU32>())
+        );
+
+        // Corrupt the magic
+        words[1] ^= 1;
+        let region = self::region(&mut words);
+        assert_eq!(
+            unsafe { SharedCell::<AtomicU32>::open(region, 8, None) }.unwrap_err(),
+            SharedCellError::BadMagic(MAGIC ^ 1)
+        );
+
+        assert_eq!(
+            unsafe { SharedCell::<AtomicU32>::open(region, 1, None) }.unwrap_err(),
+            SharedCellError::Misaligned
+        );
+        assert_eq!(
+            unsafe { SharedCell::<AtomicU32>::open(region, 16 * 8, None) }.unwrap_err(),
+            SharedCellError::OutOfBounds
+        );
+    }
+
+    #[test]
+    fn test_unsupported_fails_early() {
+        let mut words = vec![0_u64; 16];
+        let region = region(&mut words);
+        let mock = crate::probe::tests::deny_shared_futex();
+        let Err(SharedCellError::Unsupported(e)) =
+            (unsafe { SharedCell::create(region, 8, Semaphore::new(1)) })
+        else {
+            panic!();
+        };
+        drop(mock);
+        assert_eq!(
+            e.report.shared_futex,
+            crate::probe::Capability::Denied(libc::EPERM)
+        );
+        // The region is left for a creator that can use it
+        let sem = unsafe { SharedCell::create(region, 8, Semaphore::new(1)) }.unwrap();
+        sem.wait();
+    }
+}
@@ -0,0 +1,367 @@
+use std::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::{
+    observer::{futex_wake_from, observed_futex_wait, Primitive},
+    FutexError, FutexScope, FutexWaitContext, WakeWaiters,
+};
+
+/// Single writer; single reader; both possibly in different processes mapping the same memory.
+///
+/// Nothing in the shared memory records who the writer or the reader is, so [`Self::write`], [`Self::try_write`], and [`Self::attach_reader`] are `unsafe`: the caller keeps to one of each across every process.
+///
+/// Unlike [`crate::ring_buffer::RingBuffer`], the writer blocks while the buffer is full instead of overriding, and the reader only frees cells on [`Reader::commit`].
+/// A reader that dies between reading and committing leaves its cursor at the last commit, so the next [`Self::attach_reader`] re-reads the uncommitted elements: delivery is at-least-once, with a window of the elements read since the last commit.
+///
+/// # Layout
+///
+/// `repr(C)`, so the cursors are at stable offsets:
+///
+/// | Offset | Field |
+/// |-|-|
+/// | `0` | `write: u32`: elements ever written, wrapping |
+/// | `4` | `committed: u32`: elements ever committed by readers, wrapping |
+/// | `8` | `capacity: u32`: `N`, or `0` if zero-initialized |
+/// | `12` | `reader_parked: u32` |
+/// | `16` | `writer_parked: u32` |
+/// | `20..`, rounded up to the alignment of `T` | `N` cells of `T` |
+///
+/// # Zero initialization
+///
+/// The all-zero bit pattern is an empty buffer, so a zero-filled mapping needs no [`Self::init_at`].
+#[repr(C)]
+pub struct SharedRingBuffer<T, const N: usize> {
+    /// Also the futex word the reader sleeps on
+    write: AtomicU32,
+    /// Also the futex word the writer sleeps on
+    committed: AtomicU32,
+    capacity: u32,
+    reader_parked: AtomicU32,
+    writer_parked: AtomicU32,
+    cells: UnsafeCell<[MaybeUninit<T>; N]>,
+}
+// A cell is only accessed by the writer before it is published and by the reader after
+unsafe impl<T: Send, const N: usize> Sync for SharedRingBuffer<T, N> {}
+/// Learn more from the zero initialization of [`SharedRingBuffer`].
+#[cfg(feature = "bytemuck")]
+unsafe impl<T: Copy, const N: usize> bytemuck::Zeroable for SharedRingBuffer<T, N> {}
+impl<T: Copy, const N: usize> SharedRingBuffer<T, N> {
+    /// # Panic
+    ///
+    /// If `N` is not a power of two no greater than `2^31`, which keeps the wrapping cursors consistent with the cell indices.
+    pub fn new() -> Self {
+        Self::assert_capacity();
+        Self {
+            write: AtomicU32::new(0),
+            committed: AtomicU32::new(0),
+            capacity: N as u32,
+            reader_parked: AtomicU32::new(0),
+            writer_parked: AtomicU32::new(0),
+            cells: UnsafeCell::new([MaybeUninit::uninit(); N]),
+        }
+    }
+
+    pub(crate) fn assert_capacity() {
+        assert!(N.is_power_of_two() && N <= 1 << 31);
+    }
+
+    /// Write an empty buffer at `ptr`, e.g., in freshly mapped shared memory.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for writes and properly aligned, and stay so for `'a`.
+    pub unsafe fn init_at<'a>(ptr: *mut Self) -> &'a Self {
+        ptr.write(Self::new());
+        &*ptr
+    }
+
+    /// Block while the buffer is full.
+    ///
+    /// # Safety
+    ///
+    /// No other thread or process may write to this buffer concurrently.
+    pub unsafe fn write(&self, value: T) {
+        let mut value = value;
+        loop {
+            value = match unsafe { self.try_write(value) } {
+                Ok(()) => return,
+                Err(value) => value,
+            };
+            // Same protocol as `Reader::read`, with the roles swapped
+            self.writer_parked.store(1, Ordering::SeqCst);
+            let committed = self.committed.load(Ordering::SeqCst);
+            let write = self.write.load(Ordering::Relaxed);
+            if write.wrapping_sub(committed) == N as u32 {
+                wait(&self.committed, committed);
+            }
+            self.writer_parked.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Give `value` back if the buffer is full.
+    ///
+    /// # Safety
+    ///
+    /// Learn more from [`Self::write`].
+    pub unsafe fn try_write(&self, value: T) -> Result<(), T> {
+        // Only this writer moves `write`
+        let write = self.write.load(Ordering::Relaxed);
+        let committed = self.committed.load(Ordering::Acquire);
+        if write.wrapping_sub(committed) == N as u32 {
+            return Err(value);
+        }
+        unsafe { self.cell(write).write(MaybeUninit::new(value)) };
+        self.write.store(write.wrapping_add(1), Ordering::SeqCst);
+        if self.reader_parked.load(Ordering::SeqCst) != 0 {
+            wake(&self.write);
+        }
+        Ok(())
+    }
+
+    /// Resume reading from the last committed element.
+    ///
+    /// # Panic
+    ///
+    /// If `N` is not a valid capacity; learn more from [`Self::new`].
+    ///
+    /// # Safety
+    ///
+    /// No other [`Reader`] of this buffer may be alive in any process, until the returned one is dropped or its process dies.
+    pub unsafe fn attach_reader(&self) -> Result<Reader<'_, T, N>, CorruptCursor> {
+        Self::assert_capacity();
+        let write = self.write.load(Ordering::Acquire);
+        let committed = self.committed.load(Ordering::Acquire);
+        let stamped = self.capacity == N as u32 || self.capacity == 0;
+        if !stamped || (N as u32) < write.wrapping_sub(committed) {
+            return Err(CorruptCursor {
+                write,
+                committed,
+                capacity: self.capacity,
+            });
+        }
+        Ok(Reader {
+            buf: self,
+            claimed: committed,
+        })
+    }
+
+    /// Number of written elements not committed yet.
+    ///
+    /// Only a snapshot.
+    pub fn len(&self) -> usize {
+        let committed = self.committed.load(Ordering::Relaxed);
+        let write = self.write.load(Ordering::Relaxed);
+        write.wrapping_sub(committed) as usize
+    }
+
+    /// Only a snapshot.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn cell(&self, cursor: u32) -> *mut MaybeUninit<T> {
+        let cells = self.cells.get().cast::<MaybeUninit<T>>();
+        unsafe { cells.add(cursor as usize % N) }
+    }
+}
+impl<T: Copy, const N: usize> Default for SharedRingBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<T, const N: usize> std::fmt::Debug for SharedRingBuffer<T, N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedRingBuffer")
+            .field("write", &self.write)
+            .field("committed", &self.committed)
+            .field("capacity", &self.capacity)
+            .finish()
+    }
+}
+
+/// Reads ahead of the committed cursor; the cells read are only handed back to the writer on [`Self::commit`].
+#[derive(Debug)]
+pub struct Reader<'a, T, const N: usize> {
+    buf: &'a SharedRingBuffer<T, N>,
+    /// Local to this reader, so it is lost on a crash
+    claimed: u32,
+}
+impl<T: Copy, const N: usize> Reader<'_, T, N> {
+    /// Block while there is nothing to read.
+    pub fn read(&mut self) -> T {
+        loop {
+            if let Some(value) = self.try_read() {
+                return value;
+            }
+            // Register before sampling, both `SeqCst`, so that a writer publishing in between either sees the registration or is seen by the sample
+            self.buf.reader_parked.store(1, Ordering::SeqCst);
+            let write = self.buf.write.load(Ordering::SeqCst);
+            if write == self.claimed {
+                wait(&self.buf.write, write);
+            }
+            self.buf.reader_parked.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn try_read(&mut self) -> Option<T> {
+        let write = self.buf.write.load(Ordering::Acquire);
+        if write == self.claimed {
+            return None;
+        }
+        let value = unsafe { self.buf.cell(self.claimed).read().assume_init() };
+        self.claimed = self.claimed.wrapping_add(1);
+        Some(value)
+    }
+
+    /// Confirm that every element read so far has been processed, handing their cells back to the writer.
+    pub fn commit(&mut self) {
+        self.buf.committed.store(self.claimed, Ordering::SeqCst);
+        if self.buf.writer_parked.load(Ordering::SeqCst) != 0 {
+            wake(&self.buf.committed);
+        }
+    }
+
+    /// Number of elements read but not committed, which the next reader would read again if this one died now.
+    pub fn uncommitted(&self) -> u32 {
+        let committed = self.buf.committed.load(Ordering::Relaxed);
+        self.claimed.wrapping_sub(committed)
+    }
+}
+
+/// The cursors of a [`SharedRingBuffer`] are out of bounds, e.g., torn by a crash or overwritten by another process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorruptCursor {
+    pub write: u32,
+    pub committed: u32,
+    pub capacity: u32,
+}
+impl std::fmt::Display for CorruptCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "corrupt cursors: write {}, committed {}, capacity {}",
+            self.write, self.committed, self.capacity
+        )
+    }
+}
+impl std::error::Error for CorruptCursor {}
+
+fn wait(word: &AtomicU32, expected: u32) {
+    if let Err(e) = observed_futex_wait(
+        Primitive::RingBuffer,
+        FutexWaitContext {
+            word,
+            expected,
+            timeout: None,
+            scope: FutexScope::Shared,
+        },
+    ) {
+        if !matches!(e.error, FutexError::ValueMismatch) {
+            panic!("{e}");
+        }
+    }
+}
+
+fn wake(word: &AtomicU32) {
+    futex_wake_from(
+        Primitive::RingBuffer,
+        word,
+        WakeWaiters::ONE,
+        FutexScope::Shared,
+    )
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::{
+        sys::wait::{waitpid, WaitStatus},
+        unistd::{fork, ForkResult},
+    };
+    use rustix::mm::{mmap_anonymous, munmap, MapFlags, ProtFlags};
+
+    use super::*;
+
+    #[test]
+    fn test_blocking_write_read() {
+        let buf: SharedRingBuffer<u64, 4> = SharedRingBuffer::new();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..1000 {
+                    unsafe { buf.write(i) };
+                }
+            });
+            let mut reader = unsafe { buf.attach_reader() }.unwrap();
+            for i in 0..1000 {
+                assert_eq!(reader.read(), i);
+                if i % 3 == 0 {
+                    reader.commit();
+                }
+            }
+            reader.commit();
+        });
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_resume_after_reader_crash() {
+        type Buf = SharedRingBuffer<u64, 8>;
+        let size = std::mem::size_of::<Buf>();
+        let ptr = unsafe {
+            mmap_anonymous(
+                std::ptr::null_mut(),
+                size,
+                ProtFlags::READ | ProtFlags::WRITE,
+                MapFlags::SHARED,
+            )
+        }
+        .unwrap();
+        let buf = unsafe { Buf::init_at(ptr.cast()) };
+        for i in 0..6 {
+            unsafe { buf.write(i) };
+        }
+
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                // The parent only attaches once this reader died
+                let mut reader = unsafe { buf.attach_reader() }.unwrap();
+                let ok = (0..3).all(|i| reader.read() == i);
+                reader.commit();
+                // Read two more and die before committing them
+                let ok = ok && reader.read() == 3 && reader.read() == 4;
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+
+        // The two uncommitted elements are delivered again; nothing is lost
+        let mut reader = unsafe { buf.attach_reader() }.unwrap();
+        assert_eq!(buf.len(), 3);
+        assert_eq!((0..3).map(|_| reader.read()).collect::<Vec<_>>(), [3, 4, 5]);
+        assert_eq!(reader.uncommitted(), 3);
+        reader.commit();
+        assert!(buf.is_empty());
+
+        unsafe { munmap(ptr, size) }.unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_cursor() {
+        let buf: SharedRingBuffer<u8, 4> = SharedRingBuffer::new();
+        unsafe { buf.write(1) };
+        buf.committed.store(100, Ordering::SeqCst);
+        assert_eq!(
+            unsafe { buf.attach_reader() }.unwrap_err(),
+            CorruptCursor {
+                write: 1,
+                committed: 100,
+                capacity: 4
+            }
+        );
+    }
+}
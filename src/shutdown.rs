@@ -0,0 +1,184 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+use crate::{
+    composite::{composite_wait, WaitSource},
+    futex_wake,
+    observer::{futex_wake_from, Primitive},
+    FutexScope, WakeWaiters,
+};
+
+/// Release every thread blocked in a `*_or_shutdown` call, current and future, with a single [`Self::shutdown`].
+///
+/// Clones share the same futex word.
+///
+/// # Waking
+///
+/// A waiter sleeps on its primitive's futex word and the token's word at once with [`composite_wait`], so tripping the token is a single `FUTEX_WAKE` on the token's word.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownToken {
+    word: Arc<AtomicU32>,
+}
+impl ShutdownToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Idempotent.
+    pub fn shutdown(&self) {
+        if self.word.swap(1, Ordering::SeqCst) != 0 {
+            return;
+        }
+        futex_wake(&self.word, WakeWaiters::All).unwrap();
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.word.load(Ordering::SeqCst) != 0
+    }
+
+    pub(crate) fn word(&self) -> &AtomicU32 {
+        &self.word
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shutdown;
+impl std::fmt::Display for Shutdown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "shut down while blocking")
+    }
+}
+impl std::error::Error for Shutdown {}
+
+/// Sleep on `word` unless its value is not `expected`, until it is woken up in `scope` or `token` trips.
+///
+/// Like [`crate::futex_wait`], an [`Ok`] return can be a spurious wake-up.
+///
+/// A waiter giving up on shutdown after sleeping could have been woken on `word` rather than on the token, taking a wake the waiters of `primitive` that stay behind need; it passes one on to them.
+pub(crate) fn futex_wait_or_shutdown(
+    primitive: Primitive,
+    word: &AtomicU32,
+    expected: u32,
+    scope: FutexScope,
+    token: &ShutdownToken,
+) -> Result<(), Shutdown> {
+    if token.is_shutdown() {
+        return Err(Shutdown);
+    }
+    composite_wait(
+        &[
+            WaitSource::word_in(word, expected, scope),
+            WaitSource::shutdown(token),
+        ],
+        None,
+    );
+    if token.is_shutdown() {
+        futex_wake_from(primitive, word, WakeWaiters::ONE, scope).unwrap();
+        return Err(Shutdown);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{cond_var::CondVar, mutex::Mutex, semaphore::Semaphore};
+
+    use super::*;
+
+    const PATIENCE: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn test_shutdown_releases_waiter() {
+        let word = AtomicU32::new(0);
+        let token = ShutdownToken::new();
+        thread::scope(|s| {
+            let waiter = s.spawn(|| loop {
+                futex_wait_or_shutdown(Primitive::Event, &word, 0, FutexScope::Shared, &token)?;
+            });
+            thread::sleep(Duration::from_millis(50));
+            assert!(!waiter.is_finished());
+            token.clone().shutdown();
+            let res: Result<(), Shutdown> = waiter.join().unwrap();
+            assert_eq!(res, Err(Shutdown));
+        });
+        assert_eq!(
+            futex_wait_or_shutdown(Primitive::Event, &word, 0, FutexScope::Shared, &token),
+            Err(Shutdown)
+        );
+    }
+
+    /// Queue a shutdown waiter and then a plain waiter, and have `release` wake one of them while the token is tripped.
+    ///
+    /// The wake reaches the shutdown waiter, queued first, which has to pass it on to the plain waiter; `unblock` frees the plain waiter otherwise.
+    fn race_plain_waiter(
+        shutdown_waiter: impl FnOnce(&ShutdownToken) -> Result<(), Shutdown> + Send,
+        plain_waiter: impl FnOnce() + Send,
+        release: impl FnOnce(),
+        unblock: impl FnOnce(),
+    ) {
+        let token = ShutdownToken::new();
+        thread::scope(|s| {
+            let shutdown = s.spawn(|| shutdown_waiter(&token));
+            thread::sleep(Duration::from_millis(50));
+            let plain = s.spawn(plain_waiter);
+            thread::sleep(Duration::from_millis(50));
+            // Trip the token without waking anyone, so that the only wake is that of `release`
+            token.word().store(1, Ordering::SeqCst);
+            release();
+            let deadline = Instant::now() + PATIENCE;
+            while !plain.is_finished() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(1));
+            }
+            let passed_on = plain.is_finished();
+            if !passed_on {
+                unblock();
+            }
+            assert_eq!(shutdown.join().unwrap(), Err(Shutdown));
+            assert!(passed_on);
+        });
+    }
+
+    #[test]
+    fn test_mutex_wake_passed_on() {
+        let m = Mutex::new(());
+        let guard = m.lock();
+        race_plain_waiter(
+            |token| m.lock_or_shutdown(token).map(drop),
+            || drop(m.lock()),
+            || drop(guard),
+            || {
+                futex_wake(m.futex_word(), WakeWaiters::All).unwrap();
+            },
+        );
+    }
+
+    #[test]
+    fn test_semaphore_wake_passed_on() {
+        let sem = Semaphore::new(0);
+        race_plain_waiter(
+            |token| sem.wait_or_shutdown(token),
+            || sem.wait(),
+            || sem.signal(),
+            || sem.signal(),
+        );
+    }
+
+    #[test]
+    fn test_cond_var_wake_passed_on() {
+        let m = Mutex::new(());
+        let cond_var = CondVar::new();
+        race_plain_waiter(
+            |token| cond_var.wait_or_shutdown(m.lock(), token).map(drop),
+            || drop(cond_var.wait(m.lock())),
+            || cond_var.notify_one(),
+            || cond_var.notify_all(),
+        );
+    }
+}
@@ -0,0 +1,188 @@
+//! Waking a thread from a POSIX signal handler.
+//!
+//! # Async-signal safety
+//!
+//! A signal handler may only do what cannot deadlock or corrupt state if it interrupts the same thread mid-operation: no allocation, no locks, no panics, no formatting.
+//!
+//! - [`SignalSafeEvent::post`] is async-signal-safe: one atomic swap and at most one `FUTEX_WAKE`, whose failure is ignored.
+//! - [`crate::futex_wake`] on a word that outlives the handler is a single syscall whose error is built from the errno without allocating, so it is safe as long as the handler does not unwrap the result.
+//! - No release path of the other primitives is safe.
+//!   They panic on an unexpected wake failure, report to the process-wide observer, and may defer their wakes into a thread-local batch inside a [`crate::wake_scope`].
+//!   Some also take internal locks that the interrupted thread may be holding.
+//!
+//! Waiting is never signal-safe.
+//!
+//! With the `no-panic` feature, a release build fails to link if the optimizer cannot prove that [`SignalSafeEvent::post`] never panics:
+//!
+//! ```text
+//! cargo test --release --features no-panic
+//! ```
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::{
+    observer::{observed_futex_wait, Primitive},
+    FutexError, FutexScope, FutexWaitContext,
+};
+
+const POSTED: u32 = 1;
+
+/// An auto-reset event posted from a signal handler and waited on by a normal thread.
+///
+/// Posts that land before the waiter consumes them coalesce into one.
+#[derive(Debug, Default)]
+pub struct SignalSafeEvent {
+    word: AtomicU32,
+}
+impl SignalSafeEvent {
+    /// Usable in a `static` for the handler to reach.
+    pub const fn new() -> Self {
+        Self {
+            word: AtomicU32::new(0),
+        }
+    }
+
+    /// Async-signal-safe: never allocates, locks, or panics.
+    #[cfg_attr(feature = "no-panic", no_panic::no_panic)]
+    pub fn post(&self) {
+        if self.word.swap(POSTED, Ordering::Release) == POSTED {
+            return;
+        }
+        // Bypass `crate::futex_wake_ptr_in` and the observer, which are not signal-safe under test or with a hook
+        let _ = unsafe {
+            rustix::thread::futex(
+                self.word.as_ptr(),
+                rustix::thread::FutexOperation::Wake,
+                rustix::thread::FutexFlags::empty(),
+                1,
+                std::ptr::null(),     // ignored
+                std::ptr::null_mut(), // ignored
+                0,                    // ignored
+            )
+        };
+    }
+
+    /// Consume a post if there is one.
+    pub fn try_wait(&self) -> bool {
+        self.word.swap(0, Ordering::Acquire) == POSTED
+    }
+
+    /// Block until a post and consume it.
+    ///
+    /// Not to be called from a signal handler.
+    pub fn wait(&self) {
+        while !self.try_wait() {
+            if let Err(e) = observed_futex_wait(
+                Primitive::SignalSafeEvent,
+                FutexWaitContext {
+                    word: &self.word,
+                    expected: 0,
+                    timeout: None,
+                    scope: FutexScope::Shared,
+                },
+            ) {
+                if !matches!(e.error, FutexError::ValueMismatch) {
+                    panic!("{e}");
+                }
+            }
+        }
+    }
+}
+
+/// Blocks the signals of [`Self::block`] on the calling thread until dropped, restoring the previous mask even while unwinding.
+///
+/// Signals arriving meanwhile stay pending and are delivered once the mask is restored.
+pub(crate) struct MaskedSignals {
+    previous: libc::sigset_t,
+    /// The mask belongs to the thread that set it
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+impl MaskedSignals {
+    /// Learn more from [`crate::futex_wait_uninterruptible`].
+    pub(crate) const NEVER_BLOCKED: [libc::c_int; 12] = [
+        libc::SIGKILL,
+        libc::SIGSTOP,
+        libc::SIGSEGV,
+        libc::SIGBUS,
+        libc::SIGFPE,
+        libc::SIGILL,
+        libc::SIGTRAP,
+        libc::SIGSYS,
+        libc::SIGINT,
+        libc::SIGTERM,
+        libc::SIGQUIT,
+        libc::SIGHUP,
+    ];
+
+    /// Block every signal but [`Self::NEVER_BLOCKED`].
+    pub(crate) fn block() -> Self {
+        unsafe {
+            let mut set = std::mem::zeroed::<libc::sigset_t>();
+            libc::sigfillset(&mut set);
+            for signal in Self::NEVER_BLOCKED {
+                libc::sigdelset(&mut set, signal);
+            }
+            let mut previous = std::mem::zeroed::<libc::sigset_t>();
+            assert_eq!(
+                libc::pthread_sigmask(libc::SIG_BLOCK, &set, &mut previous),
+                0
+            );
+            Self {
+                previous,
+                _not_send: std::marker::PhantomData,
+            }
+        }
+    }
+}
+impl Drop for MaskedSignals {
+    fn drop(&mut self) {
+        let ret = unsafe {
+            libc::pthread_sigmask(libc::SIG_SETMASK, &self.previous, std::ptr::null_mut())
+        };
+        debug_assert_eq!(ret, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    static EVENT: SignalSafeEvent = SignalSafeEvent::new();
+
+    #[test]
+    fn test_post_from_handler() {
+        extern "C" fn post(_: libc::c_int) {
+            EVENT.post();
+        }
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = post as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            // `SIGUSR1` is taken by the mutex tests running alongside
+            assert_eq!(
+                libc::sigaction(libc::SIGUSR2, &action, std::ptr::null_mut()),
+                0
+            );
+        }
+
+        thread::scope(|s| {
+            let waiter = s.spawn(|| EVENT.wait());
+            thread::sleep(std::time::Duration::from_millis(20));
+            assert_eq!(unsafe { libc::raise(libc::SIGUSR2) }, 0);
+            waiter.join().unwrap();
+        });
+        assert!(!EVENT.try_wait());
+    }
+
+    #[test]
+    fn test_posts_coalesce() {
+        let event = SignalSafeEvent::new();
+        event.post();
+        event.post();
+        assert!(event.try_wait());
+        assert!(!event.try_wait());
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cond_var,
+    idle::{IdleStep, IdleStrategy},
+    mutex,
+    shutdown::{Shutdown, ShutdownToken},
+};
+
+/// A slot that is vacant, filled, or cancelled, where consumers can block until it is filled.
+///
+/// A contained value is dropped along with the slot.
+#[derive(Debug)]
+pub struct SlotCell<T> {
+    cond_var: cond_var::CondVar,
+    mutex: mutex::PinnedMutex<CellValue<T>>,
+}
+impl<T> SlotCell<T> {
+    pub fn new() -> Self {
+        Self {
+            cond_var: cond_var::CondVar::new(),
+            mutex: mutex::PinnedMutex::new(CellValue::Vacant),
+        }
+    }
+
+    /// Learn more from [`mutex::Mutex::new_private`] and [`cond_var::CondVar::new_private`].
+    pub fn new_private() -> Self {
+        Self {
+            cond_var: cond_var::CondVar::new_private(),
+            mutex: mutex::PinnedMutex::new_private(CellValue::Vacant),
+        }
+    }
+
+    /// Learn more from [`mutex::Mutex::new_pi`].
+    #[cfg(feature = "pi")]
+    pub fn new_pi() -> Self {
+        Self {
+            cond_var: cond_var::CondVar::new(),
+            mutex: mutex::PinnedMutex::new_pi(CellValue::Vacant),
+        }
+    }
+
+    /// Return the value back if the slot is not vacant.
+    pub fn put(&self, value: T) -> Result<(), T> {
+        let mut m = self.write();
+        if !m.locked().is_vacant() {
+            return Err(value);
+        }
+        **m.locked() = CellValue::Some(value);
+        Ok(())
+    }
+
+    pub fn try_take(&self) -> Option<T> {
+        self.mutex.lock().take()
+    }
+
+    /// Block until the slot is filled or cancelled.
+    pub fn take_blocking(&self, timeout: Option<Duration>) -> Result<T, SlotError> {
+        let deadline = timeout.and_then(|t| Instant::now().checked_add(t));
+        let mut m = self.mutex.lock();
+        loop {
+            match &*m {
+                CellValue::Some(_) => return Ok(m.take().unwrap()),
+                CellValue::Cancelled => return Err(SlotError::Cancelled),
+                CellValue::Vacant => (),
+            }
+            m = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(SlotError::TimedOut);
+                    }
+                    self.cond_var.wait_timeout(m, remaining).0
+                }
+                None => self.cond_var.wait(m),
+            };
+        }
+    }
+
+    /// Drop the contained value if any and fail all consumers, current and future, with [`SlotError::Cancelled`].
+    pub fn cancel(&self) {
+        let mut m = self.write();
+        **m.locked() = CellValue::Cancelled;
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        matches!(*self.mutex.lock(), CellValue::Cancelled)
+    }
+
+    /// Lock the slot and notify consumers on unlock.
+    pub(crate) fn write(&self) -> WriteGuard<'_, T> {
+        let m = self.mutex.lock();
+        WriteGuard {
+            locked: m,
+            cond_var: &self.cond_var,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Lock the slot without notifying consumers.
+    pub(crate) fn lock(&self) -> mutex::MutexGuard<'_, CellValue<T>> {
+        self.mutex.lock()
+    }
+
+    /// Park until the next [`WriteGuard`] of this slot is dropped.
+    ///
+    /// Could be a spurious wake-up
+    pub(crate) fn wait<'a>(
+        &'a self,
+        m: mutex::MutexGuard<'a, CellValue<T>>,
+    ) -> mutex::MutexGuard<'a, CellValue<T>> {
+        self.cond_var.wait(m)
+    }
+
+    /// [`Self::wait`] for at most `timeout`.
+    pub(crate) fn wait_timeout<'a>(
+        &'a self,
+        m: mutex::MutexGuard<'a, CellValue<T>>,
+        timeout: Duration,
+    ) -> mutex::MutexGuard<'a, CellValue<T>> {
+        self.cond_var.wait_timeout(m, timeout).0
+    }
+
+    /// Spin or yield with the slot unlocked, or park as in [`Self::wait`] for at most the strategy's park duration.
+    pub(crate) fn wait_idle<'a>(
+        &'a self,
+        m: mutex::MutexGuard<'a, CellValue<T>>,
+        idle: &mut IdleStrategy,
+    ) -> mutex::MutexGuard<'a, CellValue<T>> {
+        match idle.step() {
+            IdleStep::Spin => {
+                let mutex = m.unlock();
+                std::hint::spin_loop();
+                mutex.lock()
+            }
+            IdleStep::Yield => {
+                let mutex = m.unlock();
+                std::thread::yield_now();
+                mutex.lock()
+            }
+            IdleStep::Park(park) => self.cond_var.wait_timeout(m, park).0,
+        }
+    }
+
+    /// Learn more from [`cond_var::CondVar::wait_or_shutdown`].
+    pub(crate) fn wait_or_shutdown<'a>(
+        &'a self,
+        m: mutex::MutexGuard<'a, CellValue<T>>,
+        token: &ShutdownToken,
+    ) -> Result<mutex::MutexGuard<'a, CellValue<T>>, Shutdown> {
+        self.cond_var.wait_or_shutdown(m, token)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn waiters(&self) -> Option<usize> {
+        self.cond_var.waiters()
+    }
+}
+impl<T> Default for SlotCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotError {
+    TimedOut,
+    Cancelled,
+}
+impl std::fmt::Display for SlotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SlotError::TimedOut => write!(f, "timed out waiting for the slot"),
+            SlotError::Cancelled => write!(f, "slot cancelled"),
+        }
+    }
+}
+impl std::error::Error for SlotError {}
+
+#[derive(Debug)]
+pub enum CellValue<T> {
+    Vacant,
+    Some(T),
+    Cancelled,
+}
+impl<T> CellValue<T> {
+    pub fn take(&mut self) -> Option<T> {
+        match self {
+            CellValue::Vacant => return None,
+            CellValue::Some(_) => (),
+            CellValue::Cancelled => return None,
+        }
+        let a = std::mem::replace(self, Self::Vacant);
+        match a {
+            CellValue::Vacant => unreachable!(),
+            CellValue::Some(v) => Some(v),
+            CellValue::Cancelled => unreachable!(),
+        }
+    }
+
+    pub fn is_vacant(&self) -> bool {
+        match self {
+            CellValue::Vacant => true,
+            CellValue::Some(_) => false,
+            CellValue::Cancelled => false,
+        }
+    }
+}
+
+/// Not [`Send`], like the [`mutex::MutexGuard`] it wraps; spelled out so that the notifying drop stays on the locking thread whatever the fields become.
+pub(crate) struct WriteGuard<'a, T> {
+    locked: mutex::MutexGuard<'a, CellValue<T>>,
+    cond_var: &'a cond_var::CondVar,
+    _not_send: PhantomData<*const ()>,
+}
+impl<'a, T> WriteGuard<'a, T> {
+    pub fn locked(&mut self) -> &mut mutex::MutexGuard<'a, CellValue<T>> {
+        &mut self.locked
+    }
+}
+impl<T> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.cond_var.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_put_take() {
+        let slot = SlotCell::new();
+        thread::scope(|s| {
+            let consumer = s.spawn(|| slot.take_blocking(None));
+            thread::sleep(Duration::from_millis(50));
+            assert!(!consumer.is_finished());
+            slot.put(1).unwrap();
+            assert_eq!(consumer.join().unwrap(), Ok(1));
+        });
+
+        slot.put(2).unwrap();
+        assert_eq!(slot.put(3), Err(3));
+        assert_eq!(slot.try_take(), Some(2));
+        assert_eq!(slot.try_take(), None);
+        assert_eq!(
+            slot.take_blocking(Some(Duration::from_millis(10))),
+            Err(SlotError::TimedOut)
+        );
+    }
+
+    #[test]
+    fn test_cancel_wakes_consumer() {
+        let slot = SlotCell::<usize>::new();
+        thread::scope(|s| {
+            let consumer = s.spawn(|| slot.take_blocking(None));
+            thread::sleep(Duration::from_millis(50));
+            slot.cancel();
+            assert_eq!(consumer.join().unwrap(), Err(SlotError::Cancelled));
+        });
+        assert!(slot.is_cancelled());
+        assert_eq!(slot.put(1), Err(1));
+    }
+
+    #[test]
+    fn test_drop_contained_value() {
+        struct Counted(Arc<AtomicUsize>);
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let slot = SlotCell::new();
+        assert!(slot.put(Counted(drops.clone())).is_ok());
+        drop(slot);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+
+        let slot = SlotCell::new();
+        assert!(slot.put(Counted(drops.clone())).is_ok());
+        slot.cancel();
+        assert_eq!(drops.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,359 @@
+use std::{
+    marker::PhantomData,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    observer::{futex_wake_from, observed_futex_wait, Primitive},
+    violation::violation,
+    FutexError, FutexScope, FutexTimeout, FutexWaitContext, TimeoutMeasure, WakeWaiters,
+};
+
+/// Set in the futex word once poisoned; no state of `E` may encode with it.
+const POISONED: u32 = 1 << 31;
+
+/// A state stored in a futex word that threads can block on.
+///
+/// `E` is usually a small unit enum, like the lifecycle of a component.
+/// The lock words of the crate, e.g., [`crate::mutex::State`], stay outside of it: their transitions are swaps that wake one waiter at most, not validated moves that wake all.
+///
+/// # Poisoning
+///
+/// A controller that panics halfway between two states would leave the waiters blocked for good.
+/// [`Self::poison`], or a [`PoisonOnPanic`] guard dropped while panicking, marks the machine poisoned and wakes them; every wait and transition then fails with [`StateError::Poisoned`] until [`Self::clear_poison`].
+/// [`Self::set`] keeps the poison.
+#[derive(Debug)]
+pub struct FutexStateMachine<E> {
+    word: AtomicU32,
+    _state: PhantomData<E>,
+}
+impl<E> FutexStateMachine<E>
+where
+    E: Into<u32> + TryFrom<u32> + Copy + PartialEq,
+{
+    /// # Panic
+    ///
+    /// If `initial` encodes with the top bit set, which marks poisoning.
+    pub fn new(initial: E) -> Self {
+        Self {
+            word: AtomicU32::new(encode(initial)),
+            _state: PhantomData,
+        }
+    }
+
+    /// The state, poisoned or not.
+    ///
+    /// # Panic
+    ///
+    /// If the futex word is not any of `E`.
+    pub fn get(&self) -> E {
+        decode(self.word.load(Ordering::Acquire))
+    }
+
+    /// Move from `from` to `to` and wake all waiters.
+    ///
+    /// Fail with the actual state if it is not `from`, or if poisoned.
+    ///
+    /// # Panic
+    ///
+    /// If `to` encodes with the top bit set.
+    pub fn transition(&self, from: E, to: E) -> Result<(), StateError<E>> {
+        if let Err(actual) = self.word.compare_exchange(
+            encode(from),
+            encode(to),
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            return Err(StateError::from_word(actual));
+        }
+        self.wake_all();
+        Ok(())
+    }
+
+    /// Move to `to` unconditionally and wake all waiters.
+    ///
+    /// # Panic
+    ///
+    /// If `to` encodes with the top bit set.
+    pub fn set(&self, to: E) {
+        let to = encode(to);
+        self.word
+            .fetch_update(Ordering::Release, Ordering::Relaxed, |word| {
+                Some(word & POISONED | to)
+            })
+            .unwrap();
+        self.wake_all();
+    }
+
+    /// Block until the state is `state`.
+    ///
+    /// Fail with the state observed on timeout, or once poisoned.
+    pub fn wait_for(&self, state: E, timeout: Option<Duration>) -> Result<(), StateError<E>> {
+        self.wait_until(|s| s == state, timeout).map(|_| ())
+    }
+
+    /// Block until the state is no longer `state`.
+    ///
+    /// Return the new state; fail with `state` on timeout, or with the state observed once poisoned.
+    pub fn wait_while(&self, state: E, timeout: Option<Duration>) -> Result<E, StateError<E>> {
+        self.wait_until(|s| s != state, timeout)
+    }
+
+    fn wait_until(
+        &self,
+        mut done: impl FnMut(E) -> bool,
+        timeout: Option<Duration>,
+    ) -> Result<E, StateError<E>> {
+        let deadline = timeout.and_then(|t| Instant::now().checked_add(t));
+        loop {
+            let word = self.word.load(Ordering::Acquire);
+            let state = decode(word);
+            if word & POISONED != 0 {
+                return Err(StateError::Poisoned(state));
+            }
+            if done(state) {
+                return Ok(state);
+            }
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(StateError::Actual(state));
+                    }
+                    Some(FutexTimeout::For(remaining, TimeoutMeasure::MonoTime))
+                }
+                None => None,
+            };
+            if let Err(e) = observed_futex_wait(
+                Primitive::StateMachine,
+                FutexWaitContext {
+                    word: &self.word,
+                    expected: word,
+                    timeout,
+                    scope: FutexScope::Shared,
+                },
+            ) {
+                if !matches!(e.error, FutexError::ValueMismatch | FutexError::TimedOut) {
+                    panic!("{e}");
+                }
+            }
+        }
+    }
+}
+impl<E> FutexStateMachine<E> {
+    /// Mark the machine poisoned and wake all waiters, leaving the state as it is.
+    pub fn poison(&self) {
+        self.word.fetch_or(POISONED, Ordering::Release);
+        self.wake_all();
+    }
+
+    /// Only a snapshot.
+    pub fn is_poisoned(&self) -> bool {
+        self.word.load(Ordering::Acquire) & POISONED != 0
+    }
+
+    /// Take the machine back into use, in whatever state it was poisoned.
+    pub fn clear_poison(&self) {
+        // The waiters all left when it was poisoned
+        self.word.fetch_and(!POISONED, Ordering::Release);
+    }
+
+    /// Poison the machine if this thread panics before the guard is dropped, e.g., around the work a controller does between two transitions.
+    ///
+    /// A thread already panicking when it takes the guard does not poison.
+    pub fn poison_on_panic(&self) -> PoisonOnPanic<'_, E> {
+        PoisonOnPanic {
+            machine: self,
+            panicking: std::thread::panicking(),
+        }
+    }
+
+    fn wake_all(&self) {
+        futex_wake_from(
+            Primitive::StateMachine,
+            &self.word,
+            WakeWaiters::All,
+            FutexScope::Shared,
+        )
+        .unwrap();
+    }
+}
+
+/// Learn more from [`FutexStateMachine::poison_on_panic`].
+#[must_use = "if unused the guard will immediately be dropped without poisoning"]
+#[derive(Debug)]
+pub struct PoisonOnPanic<'a, E> {
+    machine: &'a FutexStateMachine<E>,
+    panicking: bool,
+}
+impl<E> Drop for PoisonOnPanic<'_, E> {
+    fn drop(&mut self) {
+        if !self.panicking && std::thread::panicking() {
+            self.machine.poison();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError<E> {
+    /// The state was not the expected one, or the wait timed out in it
+    Actual(E),
+    /// The machine is poisoned in this state
+    Poisoned(E),
+}
+impl<E: TryFrom<u32>> StateError<E> {
+    fn from_word(word: u32) -> Self {
+        match word & POISONED {
+            0 => Self::Actual(decode(word)),
+            _ => Self::Poisoned(decode(word)),
+        }
+    }
+}
+impl<E: std::fmt::Debug> std::fmt::Display for StateError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Actual(state) => write!(f, "state is {state:?}"),
+            Self::Poisoned(state) => write!(f, "state machine poisoned in {state:?}"),
+        }
+    }
+}
+impl<E: std::fmt::Debug> std::error::Error for StateError<E> {}
+
+fn encode<E: Into<u32>>(state: E) -> u32 {
+    let word = state.into();
+    assert!(word & POISONED == 0, "state {word:#x} uses the poison bit");
+    word
+}
+
+fn decode<E: TryFrom<u32>>(word: u32) -> E {
+    let word = word & !POISONED;
+    match E::try_from(word) {
+        Ok(state) => state,
+        Err(_) => violation!(UnknownState, word),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Lifecycle {
+        Init = 0,
+        Running,
+        Stopped,
+    }
+    impl From<Lifecycle> for u32 {
+        fn from(value: Lifecycle) -> Self {
+            value as u32
+        }
+    }
+    impl TryFrom<u32> for Lifecycle {
+        type Error = ();
+        fn try_from(value: u32) -> Result<Self, Self::Error> {
+            [Lifecycle::Init, Lifecycle::Running, Lifecycle::Stopped]
+                .into_iter()
+                .find(|s| u32::from(*s) == value)
+                .ok_or(())
+        }
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let state = FutexStateMachine::new(Lifecycle::Init);
+        thread::scope(|s| {
+            let running = s.spawn(|| state.wait_for(Lifecycle::Running, None));
+            let left_init = s.spawn(|| state.wait_while(Lifecycle::Init, None));
+            let stopped = s.spawn(|| state.wait_for(Lifecycle::Stopped, None));
+
+            thread::sleep(Duration::from_millis(50));
+            state
+                .transition(Lifecycle::Init, Lifecycle::Running)
+                .unwrap();
+            running.join().unwrap().unwrap();
+            assert_eq!(left_init.join().unwrap(), Ok(Lifecycle::Running));
+            assert!(!stopped.is_finished());
+
+            state.set(Lifecycle::Stopped);
+            stopped.join().unwrap().unwrap();
+        });
+        assert_eq!(state.get(), Lifecycle::Stopped);
+    }
+
+    #[test]
+    fn test_invalid_transition() {
+        let state = FutexStateMachine::new(Lifecycle::Init);
+        assert_eq!(
+            state.transition(Lifecycle::Running, Lifecycle::Stopped),
+            Err(StateError::Actual(Lifecycle::Init))
+        );
+        assert_eq!(state.get(), Lifecycle::Init);
+    }
+
+    #[test]
+    fn test_wait_timeout() {
+        let state = FutexStateMachine::new(Lifecycle::Init);
+        let start = Instant::now();
+        assert_eq!(
+            state.wait_for(Lifecycle::Running, Some(Duration::from_millis(50))),
+            Err(StateError::Actual(Lifecycle::Init))
+        );
+        assert!(Duration::from_millis(50) <= start.elapsed());
+    }
+
+    #[test]
+    fn test_poisoned_on_panic() {
+        let state = FutexStateMachine::new(Lifecycle::Init);
+        let release = std::sync::Barrier::new(2);
+        thread::scope(|s| {
+            let controller = s.spawn(|| {
+                let _poison = state.poison_on_panic();
+                state
+                    .transition(Lifecycle::Init, Lifecycle::Running)
+                    .unwrap();
+                release.wait();
+                panic!("controller failed while running");
+            });
+            state.wait_for(Lifecycle::Running, None).unwrap();
+            let stopped = s.spawn(|| state.wait_for(Lifecycle::Stopped, None));
+            let left_running = s.spawn(|| state.wait_while(Lifecycle::Running, None));
+            thread::sleep(Duration::from_millis(50));
+            release.wait();
+            assert!(controller.join().is_err());
+            // Woken instead of waiting for a stop that never comes
+            assert_eq!(
+                stopped.join().unwrap(),
+                Err(StateError::Poisoned(Lifecycle::Running))
+            );
+            assert_eq!(
+                left_running.join().unwrap(),
+                Err(StateError::Poisoned(Lifecycle::Running))
+            );
+        });
+        assert!(state.is_poisoned());
+        assert_eq!(state.get(), Lifecycle::Running);
+        assert_eq!(
+            state.transition(Lifecycle::Running, Lifecycle::Stopped),
+            Err(StateError::Poisoned(Lifecycle::Running))
+        );
+
+        // The poison outlives an unconditional move, until cleared
+        state.set(Lifecycle::Stopped);
+        assert!(state.is_poisoned());
+        state.clear_poison();
+        assert_eq!(state.wait_for(Lifecycle::Stopped, None), Ok(()));
+        state
+            .transition(Lifecycle::Stopped, Lifecycle::Init)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_no_poison_without_panic() {
+        let state = FutexStateMachine::new(Lifecycle::Init);
+        drop(state.poison_on_panic());
+        assert!(!state.is_poisoned());
+    }
+}
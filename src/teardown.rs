@@ -0,0 +1,87 @@
+//! Tolerating wakes on memory shared between processes while it is being unmapped.
+//!
+//! A process waking a primitive in a mapping that another party is tearing down can race the unmap, and the `FUTEX_WAKE` then fails with `EFAULT`.
+//! Normally that is a bug and the primitives panic on it; between [`begin_teardown`] and the drop of its [`Teardown`], they count it in [`ignored_faults`] and carry on as if no waiter was woken.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of live [`Teardown`]s
+static TEARDOWNS: AtomicUsize = AtomicUsize::new(0);
+static IGNORED_FAULTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Mark the process as tearing down shared mappings until the returned [`Teardown`] is dropped.
+///
+/// Process-wide: the wakes of every primitive in the process tolerate `EFAULT` meanwhile.
+pub fn begin_teardown() -> Teardown {
+    TEARDOWNS.fetch_add(1, Ordering::SeqCst);
+    Teardown { _private: () }
+}
+
+/// Learn more from [`begin_teardown`].
+#[derive(Debug)]
+#[must_use = "if unused the teardown will immediately end"]
+pub struct Teardown {
+    _private: (),
+}
+impl Drop for Teardown {
+    fn drop(&mut self) {
+        TEARDOWNS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn is_tearing_down() -> bool {
+    TEARDOWNS.load(Ordering::SeqCst) != 0
+}
+
+/// Number of wakes that failed with `EFAULT` during a teardown and were ignored.
+///
+/// Only a snapshot.
+pub fn ignored_faults() -> usize {
+    IGNORED_FAULTS.load(Ordering::Relaxed)
+}
+
+/// Return `true` if the failed wake is to be ignored, recording it.
+pub(crate) fn ignore_wake_error(e: &crate::FutexError) -> bool {
+    if e.raw_os_error() != Some(libc::EFAULT) || !is_tearing_down() {
+        return false;
+    }
+    IGNORED_FAULTS.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cond_var::CondVar, mock_backend::with_failing_wakes, semaphore::Semaphore};
+
+    use super::*;
+
+    #[test]
+    fn test_fault_ignored_only_during_teardown() {
+        // Slow variants wake without checking for waiters
+        let sem = Semaphore::new_slow(0);
+        let cv = CondVar::new_slow();
+
+        let panic = with_failing_wakes(libc::EFAULT, || sem.signal()).unwrap_err();
+        assert!(panic
+            .downcast_ref::<String>()
+            .unwrap()
+            .contains("Bad address"));
+
+        let teardown = begin_teardown();
+        let before = ignored_faults();
+        with_failing_wakes(libc::EFAULT, || {
+            sem.signal();
+            cv.notify_all();
+        })
+        .unwrap();
+        // Other tests never fault
+        assert_eq!(ignored_faults() - before, 2);
+        // Any other error is still fatal
+        assert!(with_failing_wakes(libc::EINVAL, || cv.notify_all()).is_err());
+        drop(teardown);
+
+        assert!(with_failing_wakes(libc::EFAULT, || cv.notify_all()).is_err());
+        // A failed wake still leaves the permit deposited
+        assert_eq!(sem.available_permits(), 2);
+    }
+}
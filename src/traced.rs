@@ -0,0 +1,275 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{cond_var, mutex, semaphore};
+
+/// Receives the timings recorded by the traced primitives.
+///
+/// Callbacks are invoked outside of any critical section of the traced primitive.
+pub trait SyncObserver {
+    fn observe(&self, event: TracedEvent);
+}
+impl<O: SyncObserver + ?Sized> SyncObserver for &O {
+    fn observe(&self, event: TracedEvent) {
+        (**self).observe(event)
+    }
+}
+impl<O: SyncObserver + ?Sized> SyncObserver for Arc<O> {
+    fn observe(&self, event: TracedEvent) {
+        (**self).observe(event)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TracedEvent {
+    /// Time spent acquiring the mutex
+    MutexWait(Duration),
+    /// Time the mutex was held before it got released
+    MutexHold(Duration),
+    /// Time spent in [`semaphore::Semaphore::wait`]
+    SemaphoreWait(Duration),
+    /// Time spent in [`cond_var::CondVar::wait`], including relocking the mutex
+    CondVarWait(Duration),
+}
+
+#[derive(Debug)]
+pub struct TracedMutex<T, O> {
+    mutex: mutex::Mutex<T>,
+    observer: O,
+}
+impl<T, O: SyncObserver> TracedMutex<T, O> {
+    pub fn new(value: T, observer: O) -> Self {
+        Self {
+            mutex: mutex::Mutex::new(value),
+            observer,
+        }
+    }
+
+    pub fn lock(&self) -> TracedMutexGuard<'_, T, O> {
+        let start = Instant::now();
+        let guard = self.mutex.lock();
+        let acquired = Instant::now();
+        self.observer
+            .observe(TracedEvent::MutexWait(acquired - start));
+        TracedMutexGuard {
+            guard: Some(guard),
+            acquired,
+            observer: &self.observer,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<TracedMutexGuard<'_, T, O>> {
+        let guard = self.mutex.try_lock()?;
+        Some(TracedMutexGuard {
+            guard: Some(guard),
+            acquired: Instant::now(),
+            observer: &self.observer,
+        })
+    }
+
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    pub fn into_inner(self) -> T {
+        self.mutex.into_inner()
+    }
+}
+
+pub struct TracedMutexGuard<'a, T, O: SyncObserver> {
+    /// Always [`Some`] until dropped
+    guard: Option<mutex::MutexGuard<'a, T>>,
+    acquired: Instant,
+    observer: &'a O,
+}
+impl<T, O: SyncObserver> Drop for TracedMutexGuard<'_, T, O> {
+    fn drop(&mut self) {
+        let Some(guard) = self.guard.take() else {
+            return;
+        };
+        let held = self.acquired.elapsed();
+        guard.unlock();
+        self.observer.observe(TracedEvent::MutexHold(held));
+    }
+}
+impl<T, O: SyncObserver> Deref for TracedMutexGuard<'_, T, O> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.guard.as_ref().unwrap()
+    }
+}
+impl<T, O: SyncObserver> DerefMut for TracedMutexGuard<'_, T, O> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+#[derive(Debug)]
+pub struct TracedSemaphore<O> {
+    semaphore: semaphore::Semaphore,
+    observer: O,
+}
+impl<O: SyncObserver> TracedSemaphore<O> {
+    pub fn new(value: u32, observer: O) -> Self {
+        Self {
+            semaphore: semaphore::Semaphore::new(value),
+            observer,
+        }
+    }
+
+    pub fn wait(&self) {
+        let start = Instant::now();
+        self.semaphore.wait();
+        self.observer
+            .observe(TracedEvent::SemaphoreWait(start.elapsed()));
+    }
+
+    pub fn signal(&self) {
+        self.semaphore.signal();
+    }
+
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+}
+
+/// Reports to the observer of the [`TracedMutex`] it waits on.
+#[derive(Debug, Default)]
+pub struct TracedCondVar {
+    cond_var: cond_var::CondVar,
+}
+impl TracedCondVar {
+    pub fn new() -> Self {
+        Self {
+            cond_var: cond_var::CondVar::new(),
+        }
+    }
+
+    /// Could be a spurious wake-up
+    ///
+    /// The hold time up to this call is reported on unlock, and a new hold period starts after relocking.
+    pub fn wait<'a, T, O: SyncObserver>(
+        &self,
+        mut m: TracedMutexGuard<'a, T, O>,
+    ) -> TracedMutexGuard<'a, T, O> {
+        let observer = m.observer;
+        let start = Instant::now();
+        let held = m.acquired.elapsed();
+        let guard = m.guard.take().unwrap();
+        let guard = self.cond_var.wait(guard);
+        let acquired = Instant::now();
+        observer.observe(TracedEvent::MutexHold(held));
+        observer.observe(TracedEvent::CondVarWait(acquired - start));
+        TracedMutexGuard {
+            guard: Some(guard),
+            acquired,
+            observer,
+        }
+    }
+
+    pub fn notify_one(&self) {
+        self.cond_var.notify_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.cond_var.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex as StdMutex, thread};
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Recorder {
+        events: StdMutex<Vec<TracedEvent>>,
+    }
+    impl SyncObserver for Recorder {
+        fn observe(&self, event: TracedEvent) {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    #[test]
+    fn test_long_hold() {
+        let recorder = Recorder::default();
+        let m = TracedMutex::new(0, &recorder);
+        thread::scope(|s| {
+            let mut guard = m.lock();
+            s.spawn(|| {
+                *m.lock() += 1;
+            });
+            thread::sleep(Duration::from_millis(200));
+            *guard += 1;
+        });
+        assert_eq!(m.into_inner(), 2);
+
+        let events = recorder.events.lock().unwrap();
+        let max_hold = events
+            .iter()
+            .filter_map(|e| match e {
+                TracedEvent::MutexHold(d) => Some(*d),
+                _ => None,
+            })
+            .max()
+            .unwrap();
+        let max_wait = events
+            .iter()
+            .filter_map(|e| match e {
+                TracedEvent::MutexWait(d) => Some(*d),
+                _ => None,
+            })
+            .max()
+            .unwrap();
+        assert!(Duration::from_millis(200) <= max_hold);
+        assert!(max_hold < Duration::from_secs(10));
+        assert!(Duration::from_millis(100) <= max_wait);
+    }
+
+    #[test]
+    fn test_cond_var_and_semaphore() {
+        let recorder = Recorder::default();
+        let m = TracedMutex::new(false, &recorder);
+        let cv = TracedCondVar::new();
+        let sem = TracedSemaphore::new(0, &recorder);
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(100));
+                *m.lock() = true;
+                cv.notify_one();
+                thread::sleep(Duration::from_millis(100));
+                sem.signal();
+            });
+            let mut ready = m.lock();
+            while !*ready {
+                ready = cv.wait(ready);
+            }
+            drop(ready);
+            sem.wait();
+        });
+
+        let events = recorder.events.lock().unwrap();
+        let cond_var_wait: Duration = events
+            .iter()
+            .filter_map(|e| match e {
+                TracedEvent::CondVarWait(d) => Some(*d),
+                _ => None,
+            })
+            .sum();
+        let semaphore_wait = events
+            .iter()
+            .find_map(|e| match e {
+                TracedEvent::SemaphoreWait(d) => Some(*d),
+                _ => None,
+            })
+            .unwrap();
+        assert!(Duration::from_millis(50) <= cond_var_wait);
+        assert!(Duration::from_millis(50) <= semaphore_wait);
+    }
+}
@@ -0,0 +1,164 @@
+//! What happens when a futex word is found breaking its primitive's protocol, e.g., written to by a misbehaving process sharing it.
+//!
+//! Chosen at compile time by cargo features:
+//!
+//! - `violation-panic` (default): panic.
+//! - `violation-abort`: print the violation and abort the process; takes precedence over the others.
+//! - `violation-error`: the checked APIs (e.g., [`crate::mutex::unlock_checked`]) return [`ProtocolViolation`] instead; everywhere else still panics.
+//!
+//! The checked APIs return [`ViolationKind::UnknownState`] under every policy, since reporting it is what they are for.
+
+/// A futex word broke its primitive's protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolViolation {
+    pub kind: ViolationKind,
+    /// The offending value of the futex word
+    pub word: u64,
+}
+impl std::fmt::Display for ProtocolViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            ViolationKind::UnknownState => "unknown state",
+            ViolationKind::DoubleUnlock => "unlocked while not locked",
+            ViolationKind::Overflow => "counter overflow",
+        };
+        write!(f, "protocol violation: {kind}: {}", self.word)
+    }
+}
+impl std::error::Error for ProtocolViolation {}
+impl From<crate::futex_enum::UnknownState> for ProtocolViolation {
+    fn from(value: crate::futex_enum::UnknownState) -> Self {
+        Self {
+            kind: ViolationKind::UnknownState,
+            word: value.0.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// The word holds none of the states of the primitive.
+    UnknownState,
+    /// The word was unlocked while it was not locked.
+    DoubleUnlock,
+    /// A count in the word would overflow.
+    Overflow,
+}
+
+/// `violation!(kind, word)` panics or aborts according to the policy.
+///
+/// `violation!(kind, word, return)` returns the [`ProtocolViolation`] as an error from the enclosing function instead under `violation-error`.
+macro_rules! violation {
+    ($kind:ident, $word:expr) => {
+        $crate::violation::violated($crate::violation::ProtocolViolation {
+            kind: $crate::violation::ViolationKind::$kind,
+            word: $word.into(),
+        })
+    };
+    ($kind:ident, $word:expr, return) => {{
+        let violation = $crate::violation::ProtocolViolation {
+            kind: $crate::violation::ViolationKind::$kind,
+            word: $word.into(),
+        };
+        if cfg!(all(
+            feature = "violation-error",
+            not(feature = "violation-abort")
+        )) {
+            return Err(violation.into());
+        }
+        $crate::violation::violated(violation)
+    }};
+}
+pub(crate) use violation;
+
+#[cold]
+#[inline(never)]
+pub(crate) fn violated(violation: ProtocolViolation) -> ! {
+    if cfg!(feature = "violation-abort") {
+        eprintln!("{violation}");
+        std::process::abort();
+    }
+    panic!("{violation}");
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mutex::{new_unlocked_futex, unlock, unlock_checked};
+
+    use super::*;
+
+    #[test]
+    fn test_checked_unknown_state_under_every_policy() {
+        let word = std::sync::atomic::AtomicU32::new(7);
+        assert_eq!(
+            unlock_checked(&word, None),
+            Err(ProtocolViolation {
+                kind: ViolationKind::UnknownState,
+                word: 7
+            })
+        );
+    }
+
+    #[cfg(not(feature = "violation-abort"))]
+    mod panic {
+        use super::*;
+
+        #[test]
+        #[should_panic(expected = "protocol violation: unlocked while not locked: 0")]
+        fn test_double_unlock_panics() {
+            unlock(&new_unlocked_futex(), None);
+        }
+
+        #[cfg(not(feature = "violation-error"))]
+        #[test]
+        #[should_panic(expected = "protocol violation: unlocked while not locked: 0")]
+        fn test_checked_double_unlock_panics() {
+            let _ = unlock_checked(&new_unlocked_futex(), None);
+        }
+    }
+
+    #[cfg(all(feature = "violation-error", not(feature = "violation-abort")))]
+    mod error {
+        use super::*;
+
+        #[test]
+        fn test_checked_double_unlock_errs() {
+            assert_eq!(
+                unlock_checked(&new_unlocked_futex(), None),
+                Err(ProtocolViolation {
+                    kind: ViolationKind::DoubleUnlock,
+                    word: 0
+                })
+            );
+        }
+    }
+
+    #[cfg(feature = "violation-abort")]
+    mod abort {
+        use nix::{
+            sys::{
+                signal::Signal,
+                wait::{waitpid, WaitStatus},
+            },
+            unistd::{fork, ForkResult},
+        };
+
+        use super::*;
+
+        #[test]
+        fn test_double_unlock_aborts() {
+            match unsafe { fork() }.unwrap() {
+                ForkResult::Child => {
+                    unlock(&new_unlocked_futex(), None);
+                    unsafe { libc::_exit(0) };
+                }
+                ForkResult::Parent { child } => {
+                    assert!(matches!(
+                        waitpid(child, None).unwrap(),
+                        WaitStatus::Signaled(_, Signal::SIGABRT, _)
+                    ));
+                }
+            }
+        }
+    }
+}
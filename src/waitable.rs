@@ -0,0 +1,245 @@
+//! Things a thread can block on, behind one trait for generic code such as supervisors and test harnesses.
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use futex::{
+//!     deadline::Deadline, event::Event, semaphore::Semaphore, shutdown::ShutdownToken,
+//!     waitable::{wait_any, Waitable},
+//! };
+//!
+//! let work = Semaphore::new(0);
+//! let paused = Event::new();
+//! let token = ShutdownToken::new();
+//! token.shutdown();
+//! let waitables: [&dyn Waitable<Output = ()>; 3] = [&work, &paused, &token];
+//! let ready = wait_any(&waitables, Deadline::after(Duration::from_secs(1)));
+//! assert_eq!(ready, Ok(2));
+//! ```
+
+use std::time::{Duration, Instant};
+
+use crate::{
+    composite::{composite_wait, WaitSource},
+    deadline::Deadline,
+    event::Event,
+    ring_buffer::{RecvError, RingBuffer},
+    semaphore::Semaphore,
+    shutdown::ShutdownToken,
+};
+
+/// How often [`wait_any`] polls a [`Waitable`] without a [`Waitable::wait_source`].
+pub const POLL_SLICE: Duration = Duration::from_millis(10);
+
+pub trait Waitable {
+    /// What a successful wait hands over, e.g., an element, or `()` for a permit or a signal.
+    type Output;
+
+    /// Block until ready or until `deadline` passes, and consume what the wait is for, e.g., a permit.
+    ///
+    /// A passed `deadline` still succeeds if the waitable is ready already.
+    fn wait_deadline(&self, deadline: Deadline) -> Result<Self::Output, WaitError>;
+
+    /// A futex word for [`wait_any`] to sleep on, which changes once this is probably ready.
+    ///
+    /// Without one, [`wait_any`] polls this every [`POLL_SLICE`].
+    fn wait_source(&self) -> Option<WaitSource<'_>> {
+        None
+    }
+}
+
+impl Waitable for Event {
+    type Output = ();
+
+    fn wait_deadline(&self, deadline: Deadline) -> Result<(), WaitError> {
+        self.wait_timeout(deadline.remaining())
+            .then_some(())
+            .ok_or(WaitError::TimedOut)
+    }
+
+    fn wait_source(&self) -> Option<WaitSource<'_>> {
+        Some(WaitSource::event(self))
+    }
+}
+
+/// Takes a permit.
+impl Waitable for Semaphore {
+    type Output = ();
+
+    fn wait_deadline(&self, deadline: Deadline) -> Result<(), WaitError> {
+        Semaphore::wait_deadline(self, deadline).map_err(|_| WaitError::TimedOut)
+    }
+
+    // Polled: a composite waiter counted among the semaphore's waiters could absorb a wake meant for one that takes the permit
+}
+
+/// Ready once tripped.
+impl Waitable for ShutdownToken {
+    type Output = ();
+
+    fn wait_deadline(&self, deadline: Deadline) -> Result<(), WaitError> {
+        while !self.is_shutdown() {
+            if deadline.is_expired() {
+                return Err(WaitError::TimedOut);
+            }
+            composite_wait(&[WaitSource::shutdown(self)], Some(deadline.instant()));
+        }
+        Ok(())
+    }
+
+    fn wait_source(&self) -> Option<WaitSource<'_>> {
+        Some(WaitSource::shutdown(self))
+    }
+}
+
+/// Reads an element.
+impl<T, const N: usize> Waitable for RingBuffer<T, N> {
+    type Output = T;
+
+    fn wait_deadline(&self, deadline: Deadline) -> Result<T, WaitError> {
+        self.read_timeout(deadline.remaining())
+            .map_err(|e| match e {
+                RecvError::Empty | RecvError::TimedOut => WaitError::TimedOut,
+                RecvError::Disconnected => WaitError::Disconnected,
+            })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    TimedOut,
+    /// Nothing will ever be ready, e.g., a closed and drained [`RingBuffer`]
+    Disconnected,
+}
+impl std::fmt::Display for WaitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WaitError::TimedOut => write!(f, "deadline reached while blocking"),
+            WaitError::Disconnected => write!(f, "disconnected while blocking"),
+        }
+    }
+}
+impl std::error::Error for WaitError {}
+
+/// Wait on all of `waitables` at once and consume from the first ready one; return its index, the lowest one if several are ready.
+///
+/// Sleeps with [`composite_wait`] on the [`Waitable::wait_source`]s, waking every [`POLL_SLICE`] if some waitables have none.
+///
+/// # Panic
+///
+/// If `waitables` is empty or holds more than [`crate::composite::MAX_SOURCES`].
+pub fn wait_any(
+    waitables: &[&dyn Waitable<Output = ()>],
+    deadline: Deadline,
+) -> Result<usize, WaitError> {
+    assert!(!waitables.is_empty());
+    assert!(waitables.len() <= crate::composite::MAX_SOURCES);
+    loop {
+        // Taken before polling, so that a change after the poll cuts the sleep short
+        let sources = waitables
+            .iter()
+            .filter_map(|waitable| waitable.wait_source())
+            .collect::<Vec<_>>();
+        let now = Deadline::at(Instant::now());
+        for (i, waitable) in waitables.iter().enumerate() {
+            match waitable.wait_deadline(now) {
+                Ok(()) => return Ok(i),
+                Err(WaitError::TimedOut) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        if deadline.is_expired() {
+            return Err(WaitError::TimedOut);
+        }
+        let until = match sources.len() < waitables.len() {
+            true => deadline.min(Deadline::after(POLL_SLICE)),
+            false => deadline,
+        };
+        if sources.is_empty() {
+            std::thread::sleep(until.remaining());
+            continue;
+        }
+        composite_wait(&sources, Some(until.instant()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    /// Times out while not ready, then hands over what `make_ready` produced.
+    fn check<W: Waitable + Sync>(waitable: &W, make_ready: impl FnOnce() + Send) -> W::Output
+    where
+        W::Output: Send,
+    {
+        assert_eq!(
+            waitable
+                .wait_deadline(Deadline::after(Duration::from_millis(10)))
+                .err(),
+            Some(WaitError::TimedOut)
+        );
+        thread::scope(|s| {
+            let waiter =
+                s.spawn(|| waitable.wait_deadline(Deadline::after(Duration::from_secs(10))));
+            thread::sleep(Duration::from_millis(10));
+            make_ready();
+            waiter.join().unwrap().ok().unwrap()
+        })
+    }
+
+    #[test]
+    fn test_generic_waitables() {
+        let event = Event::new();
+        check(&event, || event.set());
+
+        let sem = Semaphore::new(0);
+        check(&sem, || sem.signal());
+        assert_eq!(sem.available_permits(), 0);
+
+        let ring_buf = RingBuffer::<usize, 3>::new();
+        assert_eq!(check(&ring_buf, || ring_buf.write_override(7)), 7);
+        ring_buf.close();
+        assert_eq!(
+            ring_buf.wait_deadline(Deadline::after(Duration::from_secs(10))),
+            Err(WaitError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn test_wait_any() {
+        let sem = Semaphore::new(0);
+        let event = Event::new();
+        let token = ShutdownToken::new();
+        let waitables: [&dyn Waitable<Output = ()>; 3] = [&sem, &event, &token];
+        assert_eq!(
+            wait_any(&waitables, Deadline::after(Duration::from_millis(10))),
+            Err(WaitError::TimedOut)
+        );
+
+        thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                let waitables: [&dyn Waitable<Output = ()>; 3] = [&sem, &event, &token];
+                wait_any(&waitables, Deadline::after(Duration::from_secs(10)))
+            });
+            thread::sleep(Duration::from_millis(10));
+            event.set();
+            assert_eq!(waiter.join().unwrap(), Ok(1));
+        });
+
+        // Polled without a source, and only the first ready one is consumed
+        sem.signal();
+        token.shutdown();
+        assert_eq!(wait_any(&waitables, Deadline::after(Duration::ZERO)), Ok(0));
+        assert_eq!(sem.available_permits(), 0);
+        event.reset();
+        thread::scope(|s| {
+            let waiter = s.spawn(|| wait_any(&[&sem], Deadline::after(Duration::from_secs(10))));
+            thread::sleep(Duration::from_millis(10));
+            sem.signal();
+            assert_eq!(waiter.join().unwrap(), Ok(0));
+        });
+        assert_eq!(wait_any(&waitables, Deadline::after(Duration::ZERO)), Ok(2));
+    }
+}
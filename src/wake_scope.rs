@@ -0,0 +1,216 @@
+use std::{cell::RefCell, sync::atomic::AtomicU32};
+
+use crate::{futex_wake_ptr_in, FutexScope, WakeWaiters, U31};
+
+/// Distinct words a scope defers before flushing early
+const MAX_DEFERRED: usize = 64;
+
+thread_local! {
+    /// [`Some`] while inside a [`wake_scope`]
+    static BATCH: RefCell<Option<Vec<Deferred>>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Deferred {
+    /// Only an address; the primitive could be gone by the flush
+    word: *mut u32,
+    waiters: WakeWaiters,
+    /// Always the same for a word, as its waiters only pair with wakes of their scope
+    scope: FutexScope,
+}
+
+/// Run `f`, deferring the wake syscalls the crate's primitives issue on this thread until it returns.
+///
+/// The deferred wakes are then issued as one syscall per futex word, with the amounts merged.
+///
+/// Deferral only delays wake-ups:
+///
+/// - The batch is flushed before this thread sleeps on any primitive, so it never waits for a thread it has yet to wake.
+/// - The batch is flushed early once it holds 64 distinct words.
+/// - The batch is flushed if `f` unwinds.
+///
+/// Within the scope, the wake-ups are not counted: e.g., [`crate::cond_var::CondVar::notify_n`] returns `0`.
+/// A nested scope joins the outermost one.
+///
+/// ```
+/// use futex::{semaphore::Semaphore, wake_scope};
+///
+/// let sem = Semaphore::new(0);
+/// wake_scope(|| {
+///     for _ in 0..10 {
+///         sem.signal();
+///     }
+/// });
+/// assert_eq!(sem.available_permits(), 10);
+/// ```
+pub fn wake_scope<R>(f: impl FnOnce() -> R) -> R {
+    let outermost = BATCH.with_borrow_mut(|batch| {
+        if batch.is_some() {
+            return false;
+        }
+        *batch = Some(vec![]);
+        true
+    });
+    if !outermost {
+        return f();
+    }
+
+    /// Flush even if `f` unwinds
+    struct End;
+    impl Drop for End {
+        fn drop(&mut self) {
+            if let Some(batch) = BATCH.take() {
+                wake(batch);
+            }
+        }
+    }
+    let _end = End;
+    f()
+}
+
+/// Queue the wake if inside a [`wake_scope`].
+///
+/// Return `false` if the caller has to wake now.
+pub(crate) fn defer(word: &AtomicU32, waiters: WakeWaiters, scope: FutexScope) -> bool {
+    BATCH.with_borrow_mut(|batch| {
+        let Some(batch) = batch else {
+            return false;
+        };
+        if let Some(deferred) = batch.iter_mut().find(|d| d.word == word.as_ptr()) {
+            deferred.waiters = merge(deferred.waiters, waiters);
+            return true;
+        }
+        if batch.len() == MAX_DEFERRED {
+            wake(std::mem::take(batch));
+        }
+        batch.push(Deferred {
+            word: word.as_ptr(),
+            waiters,
+            scope,
+        });
+        true
+    })
+}
+
+/// Issue the wakes deferred so far; called before sleeping.
+pub(crate) fn flush() {
+    let batch = BATCH.with_borrow_mut(|batch| batch.as_mut().map(std::mem::take));
+    if let Some(batch) = batch {
+        wake(batch);
+    }
+}
+
+fn wake(batch: Vec<Deferred>) {
+    for deferred in batch {
+        // A primitive dropped since its wake was deferred fails this with `EFAULT`, or at worst wakes whoever reuses the address spuriously
+        let _ = unsafe { futex_wake_ptr_in(deferred.word, deferred.waiters, deferred.scope) };
+    }
+}
+
+fn merge(a: WakeWaiters, b: WakeWaiters) -> WakeWaiters {
+    match (a, b) {
+        (WakeWaiters::Amount(a), WakeWaiters::Amount(b)) => {
+            WakeWaiters::Amount(U31::clamping(a.get() as usize + b.get() as usize))
+        }
+        _ => WakeWaiters::All,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use crate::{
+        cond_var::CondVar, mock_backend::WAKE_SYSCALLS, mutex::Mutex, semaphore::Semaphore,
+    };
+
+    use super::*;
+
+    /// Release 3 parked semaphore waiters one signal at a time and 3 condition variable waiters one notification at a time.
+    ///
+    /// Return the number of wake syscalls issued.
+    fn release(scoped: bool) -> usize {
+        let sem = Semaphore::new(0);
+        let m = Mutex::new(false);
+        let cv = CondVar::new();
+        thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| sem.wait());
+                s.spawn(|| {
+                    let mut released = m.lock();
+                    while !*released {
+                        released = cv.wait(released);
+                    }
+                });
+            }
+            while sem.waiters() != Some(3) || cv.waiters() != Some(3) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            // Let them all fall asleep, so that no wake-up is absorbed by a waiter still on its way
+            thread::sleep(Duration::from_millis(50));
+            *m.lock() = true;
+
+            let before = WAKE_SYSCALLS.get();
+            let ops = || {
+                for _ in 0..3 {
+                    sem.signal();
+                    cv.notify_one();
+                }
+            };
+            if scoped {
+                wake_scope(ops);
+            } else {
+                ops();
+            }
+            WAKE_SYSCALLS.get() - before
+        })
+    }
+
+    #[test]
+    fn test_scope_merges_wakes() {
+        assert_eq!(release(false), 6);
+        assert_eq!(release(true), 2);
+    }
+
+    #[test]
+    fn test_flush_before_sleep() {
+        let ping = Semaphore::new(0);
+        let pong = Semaphore::new(0);
+        thread::scope(|s| {
+            s.spawn(|| {
+                ping.wait();
+                pong.signal();
+            });
+            while ping.waiters() != Some(1) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            // Would deadlock if the wake of `ping` were held back until the scope ends
+            wake_scope(|| {
+                ping.signal();
+                pong.wait();
+            });
+        });
+    }
+
+    #[test]
+    fn test_bounded_batch() {
+        let words: Vec<AtomicU32> = (0..MAX_DEFERRED + 1).map(|_| AtomicU32::new(0)).collect();
+        wake_scope(|| {
+            let before = WAKE_SYSCALLS.get();
+            for word in &words {
+                assert!(defer(word, WakeWaiters::All, FutexScope::Shared));
+            }
+            assert_eq!(WAKE_SYSCALLS.get() - before, MAX_DEFERRED);
+            // Nested scopes join
+            wake_scope(|| {
+                assert!(defer(
+                    &words[MAX_DEFERRED],
+                    WakeWaiters::All,
+                    FutexScope::Shared
+                ))
+            });
+            assert_eq!(BATCH.with_borrow(|batch| batch.as_ref().unwrap().len()), 1);
+        });
+        assert!(!defer(&words[0], WakeWaiters::All, FutexScope::Shared));
+    }
+}
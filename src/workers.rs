@@ -0,0 +1,154 @@
+//! Scoped threads sharing the crate's primitives by reference, for tests.
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use futex::{semaphore::Semaphore, workers::ScopedWorkers};
+//!
+//! let sem = Semaphore::new(0);
+//! let parked = futex::scope(|s| {
+//!     let workers = ScopedWorkers::spawn_n(s, 4, |i| {
+//!         sem.wait();
+//!         i
+//!     });
+//!     for _ in 0..4 {
+//!         sem.signal();
+//!     }
+//!     workers.join_timeout(Duration::from_secs(10))
+//! });
+//! assert_eq!(parked, [0, 1, 2, 3]);
+//! ```
+
+use std::{
+    any::Any,
+    thread::{self, Scope, ScopedJoinHandle},
+    time::{Duration, Instant},
+};
+
+/// [`std::thread::scope`]: every thread spawned on the scope is joined before it returns, so the threads can borrow primitives from the enclosing stack frame instead of sharing them through an [`std::sync::Arc`].
+pub fn scope<'env, F, R>(f: F) -> R
+where
+    F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+{
+    thread::scope(f)
+}
+
+/// A fixed set of threads spawned on a [`Scope`].
+#[derive(Debug)]
+#[must_use = "workers are only checked for panics and hangs when joined"]
+pub struct ScopedWorkers<'scope, T> {
+    handles: Vec<ScopedJoinHandle<'scope, T>>,
+}
+impl<'scope, T: Send + 'scope> ScopedWorkers<'scope, T> {
+    /// Spawn `n` threads on `scope`, the `i`-th running `f(i)`.
+    pub fn spawn_n<'env, F>(scope: &'scope Scope<'scope, 'env>, n: usize, f: F) -> Self
+    where
+        F: Fn(usize) -> T + Clone + Send + 'scope,
+    {
+        let handles = (0..n)
+            .map(|i| {
+                let f = f.clone();
+                scope.spawn(move || f(i))
+            })
+            .collect();
+        Self { handles }
+    }
+
+    /// Join all the workers and return their results in spawn order.
+    ///
+    /// # Panics
+    ///
+    /// Panic with the messages of all the workers that panicked.
+    ///
+    /// Abort the process if any worker is still running after `timeout`, since the enclosing scope would otherwise block on it forever.
+    /// Under the `registry` feature, the states of the registered primitives are printed first.
+    pub fn join_timeout(self, timeout: Duration) -> Vec<T> {
+        let deadline = Instant::now().checked_add(timeout);
+        while !self.handles.iter().all(|h| h.is_finished()) {
+            if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                let running = self.handles.iter().filter(|h| !h.is_finished()).count();
+                hung(running, self.handles.len(), timeout);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let mut results = vec![];
+        let mut panics = vec![];
+        for (i, handle) in self.handles.into_iter().enumerate() {
+            match handle.join() {
+                Ok(result) => results.push(result),
+                Err(payload) => panics.push(format!("worker {i}: {}", message(&*payload))),
+            }
+        }
+        if !panics.is_empty() {
+            panic!(
+                "{} of {} workers panicked:\n{}",
+                panics.len(),
+                panics.len() + results.len(),
+                panics.join("\n")
+            );
+        }
+        results
+    }
+}
+
+#[cold]
+fn hung(running: usize, total: usize, timeout: Duration) -> ! {
+    eprintln!("{running} of {total} workers still running after {timeout:?}");
+    #[cfg(feature = "registry")]
+    {
+        let _ = crate::registry::dump(&mut std::io::stderr());
+    }
+    std::process::abort();
+}
+
+pub(crate) fn message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        return s;
+    }
+    if let Some(s) = payload.downcast_ref::<String>() {
+        return s;
+    }
+    "<non-string panic payload>"
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        barrier::Barrier, mailbox::Mailbox, ring_buffer::RingBuffer, semaphore::Semaphore,
+    };
+
+    use super::*;
+
+    /// Shareable by reference across scoped threads without an [`std::sync::Arc`]
+    #[test]
+    fn test_primitives_are_sync() {
+        fn assert_sync<T: Sync>() {}
+        // Not `Sync` themselves
+        type Item = std::cell::Cell<u32>;
+        assert_sync::<RingBuffer<Item, 3>>();
+        assert_sync::<Mailbox<Item>>();
+        assert_sync::<crate::mutex::Mutex<Item>>();
+        assert_sync::<Semaphore>();
+        assert_sync::<Barrier>();
+    }
+
+    #[test]
+    fn test_panics_collected() {
+        let panic = std::panic::catch_unwind(|| {
+            scope(|s| {
+                ScopedWorkers::spawn_n(s, 3, |i| {
+                    if i != 1 {
+                        panic!("boom {i}");
+                    }
+                })
+                .join_timeout(Duration::from_secs(10))
+            })
+        })
+        .unwrap_err();
+        let message = message(&*panic);
+        assert!(message.starts_with("2 of 3 workers panicked"));
+        assert!(message.contains("worker 0: boom 0"));
+        assert!(message.contains("worker 2: boom 2"));
+    }
+}
@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+use futex::ipc::{
+    demo::{alternating_counters, AlternatingCounters, Role},
+    SharedMapping,
+};
+use nix::{
+    sys::wait::{waitpid, WaitStatus},
+    unistd::{fork, ForkResult},
+};
+
+#[test]
+fn test_alternating_counters_across_fork() {
+    const ITERATIONS: u32 = 1_000;
+    // A lost wake-up fails both processes instead of hanging them
+    const WATCHDOG: Option<Duration> = Some(Duration::from_secs(5));
+    let region = SharedMapping::new(AlternatingCounters::new()).unwrap();
+
+    match unsafe { fork() }.unwrap() {
+        ForkResult::Child => {
+            let res = alternating_counters(&region, Role::B, ITERATIONS, WATCHDOG, |_| ());
+            unsafe { libc::_exit(i32::from(res.is_err())) };
+        }
+        ForkResult::Parent { child } => {
+            let res = alternating_counters(&region, Role::A, ITERATIONS, WATCHDOG, |_| ());
+            assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            res.unwrap();
+        }
+    }
+    assert_eq!(region.counts(), [ITERATIONS, ITERATIONS]);
+    assert_eq!(region.out_of_order(), 0);
+}
@@ -0,0 +1,53 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use futex::{
+    cond_var::CondVar,
+    mutex::Mutex,
+    ring_buffer::RingBuffer,
+    semaphore::Semaphore,
+    shutdown::{Shutdown, ShutdownToken},
+};
+
+#[test]
+fn test_shutdown_releases_all_primitives() {
+    let token = ShutdownToken::new();
+    let mutex = Mutex::new(());
+    let semaphore = Semaphore::new(0);
+    let cond_var = CondVar::new();
+    let cond_var_mutex = Mutex::new(());
+    let ring_buf = RingBuffer::<usize, 3>::new();
+
+    let held = mutex.lock();
+    thread::scope(|s| {
+        let waiters = [
+            s.spawn(|| mutex.lock_or_shutdown(&token).map(|_| ())),
+            s.spawn(|| semaphore.wait_or_shutdown(&token)),
+            s.spawn(|| {
+                let m = cond_var_mutex.lock();
+                cond_var.wait_or_shutdown(m, &token).map(|_| ())
+            }),
+            s.spawn(|| ring_buf.read_or_shutdown(&token).map(|_| ())),
+        ];
+        thread::sleep(Duration::from_millis(100));
+        assert!(waiters.iter().all(|w| !w.is_finished()));
+
+        let start = Instant::now();
+        token.clone().shutdown();
+        for waiter in waiters {
+            assert_eq!(waiter.join().unwrap(), Err(Shutdown));
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+    });
+    drop(held);
+
+    // The primitives stay usable without the token
+    drop(mutex.lock());
+    drop(cond_var_mutex.lock());
+    semaphore.signal();
+    semaphore.wait();
+    ring_buf.write_override(1);
+    assert_eq!(ring_buf.read(), Ok(1));
+}